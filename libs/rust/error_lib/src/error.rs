@@ -15,6 +15,8 @@
 // Library error_lib creates a generic error type for rust
 // This allows simplified chaining of error callbacks using the ? operator
 
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug)]
 pub enum SgeError {
     IO(std::io::Error),
@@ -55,6 +57,30 @@ impl From<std::fmt::Error> for SgeError {
     }
 }
 
+impl From<std::num::ParseIntError> for SgeError {
+    fn from(e: std::num::ParseIntError) -> Self {
+        SgeError::StdErr(Box::new(e))
+    }
+}
+
+impl From<std::str::Utf8Error> for SgeError {
+    fn from(e: std::str::Utf8Error) -> Self {
+        SgeError::StdErr(Box::new(e))
+    }
+}
+
+impl From<std::string::FromUtf8Error> for SgeError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        SgeError::StdErr(Box::new(e))
+    }
+}
+
+impl From<regex::Error> for SgeError {
+    fn from(e: regex::Error) -> Self {
+        SgeError::StdErr(Box::new(e))
+    }
+}
+
 impl From<std::ffi::NulError> for SgeError {
     fn from(_: std::ffi::NulError) -> Self {
         SgeError::Literal("Null error")
@@ -82,7 +108,7 @@ impl std::error::Error for SgeError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match *self {
             SgeError::IO(ref e) => Some(e),
-            SgeError::StdErr(_) => None,
+            SgeError::StdErr(ref e) => Some(e.as_ref()),
             SgeError::Literal(_) => None,
             SgeError::Message(_) => None,
         }
@@ -93,7 +119,7 @@ impl std::fmt::Display for SgeError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match *self {
             SgeError::IO(ref e) => e.fmt(f),
-            SgeError::StdErr(_) => write!(f, "std err"),
+            SgeError::StdErr(ref e) => e.fmt(f),
             SgeError::Literal(ref lit) => write!(f, "{}", lit),
             SgeError::Message(ref msg) => write!(f, "{}", msg),
         }
@@ -123,6 +149,57 @@ impl PartialEq for SgeError {
     }
 }
 
+// SgeError as data, for services returning errors over json rpc, rebuilt as an SgeError by clients
+// code is io, std_err, literal or message, causes are the messages of the errors it chains
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SgeErrorView {
+    pub code: String,
+    pub message: String,
+    pub causes: Vec<String>,
+}
+
+impl From<&SgeError> for SgeErrorView {
+    fn from(e: &SgeError) -> Self {
+        use std::error::Error;
+        let (code, message, mut source) = match e {
+            SgeError::IO(e) => ("io", e.to_string(), e.source()),
+            SgeError::StdErr(e) => ("std_err", e.to_string(), e.source()),
+            SgeError::Literal(lit) => ("literal", lit.to_string(), None),
+            SgeError::Message(msg) => ("message", msg.clone(), None),
+        };
+        let mut causes = Vec::new();
+        while let Some(cause) = source {
+            causes.push(cause.to_string());
+            source = cause.source();
+        }
+        SgeErrorView {
+            code: code.to_string(),
+            message,
+            causes,
+        }
+    }
+}
+
+// the error of the other side, of the variant its code names, as a message followed by its causes
+// literals and unknown codes come back as messages
+impl From<SgeErrorView> for SgeError {
+    // io::Error::other needs rust 1.74, the toolchain is 1.48
+    #[allow(clippy::io_other_error)]
+    fn from(view: SgeErrorView) -> Self {
+        let mut message = view.message;
+        for cause in &view.causes {
+            message.push_str(": ");
+            message.push_str(cause);
+        }
+        match view.code.as_str() {
+            "io" => SgeError::IO(std::io::Error::new(std::io::ErrorKind::Other, message)),
+            "std_err" => SgeError::StdErr(message.into()),
+            _ => SgeError::Message(message),
+        }
+    }
+}
+
 pub fn err_logged<T>(msg: &'static str) -> Result<T, &'static str> {
     println!("{}", msg);
     Err(msg)
@@ -26,3 +26,92 @@ fn test_err() {
         assert_ne!(e0, e1);
     }
 }
+
+#[test]
+fn test_err_conversions() {
+    fn parse(text: &str) -> SgeResult<u32> {
+        Ok(text.parse::<u32>()?)
+    }
+    assert_eq!(parse("37"), Ok(37));
+    let e = parse("x37").unwrap_err();
+    assert_eq!(e.to_string(), "invalid digit found in string");
+    // the original error is kept, as the source
+    use std::error::Error;
+    assert!(e
+        .source()
+        .and_then(|s| s.downcast_ref::<std::num::ParseIntError>())
+        .is_some());
+
+    let bytes = vec![0xff, 0xfe];
+    let e = SgeError::from(std::str::from_utf8(&bytes).unwrap_err());
+    assert!(e.to_string().contains("invalid utf-8"));
+    let e = SgeError::from(String::from_utf8(bytes).unwrap_err());
+    assert!(e.to_string().contains("invalid utf-8"));
+
+    let pattern = String::from("(unclosed");
+    let e = SgeError::from(regex::Regex::new(&pattern).unwrap_err());
+    assert!(e.to_string().contains("unclosed group"));
+}
+
+#[derive(Debug)]
+struct Cause;
+
+impl std::fmt::Display for Cause {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "disk on fire")
+    }
+}
+
+impl std::error::Error for Cause {}
+
+#[derive(Debug)]
+struct Failure(Cause);
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "sync failed")
+    }
+}
+
+impl std::error::Error for Failure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+#[test]
+fn test_err_view() {
+    let e = SgeError::from("punk");
+    let view = SgeErrorView::from(&e);
+    assert_eq!(view.code, "literal");
+    assert_eq!(view.message, "punk");
+    assert!(view.causes.is_empty());
+
+    let e = SgeError::IO(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        Failure(Cause),
+    ));
+    let view = SgeErrorView::from(&e);
+    assert_eq!(view.code, "io");
+    assert_eq!(view.message, "sync failed");
+    assert_eq!(view.causes, vec!["disk on fire".to_string()]);
+
+    // through json, as services return it
+    let json = serde_json::to_string(&view).unwrap();
+    assert_eq!(
+        json,
+        r#"{"code":"io","message":"sync failed","causes":["disk on fire"]}"#
+    );
+    let view: SgeErrorView = serde_json::from_str(&json).unwrap();
+    let e = SgeError::from(view);
+    assert!(matches!(e, SgeError::IO(_)));
+    assert_eq!(e.to_string(), "sync failed: disk on fire");
+
+    let e = SgeError::StdErr(Box::new(Failure(Cause)));
+    let e = SgeError::from(SgeErrorView::from(&e));
+    assert!(matches!(e, SgeError::StdErr(_)));
+    assert_eq!(e.to_string(), "sync failed: disk on fire");
+
+    let e = SgeError::from(SgeErrorView::from(&SgeError::from("punk")));
+    assert_eq!(e, SgeError::Message("punk".to_string()));
+}
@@ -0,0 +1,134 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module change_watcher polls perforce for newly submitted changes
+// each poll runs p4 changes over the range after the last change seen, so none are missed however many arrive
+// the last seen changelist can be saved and restored, so tools can resume where they stopped
+
+use crate::{Change, PerforceTrait};
+use error_lib::*;
+
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+
+pub struct ChangeWatcher<T: PerforceTrait> {
+    p4: T,
+    path: String,
+    interval: Duration,
+    max_changes: u32,
+    last_seen: Option<u32>,
+}
+
+impl<T: PerforceTrait> ChangeWatcher<T> {
+    // New creates a watcher over path (eg //some-depot/...), an empty path watches everything
+    pub fn new(p4: T, path: &str) -> Self {
+        ChangeWatcher {
+            p4,
+            path: path.into(),
+            interval: Duration::from_secs(60),
+            max_changes: 100,
+            last_seen: None,
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    // WithMaxChanges limits how many changes are delivered per poll, the oldest first, later polls deliver the rest
+    pub fn with_max_changes(mut self, max_changes: u32) -> Self {
+        self.max_changes = max_changes;
+        self
+    }
+
+    // WithLastSeen resumes watching after the given changelist
+    pub fn with_last_seen(mut self, changelist: u32) -> Self {
+        self.last_seen = Some(changelist);
+        self
+    }
+
+    pub fn last_seen(&self) -> Option<u32> {
+        self.last_seen
+    }
+
+    // P4 is the perforce interface the watcher polls through
+    pub fn p4(&self) -> &T {
+        &self.p4
+    }
+
+    // Poll queries perforce once, returning submitted changes newer than the last seen, oldest first
+    // without a last seen changelist, the first poll only records the most recent change as baseline
+    pub fn poll(&mut self) -> SgeResult<Vec<Change>> {
+        let path = if self.path.is_empty() {
+            "//..."
+        } else {
+            &self.path
+        };
+        let last_seen = match self.last_seen {
+            Some(l) => l,
+            None => {
+                let changes = self.p4.changes(&["-m", "1", "-s", "submitted", path])?;
+                self.last_seen = changes.iter().map(|c| c.changelist).max();
+                return Ok(Vec::new());
+            }
+        };
+
+        let range = format!("{}@{},@now", path, last_seen + 1);
+        let mut changes = self.p4.changes(&["-s", "submitted", &range])?;
+        changes.sort_by_key(|c| c.changelist);
+        changes.retain(|c| c.changelist > last_seen);
+        changes.truncate(self.max_changes as usize);
+        if let Some(c) = changes.last() {
+            self.last_seen = Some(c.changelist);
+        }
+        Ok(changes)
+    }
+
+    // Run polls on the interval, passing each result to callback until it returns false
+    pub fn run<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(SgeResult<Vec<Change>>) -> bool,
+    {
+        loop {
+            if !callback(self.poll()) {
+                return;
+            }
+            thread::sleep(self.interval);
+        }
+    }
+}
+
+impl<T: PerforceTrait + Send + 'static> ChangeWatcher<T> {
+    // Spawn moves the watcher onto its own thread, delivering new changes over a channel
+    // poll errors are passed to on_error on that thread and retried on the next interval
+    // the thread exits once the receiver is dropped
+    pub fn spawn<E>(mut self, mut on_error: E) -> Receiver<Change>
+    where
+        E: FnMut(SgeError) + Send + 'static,
+    {
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            self.run(|result| match result {
+                Ok(changes) => changes.into_iter().all(|c| sender.send(c).is_ok()),
+                Err(e) => {
+                    on_error(e);
+                    true
+                }
+            })
+        });
+        receiver
+    }
+}
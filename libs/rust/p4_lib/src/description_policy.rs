@@ -0,0 +1,233 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module description_policy enforces changelist description hygiene
+// a template lists the tagged sections a description carries (eg "Bug:", "Test:")
+// descriptions are validated against the template, and can be rewrapped to its line length
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TemplateSection {
+    pub name: String,
+    pub required: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DescriptionTemplate {
+    pub sections: Vec<TemplateSection>,
+    // zero disables line length checks and rewrapping
+    pub max_line_length: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ViolationKind {
+    EmptyDescription,
+    MissingSummary,
+    MissingSection,
+    EmptySection,
+    LineTooLong,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    // section the violation refers to, empty if not section specific
+    pub section: String,
+    // 1 based line within the description, 0 if not line specific
+    pub line: usize,
+    pub message: String,
+}
+
+impl DescriptionTemplate {
+    // Standard returns the template used by presubmit: required Bug and Test sections
+    pub fn standard() -> Self {
+        DescriptionTemplate {
+            sections: vec![
+                TemplateSection {
+                    name: "Bug".into(),
+                    required: true,
+                },
+                TemplateSection {
+                    name: "Test".into(),
+                    required: true,
+                },
+            ],
+            max_line_length: 100,
+        }
+    }
+
+    // Render creates an empty description skeleton, ready for the author to fill in
+    pub fn render(&self, summary: &str) -> String {
+        let mut out = summary.to_string();
+        out += "\n";
+        for s in &self.sections {
+            out += &format!("\n{}: ", s.name);
+        }
+        out
+    }
+
+    // Validate checks the description against the template, returning every violation found
+    pub fn validate(&self, description: &str) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let lines: Vec<&str> = description.lines().collect();
+
+        let first = lines.iter().position(|l| !l.trim().is_empty());
+        let first = match first {
+            Some(f) => f,
+            None => {
+                violations.push(Violation {
+                    kind: ViolationKind::EmptyDescription,
+                    section: "".into(),
+                    line: 0,
+                    message: "description is empty".into(),
+                });
+                return violations;
+            }
+        };
+        if self.section_match(lines[first]).is_some() {
+            violations.push(Violation {
+                kind: ViolationKind::MissingSummary,
+                section: "".into(),
+                line: first + 1,
+                message: "description must start with a summary line".into(),
+            });
+        }
+
+        for s in &self.sections {
+            match self.section_find(&lines, &s.name) {
+                Some((line, value)) => {
+                    if value.is_empty() {
+                        violations.push(Violation {
+                            kind: ViolationKind::EmptySection,
+                            section: s.name.clone(),
+                            line: line + 1,
+                            message: format!("section {}: has no content", s.name),
+                        });
+                    }
+                }
+                None => {
+                    if s.required {
+                        violations.push(Violation {
+                            kind: ViolationKind::MissingSection,
+                            section: s.name.clone(),
+                            line: 0,
+                            message: format!("required section {}: is missing", s.name),
+                        });
+                    }
+                }
+            }
+        }
+
+        if self.max_line_length > 0 {
+            for (i, l) in lines.iter().enumerate() {
+                // lines without whitespace (eg long urls) can't be wrapped, so they are let through
+                let length = l.chars().count();
+                if length > self.max_line_length && l.trim().contains(char::is_whitespace) {
+                    violations.push(Violation {
+                        kind: ViolationKind::LineTooLong,
+                        section: "".into(),
+                        line: i + 1,
+                        message: format!(
+                            "line is {} characters, limit is {}",
+                            length, self.max_line_length
+                        ),
+                    });
+                }
+            }
+        }
+
+        violations
+    }
+
+    // Format rewraps free text paragraphs to the template line length
+    // section lines, bullet points and indented lines are kept as they are
+    pub fn format(&self, description: &str) -> String {
+        let mut out: Vec<String> = Vec::new();
+        let mut paragraph: Vec<&str> = Vec::new();
+        for line in description.lines() {
+            let trimmed = line.trim();
+            let verbatim = trimmed.is_empty()
+                || self.section_match(line).is_some()
+                || trimmed.starts_with("- ")
+                || trimmed.starts_with("* ")
+                || line.starts_with(char::is_whitespace);
+            if verbatim {
+                out.extend(self.paragraph_wrap(&paragraph));
+                paragraph.clear();
+                out.push(line.trim_end().into());
+            } else {
+                paragraph.push(trimmed);
+            }
+        }
+        out.extend(self.paragraph_wrap(&paragraph));
+        out.join("\n")
+    }
+
+    fn paragraph_wrap(&self, paragraph: &[&str]) -> Vec<String> {
+        let mut lines = Vec::new();
+        if paragraph.is_empty() {
+            return lines;
+        }
+        if self.max_line_length == 0 {
+            lines.push(paragraph.join(" "));
+            return lines;
+        }
+        let mut current = String::new();
+        for word in paragraph.iter().flat_map(|l| l.split_whitespace()) {
+            if !current.is_empty()
+                && current.chars().count() + 1 + word.chars().count() > self.max_line_length
+            {
+                lines.push(current);
+                current = String::new();
+            }
+            if !current.is_empty() {
+                current.push(' ');
+            }
+            current.push_str(word);
+        }
+        if !current.is_empty() {
+            lines.push(current);
+        }
+        lines
+    }
+
+    // returns the template section a line opens, if any
+    fn section_match<'a>(&'a self, line: &str) -> Option<&'a TemplateSection> {
+        let (key, _) = line.split_at(line.find(':')?);
+        self.sections.iter().find(|s| s.name == key.trim())
+    }
+
+    // finds a section by name, returning its line index and (possibly multi line) content
+    // content runs until a blank line or the next section
+    fn section_find(&self, lines: &[&str], name: &str) -> Option<(usize, String)> {
+        for (i, l) in lines.iter().enumerate() {
+            if let Some(s) = self.section_match(l) {
+                if s.name != name {
+                    continue;
+                }
+                let mut value = l[l.find(':')? + 1..].trim().to_string();
+                for next in lines[i + 1..].iter() {
+                    if next.trim().is_empty() || self.section_match(next).is_some() {
+                        break;
+                    }
+                    if !value.is_empty() {
+                        value += "\n";
+                    }
+                    value += next.trim();
+                }
+                return Some((i, value));
+            }
+        }
+        None
+    }
+}
@@ -16,7 +16,12 @@ use error_lib::*;
 
 use lazy_static::*;
 use regex::Regex;
-use std::process::Command;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+pub mod change_watcher;
+pub mod description_policy;
 
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct Change {
@@ -28,6 +33,16 @@ pub struct Change {
     pub status: String,
 }
 
+// Checkpoint describes the files written by a checkpoint or journal rotation
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Checkpoint {
+    // sequence number of the checkpoint written, 0 for journal only rotations
+    pub checkpoint: u32,
+    pub checkpoint_md5: String,
+    // sequence number of the journal rotated out
+    pub journal: u32,
+}
+
 #[derive(Debug, Default, PartialEq)]
 pub struct Client {
     pub access: String,
@@ -81,6 +96,38 @@ pub struct Diff {
     pub diff_type: DiffType,
 }
 
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiffHunk {
+    pub left_line_start: u32,
+    pub left_line_count: u32,
+    pub right_line_start: u32,
+    pub right_line_count: u32,
+    pub header: String,
+    pub lines: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UnifiedDiff {
+    pub left_file: String,
+    pub right_file: String,
+    pub hunks: Vec<DiffHunk>,
+}
+
+// renders the diff back into unified diff text, as expected by review tooling
+impl std::fmt::Display for UnifiedDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "--- {}", self.left_file)?;
+        writeln!(f, "+++ {}", self.right_file)?;
+        for h in &self.hunks {
+            writeln!(f, "{}", h.header)?;
+            for l in &h.lines {
+                writeln!(f, "{}", l)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct FileAction {
     pub depot_file: String,
@@ -97,6 +144,15 @@ pub struct FileOpened {
     pub revision: u32,
 }
 
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileRevision {
+    pub action: String,
+    pub changelist: u32,
+    pub depot_file: String,
+    pub file_type: String,
+    pub revision: u32,
+}
+
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct Fstat {
     pub action: String,
@@ -149,6 +205,18 @@ pub struct FstatResult {
     pub total_file_count: u32,
 }
 
+// HealthCheck summarises server responsiveness, for monitoring agents
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HealthCheck {
+    pub latency: Duration,
+    pub replica_of: String,
+    // journal bytes the replica trails its master by
+    // None if the server isn't a replica, or the journal state couldn't be compared
+    pub replica_lag: Option<u64>,
+    pub server_uptime: String,
+    pub server_version: String,
+}
+
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct Info {
     pub case_handling: String,
@@ -173,6 +241,43 @@ pub struct Info {
     pub user_name: String,
 }
 
+// LabelDiff holds the delta between two labels, sorted by depot path
+// changed files are paired as (revision at first label, revision at second label)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LabelDiff {
+    pub added: Vec<FileRevision>,
+    pub removed: Vec<FileRevision>,
+    pub changed: Vec<(FileRevision, FileRevision)>,
+}
+
+// PasswdError details why the server rejected a password change
+// it is returned boxed inside SgeError::StdErr, so callers can downcast to react to each case
+#[derive(Clone, Debug, PartialEq)]
+pub enum PasswdError {
+    BadOldPassword,
+    Mismatch,
+    TooWeak(String),
+}
+
+impl std::error::Error for PasswdError {}
+
+impl std::fmt::Display for PasswdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            PasswdError::BadOldPassword => write!(f, "old password is incorrect"),
+            PasswdError::Mismatch => write!(f, "new passwords don't match"),
+            PasswdError::TooWeak(ref msg) => write!(f, "new password is too weak: {}", msg),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Populate {
+    // changelist the branched files were submitted in, 0 for previews
+    pub changelist: u32,
+    pub files_branched: u32,
+}
+
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct Size {
     pub depot_path: String,
@@ -188,6 +293,17 @@ pub struct SizeCollection {
     pub total_file_size: u64,
 }
 
+// WorkspaceStatus lists local files that differ from what the server expects
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WorkspaceStatus {
+    // unopened files missing from the workspace
+    pub deleted: Vec<String>,
+    // unopened files that were modified locally
+    pub edited: Vec<String>,
+    // opened files that are identical to the depot revision
+    pub unchanged: Vec<String>,
+}
+
 #[derive(Debug, Default, PartialEq)]
 pub struct ViewEntry {
     pub source: String,
@@ -289,6 +405,64 @@ pub trait PerforceTrait {
         Ok(())
     }
 
+    fn admin_build(&self, args: &[&str]) -> SgeResult<Checkpoint> {
+        let mut a = vec!["admin"];
+        a.extend_from_slice(args);
+        let out = self.exec(&a)?;
+
+        lazy_static! {
+            // checkpoints and journal rotations report the numbered files they write
+            // example:
+            // Checkpointing to checkpoint.24...
+            // MD5 (checkpoint.24) = 5F7D1C6A0E9C7B6C1F0E7F34B2D7F1A0
+            // Rotating journal to journal.23...
+            // regex groups:
+            // (kind)(sequence)
+            static ref ADMIN_RX: Regex =
+                Regex::new(r#"^(Checkpointing|Rotating journal) to \S*?(?:checkpoint|journal)\.(\d+)"#).unwrap();
+
+            // regex groups:
+            // (md5)
+            static ref MD5_RX: Regex = Regex::new(r#"^MD5 \(\S+\) = (\S+)"#).unwrap();
+        }
+
+        let mut c: Checkpoint = Default::default();
+        let mut rotated = false;
+        for line in out.lines() {
+            if let Some(groups) = regex_collector(&ADMIN_RX, line) {
+                let sequence = groups[2].parse::<u32>().unwrap_or(0);
+                match groups[1] {
+                    "Checkpointing" => c.checkpoint = sequence,
+                    _ => {
+                        c.journal = sequence;
+                        rotated = true;
+                    }
+                }
+            } else if let Some(groups) = regex_collector(&MD5_RX, line) {
+                c.checkpoint_md5 = groups[1].into();
+            }
+        }
+        // every successful run rotates the journal, anything else is an error (eg permissions)
+        if !rotated {
+            return Err(SgeError::Message(out.trim().into()));
+        }
+        Ok(c)
+    }
+
+    // AdminCheckpoint executes p4 admin checkpoint, writing a checkpoint and rotating the journal
+    fn admin_checkpoint(&self) -> SgeResult<Checkpoint> {
+        let c = self.admin_build(&["checkpoint"])?;
+        if c.checkpoint == 0 {
+            return Err(SgeError::Literal("checkpoint sequence number not reported"));
+        }
+        Ok(c)
+    }
+
+    // AdminJournal executes p4 admin journal, rotating the journal without a checkpoint
+    fn admin_journal(&self) -> SgeResult<Checkpoint> {
+        self.admin_build(&["journal"])
+    }
+
     // Add executes a p4 add, marking everything in paths for add in changelist cl.
     fn changes(&self, args: &[&str]) -> SgeResult<Vec<Change>> {
         let mut a = vec!["changes"];
@@ -496,6 +670,103 @@ pub trait PerforceTrait {
         self.diffs_build("diff2", file0, file1)
     }
 
+    fn unified_diffs_build(&self, args: &[&str]) -> SgeResult<Vec<UnifiedDiff>> {
+        let out = self.exec(args)?;
+
+        lazy_static! {
+            // diff2 separates each file pair with a header line
+            // example:
+            // ==== //some-depot/file.go#3 (text) - //some-depot/file.go#4 (text) ==== content
+            // regex groups:
+            // (left_file)(right_file)
+            static ref FILE_RX: Regex =
+                Regex::new(r#"^==== (.+?)(?: \([^\)]*\))? - (.+?)(?: \([^\)]*\))? ====.*$"#).unwrap();
+
+            // each hunk opens with line ranges in both files, counts are omitted when they equal 1
+            // example:
+            // @@ -64,0 +65,4 @@
+            // regex groups:
+            // (left_start)[left_count](right_start)[right_count]
+            static ref HUNK_RX: Regex =
+                Regex::new(r#"^@@ -(\d+)(?:,(\d+))? \+(\d+)(?:,(\d+))? @@"#).unwrap();
+        }
+
+        let mut diffs = Vec::new();
+        let mut d: UnifiedDiff = Default::default();
+        let mut pending = false;
+        // lines still expected in the current hunk, for the left and right files
+        let mut left_remaining: u32 = 0;
+        let mut right_remaining: u32 = 0;
+        for line in out.lines() {
+            if left_remaining > 0 || right_remaining > 0 {
+                match line.as_bytes().first() {
+                    Some(b'-') => left_remaining = left_remaining.saturating_sub(1),
+                    Some(b'+') => right_remaining = right_remaining.saturating_sub(1),
+                    _ => {
+                        left_remaining = left_remaining.saturating_sub(1);
+                        right_remaining = right_remaining.saturating_sub(1);
+                    }
+                }
+                if let Some(h) = d.hunks.last_mut() {
+                    h.lines.push(line.into());
+                }
+            } else if line.starts_with('\\') {
+                // "\ No newline at end of file" trails the hunk it refers to
+                if let Some(h) = d.hunks.last_mut() {
+                    h.lines.push(line.into());
+                }
+            } else if let Some(groups) = regex_collector(&FILE_RX, line) {
+                if pending {
+                    diffs.push(d);
+                }
+                pending = true;
+                d = UnifiedDiff {
+                    left_file: groups[1].into(),
+                    right_file: groups[2].into(),
+                    ..Default::default()
+                };
+            } else if let Some(file) = line.strip_prefix("--- ") {
+                // p4 diff has no ==== header, so a new left file also starts a new file pair
+                if pending && (!d.hunks.is_empty() || !d.right_file.is_empty()) {
+                    diffs.push(d);
+                    d = Default::default();
+                }
+                pending = true;
+                d.left_file = file.split('\t').next().unwrap_or_default().into();
+            } else if let Some(file) = line.strip_prefix("+++ ") {
+                d.right_file = file.split('\t').next().unwrap_or_default().into();
+            } else if let Some(groups) = regex_collector(&HUNK_RX, line) {
+                let hunk = DiffHunk {
+                    left_line_start: groups[1].parse::<u32>().unwrap_or(0),
+                    left_line_count: groups[2].parse::<u32>().unwrap_or(1),
+                    right_line_start: groups[3].parse::<u32>().unwrap_or(0),
+                    right_line_count: groups[4].parse::<u32>().unwrap_or(1),
+                    header: line.into(),
+                    lines: Vec::new(),
+                };
+                left_remaining = hunk.left_line_count;
+                right_remaining = hunk.right_line_count;
+                pending = true;
+                d.hunks.push(hunk);
+            }
+        }
+        if pending {
+            diffs.push(d);
+        }
+
+        Ok(diffs)
+    }
+
+    // DiffUnified executes p4 diff -du, returning unified diffs of opened files against the depot
+    fn diff_unified(&self, path: &str) -> SgeResult<Vec<UnifiedDiff>> {
+        self.unified_diffs_build(&["diff", "-du", path])
+    }
+
+    // Diff2Unified executes p4 diff2 -du, returning unified diffs between two depot paths
+    fn diff2_unified(&self, left: &str, right: &str) -> SgeResult<Vec<UnifiedDiff>> {
+        self.unified_diffs_build(&["diff2", "-du", left, right])
+    }
+
     fn dirs(&self, root: &str) -> SgeResult<Vec<String>> {
         let out = self.exec(&["dirs", root])?;
         Ok(out
@@ -505,6 +776,62 @@ pub trait PerforceTrait {
             .collect())
     }
 
+    // FilesAtLabel executes p4 files, returning every (non deleted) file revision tagged by label
+    fn files_at_label(&self, label: &str) -> SgeResult<Vec<FileRevision>> {
+        let path = format!("//...@{}", label);
+        let out = self.exec(&["files", "-e", &path])?;
+
+        lazy_static! {
+            // files has one line per file revision
+            // example:
+            // //shared/libs/go/p4lib/p4-lib.go#11 - edit change 9381 (text)
+            // regex groups:
+            // (depot_file)(revision)(action)(changelist)(file_type)
+            static ref FILES_RX: Regex =
+                Regex::new(r#"^([^#]+)#(\d+)\s+-\s+(\S+)\s+change\s+(\d+)\s+\(([^\)]+)\)"#).unwrap();
+        }
+
+        Ok(out
+            .lines()
+            .filter_map(|s| regex_collector(&FILES_RX, s))
+            .map(|groups| FileRevision {
+                action: groups[3].into(),
+                changelist: groups[4].parse::<u32>().unwrap_or(0),
+                depot_file: groups[1].into(),
+                file_type: groups[5].into(),
+                revision: groups[2].parse::<u32>().unwrap_or(0),
+            })
+            .collect())
+    }
+
+    // LabelDiff compares the files tagged by two labels
+    fn label_diff(&self, label_a: &str, label_b: &str) -> SgeResult<LabelDiff> {
+        let files_a: std::collections::BTreeMap<String, FileRevision> = self
+            .files_at_label(label_a)?
+            .into_iter()
+            .map(|f| (f.depot_file.clone(), f))
+            .collect();
+        let mut files_b: std::collections::BTreeMap<String, FileRevision> = self
+            .files_at_label(label_b)?
+            .into_iter()
+            .map(|f| (f.depot_file.clone(), f))
+            .collect();
+
+        let mut diff: LabelDiff = Default::default();
+        for (path, a) in files_a {
+            match files_b.remove(&path) {
+                Some(b) => {
+                    if a.revision != b.revision {
+                        diff.changed.push((a, b));
+                    }
+                }
+                None => diff.removed.push(a),
+            }
+        }
+        diff.added = files_b.into_values().collect();
+        Ok(diff)
+    }
+
     fn fstat(&self, args: &[&str]) -> SgeResult<FstatResult> {
         let mut a = vec!["fstat"];
         a.extend_from_slice(args);
@@ -670,6 +997,69 @@ pub trait PerforceTrait {
         Ok(info)
     }
 
+    // HealthCheck pings the server and gathers version, uptime and replication details
+    // the ping is abandoned if the server doesn't respond within the timeout
+    fn health_check(&self, timeout: Duration) -> SgeResult<HealthCheck> {
+        // ping first, so an unreachable server fails fast before the other queries
+        let max_wait = format!("-vnet.maxwait={}", std::cmp::max(timeout.as_secs(), 1));
+        let start = Instant::now();
+        // an unreachable server fails the command, with the client error of p4
+        self.exec(&[&max_wait, "ping", "-c", "1"])?;
+        let latency = start.elapsed();
+        if latency > timeout {
+            return Err(SgeError::Message(format!(
+                "ping took {:?}, exceeding timeout of {:?}",
+                latency, timeout
+            )));
+        }
+
+        let info = self.info()?;
+        let mut health = HealthCheck {
+            latency,
+            replica_of: info.replica_of,
+            server_uptime: info.server_uptime,
+            server_version: info.server_version,
+            ..Default::default()
+        };
+        if health.replica_of.is_empty() {
+            return Ok(health);
+        }
+
+        lazy_static! {
+            // pull -lj reports the journal position of both the replica and its master
+            // example:
+            // Current replica journal state is:	Journal 1234,	Sequence 5678.
+            // regex groups:
+            // (server)(journal)(sequence)
+            static ref JOURNAL_RX: Regex = Regex::new(
+                r#"^Current\s+(replica|master)\s+journal\s+state\s+is:\s+Journal\s+(\d+),\s+Sequence\s+(\d+)"#
+            )
+            .unwrap();
+        }
+
+        let out = self.exec(&["pull", "-lj"])?;
+        let mut replica = None;
+        let mut master = None;
+        for groups in out.lines().filter_map(|s| regex_collector(&JOURNAL_RX, s)) {
+            let state = (
+                groups[2].parse::<u64>().unwrap_or(0),
+                groups[3].parse::<u64>().unwrap_or(0),
+            );
+            match groups[1] {
+                "replica" => replica = Some(state),
+                _ => master = Some(state),
+            }
+        }
+        // sequence numbers are offsets within a journal, so only comparable within the same one
+        if let (Some(r), Some(m)) = (replica, master) {
+            if r.0 == m.0 {
+                health.replica_lag = Some(m.1.saturating_sub(r.1));
+            }
+        }
+
+        Ok(health)
+    }
+
     fn opened(&self) -> SgeResult<Vec<FileOpened>> {
         lazy_static! {
             // opened contains details about all opened files
@@ -705,6 +1095,85 @@ pub trait PerforceTrait {
         Ok(opens)
     }
 
+    // Passwd executes p4 passwd, answering its prompts for the old and new password over stdin
+    // if the user has no password set yet, old should be empty as p4 won't prompt for it
+    fn passwd(&self, old: &str, new: &str) -> SgeResult<()> {
+        let mut input = String::new();
+        if !old.is_empty() {
+            input += &format!("{}\n", old);
+        }
+        input += &format!("{}\n{}\n", new, new);
+        let out = self.exec_with_input(&["passwd"], &input)?;
+
+        if out.contains("Password updated") {
+            return Ok(());
+        }
+        let reason = out
+            .lines()
+            .map(|s| s.trim())
+            .rfind(|s| !s.is_empty())
+            .unwrap_or_default();
+        let e = if out.contains("Passwords don't match") {
+            PasswdError::Mismatch
+        } else if out.contains("Password invalid") || out.contains("incorrect") {
+            PasswdError::BadOldPassword
+        } else if out.contains("Password should be") || out.contains("Password must") {
+            PasswdError::TooWeak(reason.into())
+        } else {
+            return Err(SgeError::Message(reason.into()));
+        };
+        Err(SgeError::StdErr(Box::new(e)))
+    }
+
+    fn populate_build(&self, args: &[&str]) -> SgeResult<Populate> {
+        let mut a = vec!["populate"];
+        a.extend_from_slice(args);
+        let out = self.exec(&a)?;
+
+        lazy_static! {
+            // populate summarises the branch once submitted
+            // example:
+            // 136 files branched (change 9395).
+            // regex groups:
+            // (files_branched)[changelist]
+            static ref SUMMARY_RX: Regex =
+                Regex::new(r#"^(\d+)\s+files?\s+branched(?:\s+\(change\s+(\d+)\))?"#).unwrap();
+
+            // previews list each file that would be branched instead
+            // example:
+            // //some-depot/dev/file.go#1 - branch from //some-depot/main/file.go#3
+            static ref FILE_RX: Regex = Regex::new(r#"^//\S+#\d+\s+-\s+branch"#).unwrap();
+        }
+
+        let mut p: Populate = Default::default();
+        let mut matched = false;
+        for line in out.lines() {
+            if let Some(groups) = regex_collector(&SUMMARY_RX, line) {
+                p.files_branched = groups[1].parse::<u32>().unwrap_or(0);
+                p.changelist = groups[2].parse::<u32>().unwrap_or(0);
+                return Ok(p);
+            } else if FILE_RX.is_match(line) {
+                p.files_branched += 1;
+                matched = true;
+            }
+        }
+        // anything else is the server explaining why it refused (eg target files already exist)
+        if !matched && !out.trim().is_empty() {
+            return Err(SgeError::Message(out.trim().into()));
+        }
+        Ok(p)
+    }
+
+    // Populate executes p4 populate, branching from into to and submitting with description
+    fn populate(&self, from: &str, to: &str, description: &str) -> SgeResult<Populate> {
+        self.populate_build(&["-d", description, from, to])
+    }
+
+    // PopulatePreview executes p4 populate -n, reporting what would be branched from into to
+    fn populate_preview(&self, from: &str, to: &str) -> SgeResult<Populate> {
+        self.populate_build(&["-n", from, to])
+    }
+
     fn sizes(&self, args: &[&str]) -> SgeResult<SizeCollection> {
         let mut a = vec!["fstat"];
         a.extend_from_slice(args);
@@ -746,6 +1215,31 @@ pub trait PerforceTrait {
         Ok(sizes)
     }
 
+    // WorkspaceStatus executes the p4 diff -s family to find what is dirty in the workspace
+    fn workspace_status(&self) -> SgeResult<WorkspaceStatus> {
+        // each command lists one local path per line
+        // when there is nothing to report, p4 fails with a "file(s) not ..." message instead
+        let paths_collect = |flag: &str| -> SgeResult<Vec<String>> {
+            let out = match self.exec(&["diff", flag, "//..."]) {
+                Ok(out) => out,
+                Err(e) if e.to_string().to_lowercase().contains("file(s)") => String::new(),
+                Err(e) => return Err(e),
+            };
+            Ok(out
+                .lines()
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_owned())
+                .collect())
+        };
+
+        Ok(WorkspaceStatus {
+            deleted: paths_collect("-sd")?,
+            edited: paths_collect("-se")?,
+            unchanged: paths_collect("-sr")?,
+        })
+    }
+
     fn tickets(&self) -> SgeResult<Vec<Ticket>> {
         let out = self.exec(&["tickets"])?;
         let mut ticks = Vec::new();
@@ -768,8 +1262,100 @@ pub trait PerforceTrait {
         Ok(ticks)
     }
 
-    // interface for exec command
+    // WithClient returns a scoped interface that runs each command against the given client
+    fn with_client(&self, client: &str) -> ScopedPerforce<'_, Self>
+    where
+        Self: Sized,
+    {
+        ScopedPerforce::new(self).with_client(client)
+    }
+
+    // WithUser returns a scoped interface that runs each command as the given user
+    fn with_user(&self, user: &str) -> ScopedPerforce<'_, Self>
+    where
+        Self: Sized,
+    {
+        ScopedPerforce::new(self).with_user(user)
+    }
+
+    // WithPort returns a scoped interface that runs each command against the given server
+    fn with_port(&self, port: &str) -> ScopedPerforce<'_, Self>
+    where
+        Self: Sized,
+    {
+        ScopedPerforce::new(self).with_port(port)
+    }
+
+    // interface for exec command, the stdout of the command or its stderr as the error when it
+    // fails without listing anything
     fn exec(&self, args: &[&str]) -> SgeResult<String>;
+
+    // interface for exec command that writes input to the command's stdin
+    // by default the input is dropped, which suits mocks returning prebaked output
+    fn exec_with_input(&self, args: &[&str], _input: &str) -> SgeResult<String> {
+        self.exec(args)
+    }
+}
+
+// ScopedPerforce wraps another perforce interface and injects the global -c, -u and -p options
+// onto every command issued through it, leaving the wrapped interface untouched
+// this lets a single perforce instance operate across multiple workspaces, users or servers
+pub struct ScopedPerforce<'a, T: PerforceTrait> {
+    inner: &'a T,
+    client: Option<String>,
+    user: Option<String>,
+    port: Option<String>,
+}
+
+impl<'a, T: PerforceTrait> ScopedPerforce<'a, T> {
+    pub fn new(inner: &'a T) -> Self {
+        ScopedPerforce {
+            inner,
+            client: None,
+            user: None,
+            port: None,
+        }
+    }
+
+    pub fn with_client(mut self, client: &str) -> Self {
+        self.client = Some(client.into());
+        self
+    }
+
+    pub fn with_user(mut self, user: &str) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    pub fn with_port(mut self, port: &str) -> Self {
+        self.port = Some(port.into());
+        self
+    }
+}
+
+impl<'a, T: PerforceTrait> ScopedPerforce<'a, T> {
+    // global options have to precede the command, so they are prepended before delegating
+    fn args_build<'b>(&'b self, args: &[&'b str]) -> Vec<&'b str> {
+        let mut all_args = Vec::new();
+        for (flag, value) in &[("-c", &self.client), ("-u", &self.user), ("-p", &self.port)] {
+            if let Some(v) = value {
+                all_args.push(*flag);
+                all_args.push(v.as_str());
+            }
+        }
+        all_args.extend_from_slice(args);
+        all_args
+    }
+}
+
+impl<'a, T: PerforceTrait> PerforceTrait for ScopedPerforce<'a, T> {
+    fn exec(&self, args: &[&str]) -> SgeResult<String> {
+        self.inner.exec(&self.args_build(args))
+    }
+
+    fn exec_with_input(&self, args: &[&str], input: &str) -> SgeResult<String> {
+        self.inner.exec_with_input(&self.args_build(args), input)
+    }
 }
 
 // simple function to ensure that the array has enough capcity to set value at specified index
@@ -803,11 +1389,39 @@ fn regex_collector<'a>(re: &Regex, input: &'a str) -> Option<Vec<&'a str>> {
 
 // Main trait for (non-mocked) perforce interface
 impl PerforceTrait for Perforce {
-    // exec will execute passed in command use command line p4
+    // exec will execute passed in command use command line p4, with utf8 output
+    // p4 exits with 1 when a command fails, its stderr becomes the error
+    // commands on several paths (files, fstat, ...) still list the ones that exist when others
+    // are missing, that partial output is returned
     fn exec(&self, args: &[&str]) -> SgeResult<String> {
-        let mut all_args = vec!["-c", "utf8"];
+        let mut all_args = vec!["-C", "utf8"];
         all_args.extend_from_slice(args);
         let out = Command::new("p4").args(all_args).output()?;
+        if !out.status.success() && out.stdout.is_empty() {
+            let cmd_stderr = String::from_utf8_lossy(&out.stderr);
+            return Err(SgeError::Message(match cmd_stderr.trim() {
+                "" => format!("p4 {} failed, {}", args.join(" "), out.status),
+                e => e.into(),
+            }));
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).into())
+    }
+
+    // exec_with_input pipes input to the p4 process, for commands that prompt (eg passwd)
+    // stdout and stderr are returned together, the answers to the prompts are in either
+    fn exec_with_input(&self, args: &[&str], input: &str) -> SgeResult<String> {
+        let mut all_args = vec!["-C", "utf8"];
+        all_args.extend_from_slice(args);
+        let mut child = Command::new("p4")
+            .args(all_args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(stdin) = child.stdin.as_mut() {
+            stdin.write_all(input.as_bytes())?;
+        }
+        let out = child.wait_with_output()?;
         let cmd_stdout = String::from_utf8_lossy(&out.stdout);
         let cmd_stderr = String::from_utf8_lossy(&out.stderr);
         Ok((cmd_stdout + cmd_stderr).into())
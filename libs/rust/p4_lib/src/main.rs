@@ -17,14 +17,26 @@ include!("../tests/test.rs");
 
 fn main() {
     println!("p4 main");
+    do_test_admin();
     do_test_changes();
+    do_test_change_watcher();
     do_test_client();
     do_test_describe();
+    do_test_description_policy();
     do_test_diff();
+    do_test_diff_unified();
     do_test_dirs();
+    #[cfg(unix)]
+    do_test_exec();
     do_test_fstat();
+    do_test_health_check();
     do_test_info();
+    do_test_label_diff();
     do_test_opened();
+    do_test_passwd();
+    do_test_populate();
     do_test_sizes();
     do_test_tickets();
+    do_test_workspace_status();
+    do_test_scoped();
 }
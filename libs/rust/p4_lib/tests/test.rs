@@ -13,14 +13,21 @@
 // limitations under the License.
 
 use error_lib::*;
+use p4_lib::change_watcher::*;
+use p4_lib::description_policy::*;
 use p4_lib::*;
 use std::cell::RefCell;
+use std::time::Duration;
 
 #[derive(Default)]
 struct PerforceMock {
     // we use a refcell here to give the mock interior mutability
     // this means we can use it even in trait functions that use immutable references
     inputs: RefCell<Vec<cool-companyResult<String>>>,
+    // every set of arguments passed to exec, in call order
+    commands: RefCell<Vec<Vec<String>>>,
+    // everything written to stdin through exec_with_input, in call order
+    stdin: RefCell<Vec<String>>,
 }
 
 // the perforce mock interface is used by passing a slice of inputs
@@ -30,6 +37,7 @@ impl PerforceMock {
         let v: Vec<cool-companyResult<String>> = inputs.iter().map(|&r| r.to_owned()).collect();
         PerforceMock {
             inputs: RefCell::from(v),
+            ..Default::default()
         }
     }
 }
@@ -37,12 +45,20 @@ impl PerforceMock {
 impl PerforceTrait for PerforceMock {
     // perforce mock exec function. instead of actually executing p4, it will return a prebacked stdout string
     // you can sequence this with a slice of strings for functions that repeatedly call exec()
-    fn exec(&self, _args: &[&str]) -> cool-companyResult<String> {
+    fn exec(&self, args: &[&str]) -> cool-companyResult<String> {
+        self.commands
+            .borrow_mut()
+            .push(args.iter().map(|&s| s.to_string()).collect());
         if let Some(result) = self.inputs.borrow_mut().pop() {
             return result;
         }
         Err(cool-companyError::Literal("not enough inputs in mock"))
     }
+
+    fn exec_with_input(&self, args: &[&str], input: &str) -> SgeResult<String> {
+        self.stdin.borrow_mut().push(input.into());
+        self.exec(args)
+    }
 }
 
 #[test]
@@ -917,3 +933,637 @@ finalcountdown:EUROPE (emerald) 7cc1b78f4573035f11682eb96a40d182
         assert_eq!(c, d.want);
     }
 }
+
+#[test]
+fn test_scoped() {
+    do_test_scoped();
+}
+
+fn do_test_scoped() {
+    let input: SgeResult<String> = Ok("//some-depot/build\n".into());
+    let p = PerforceMock::new(&[&input, &input, &input]);
+
+    let _ = p.with_client("build-client-1").dirs("//some-depot/*");
+    let _ = p
+        .with_user("ci-runner")
+        .with_port("ssl:some-server:1666")
+        .dirs("//some-depot/*");
+    let _ = p.dirs("//some-depot/*");
+
+    let want: &[&[&str]] = &[
+        &["-c", "build-client-1", "dirs", "//some-depot/*"],
+        &["-u", "ci-runner", "-p", "ssl:some-server:1666", "dirs", "//some-depot/*"],
+        &["dirs", "//some-depot/*"],
+    ];
+    assert_eq!(*p.commands.borrow(), want);
+}
+
+#[test]
+fn test_diff_unified() {
+    do_test_diff_unified();
+}
+
+fn do_test_diff_unified() {
+    struct DiffUnifiedTestItem {
+        input: SgeResult<String>,
+        want: SgeResult<Vec<UnifiedDiff>>,
+    }
+
+    let items: &[DiffUnifiedTestItem] = &[
+        DiffUnifiedTestItem {
+            input: Ok(r#"==== //some-depot/some/path/some-project.go#3 (text) - //some-depot/some/path/some-project.go#4 (text) ==== content
+@@ -64,0 +65,2 @@
++	// SetClient commits the given client configuration into the server.
++	SetClient(client *P4Client) (string, error)
+@@ -346,3 +348,3 @@
+ func (p4 P4Impl) SetClient(client *P4Client) (string, error) {
+-	return p4SetClient(nil)
++	return p4SetClient(client)
+ }
+==== //some-depot/some/path/BUILD#1 (text) - //some-depot/some/path/BUILD#2 (text) ==== identical
+"#
+            .into()),
+            want: Ok(vec![
+                UnifiedDiff {
+                    left_file: "//some-depot/some/path/some-project.go#3".into(),
+                    right_file: "//some-depot/some/path/some-project.go#4".into(),
+                    hunks: vec![
+                        DiffHunk {
+                            left_line_start: 64,
+                            left_line_count: 0,
+                            right_line_start: 65,
+                            right_line_count: 2,
+                            header: "@@ -64,0 +65,2 @@".into(),
+                            lines: vec![
+                                "+\t// SetClient commits the given client configuration into the server.".into(),
+                                "+\tSetClient(client *P4Client) (string, error)".into(),
+                            ],
+                        },
+                        DiffHunk {
+                            left_line_start: 346,
+                            left_line_count: 3,
+                            right_line_start: 348,
+                            right_line_count: 3,
+                            header: "@@ -346,3 +348,3 @@".into(),
+                            lines: vec![
+                                " func (p4 P4Impl) SetClient(client *P4Client) (string, error) {".into(),
+                                "-\treturn p4SetClient(nil)".into(),
+                                "+\treturn p4SetClient(client)".into(),
+                                " }".into(),
+                            ],
+                        },
+                    ],
+                },
+                UnifiedDiff {
+                    left_file: "//some-depot/some/path/BUILD#1".into(),
+                    right_file: "//some-depot/some/path/BUILD#2".into(),
+                    ..Default::default()
+                },
+            ]),
+        },
+        DiffUnifiedTestItem {
+            input: Ok(r#"--- //some-depot/WORKSPACE	1997/06/20 10:12:01
++++ d:\p4-cool-company\shared\WORKSPACE	1997/06/21 09:30:45
+@@ -1 +1,2 @@
+ workspace(name = "shared")
++load("//build:defs.bzl", "setup")
+\ No newline at end of file
+"#
+            .into()),
+            want: Ok(vec![UnifiedDiff {
+                left_file: "//some-depot/WORKSPACE".into(),
+                right_file: r#"d:\p4-cool-company\shared\WORKSPACE"#.into(),
+                hunks: vec![DiffHunk {
+                    left_line_start: 1,
+                    left_line_count: 1,
+                    right_line_start: 1,
+                    right_line_count: 2,
+                    header: "@@ -1 +1,2 @@".into(),
+                    lines: vec![
+                        r#" workspace(name = "shared")"#.into(),
+                        r#"+load("//build:defs.bzl", "setup")"#.into(),
+                        r#"\ No newline at end of file"#.into(),
+                    ],
+                }],
+            }]),
+        },
+    ];
+
+    for d in items {
+        let p = PerforceMock::new(&[&d.input]);
+        let c = p.diff2_unified("a", "b");
+        assert_eq!(c, d.want);
+    }
+}
+
+#[test]
+fn test_description_policy() {
+    do_test_description_policy();
+}
+
+fn do_test_description_policy() {
+    struct PolicyTestItem {
+        input: &'static str,
+        want: Vec<(ViolationKind, &'static str, usize)>,
+    }
+
+    let items: &[PolicyTestItem] = &[
+        PolicyTestItem {
+            input: r#"some-project: support for p4 describe
+
+Bug: 1234
+Test: unit tests"#,
+            want: vec![],
+        },
+        PolicyTestItem {
+            input: "",
+            want: vec![(ViolationKind::EmptyDescription, "", 0)],
+        },
+        PolicyTestItem {
+            input: r#"Bug: 1234
+Test:"#,
+            want: vec![
+                (ViolationKind::MissingSummary, "", 1),
+                (ViolationKind::EmptySection, "Test", 2),
+            ],
+        },
+        PolicyTestItem {
+            input: r#"some-project: fstat & diff support, supporting both diff (local vs server) and diff2 (server vs server)
+
+Test: ran locally
+    against the edge server"#,
+            want: vec![
+                (ViolationKind::MissingSection, "Bug", 0),
+                (ViolationKind::LineTooLong, "", 1),
+            ],
+        },
+    ];
+
+    let template = DescriptionTemplate::standard();
+    for d in items {
+        let violations = template.validate(d.input);
+        let got: Vec<(ViolationKind, &str, usize)> = violations
+            .iter()
+            .map(|v| (v.kind.clone(), v.section.as_str(), v.line))
+            .collect();
+        assert_eq!(got, d.want);
+    }
+
+    let template = DescriptionTemplate {
+        max_line_length: 30,
+        ..DescriptionTemplate::standard()
+    };
+    let formatted = template.format(
+        r#"some-project: unify diff query processing so that
+both diff and diff2 share a parser
+- bullet points are kept as written, however long they are
+
+Bug: 1234
+Test: unit tests"#,
+    );
+    assert_eq!(
+        formatted,
+        r#"some-project: unify diff query
+processing so that both diff
+and diff2 share a parser
+- bullet points are kept as written, however long they are
+
+Bug: 1234
+Test: unit tests"#
+    );
+    assert!(template.validate(&template.render("summary")).len() == 2);
+}
+
+#[test]
+fn test_label_diff() {
+    do_test_label_diff();
+}
+
+fn do_test_label_diff() {
+    let label_a: SgeResult<String> = Ok(r#"//some-depot/WORKSPACE#45 - edit change 9381 (text)
+//some-depot/build/build-dist/BUILD#2 - edit change 8141 (text)
+//some-depot/tools/some-tool/file.go#2 - add change 8090 (text)
+"#
+    .into());
+    let label_b: SgeResult<String> = Ok(r#"//some-depot/WORKSPACE#46 - edit change 9395 (text)
+//some-depot/build/build-dist/BUILD#2 - edit change 8141 (text)
+//some-depot/experimental/api_vulkan.rs#1 - add change 9390 (text+x)
+"#
+    .into());
+
+    // the mock pops its inputs from the back
+    let p = PerforceMock::new(&[&label_b, &label_a]);
+    let d = p.label_diff("release-1.0", "release-1.1");
+    assert_eq!(
+        d,
+        Ok(LabelDiff {
+            added: vec![FileRevision {
+                action: "add".into(),
+                changelist: 9390,
+                depot_file: "//some-depot/experimental/api_vulkan.rs".into(),
+                file_type: "text+x".into(),
+                revision: 1,
+            }],
+            removed: vec![FileRevision {
+                action: "add".into(),
+                changelist: 8090,
+                depot_file: "//some-depot/tools/some-tool/file.go".into(),
+                file_type: "text".into(),
+                revision: 2,
+            }],
+            changed: vec![(
+                FileRevision {
+                    action: "edit".into(),
+                    changelist: 9381,
+                    depot_file: "//some-depot/WORKSPACE".into(),
+                    file_type: "text".into(),
+                    revision: 45,
+                },
+                FileRevision {
+                    action: "edit".into(),
+                    changelist: 9395,
+                    depot_file: "//some-depot/WORKSPACE".into(),
+                    file_type: "text".into(),
+                    revision: 46,
+                }
+            )],
+        })
+    );
+    let want: &[&[&str]] = &[
+        &["files", "-e", "//...@release-1.0"],
+        &["files", "-e", "//...@release-1.1"],
+    ];
+    assert_eq!(*p.commands.borrow(), want);
+}
+
+#[test]
+fn test_workspace_status() {
+    do_test_workspace_status();
+}
+
+fn do_test_workspace_status() {
+    let deleted: SgeResult<String> = Ok(r#"d:\p4-cool-company\shared\WORKSPACE
+d:\p4-cool-company\shared\build\build-dist\BUILD
+"#
+    .into());
+    // p4 fails when there is nothing to report
+    let edited: SgeResult<String> = Err(SgeError::Message("//... - file(s) up-to-date.".into()));
+    let unchanged: SgeResult<String> = Ok(r#"d:\p4-cool-company\shared\experimental\api_vulkan.rs
+"#
+    .into());
+
+    // the mock pops its inputs from the back
+    let p = PerforceMock::new(&[&unchanged, &edited, &deleted]);
+    let s = p.workspace_status();
+    assert_eq!(
+        s,
+        Ok(WorkspaceStatus {
+            deleted: vec![
+                r#"d:\p4-cool-company\shared\WORKSPACE"#.into(),
+                r#"d:\p4-cool-company\shared\build\build-dist\BUILD"#.into(),
+            ],
+            edited: vec![],
+            unchanged: vec![r#"d:\p4-cool-company\shared\experimental\api_vulkan.rs"#.into()],
+        })
+    );
+
+    // other failures are errors
+    let failed: SgeResult<String> = Err(SgeError::Message(
+        "Client 'cool-guy-ws' unknown - use 'client' command to create it.".into(),
+    ));
+    let p = PerforceMock::new(&[&failed]);
+    assert_eq!(
+        p.workspace_status(),
+        Err(SgeError::Message(
+            "Client 'cool-guy-ws' unknown - use 'client' command to create it.".into()
+        ))
+    );
+}
+
+#[test]
+fn test_health_check() {
+    do_test_health_check();
+}
+
+fn do_test_health_check() {
+    let ping: SgeResult<String> = Ok("Count 1 Message size 10/10 Time 0.01 secs\n".into());
+    let info: SgeResult<String> = Ok(r#"User name: cool-guy
+Server uptime: 324:36:08
+Server version: SOME_VERSION
+Replica of: ssl:cool-company-commit:1666
+"#
+    .into());
+    let pull: SgeResult<String> = Ok(r#"Current replica journal state is:	Journal 1234,	Sequence 5678.
+Current master journal state is:	Journal 1234,	Sequence 9999.
+The statefile was last modified at:	1997/06/22 02:12:43.
+"#
+    .into());
+
+    // the mock pops its inputs from the back
+    let p = PerforceMock::new(&[&pull, &info, &ping]);
+    let h = p.health_check(Duration::from_secs(5)).unwrap();
+    assert_eq!(h.replica_of, "ssl:cool-company-commit:1666");
+    assert_eq!(h.replica_lag, Some(4321));
+    assert_eq!(h.server_uptime, "324:36:08");
+    assert_eq!(h.server_version, "SOME_VERSION");
+    assert_eq!(p.commands.borrow()[0][0], "-vnet.maxwait=5");
+
+    // an unreachable server fails the ping, the other queries aren't run
+    let failed: SgeResult<String> = Err(SgeError::Message(
+        "Perforce client error:\n\tConnect to server failed; check $P4PORT.".into(),
+    ));
+    let p = PerforceMock::new(&[&failed]);
+    assert_eq!(
+        p.health_check(Duration::from_secs(5)),
+        Err(SgeError::Message(
+            "Perforce client error:\n\tConnect to server failed; check $P4PORT.".into()
+        ))
+    );
+    assert_eq!(p.commands.borrow().len(), 1);
+}
+
+#[test]
+fn test_passwd() {
+    do_test_passwd();
+}
+
+fn do_test_passwd() {
+    struct PasswdTestItem {
+        input: SgeResult<String>,
+        want: Option<PasswdError>,
+    }
+
+    let items: &[PasswdTestItem] = &[
+        PasswdTestItem {
+            input: Ok(
+                "Enter old password: Enter new password: Re-enter new password: Password updated.\n"
+                    .into(),
+            ),
+            want: None,
+        },
+        PasswdTestItem {
+            input: Ok(r#"Enter old password: Enter new password: Re-enter new password:
+Password should be at least 8 characters in length and contain mixed case or both alphabetic and non-alphabetic characters.
+"#
+            .into()),
+            want: Some(PasswdError::TooWeak("Password should be at least 8 characters in length and contain mixed case or both alphabetic and non-alphabetic characters.".into())),
+        },
+        PasswdTestItem {
+            input: Ok(r#"Enter old password: Enter new password: Re-enter new password:
+Password invalid.
+"#
+            .into()),
+            want: Some(PasswdError::BadOldPassword),
+        },
+    ];
+
+    for d in items {
+        let p = PerforceMock::new(&[&d.input]);
+        match p.passwd("old-secret", "new-Secret1") {
+            Ok(()) => assert_eq!(None, d.want),
+            Err(SgeError::StdErr(e)) => assert_eq!(e.downcast_ref::<PasswdError>(), d.want.as_ref()),
+            Err(e) => panic!("unexpected error {}", e),
+        }
+        assert_eq!(*p.stdin.borrow(), &["old-secret\nnew-Secret1\nnew-Secret1\n"]);
+    }
+}
+
+#[test]
+fn test_populate() {
+    do_test_populate();
+}
+
+fn do_test_populate() {
+    struct PopulateTestItem {
+        input: SgeResult<String>,
+        want: SgeResult<Populate>,
+    }
+
+    let items: &[PopulateTestItem] = &[
+        PopulateTestItem {
+            input: Ok("136 files branched (change 9395).\n".into()),
+            want: Ok(Populate {
+                changelist: 9395,
+                files_branched: 136,
+            }),
+        },
+        PopulateTestItem {
+            input: Ok(r#"//some-depot/dev/WORKSPACE#1 - branch from //some-depot/main/WORKSPACE#45
+//some-depot/dev/build/BUILD#1 - branch from //some-depot/main/build/BUILD#2
+"#
+            .into()),
+            want: Ok(Populate {
+                changelist: 0,
+                files_branched: 2,
+            }),
+        },
+        PopulateTestItem {
+            input: Ok("Can't populate target path when files already exist.\n".into()),
+            want: Err(SgeError::Message(
+                "Can't populate target path when files already exist.".into(),
+            )),
+        },
+    ];
+
+    for d in items {
+        let p = PerforceMock::new(&[&d.input]);
+        let c = p.populate("//some-depot/main/...", "//some-depot/dev/...", "seed dev");
+        assert_eq!(c, d.want);
+    }
+
+    let input: SgeResult<String> = Ok("".into());
+    let p = PerforceMock::new(&[&input]);
+    let _ = p.populate_preview("//some-depot/main/...", "//some-depot/dev/...");
+    let want: &[&[&str]] = &[&["populate", "-n", "//some-depot/main/...", "//some-depot/dev/..."]];
+    assert_eq!(*p.commands.borrow(), want);
+}
+
+#[test]
+fn test_admin() {
+    do_test_admin();
+}
+
+fn do_test_admin() {
+    let input: SgeResult<String> = Ok(r#"Checkpointing to checkpoint.24...
+MD5 (checkpoint.24) = 5F7D1C6A0E9C7B6C1F0E7F34B2D7F1A0
+Rotating journal to journal.23...
+"#
+    .into());
+    let p = PerforceMock::new(&[&input]);
+    assert_eq!(
+        p.admin_checkpoint(),
+        Ok(Checkpoint {
+            checkpoint: 24,
+            checkpoint_md5: "5F7D1C6A0E9C7B6C1F0E7F34B2D7F1A0".into(),
+            journal: 23,
+        })
+    );
+
+    let input: SgeResult<String> = Ok("Rotating journal to journal.23...\n".into());
+    let p = PerforceMock::new(&[&input]);
+    assert_eq!(
+        p.admin_journal(),
+        Ok(Checkpoint {
+            journal: 23,
+            ..Default::default()
+        })
+    );
+
+    let input: SgeResult<String> = Ok("You don't have permission for this operation.\n".into());
+    let p = PerforceMock::new(&[&input]);
+    assert_eq!(
+        p.admin_checkpoint(),
+        Err(SgeError::Message(
+            "You don't have permission for this operation.".into()
+        ))
+    );
+}
+
+#[test]
+fn test_change_watcher() {
+    do_test_change_watcher();
+}
+
+fn do_test_change_watcher() {
+    let first: SgeResult<String> = Ok(r#"Change 9346 on 1997/06/19 by beehive@beehive-3a2c3885 'Add check proto and implement g'
+Change 9252 on 1997/06/18 by da-mastah@da-mastah_da-mastah2-WS '[cicd] Glob maching for PathExp'
+"#
+    .into());
+    let second: SgeResult<String> = Ok(r#"Change 9395 on 1997/06/20 by cool-guy@cool-guy2-w 'p4 lib rust 2 '
+Change 9390 on 1997/06/20 by cool-guy@cool-guy2-w 'p4 lib rust 1 '
+Change 9346 on 1997/06/19 by beehive@beehive-3a2c3885 'Add check proto and implement g'
+"#
+    .into());
+    let third: SgeResult<String> = Ok(r#"Change 9395 on 1997/06/20 by cool-guy@cool-guy2-w 'p4 lib rust 2 '
+"#
+    .into());
+
+    // the mock pops its inputs from the back
+    let p = PerforceMock::new(&[&third, &second, &first]);
+    let mut w = ChangeWatcher::new(p, "//some-depot/...");
+
+    // the first poll establishes the baseline
+    assert_eq!(w.poll(), Ok(vec![]));
+    assert_eq!(w.last_seen(), Some(9346));
+
+    let changes: Vec<u32> = w.poll().unwrap().iter().map(|c| c.changelist).collect();
+    assert_eq!(changes, vec![9390, 9395]);
+    assert_eq!(w.last_seen(), Some(9395));
+
+    assert_eq!(w.poll(), Ok(vec![]));
+    assert_eq!(w.last_seen(), Some(9395));
+
+    // later polls ask for every change after the last seen, not the latest few
+    let p = PerforceMock::new(&[&second]);
+    let mut w = ChangeWatcher::new(p, "//some-depot/...").with_last_seen(9346);
+    let changes: Vec<u32> = w.poll().unwrap().iter().map(|c| c.changelist).collect();
+    assert_eq!(changes, vec![9390, 9395]);
+    assert_eq!(
+        *w.p4().commands.borrow(),
+        vec![vec!["changes", "-s", "submitted", "//some-depot/...@9347,@now"]]
+    );
+
+    // the first poll only asks for the latest change
+    let p = PerforceMock::new(&[&first]);
+    let mut w = ChangeWatcher::new(p, "");
+    assert_eq!(w.poll(), Ok(vec![]));
+    assert_eq!(
+        *w.p4().commands.borrow(),
+        vec![vec!["changes", "-m", "1", "-s", "submitted", "//..."]]
+    );
+
+    // beyond the limit, changes are delivered on the next polls
+    let p = PerforceMock::new(&[&third, &second]);
+    let mut w = ChangeWatcher::new(p, "//some-depot/...")
+        .with_last_seen(9346)
+        .with_max_changes(1);
+    let changes: Vec<u32> = w.poll().unwrap().iter().map(|c| c.changelist).collect();
+    assert_eq!(changes, vec![9390]);
+    let changes: Vec<u32> = w.poll().unwrap().iter().map(|c| c.changelist).collect();
+    assert_eq!(changes, vec![9395]);
+
+    // a spawned watcher hands its poll errors to the callback
+    // the mock's results aren't Send, this one pops outputs too, failing for None
+    struct FlakyPerforce {
+        outputs: std::sync::Mutex<Vec<Option<String>>>,
+    }
+    impl PerforceTrait for FlakyPerforce {
+        fn exec(&self, _args: &[&str]) -> SgeResult<String> {
+            match self.outputs.lock().unwrap().pop() {
+                Some(Some(out)) => Ok(out),
+                Some(None) => Err(SgeError::Literal("connect to server failed")),
+                None => Err(SgeError::Literal("not enough inputs in mock")),
+            }
+        }
+    }
+    let p = FlakyPerforce {
+        outputs: std::sync::Mutex::new(vec![second.ok(), first.ok(), None]),
+    };
+    let (errors_sender, errors) = std::sync::mpsc::channel();
+    let receiver = ChangeWatcher::new(p, "//some-depot/...")
+        .with_interval(Duration::from_millis(1))
+        .spawn(move |e| {
+            let _ = errors_sender.send(e.to_string());
+        });
+    assert_eq!(
+        errors.recv_timeout(Duration::from_secs(10)),
+        Ok("connect to server failed".to_string())
+    );
+    let changes: Vec<u32> = receiver.iter().take(2).map(|c| c.changelist).collect();
+    assert_eq!(changes, vec![9390, 9395]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_exec() {
+    do_test_exec();
+}
+
+// runs a fake p4 first on the PATH, echoing its arguments, failing like p4 does for fail and
+// listing what exists of several paths for partial
+#[cfg(unix)]
+fn do_test_exec() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("p4_lib_test_exec_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let fake = dir.join("p4");
+    std::fs::write(
+        &fake,
+        r#"#!/bin/sh
+if [ "$3" = fail ]; then
+    echo "Perforce password (P4PASSWD) invalid or unset." >&2
+    exit 1
+fi
+if [ "$3" = partial ]; then
+    echo "//depot/a.txt#1 - add change 1 (text)"
+    echo "//depot/missing/... - no such file(s)." >&2
+    exit 1
+fi
+echo "$@"
+echo "a warning" >&2
+"#,
+    )
+    .unwrap();
+    std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = std::env::var("PATH").unwrap_or_default();
+    std::env::set_var("PATH", format!("{}:{}", dir.display(), path));
+
+    // the charset is set with -C, -c would name the client
+    let p = Perforce {};
+    assert_eq!(p.exec(&["info"]), Ok("-C utf8 info\n".into()));
+    assert_eq!(
+        p.exec(&["fail"]),
+        Err(SgeError::Message(
+            "Perforce password (P4PASSWD) invalid or unset.".into()
+        ))
+    );
+    // what was listed is returned, without the paths that don't exist
+    assert_eq!(
+        p.exec(&["partial"]),
+        Ok("//depot/a.txt#1 - add change 1 (text)\n".into())
+    );
+
+    std::env::set_var("PATH", path);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
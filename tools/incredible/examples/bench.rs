@@ -0,0 +1,161 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Incredible benchmark
+//
+//	Scans a generated header tree, the same on every machine and every run,
+//	single threaded and with the default workers, and shows the median wall
+//	time and the profile of the median run:
+//
+//	cargo run --release --example bench [-- <runs>]
+//
+//	the tree is written to the temp directory: sources including headers of
+//	several include directories, headers including each other with guards,
+//	comments, conditions and system includes that aren't found
+//-----------------------------------------------------------------------------
+
+use incredible::{scan, ScanOptions, WorkerProfile};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const INCLUDE_DIRS: usize = 8;
+const HEADERS: usize = 2000;
+const SOURCES: usize = 200;
+// includes of each header and of each source
+const HEADER_INCLUDES: usize = 4;
+const SOURCE_INCLUDES: usize = 12;
+const RUNS_DEFAULT: usize = 5;
+
+//	a linear congruential generator, the tree is the same on every platform
+
+struct Random(u64);
+
+impl Random {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 = self
+            .0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        ((self.0 >> 33) % n as u64) as usize
+    }
+}
+
+//-----------------------------------------------------------------------------
+// write the tree below root, returns the sources and the include directories
+//-----------------------------------------------------------------------------
+
+fn tree_write(root: &Path) -> std::io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let _ = fs::remove_dir_all(root);
+    let include_dirs: Vec<PathBuf> = (0..INCLUDE_DIRS)
+        .map(|d| root.join(format!("lib{}", d)))
+        .collect();
+    for dir in include_dirs.iter() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::create_dir_all(root.join("src"))?;
+
+    let mut random = Random(0x5eed);
+    let body = "// declarations of the header\n\
+                struct Widget { int size; /* #include \"not_an_include.h\" */ };\n\
+                inline int widget_size(const Widget& w) { return w.size; }\n";
+
+    // headers only include headers after them, the tree has no cycles
+    for h in 0..HEADERS {
+        let mut text = format!("#ifndef H{0}_H\n#define H{0}_H\n", h);
+        for _ in 0..HEADER_INCLUDES {
+            if h + 1 < HEADERS {
+                let included = h + 1 + random.below(HEADERS - h - 1);
+                text.push_str(&format!("#include <h{}.h>\n", included));
+            }
+        }
+        text.push_str(
+            "#if defined(_WIN32)\n#include <windows.h>\n#else\n#include <unistd.h>\n#endif\n",
+        );
+        text.push_str(&body.repeat(8));
+        text.push_str("#endif\n");
+        fs::write(
+            include_dirs[h % INCLUDE_DIRS].join(format!("h{}.h", h)),
+            text,
+        )?;
+    }
+
+    let mut sources = Vec::new();
+    for s in 0..SOURCES {
+        let mut text = String::new();
+        for _ in 0..SOURCE_INCLUDES {
+            text.push_str(&format!("#include \"h{}.h\"\n", random.below(HEADERS)));
+        }
+        text.push_str(&body.repeat(32));
+        let source = root.join("src").join(format!("s{}.cpp", s));
+        fs::write(&source, text)?;
+        sources.push(source);
+    }
+    Ok((sources, include_dirs))
+}
+
+//-----------------------------------------------------------------------------
+// scan the sources runs times, returns the median run and its wall time
+//-----------------------------------------------------------------------------
+
+fn bench_run(
+    sources: &[PathBuf],
+    options: &ScanOptions,
+    runs: usize,
+) -> (Vec<WorkerProfile>, std::time::Duration) {
+    let mut timed = Vec::new();
+    for _ in 0..runs {
+        let started = Instant::now();
+        let graph = scan(sources, options).expect("the generated tree scans");
+        timed.push((started.elapsed(), graph.profile));
+    }
+    timed.sort_by_key(|t| t.0);
+    let (wall, profile) = timed.swap_remove(timed.len() / 2);
+    (profile, wall)
+}
+
+fn main() {
+    let runs = std::env::args()
+        .nth(1)
+        .and_then(|r| r.parse().ok())
+        .unwrap_or(RUNS_DEFAULT)
+        .max(1);
+
+    let root = std::env::temp_dir().join("incredible_bench");
+    let (sources, include_dirs) = match tree_write(&root) {
+        Ok(tree) => tree,
+        Err(e) => {
+            println!("couldn't write the tree to {}: {}", root.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut options = ScanOptions {
+        include_dirs,
+        profile: true,
+        ..Default::default()
+    };
+    for worker_count in [1, options.worker_count].iter() {
+        options.worker_count = *worker_count;
+        let (profile, wall) = bench_run(&sources, &options, runs);
+        println!(
+            "{} workers, median of {} runs\n{}",
+            worker_count,
+            runs,
+            WorkerProfile::format(&profile, wall)
+        );
+    }
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,393 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Scan cache
+//
+//	Keeps the includes found in each file between runs (-cache=file), so
+//	repeat scans only read the files modified since. Entries are keyed by path
+//	and checked against the modification time and size of the file
+//
+//	the includes of a file depend on the defines it is scanned with, the cache
+//	is dropped when the -d defines change, and an entry is only used for the
+//	defines its includer passed the file. the defines and undefs of the file are
+//	kept so the defines it passes its includes are the same when cached. files
+//	probing other files with __has_include aren't cached, their includes depend
+//	on more than their contents
+//
+//	text format, tab separated:
+//	incredible-cache	<version>	<defines key>
+//	F	<mtime ns>	<size>	<guard>	<defines key>	<path>	(guard 0 none, 1 #pragma once, or the macro)
+//	L|S	<line>	<include>	[<file>]	(the includes of the file above, local or system,
+//				with the file of a #line directive before them)
+//	D	<includes before>	<name>	<value>	(the defines of the file above)
+//	U	<includes before>	<name>	(its undefs)
+//-----------------------------------------------------------------------------
+
+use crate::guard::Guard;
+use crate::{IncResult, IncludeSearch};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+const CACHE_HEADER: &str = "incredible-cache";
+const CACHE_VERSION: u32 = 5;
+
+//	modification time and size identifying a version of a file
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileStamp {
+    mtime: u128,
+    size: u64,
+}
+
+impl FileStamp {
+    pub fn new(metadata: &fs::Metadata) -> Option<Self> {
+        let modified = metadata.modified().ok()?;
+        Some(FileStamp {
+            mtime: modified.duration_since(UNIX_EPOCH).ok()?.as_nanos(),
+            size: metadata.len(),
+        })
+    }
+
+    pub fn read(path: &Path) -> Option<Self> {
+        FileStamp::new(&fs::metadata(path).ok()?)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheEntry {
+    pub stamp: FileStamp,
+    pub guard: Option<Guard>,
+    // key of the defines the file was scanned with
+    pub defines: u64,
+    // include, local or system, line and file as set by #line
+    pub includes: Vec<(String, IncludeSearch, usize, Option<String>)>,
+    // defines of the file, none for an undef, after the number of includes before them
+    pub changes: Vec<(usize, String, Option<String>)>,
+}
+
+#[derive(Clone)]
+pub struct ScanCache {
+    // entries loaded from the cache file
+    previous: Arc<HashMap<PathBuf, CacheEntry>>,
+    // entries of the files of this scan, the ones saved
+    current: Arc<Mutex<HashMap<PathBuf, CacheEntry>>>,
+    key: u64,
+}
+
+//-----------------------------------------------------------------------------
+// key of the defines a cache was made with, independent of their order
+//-----------------------------------------------------------------------------
+
+pub fn defines_key(defines: &HashMap<String, String>) -> u64 {
+    let mut sorted: Vec<_> = defines.iter().collect();
+    sorted.sort();
+    let mut hasher = DefaultHasher::new();
+    sorted.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn cache_header(key: u64) -> String {
+    format!("{}\t{}\t{:x}", CACHE_HEADER, CACHE_VERSION, key)
+}
+
+//-----------------------------------------------------------------------------
+// parse cache file contents, none when written by another version or for other defines
+//-----------------------------------------------------------------------------
+
+fn cache_parse(text: &str, key: u64) -> Option<HashMap<PathBuf, CacheEntry>> {
+    let mut lines = text.lines();
+    if lines.next()? != cache_header(key) {
+        return None;
+    }
+
+    let mut entries = HashMap::new();
+    let mut entry: Option<(PathBuf, CacheEntry)> = None;
+    for line in lines {
+        let fields: Vec<&str> = line.splitn(6, '\t').collect();
+        match fields.as_slice() {
+            ["F", mtime, size, guard, defines, path] => {
+                if let Some((p, e)) = entry.take() {
+                    entries.insert(p, e);
+                }
+                let stamp = FileStamp {
+                    mtime: mtime.parse().ok()?,
+                    size: size.parse().ok()?,
+                };
+                entry = Some((
+                    PathBuf::from(path),
+                    CacheEntry {
+                        stamp,
+                        guard: match *guard {
+                            "0" => None,
+                            "1" => Some(Guard::PragmaOnce),
+                            name => Some(Guard::Macro(name.to_string())),
+                        },
+                        defines: u64::from_str_radix(defines, 16).ok()?,
+                        includes: Vec::new(),
+                        changes: Vec::new(),
+                    },
+                ));
+            }
+            // values may have tabs, the last field keeps the rest of the line
+            ["D", position, name, value @ ..] if !value.is_empty() => {
+                entry.as_mut()?.1.changes.push((
+                    position.parse().ok()?,
+                    name.to_string(),
+                    Some(value.join("\t")),
+                ));
+            }
+            ["U", position, name] => {
+                entry
+                    .as_mut()?
+                    .1
+                    .changes
+                    .push((position.parse().ok()?, name.to_string(), None));
+            }
+            [kind, line, include, file @ ..] if file.len() <= 1 => {
+                let search_type = match *kind {
+                    "L" => IncludeSearch::Local,
+                    "S" => IncludeSearch::System,
+                    _ => return None,
+                };
+                entry.as_mut()?.1.includes.push((
+                    include.to_string(),
+                    search_type,
+                    line.parse().ok()?,
+                    file.first().map(|f| f.to_string()),
+                ));
+            }
+            _ => return None,
+        }
+    }
+    if let Some((p, e)) = entry {
+        entries.insert(p, e);
+    }
+
+    Some(entries)
+}
+
+impl ScanCache {
+    // an empty cache, kept in memory
+    pub fn new(key: u64) -> Self {
+        ScanCache {
+            previous: Arc::new(HashMap::new()),
+            current: Arc::new(Mutex::new(HashMap::new())),
+            key,
+        }
+    }
+
+    //-----------------------------------------------------------------------------
+    // load a cache file, an empty cache when it is missing, stale or unreadable
+    //-----------------------------------------------------------------------------
+
+    pub fn load(path: &Path, key: u64) -> Self {
+        let previous = fs::read_to_string(path)
+            .ok()
+            .and_then(|text| cache_parse(&text, key))
+            .unwrap_or_default();
+        ScanCache {
+            previous: Arc::new(previous),
+            current: Arc::new(Mutex::new(HashMap::new())),
+            key,
+        }
+    }
+
+    // a cache of the entries of this scan for the next one, kept in memory
+    pub fn next(&self) -> Self {
+        let current = match self.current.lock() {
+            Ok(current) => current.clone(),
+            Err(_) => HashMap::new(),
+        };
+        ScanCache {
+            previous: Arc::new(current),
+            current: Arc::new(Mutex::new(HashMap::new())),
+            key: self.key,
+        }
+    }
+
+    // a cache of the entries of this scan and of earlier ones it didn't read, kept in memory
+    pub fn merged(&self) -> Self {
+        let mut merged = (*self.previous).clone();
+        if let Ok(current) = self.current.lock() {
+            merged.extend(current.iter().map(|(p, e)| (p.clone(), e.clone())));
+        }
+        ScanCache {
+            previous: Arc::new(merged),
+            current: Arc::new(Mutex::new(HashMap::new())),
+            key: self.key,
+        }
+    }
+
+    // the cached scan of a file, when it hasn't changed since and has the same defines
+    pub fn lookup(&self, path: &Path, stamp: FileStamp, defines: u64) -> Option<CacheEntry> {
+        let entry = self
+            .previous
+            .get(path)
+            .filter(|e| e.stamp == stamp && e.defines == defines)?;
+        if let Ok(mut current) = self.current.lock() {
+            current.insert(path.to_path_buf(), entry.clone());
+        }
+        Some(entry.clone())
+    }
+
+    pub fn insert(&self, path: &Path, entry: CacheEntry) {
+        if let Ok(mut current) = self.current.lock() {
+            current.insert(path.to_path_buf(), entry);
+        }
+    }
+
+    //-----------------------------------------------------------------------------
+    // format the entries of this scan, sorted by path
+    //-----------------------------------------------------------------------------
+
+    fn format(&self) -> String {
+        let mut text = cache_header(self.key);
+        text.push('\n');
+        if let Ok(current) = self.current.lock() {
+            let mut sorted: Vec<_> = current.iter().collect();
+            sorted.sort_by(|a, b| a.0.cmp(b.0));
+            for (path, entry) in sorted {
+                text.push_str(&format!(
+                    "F\t{}\t{}\t{}\t{:x}\t{}\n",
+                    entry.stamp.mtime,
+                    entry.stamp.size,
+                    match &entry.guard {
+                        None => "0",
+                        Some(Guard::PragmaOnce) => "1",
+                        Some(Guard::Macro(name)) => name,
+                    },
+                    entry.defines,
+                    path.to_string_lossy()
+                ));
+                for (include, search_type, line, file) in entry.includes.iter() {
+                    let kind = match search_type {
+                        IncludeSearch::Local => "L",
+                        IncludeSearch::System => "S",
+                    };
+                    text.push_str(&format!("{}\t{}\t{}", kind, line, include));
+                    if let Some(file) = file {
+                        text.push_str(&format!("\t{}", file));
+                    }
+                    text.push('\n');
+                }
+                for (position, name, value) in entry.changes.iter() {
+                    match value {
+                        Some(value) => {
+                            text.push_str(&format!("D\t{}\t{}\t{}\n", position, name, value))
+                        }
+                        None => text.push_str(&format!("U\t{}\t{}\n", position, name)),
+                    }
+                }
+            }
+        }
+        text
+    }
+
+    pub fn save(&self, path: &Path) -> IncResult<()> {
+        fs::write(path, self.format())?;
+        Ok(())
+    }
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_cache {
+
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_cache_format() {
+        let stamp = FileStamp {
+            mtime: 1_600_000_000_000_000_000,
+            size: 42,
+        };
+        let entry = CacheEntry {
+            stamp,
+            guard: Some(Guard::Macro("MAIN_H".to_string())),
+            defines: 0xab,
+            includes: vec![
+                ("a.h".to_string(), IncludeSearch::Local, 3, None),
+                (
+                    "dir with space/b.h".to_string(),
+                    IncludeSearch::System,
+                    12,
+                    Some("gen.y".to_string()),
+                ),
+            ],
+            changes: vec![
+                (0, "A_H".to_string(), Some(String::new())),
+                (1, "VALUE".to_string(), Some("1\t+ 2".to_string())),
+                (2, "A_H".to_string(), None),
+            ],
+        };
+
+        let cache = ScanCache::load(Path::new("missing.cache"), 7);
+        cache.insert(Path::new("src/main.cpp"), entry.clone());
+        let text = cache.format();
+        assert_eq!(
+            "incredible-cache\t5\t7\nF\t1600000000000000000\t42\tMAIN_H\tab\tsrc/main.cpp\nL\t3\ta.h\nS\t12\tdir with space/b.h\tgen.y\nD\t0\tA_H\t\nD\t1\tVALUE\t1\t+ 2\nU\t2\tA_H\n",
+            text
+        );
+
+        // round trip, only for the same defines and unchanged files
+        let entries = cache_parse(&text, 7).unwrap();
+        assert_eq!(Some(&entry), entries.get(Path::new("src/main.cpp")));
+        assert_eq!(None, cache_parse(&text, 8));
+        assert_eq!(None, cache_parse("incredible-cache\t5\t7\nL\t3\ta.h\n", 7));
+
+        let cache = ScanCache {
+            previous: Arc::new(entries),
+            current: Arc::new(Mutex::new(HashMap::new())),
+            key: 7,
+        };
+        assert_eq!(
+            Some(entry.clone()),
+            cache.lookup(Path::new("src/main.cpp"), stamp, 0xab)
+        );
+        let changed = FileStamp { size: 43, ..stamp };
+        assert_eq!(None, cache.lookup(Path::new("src/main.cpp"), changed, 0xab));
+        assert_eq!(None, cache.lookup(Path::new("src/other.cpp"), stamp, 0xab));
+        // scanned with other defines by its includer
+        assert_eq!(None, cache.lookup(Path::new("src/main.cpp"), stamp, 0xac));
+
+        // only the entries looked up or inserted are kept for the next scan
+        let next = cache.next();
+        assert_eq!(
+            Some(entry),
+            next.lookup(Path::new("src/main.cpp"), stamp, 0xab)
+        );
+        assert_eq!(
+            None,
+            next.next().lookup(Path::new("src/other.cpp"), stamp, 0xab)
+        );
+
+        let mut a = HashMap::new();
+        a.insert("A".to_string(), "1".to_string());
+        a.insert("B".to_string(), "2".to_string());
+        let mut b = a.clone();
+        assert_eq!(defines_key(&a), defines_key(&b));
+        b.insert("B".to_string(), "3".to_string());
+        assert_ne!(defines_key(&a), defines_key(&b));
+    }
+}
@@ -0,0 +1,307 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Compilation database
+//
+//	Reads the translation units of a compile_commands.json (as written by
+//	cmake, bazel or ninja -t compdb), with the include directories and defines
+//	of their command lines:
+//
+//	[
+//	  {"directory": "/build", "file": "../src/a.cpp", "output": "a.o",
+//	   "command": "clang++ -I../inc -DNDEBUG -c ../src/a.cpp -o a.o"},
+//	  {"directory": "/build", "file": "../src/b.cpp",
+//	   "arguments": ["cl.exe", "/I..\\inc", "/DWIN32", "/c", "..\\src\\b.cpp"]}
+//	]
+//
+//	-I -isystem -iquote -idirafter add include directories, -D adds defines
+//	(1 when without a value), -U removes them, -include adds forced includes.
+//	/I /D /U /FI too for cl and clang-cl, elsewhere they are paths. Relative
+//	include directories are relative to the directory of the entry
+//-----------------------------------------------------------------------------
+
+use crate::{IncError, IncResult, ScanOptions};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//	a translation unit of the database
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompileCommand {
+    pub directory: PathBuf,
+    pub file: PathBuf,
+    // object file of the command, when the database has it
+    pub output: Option<PathBuf>,
+    pub include_dirs: Vec<PathBuf>,
+    pub defines: HashMap<String, String>,
+    // headers included before the file, as written (-include, /FI)
+    pub forced_includes: Vec<String>,
+}
+
+const JSON_MALFORMED: IncError = IncError::Literal("malformed compilation database");
+
+//-----------------------------------------------------------------------------
+// split a command into arguments, quotes group arguments containing whitespace
+// backslashes only escape quotes and backslashes, so windows paths survive
+//-----------------------------------------------------------------------------
+
+fn command_split(command: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut quote: Option<char> = None;
+    let mut started = false;
+    let mut chars = command.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if matches!(chars.peek(), Some('"') | Some('\'') | Some('\\')) => {
+                arg.push(chars.next().unwrap_or('\\'));
+                started = true;
+            }
+            '"' | '\'' if quote.is_none() => {
+                quote = Some(c);
+                started = true;
+            }
+            c if Some(c) == quote => quote = None,
+            c if c.is_whitespace() && quote.is_none() => {
+                if started {
+                    args.push(std::mem::take(&mut arg));
+                    started = false;
+                }
+            }
+            c => {
+                arg.push(c);
+                started = true;
+            }
+        }
+    }
+    if started {
+        args.push(arg);
+    }
+
+    args
+}
+
+//-----------------------------------------------------------------------------
+// include directories, defines and forced includes of compiler arguments, in order
+//-----------------------------------------------------------------------------
+
+fn arguments_parse(directory: &Path, args: &[String]) -> CompileCommand {
+    let mut include_dirs = Vec::new();
+    let mut defines = HashMap::new();
+    let mut forced_includes = Vec::new();
+
+    let compiler = args
+        .first()
+        .and_then(|a| {
+            Path::new(&a.replace('\\', "/"))
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_lowercase())
+        })
+        .unwrap_or_default();
+    let msvc = "cl" == compiler || "clang-cl" == compiler;
+    let flags = |gcc: &'static [&'static str], cl: &'static str| {
+        let mut flags = gcc.to_vec();
+        if msvc {
+            flags.push(cl);
+        }
+        flags
+    };
+    let include_flags = flags(&["-isystem", "-iquote", "-idirafter", "-I"], "/I");
+    let define_flags = flags(&["-D"], "/D");
+    let undef_flags = flags(&["-U"], "/U");
+    let forced_flags = flags(&["-include"], "/FI");
+
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        // the value of a flag, attached or the next argument
+        let mut value = |flag: &str| -> Option<String> {
+            let attached = arg.strip_prefix(flag)?;
+            if !attached.is_empty() {
+                Some(attached.to_string())
+            } else {
+                i += 1;
+                args.get(i).cloned()
+            }
+        };
+
+        if let Some(dir) = include_flags
+            .iter()
+            .find(|flag| arg.starts_with(*flag))
+            .and_then(|flag| value(flag))
+        {
+            include_dirs.push(directory.join(dir));
+        } else if let Some(d) = define_flags
+            .iter()
+            .find(|flag| arg.starts_with(*flag))
+            .and_then(|flag| value(flag))
+        {
+            let mut parts = d.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            if !key.is_empty() {
+                defines.insert(key.to_string(), parts.next().unwrap_or("1").to_string());
+            }
+        } else if let Some(u) = undef_flags
+            .iter()
+            .find(|flag| arg.starts_with(*flag))
+            .and_then(|flag| value(flag))
+        {
+            defines.remove(&u);
+        } else if let Some(f) = forced_flags
+            .iter()
+            // -include-pch names a precompiled header, not a header
+            .find(|flag| arg.starts_with(*flag) && !arg.starts_with("-include-"))
+            .and_then(|flag| value(flag))
+        {
+            forced_includes.push(f);
+        }
+        i += 1;
+    }
+
+    CompileCommand {
+        include_dirs,
+        defines,
+        forced_includes,
+        ..Default::default()
+    }
+}
+
+//-----------------------------------------------------------------------------
+// parse the contents of a compilation database
+//-----------------------------------------------------------------------------
+
+pub fn compile_commands_parse(text: &str) -> IncResult<Vec<CompileCommand>> {
+    let entries = match serde_json::from_str(text).map_err(|e| IncError::StdErr(e.into()))? {
+        Value::Array(entries) => entries,
+        _ => return Err(JSON_MALFORMED),
+    };
+
+    let mut commands = Vec::new();
+    for entry in entries.iter() {
+        let field = |key| entry.get(key).and_then(Value::as_str);
+        let directory = PathBuf::from(field("directory").ok_or(JSON_MALFORMED)?);
+        let file = directory.join(field("file").ok_or(JSON_MALFORMED)?);
+        let args = match (entry.get("arguments"), field("command")) {
+            (Some(Value::Array(args)), _) => args
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect(),
+            (_, Some(command)) => command_split(command),
+            _ => return Err(JSON_MALFORMED),
+        };
+        let parsed = arguments_parse(&directory, &args);
+        commands.push(CompileCommand {
+            output: field("output").map(|o| directory.join(o)),
+            directory,
+            file,
+            ..parsed
+        });
+    }
+
+    Ok(commands)
+}
+
+pub fn compile_commands_load(path: &Path) -> IncResult<Vec<CompileCommand>> {
+    compile_commands_parse(&fs::read_to_string(path)?)
+}
+
+impl CompileCommand {
+    // scan options of the translation unit, the includes and defines of base are added on top
+    pub fn scan_options(&self, base: &ScanOptions) -> ScanOptions {
+        let mut options = base.clone();
+        options.include_dirs = self.include_dirs.clone();
+        options
+            .include_dirs
+            .extend(base.include_dirs.iter().cloned());
+        options.defines = self.defines.clone();
+        options.defines.extend(base.defines.clone());
+        options.forced_includes = self.forced_includes.clone();
+        options
+            .forced_includes
+            .extend(base.forced_includes.iter().cloned());
+        options
+    }
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_compdb {
+
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_compile_commands_parse() {
+        assert_eq!(
+            vec!["cc", "-DNAME=\"a b\"", "-Ic:\\sdk dir", "a.c"],
+            command_split(r#"cc "-DNAME=\"a b\"" '-Ic:\sdk dir'  a.c"#)
+        );
+
+        let text = r#"[
+  {"directory": "/build", "file": "../src/a.cpp", "output": "a.o",
+   "command": "clang++ -I../inc -isystem /usr/local/include -DNDEBUG -D VERSION=3 -UDEBUG -include pch.h -include-pch pch.pch -c ../src/a.cpp -o a.o"},
+  {"directory": "/build", "file": "/src/b.cpp",
+   "arguments": ["C:\\VS\\bin\\CL.exe", "/I", "sdk", "/DWIN32", "/FIstdafx.h", "/c", "b.cpp"]},
+  {"directory": "/build", "file": "c.cpp", "command": "clang /Users/dev/c.cpp /Dir/c.o"}
+]"#;
+        let commands = compile_commands_parse(text).unwrap();
+        assert_eq!(3, commands.len());
+
+        let a = &commands[0];
+        assert_eq!(Path::new("/build/../src/a.cpp"), a.file);
+        assert_eq!(Some(PathBuf::from("/build/a.o")), a.output);
+        assert_eq!(
+            vec![
+                PathBuf::from("/build/../inc"),
+                PathBuf::from("/usr/local/include")
+            ],
+            a.include_dirs
+        );
+        assert_eq!(Some(&"1".to_string()), a.defines.get("NDEBUG"));
+        assert_eq!(Some(&"3".to_string()), a.defines.get("VERSION"));
+        assert_eq!(2, a.defines.len());
+        assert_eq!(vec!["pch.h"], a.forced_includes);
+
+        let b = &commands[1];
+        assert_eq!(Path::new("/src/b.cpp"), b.file);
+        assert_eq!(None, b.output);
+        assert_eq!(vec![PathBuf::from("/build/sdk")], b.include_dirs);
+        assert!(b.defines.contains_key("WIN32"));
+        assert_eq!(vec!["stdafx.h"], b.forced_includes);
+        assert!(commands[2].defines.is_empty());
+        assert!(commands[2].forced_includes.is_empty());
+
+        let mut base = ScanOptions::default();
+        base.include_dirs.push(PathBuf::from("extra"));
+        base.defines.insert("VERSION".to_string(), "4".to_string());
+        base.forced_includes.push("extra.h".to_string());
+        let options = a.scan_options(&base);
+        assert_eq!(vec!["pch.h", "extra.h"], options.forced_includes);
+        assert_eq!(PathBuf::from("extra"), options.include_dirs[2]);
+        assert_eq!(Some(&"4".to_string()), options.defines.get("VERSION"));
+        assert_eq!(Some(&"1".to_string()), options.defines.get("NDEBUG"));
+
+        assert!(compile_commands_parse(r#"[{"directory": "/build"}]"#).is_err());
+        assert!(compile_commands_parse(r#"{}"#).is_err());
+        assert!(compile_commands_parse("[{},]").is_err());
+    }
+}
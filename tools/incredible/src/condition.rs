@@ -0,0 +1,630 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Conditional compilation
+//
+//	Evaluates #if/#elif/#ifdef/#ifndef expressions against the known defines
+//	and tracks which branches of nested #if blocks are active, so includes in
+//	disabled branches aren't followed
+//
+//	__has_include(<file>) and __has_include("file") probe the include paths
+//	through a callback given the header name with its delimiters
+//-----------------------------------------------------------------------------
+
+use crate::{IncError, IncResult};
+use std::collections::HashMap;
+
+// limit of nested macro expansions, guards against self referencing macros
+
+const EXPANSION_DEPTH_MAX: usize = 32;
+
+// probes of __has_include, their argument is a header name rather than tokens
+
+const HAS_INCLUDE: [&str; 2] = ["__has_include", "__has_include_next"];
+
+// punctuators, longest first so they match greedily
+
+const PUNCTUATORS: [&str; 24] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "(", ")", "!", "~", "*", "/", "%", "+", "-",
+    "<", ">", "&", "^", "|", "?", ":",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Punct(&'static str),
+    Header(String),
+}
+
+//-----------------------------------------------------------------------------
+// strip a trailing comment from a directive line, ignoring comment markers in quotes
+//-----------------------------------------------------------------------------
+
+pub fn comment_strip(line: &str) -> &str {
+    let mut quoted = false;
+    let bytes = line.as_bytes();
+    for (i, c) in bytes.iter().enumerate() {
+        match c {
+            b'"' => quoted = !quoted,
+            b'/' if !quoted
+                && i + 1 < bytes.len()
+                && (bytes[i + 1] == b'/' || bytes[i + 1] == b'*') =>
+            {
+                return &line[..i];
+            }
+            _ => {}
+        }
+    }
+    line
+}
+
+//-----------------------------------------------------------------------------
+// parse an integer literal, ignoring its suffix (u, l, ul, ...)
+//-----------------------------------------------------------------------------
+
+fn number_parse(literal: &str) -> IncResult<i64> {
+    let digits = literal.trim_end_matches(&['u', 'U', 'l', 'L'][..]);
+    let parsed = if digits.starts_with("0x") || digits.starts_with("0X") {
+        i64::from_str_radix(&digits[2..], 16)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i64::from_str_radix(&digits[1..], 8)
+    } else {
+        digits.parse::<i64>()
+    };
+    parsed.map_err(|_| IncError::Literal("malformed number"))
+}
+
+//-----------------------------------------------------------------------------
+// split an expression into tokens, skipping comments and line continuations
+//-----------------------------------------------------------------------------
+
+fn expression_tokenise(expression: &str) -> IncResult<Vec<Token>> {
+    let bytes = expression.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() || b'\\' == c {
+            i += 1;
+        } else if expression[i..].starts_with("//") {
+            break;
+        } else if expression[i..].starts_with("/*") {
+            match expression[i + 2..].find("*/") {
+                Some(end) => i += end + 4,
+                None => break,
+            }
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < bytes.len() && bytes[i].is_ascii_alphanumeric() {
+                i += 1;
+            }
+            tokens.push(Token::Number(number_parse(&expression[start..i])?));
+        } else if c.is_ascii_alphabetic() || b'_' == c {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || b'_' == bytes[i]) {
+                i += 1;
+            }
+            let ident = &expression[start..i];
+            tokens.push(Token::Ident(ident.to_string()));
+
+            // the header name of a probe, up to the closing parenthesis
+            let rest = expression[i..].trim_start();
+            if HAS_INCLUDE.contains(&ident) && rest.starts_with('(') {
+                let end = rest.find(')').ok_or("malformed __has_include")?;
+                tokens.push(Token::Header(rest[1..end].trim().to_string()));
+                i = expression.len() - rest.len() + end + 1;
+            }
+        } else {
+            let punct = PUNCTUATORS
+                .iter()
+                .find(|p| expression[i..].starts_with(*p))
+                .ok_or("unexpected character")?;
+            tokens.push(Token::Punct(punct));
+            i += punct.len();
+        }
+    }
+
+    Ok(tokens)
+}
+
+//-----------------------------------------------------------------------------
+// precedence of binary operators, higher binds tighter
+//-----------------------------------------------------------------------------
+
+fn binary_precedence(op: &str) -> Option<u8> {
+    match op {
+        "*" | "/" | "%" => Some(10),
+        "+" | "-" => Some(9),
+        "<<" | ">>" => Some(8),
+        "<" | "<=" | ">" | ">=" => Some(7),
+        "==" | "!=" => Some(6),
+        "&" => Some(5),
+        "^" => Some(4),
+        "|" => Some(3),
+        "&&" => Some(2),
+        "||" => Some(1),
+        _ => None,
+    }
+}
+
+fn binary_apply(op: &str, lhs: i64, rhs: i64) -> IncResult<i64> {
+    let value = match op {
+        "*" => lhs.wrapping_mul(rhs),
+        "/" => lhs.checked_div(rhs).ok_or("division by zero")?,
+        "%" => lhs.checked_rem(rhs).ok_or("division by zero")?,
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "<<" => lhs.wrapping_shl(rhs as u32),
+        ">>" => lhs.wrapping_shr(rhs as u32),
+        "<" => (lhs < rhs) as i64,
+        "<=" => (lhs <= rhs) as i64,
+        ">" => (lhs > rhs) as i64,
+        ">=" => (lhs >= rhs) as i64,
+        "==" => (lhs == rhs) as i64,
+        "!=" => (lhs != rhs) as i64,
+        "&" => lhs & rhs,
+        "^" => lhs ^ rhs,
+        "|" => lhs | rhs,
+        "&&" => (lhs != 0 && rhs != 0) as i64,
+        "||" => (lhs != 0 || rhs != 0) as i64,
+        _ => return Err("unknown operator".into()),
+    };
+    Ok(value)
+}
+
+struct Evaluator<'a> {
+    tokens: Vec<Token>,
+    cursor: usize,
+    defines: &'a HashMap<String, String>,
+    has_include: &'a dyn Fn(&str) -> bool,
+    // macros used without being defined, taken as 0
+    unknown: &'a mut Vec<String>,
+    depth: usize,
+}
+
+impl<'a> Evaluator<'a> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.cursor).cloned();
+        self.cursor += 1;
+        token
+    }
+
+    fn next_is(&mut self, punct: &str) -> bool {
+        if let Some(Token::Punct(p)) = self.tokens.get(self.cursor) {
+            if *p == punct {
+                self.cursor += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn expect(&mut self, punct: &'static str) -> IncResult<()> {
+        if self.next_is(punct) {
+            Ok(())
+        } else {
+            Err("malformed expression".into())
+        }
+    }
+
+    fn ternary(&mut self) -> IncResult<i64> {
+        let condition = self.binary(1)?;
+        if !self.next_is("?") {
+            return Ok(condition);
+        }
+        let if_true = self.ternary()?;
+        self.expect(":")?;
+        let if_false = self.ternary()?;
+        Ok(if condition != 0 { if_true } else { if_false })
+    }
+
+    // the next token when it is a binary operator binding at least as tight as precedence_min
+    fn binary_next(&self, precedence_min: u8) -> Option<(&'static str, u8)> {
+        match self.tokens.get(self.cursor) {
+            Some(Token::Punct(op)) => match binary_precedence(op) {
+                Some(p) if p >= precedence_min => Some((*op, p)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn binary(&mut self, precedence_min: u8) -> IncResult<i64> {
+        let mut lhs = self.unary()?;
+        while let Some((op, precedence)) = self.binary_next(precedence_min) {
+            self.cursor += 1;
+            let rhs = self.binary(precedence + 1)?;
+            lhs = binary_apply(op, lhs, rhs)?;
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> IncResult<i64> {
+        match self.next() {
+            Some(Token::Punct("!")) => Ok((self.unary()? == 0) as i64),
+            Some(Token::Punct("~")) => Ok(!self.unary()?),
+            Some(Token::Punct("-")) => Ok(self.unary()?.wrapping_neg()),
+            Some(Token::Punct("+")) => self.unary(),
+            Some(Token::Punct("(")) => {
+                let value = self.ternary()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some(Token::Number(n)) => Ok(n),
+            Some(Token::Ident(name)) => self.identifier(&name),
+            _ => Err("malformed expression".into()),
+        }
+    }
+
+    fn identifier(&mut self, name: &str) -> IncResult<i64> {
+        match name {
+            "defined" => {
+                let parenthesised = self.next_is("(");
+                let key = match self.next() {
+                    Some(Token::Ident(key)) => key,
+                    _ => return Err("malformed defined".into()),
+                };
+                if parenthesised {
+                    self.expect(")")?;
+                }
+                Ok((self.defines.contains_key(&key) || HAS_INCLUDE.contains(&key.as_str())) as i64)
+            }
+            _ if HAS_INCLUDE.contains(&name) => {
+                let header = match self.next() {
+                    Some(Token::Header(header)) => header,
+                    _ => return Err("malformed __has_include".into()),
+                };
+                // the header name may be given by a macro
+                let header = match self.defines.get(&header) {
+                    Some(value) => value.trim(),
+                    None => header.as_str(),
+                };
+                let delimited = (header.starts_with('"') && header.ends_with('"'))
+                    || (header.starts_with('<') && header.ends_with('>'));
+                if header.len() < 2 || !delimited {
+                    return Err("malformed __has_include".into());
+                }
+                Ok((self.has_include)(header) as i64)
+            }
+            "true" => Ok(1),
+            "false" => Ok(0),
+            // macros expand to their value, anything undefined is 0
+            _ => match self.defines.get(name) {
+                Some(value) => expression_value(
+                    value,
+                    self.defines,
+                    self.has_include,
+                    self.unknown,
+                    self.depth + 1,
+                ),
+                None => {
+                    if !self.unknown.iter().any(|u| u == name) {
+                        self.unknown.push(name.to_string());
+                    }
+                    Ok(0)
+                }
+            },
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+// evaluate a preprocessor expression to its integer value
+//-----------------------------------------------------------------------------
+
+fn expression_value(
+    expression: &str,
+    defines: &HashMap<String, String>,
+    has_include: &dyn Fn(&str) -> bool,
+    unknown: &mut Vec<String>,
+    depth: usize,
+) -> IncResult<i64> {
+    if depth > EXPANSION_DEPTH_MAX {
+        return Err("macro expansion too deep".into());
+    }
+
+    let tokens = expression_tokenise(expression)?;
+    if tokens.is_empty() {
+        return Err("missing expression".into());
+    }
+
+    let mut evaluator = Evaluator {
+        tokens,
+        cursor: 0,
+        defines,
+        has_include,
+        unknown,
+        depth,
+    };
+    let value = evaluator.ternary()?;
+    if evaluator.cursor != evaluator.tokens.len() {
+        return Err("unexpected token".into());
+    }
+    Ok(value)
+}
+
+//-----------------------------------------------------------------------------
+// evaluate the expression of an #if or #elif, the macros it uses undefined are added to unknown
+//-----------------------------------------------------------------------------
+
+pub fn condition_evaluate(
+    expression: &str,
+    defines: &HashMap<String, String>,
+    has_include: &dyn Fn(&str) -> bool,
+    unknown: &mut Vec<String>,
+) -> IncResult<bool> {
+    Ok(expression_value(expression, defines, has_include, unknown, 0)? != 0)
+}
+
+//	a branch of an #if block
+
+struct Branch {
+    // the branch currently being scanned is active
+    active: bool,
+    // a branch of the block was (or can no longer be) taken, later #elif/#else are inactive
+    taken: bool,
+}
+
+//	branches of the #if blocks enclosing the current line
+
+pub struct ConditionStack {
+    branches: Vec<Branch>,
+    // macros the evaluated expressions used without them being defined
+    unknown: Vec<String>,
+}
+
+impl ConditionStack {
+    pub fn new() -> Self {
+        ConditionStack {
+            branches: Vec::new(),
+            unknown: Vec::new(),
+        }
+    }
+
+    // macros used undefined by the directives applied since the last call
+    pub fn unknown_take(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.unknown)
+    }
+
+    // lines are active when every enclosing branch is
+    pub fn active(&self) -> bool {
+        match self.branches.last() {
+            Some(branch) => branch.active,
+            None => true,
+        }
+    }
+
+    fn branch_if(&mut self, condition: impl FnOnce() -> bool) {
+        // branches nested in inactive ones are never taken, or evaluated
+        let enclosing = self.active();
+        let active = enclosing && condition();
+        self.branches.push(Branch {
+            active,
+            taken: active || !enclosing,
+        });
+    }
+
+    fn branch_elif(&mut self, condition: impl FnOnce() -> bool) {
+        if let Some(branch) = self.branches.last_mut() {
+            branch.active = !branch.taken && condition();
+            branch.taken |= branch.active;
+        }
+    }
+
+    fn branch_else(&mut self) {
+        if let Some(branch) = self.branches.last_mut() {
+            branch.active = !branch.taken;
+            branch.taken = true;
+        }
+    }
+
+    fn branch_end(&mut self) {
+        self.branches.pop();
+    }
+
+    //-----------------------------------------------------------------------------
+    // update the branches for a conditional directive (if, ifdef, ifndef, elif, else, endif)
+    // expressions that can't be evaluated are taken as true, extra dependencies are safer than
+    // missing ones, and the reason is returned as a warning. macros that aren't
+    // defined are 0, as in the standard, and kept for unknown_take
+    //-----------------------------------------------------------------------------
+
+    pub fn directive_apply(
+        &mut self,
+        directive: &str,
+        expression: &str,
+        defines: &HashMap<String, String>,
+        has_include: &dyn Fn(&str) -> bool,
+    ) -> Option<String> {
+        let expression = comment_strip(expression).trim();
+        let mut warning = None;
+        let mut unknown = Vec::new();
+        let evaluate = || match directive {
+            "ifdef" => defines.contains_key(expression) || HAS_INCLUDE.contains(&expression),
+            "ifndef" => !defines.contains_key(expression) && !HAS_INCLUDE.contains(&expression),
+            _ => match condition_evaluate(expression, defines, has_include, &mut unknown) {
+                Ok(condition) => condition,
+                Err(e) => {
+                    warning = Some(format!(
+                        "couldn't evaluate #{} {} : {}",
+                        directive, expression, e
+                    ));
+                    true
+                }
+            },
+        };
+
+        match directive {
+            "if" | "ifdef" | "ifndef" => self.branch_if(evaluate),
+            "elif" => self.branch_elif(evaluate),
+            "else" => self.branch_else(),
+            "endif" => self.branch_end(),
+            _ => {}
+        }
+        for name in unknown {
+            if !self.unknown.contains(&name) {
+                self.unknown.push(name);
+            }
+        }
+        warning
+    }
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_condition {
+
+    #[cfg(test)]
+    use super::*;
+
+    fn defines() -> HashMap<String, String> {
+        let mut defines = HashMap::new();
+        defines.insert("PLATFORM_WINDOWS".to_string(), "1".to_string());
+        defines.insert("VERSION".to_string(), "(3 * 100 + 2)".to_string());
+        defines.insert("USE_VERSION".to_string(), "VERSION".to_string());
+        defines.insert("EMPTY".to_string(), "".to_string());
+        defines.insert("SELF".to_string(), "SELF".to_string());
+        defines
+    }
+
+    #[test]
+    fn test_condition_evaluate() {
+        let d = defines();
+        let none = |_: &str| false;
+        let eval = |e| condition_evaluate(e, &d, &none, &mut Vec::new()).unwrap();
+
+        assert!(eval("1"));
+        assert!(!eval("0"));
+        assert!(eval("PLATFORM_WINDOWS"));
+        assert!(!eval("PLATFORM_LINUX"));
+        assert!(eval("defined(PLATFORM_WINDOWS) && !defined PLATFORM_LINUX"));
+        assert!(eval("defined(EMPTY)"));
+        assert!(eval("VERSION >= 302 && USE_VERSION < 0x200"));
+        assert!(eval("(1 + 2 * 3) == 7 && 10 % 4 == 2 && (1 << 4) == 16"));
+        assert!(eval("-1 < 0 && ~0 == -1"));
+        assert!(eval("PLATFORM_LINUX || PLATFORM_WINDOWS"));
+        assert!(eval("0 ? 0 : 1"));
+        assert!(eval("1L == 1u && 010 == 8"));
+        assert!(eval("1 // trailing comment"));
+        assert!(eval("/* leading */ 1"));
+        assert!(eval("defined(PLATFORM_WINDOWS) \\\r\n && 1"));
+
+        let mut unknown = Vec::new();
+        assert!(
+            !condition_evaluate("_WIN32 || _WIN32 + __cplusplus", &d, &none, &mut unknown).unwrap()
+        );
+        assert_eq!(vec!["_WIN32", "__cplusplus"], unknown);
+
+        assert!(condition_evaluate("", &d, &none, &mut Vec::new()).is_err());
+        assert!(condition_evaluate("EMPTY", &d, &none, &mut Vec::new()).is_err());
+        assert!(condition_evaluate("SELF", &d, &none, &mut Vec::new()).is_err());
+        assert!(condition_evaluate("1 / 0", &d, &none, &mut Vec::new()).is_err());
+        assert!(condition_evaluate("(1", &d, &none, &mut Vec::new()).is_err());
+        assert!(condition_evaluate("1 2", &d, &none, &mut Vec::new()).is_err());
+
+        let found = |h: &str| "<found.h>" == h;
+        let mut header = d.clone();
+        header.insert("FOUND".to_string(), "<found.h>".to_string());
+        let probe = |e| condition_evaluate(e, &header, &found, &mut Vec::new()).unwrap();
+        assert!(probe("__has_include(<found.h>)"));
+        assert!(!probe("__has_include(<missing.h>)"));
+        assert!(!probe("__has_include(\"found.h\")"));
+        assert!(probe(
+            "defined(__has_include) && __has_include_next(<found.h>)"
+        ));
+        assert!(probe(
+            "__has_include(FOUND) && !__has_include(<dir/missing.h>)"
+        ));
+        assert!(
+            condition_evaluate("__has_include(found.h)", &header, &found, &mut Vec::new()).is_err()
+        );
+        assert!(condition_evaluate("__has_include", &header, &found, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_condition_stack() {
+        let d = defines();
+        let none = |_: &str| false;
+        let mut c = ConditionStack::new();
+        assert!(c.active());
+
+        c.directive_apply("ifdef", "PLATFORM_LINUX", &d, &none);
+        assert!(!c.active());
+        c.directive_apply("elif", "PLATFORM_WINDOWS // windows", &d, &none);
+        assert!(c.active());
+        c.directive_apply("else", "", &d, &none);
+        assert!(!c.active());
+        c.directive_apply("endif", "", &d, &none);
+        assert!(c.active());
+
+        // nested in an inactive branch, nothing is taken
+        c.directive_apply("if", "0", &d, &none);
+        c.directive_apply("if", "1", &d, &none);
+        assert!(!c.active());
+        c.directive_apply("else", "", &d, &none);
+        assert!(!c.active());
+        c.directive_apply("endif", "", &d, &none);
+        c.directive_apply("elif", "1", &d, &none);
+        assert!(c.active());
+        c.directive_apply("else", "", &d, &none);
+        assert!(!c.active());
+        c.directive_apply("endif", "", &d, &none);
+
+        c.directive_apply("ifndef", "PLATFORM_LINUX", &d, &none);
+        assert!(c.active());
+        c.directive_apply("endif", "", &d, &none);
+
+        // unbalanced endif is ignored
+        c.directive_apply("endif", "", &d, &none);
+        assert!(c.active());
+
+        // expressions that can't be evaluated are taken, with a warning
+        assert!(c.directive_apply("if", "(1", &d, &none).is_some());
+        assert!(c.active());
+        c.directive_apply("endif", "", &d, &none);
+        assert_eq!(None, c.directive_apply("if", "1", &d, &none));
+        assert_eq!(None, c.directive_apply("elif", "1 / 0", &d, &none));
+        c.directive_apply("endif", "", &d, &none);
+
+        // only the headers the callback finds exist
+        let found = |h: &str| "<found.h>" == h || "\"sub/found.h\"" == h;
+        c.directive_apply("if", "__has_include(<missing.h>)", &d, &found);
+        assert!(!c.active());
+        c.directive_apply("elif", "__has_include( \"sub/found.h\" )", &d, &found);
+        assert!(c.active());
+        c.directive_apply("endif", "", &d, &found);
+
+        // undefined macros are 0, each kept once until taken, defined() doesn't use them
+        assert!(c.unknown_take().is_empty());
+        c.directive_apply("if", "_MSC_VER >= 1900 || defined(_WIN32)", &d, &none);
+        assert!(!c.active());
+        c.directive_apply("elif", "_MSC_VER || USE_VERSION", &d, &none);
+        assert!(c.active());
+        c.directive_apply("endif", "", &d, &none);
+        assert_eq!(vec!["_MSC_VER".to_string()], c.unknown_take());
+        assert!(c.unknown_take().is_empty());
+
+        assert_eq!(
+            "#include \"a//b.h\" ",
+            comment_strip("#include \"a//b.h\" // b")
+        );
+        assert_eq!("1 ", comment_strip("1 /* x */"));
+    }
+}
@@ -0,0 +1,211 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Diagnostics
+//
+//	What a run has to report besides dependencies, collected from every worker
+//	and rendered once the scan is done:
+//
+//	error		the run failed, or an include is unresolved under --strict
+//	warning		unknown macros, conditions that couldn't be evaluated,
+//			files that couldn't be read, unresolved includes
+//	note		what was scanned, shown with --verbose
+//
+//	each is about a file and line when it can be (those of a #line), and is
+//	rendered as compilers do (warning: main.cpp:12: message) or as json:
+//
+//	[
+//	  {"severity": "warning", "file": "main.cpp", "line": 12, "message": "..."},
+//	  {"severity": "error", "file": null, "line": null, "message": "..."}
+//	]
+//-----------------------------------------------------------------------------
+
+use crate::tree::json_string;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+//	most severe first, a level shows the severities up to it
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+}
+
+//	sorted by location, then severity
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Diagnostic {
+    // the file as reported, path of a scanned file or file of a #line
+    pub file: Option<String>,
+    // 0 when not about a line
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: String) -> Self {
+        Diagnostic {
+            file: None,
+            line: 0,
+            severity,
+            message,
+        }
+    }
+
+    pub fn at(self, file: &str, line: usize) -> Self {
+        Diagnostic {
+            file: Some(file.to_string()),
+            line,
+            ..self
+        }
+    }
+
+    //-----------------------------------------------------------------------------
+    // diagnostics as a json array, one per line
+    //-----------------------------------------------------------------------------
+
+    pub fn json_format(diagnostics: &[Diagnostic]) -> String {
+        let entries: Vec<String> = diagnostics
+            .iter()
+            .map(|d| {
+                format!(
+                    "  {{\"severity\": \"{}\", \"file\": {}, \"line\": {}, \"message\": {}}}",
+                    d.severity.name(),
+                    match &d.file {
+                        Some(f) => json_string(f),
+                        None => "null".to_string(),
+                    },
+                    match d.line {
+                        0 => "null".to_string(),
+                        l => l.to_string(),
+                    },
+                    json_string(&d.message)
+                )
+            })
+            .collect();
+        if entries.is_empty() {
+            "[]\n".to_string()
+        } else {
+            format!("[\n{}\n]\n", entries.join(",\n"))
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.severity.name())?;
+        match (&self.file, self.line) {
+            (Some(file), 0) => write!(f, "{}: ", file)?,
+            (Some(file), line) => write!(f, "{}:{}: ", file, line)?,
+            (None, _) => {}
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+//	diagnostics of all workers, returned with the graph
+
+#[derive(Clone)]
+pub(crate) struct Diagnostics {
+    diagnostics: Arc<Mutex<Vec<Diagnostic>>>,
+}
+
+impl Diagnostics {
+    pub(crate) fn new() -> Self {
+        Diagnostics {
+            diagnostics: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub(crate) fn add(&self, diagnostic: Diagnostic) {
+        if let Ok(mut diagnostics) = self.diagnostics.lock() {
+            diagnostics.push(diagnostic);
+        }
+    }
+
+    // sorted without duplicates, the same missing include is reported by every includer
+    pub(crate) fn sorted(&self) -> Vec<Diagnostic> {
+        let mut sorted = match self.diagnostics.lock() {
+            Ok(diagnostics) => diagnostics.clone(),
+            Err(_) => Vec::new(),
+        };
+        sorted.sort();
+        sorted.dedup();
+        sorted
+    }
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_diagnostic {
+
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_diagnostic_format() {
+        let warning = Diagnostic::new(Severity::Warning, "couldn't find macro: X".into());
+        assert_eq!("warning: couldn't find macro: X", warning.to_string());
+        assert_eq!(
+            "warning: gen.y:12: couldn't find macro: X",
+            warning.clone().at("gen.y", 12).to_string()
+        );
+        assert_eq!(
+            "error: a.cpp: unreadable",
+            Diagnostic::new(Severity::Error, "unreadable".into())
+                .at("a.cpp", 0)
+                .to_string()
+        );
+
+        assert_eq!("[]\n", Diagnostic::json_format(&[]));
+        assert_eq!(
+            "[\n  {\"severity\": \"warning\", \"file\": \"gen.y\", \"line\": 12, \"message\": \"couldn't find macro: X\"},\n  {\"severity\": \"warning\", \"file\": null, \"line\": null, \"message\": \"couldn't find macro: X\"}\n]\n",
+            Diagnostic::json_format(&[warning.clone().at("gen.y", 12), warning])
+        );
+    }
+
+    #[test]
+    fn test_diagnostics_sorted() {
+        let diagnostics = Diagnostics::new();
+        let missing = |file: &str, line| {
+            Diagnostic::new(Severity::Warning, "couldn't find macro: X".into()).at(file, line)
+        };
+        diagnostics.add(missing("b.h", 3));
+        diagnostics.add(missing("a.h", 7));
+        diagnostics.add(missing("b.h", 3));
+        diagnostics.add(missing("a.h", 2));
+        assert_eq!(
+            vec![missing("a.h", 2), missing("a.h", 7), missing("b.h", 3)],
+            diagnostics.sorted()
+        );
+        assert!(Severity::Error < Severity::Warning && Severity::Warning < Severity::Note);
+    }
+}
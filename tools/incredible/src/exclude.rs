@@ -0,0 +1,158 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Exclude patterns
+//
+//	Includes resolved to a path matching an --exclude=<glob> are neither
+//	scanned nor listed as dependencies, e.g. third party or generated headers
+//
+//	*	any characters but a separator
+//	**	any characters, separators included
+//	?	a single character but a separator
+//
+//	a pattern matching a directory excludes everything below it, patterns not
+//	starting with a separator or a drive match at any depth (third_party
+//	excludes /src/third_party/zlib/zlib.h). Both separators are accepted
+//-----------------------------------------------------------------------------
+
+use std::path::Path;
+
+#[derive(Clone, Debug, Default)]
+pub struct ExcludeSet {
+    patterns: Vec<Vec<char>>,
+}
+
+//-----------------------------------------------------------------------------
+// path with forward slashes, the separator patterns are written with
+//-----------------------------------------------------------------------------
+
+pub fn glob_normalise(path: &str) -> String {
+    path.replace('\\', "/").trim_end_matches('/').to_string()
+}
+
+//-----------------------------------------------------------------------------
+// match a whole path against a pattern
+//-----------------------------------------------------------------------------
+
+pub fn glob_match(pattern: &[char], path: &[char]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some('*') if Some(&'*') == pattern.get(1) => {
+            // **/ also matches no directory at all
+            if Some(&'/') == pattern.get(2) {
+                let rest = &pattern[3..];
+                glob_match(rest, path)
+                    || (0..path.len()).any(|i| '/' == path[i] && glob_match(rest, &path[i + 1..]))
+            } else {
+                let rest = &pattern[2..];
+                (0..=path.len()).any(|i| glob_match(rest, &path[i..]))
+            }
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+            for i in 0..=path.len() {
+                if glob_match(rest, &path[i..]) {
+                    return true;
+                }
+                if i < path.len() && '/' == path[i] {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => match path.first() {
+            Some(c) if '/' != *c => glob_match(&pattern[1..], &path[1..]),
+            _ => false,
+        },
+        Some(c) => match path.first() {
+            Some(p) if p == c => glob_match(&pattern[1..], &path[1..]),
+            _ => false,
+        },
+    }
+}
+
+impl ExcludeSet {
+    pub fn new(globs: &[String]) -> Self {
+        let patterns = globs
+            .iter()
+            .map(|g| glob_normalise(g.trim()))
+            .filter(|g| !g.is_empty())
+            .map(|g| {
+                let anchored = g.starts_with('/') || g.starts_with("**") || g.contains(':');
+                let pattern = if anchored { g } else { format!("**/{}", g) };
+                pattern.chars().collect()
+            })
+            .collect();
+        ExcludeSet { patterns }
+    }
+
+    // true when the path or one of its directories matches a pattern
+    pub fn matches(&self, path: &Path) -> bool {
+        if self.patterns.is_empty() {
+            return false;
+        }
+        // the path and each of its directories, as written in it
+        let text: Vec<char> = glob_normalise(&path.to_string_lossy()).chars().collect();
+        (1..=text.len())
+            .filter(|&i| i == text.len() || '/' == text[i])
+            .any(|i| self.patterns.iter().any(|p| glob_match(p, &text[..i])))
+    }
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_exclude {
+
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_exclude_matches() {
+        let glob = |p: &str, s: &str| {
+            glob_match(
+                &p.chars().collect::<Vec<_>>(),
+                &s.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(glob("a/*.h", "a/b.h"));
+        assert!(!glob("a/*.h", "a/b/c.h"));
+        assert!(glob("a/**.h", "a/b/c.h"));
+        assert!(glob("a/**/c.h", "a/c.h"));
+        assert!(glob("a/**/c.h", "a/b/d/c.h"));
+        assert!(glob("a/?.h", "a/b.h"));
+        assert!(!glob("a/?.h", "a/bb.h"));
+        assert!(!glob("a/b", "a/bc"));
+
+        let excludes = ExcludeSet::new(&[
+            "third_party".to_string(),
+            "/gen/*_generated.h".to_string(),
+            "sdk\\include\\".to_string(),
+        ]);
+        assert!(excludes.matches(Path::new("/src/third_party/zlib/zlib.h")));
+        assert!(excludes.matches(Path::new("third_party/a.h")));
+        assert!(excludes.matches(Path::new("./third_party/a.h")));
+        assert!(!excludes.matches(Path::new("/src/third_party_shim/a.h")));
+        assert!(excludes.matches(Path::new("/gen/types_generated.h")));
+        assert!(!excludes.matches(Path::new("/src/gen/types_generated.h")));
+        assert!(excludes.matches(Path::new("c:\\sdk\\include\\windows.h")));
+        assert!(!excludes.matches(Path::new("/src/include/a.h")));
+
+        assert!(!ExcludeSet::new(&[]).matches(Path::new("/src/a.h")));
+        assert!(!ExcludeSet::new(&["".to_string()]).matches(Path::new("/src/a.h")));
+    }
+}
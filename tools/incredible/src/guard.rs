@@ -0,0 +1,238 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Include guards
+//
+//	Detects headers protected by #pragma once or a classic include guard
+//	(#ifndef X / #define X ... #endif around the whole file), in source
+//	already cleaned of comments and literals (lexer::source_clean). A header
+//	reached again once scanned is skipped without reading it, under #pragma once
+//	or when the defines it is reached with have its guard macro
+//-----------------------------------------------------------------------------
+
+use crate::file_id;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Guard {
+    PragmaOnce,
+    Macro(String),
+}
+
+//	guards of the files already scanned, by physical file (as FileAliases) so
+//	the same file reached through different paths matches
+
+#[derive(Clone)]
+pub struct GuardedFiles {
+    scanned: Arc<Mutex<HashMap<(u64, u64), Guard>>>,
+}
+
+impl GuardedFiles {
+    pub fn new() -> Self {
+        GuardedFiles {
+            scanned: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // true when the file was already scanned, and its guard excludes it with these defines
+    pub fn contains(&self, path: &Path, defines: &HashMap<String, String>) -> bool {
+        let scanned = match self.scanned.lock() {
+            Ok(scanned) => scanned,
+            Err(_) => return false,
+        };
+        match file_id(path).and_then(|id| scanned.get(&id)) {
+            Some(Guard::PragmaOnce) => true,
+            Some(Guard::Macro(name)) => defines.contains_key(name),
+            None => false,
+        }
+    }
+
+    pub fn insert(&self, path: &Path, guard: Guard) {
+        if let (Some(id), Ok(mut scanned)) = (file_id(path), self.scanned.lock()) {
+            scanned.insert(id, guard);
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+// the macro an #ifndef X or #if !defined(X) guard line tests
+//-----------------------------------------------------------------------------
+
+fn guard_macro(line: &str) -> Option<&str> {
+    let condition = match line.strip_prefix("ifndef") {
+        Some(condition) => condition,
+        None => line
+            .strip_prefix("if")?
+            .trim_start()
+            .strip_prefix('!')?
+            .trim_start()
+            .strip_prefix("defined")?,
+    };
+
+    let name = condition
+        .trim()
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .trim();
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || '_' == c) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+//-----------------------------------------------------------------------------
+// detect the guard of a cleaned header, none when parts of it are outside the guard
+//-----------------------------------------------------------------------------
+
+pub fn guard_detect(data: &[u8]) -> Option<Guard> {
+    let text = String::from_utf8_lossy(data);
+
+    // directives without the hash, code lines as they are
+    let lines: Vec<(bool, String)> = text
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .map(|l| match l.strip_prefix('#') {
+            Some(directive) => (true, directive.trim_start().to_string()),
+            None => (false, l.to_string()),
+        })
+        .collect();
+
+    let words = |l: &str| l.split_whitespace().collect::<Vec<_>>().join(" ");
+    if lines
+        .iter()
+        .any(|(directive, l)| *directive && "pragma once" == words(l))
+    {
+        return Some(Guard::PragmaOnce);
+    }
+
+    // #ifndef X and #define X, then the #endif closing the first line is the last line
+    let name = match lines.first() {
+        Some((true, l)) => guard_macro(l)?,
+        _ => return None,
+    };
+    match lines.get(1) {
+        Some((true, l)) if l.strip_prefix("define").map(|d| d.trim()) == Some(name) => {}
+        _ => return None,
+    }
+
+    let mut depth = 0;
+    for (i, (directive, l)) in lines.iter().enumerate() {
+        if !directive {
+            continue;
+        }
+        let keyword = l.split_whitespace().next().unwrap_or("");
+        match keyword {
+            "if" | "ifdef" | "ifndef" => depth += 1,
+            "endif" => {
+                depth -= 1;
+                if 0 == depth {
+                    return if i + 1 == lines.len() {
+                        Some(Guard::Macro(name.to_string()))
+                    } else {
+                        None
+                    };
+                }
+            }
+            // an #else of the guard would be outside of it
+            "else" | "elif" if 1 == depth => return None,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_guard {
+
+    #[cfg(test)]
+    use super::*;
+    #[cfg(test)]
+    use crate::lexer::source_clean;
+
+    #[test]
+    fn test_guard_detect() {
+        let detect = |s: &str| guard_detect(&source_clean(s.as_bytes()));
+
+        assert_eq!(
+            Some(Guard::PragmaOnce),
+            detect("#pragma once\n#include \"a.h\"\n")
+        );
+        assert_eq!(
+            Some(Guard::PragmaOnce),
+            detect("// header\n#  pragma   once\r\n")
+        );
+
+        let classic = "// Copyright\n/* multi\n line */\n#ifndef FOO_H\n#define FOO_H\n#ifdef BAR\n#include \"bar.h\"\n#endif\nint x;\n#endif // FOO_H\n";
+        assert_eq!(Some(Guard::Macro("FOO_H".to_string())), detect(classic));
+        assert_eq!(
+            Some(Guard::Macro("FOO_H".to_string())),
+            detect("#if !defined(FOO_H)\n#define FOO_H\n#endif\n")
+        );
+
+        // includes outside the guard
+        assert_eq!(
+            None,
+            detect("#include \"a.h\"\n#ifndef FOO_H\n#define FOO_H\n#endif\n")
+        );
+        assert_eq!(
+            None,
+            detect("#ifndef FOO_H\n#define FOO_H\n#endif\n#include \"a.h\"\n")
+        );
+        assert_eq!(
+            None,
+            detect("#ifndef FOO_H\n#define FOO_H\n#else\n#include \"a.h\"\n#endif\n")
+        );
+
+        // not a guard
+        assert_eq!(None, detect("#ifndef FOO_H\n#define BAR_H\n#endif\n"));
+        assert_eq!(None, detect("#ifdef FOO_H\n#define FOO_H\n#endif\n"));
+        assert_eq!(None, detect("#include \"a.h\"\n"));
+        assert_eq!(None, detect("// #pragma once\n"));
+        assert_eq!(None, detect(""));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_guarded_files() {
+        let dir = std::env::temp_dir().join("incredible_test_guarded_files");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.h"), "").unwrap();
+        std::fs::write(dir.join("b.h"), "").unwrap();
+        let _ = std::fs::remove_file(dir.join("link.h"));
+        std::fs::hard_link(dir.join("a.h"), dir.join("link.h")).unwrap();
+
+        let mut defines = HashMap::new();
+        let guarded = GuardedFiles::new();
+        guarded.insert(&dir.join("a.h"), Guard::Macro("A_H".to_string()));
+        guarded.insert(&dir.join("b.h"), Guard::PragmaOnce);
+
+        // a macro guard only excludes the file once its macro is defined
+        assert!(!guarded.contains(&dir.join("link.h"), &defines));
+        defines.insert("A_H".to_string(), String::new());
+        assert!(guarded.contains(&dir.join("link.h"), &defines));
+        assert!(guarded.contains(&dir.join("b.h"), &HashMap::new()));
+        assert!(!guarded.contains(&dir.join("missing.h"), &defines));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
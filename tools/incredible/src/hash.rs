@@ -0,0 +1,174 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Content hashes
+//
+//	SHA-256 of the files of a scan (-o-hashes=file), so build caches can
+//	fingerprint a translation unit without reading its files again. Files are
+//	hashed by a pool of workers, the closure hash covers the path and hash of
+//	every file, a file renamed or changed changes it:
+//
+//	closure	<sha-256 of the lines below>
+//	<sha-256>	<path>		(a line per file, sorted by path)
+//-----------------------------------------------------------------------------
+
+use crate::pool::{pool_run, WorkQueue};
+use crate::IncResult;
+use sha2::Digest;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+pub type Sha256 = [u8; 32];
+
+//-----------------------------------------------------------------------------
+// sha-256 of data
+//-----------------------------------------------------------------------------
+
+pub fn sha256(data: &[u8]) -> Sha256 {
+    sha2::Sha256::digest(data).into()
+}
+
+pub fn sha256_hex(hash: &Sha256) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+//-----------------------------------------------------------------------------
+// hash files on worker_count threads, in the order given, with the error of
+// those that couldn't be read
+//-----------------------------------------------------------------------------
+
+pub fn files_hash(paths: &[PathBuf], worker_count: usize) -> Vec<(PathBuf, IncResult<Sha256>)> {
+    let work = Arc::new(WorkQueue::new());
+    for path in paths.iter() {
+        work.push(path.clone());
+    }
+
+    let hashed: Arc<Mutex<HashMap<PathBuf, io::Result<Sha256>>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    {
+        let hashed = hashed.clone();
+        pool_run(&work, worker_count, move |_, path| {
+            let hash = fs::read(&path).map(|data| sha256(&data));
+            if let Ok(mut hashed) = hashed.lock() {
+                hashed.insert(path, hash);
+            }
+        });
+    }
+
+    let hashed = match hashed.lock() {
+        Ok(mut hashed) => std::mem::take(&mut *hashed),
+        Err(_) => HashMap::new(),
+    };
+    paths
+        .iter()
+        .map(|p| {
+            let hash = match hashed.get(p) {
+                Some(Ok(hash)) => Ok(*hash),
+                Some(Err(e)) => Err(io::Error::new(e.kind(), e.to_string()).into()),
+                None => Err("couldn't hash file".into()),
+            };
+            (p.clone(), hash)
+        })
+        .collect()
+}
+
+//-----------------------------------------------------------------------------
+// format the hashes of files, by path as written, after their closure hash
+//-----------------------------------------------------------------------------
+
+pub fn hashes_format(hashes: &[(String, Sha256)]) -> String {
+    let mut sorted: Vec<&(String, Sha256)> = hashes.iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+    sorted.dedup_by(|a, b| a.0 == b.0);
+    let lines: String = sorted
+        .iter()
+        .map(|(path, hash)| format!("{}\t{}\n", sha256_hex(hash), path))
+        .collect();
+    format!(
+        "closure\t{}\n{}",
+        sha256_hex(&sha256(lines.as_bytes())),
+        lines
+    )
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_hash {
+
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_sha256() {
+        let hex = |data: &[u8]| sha256_hex(&sha256(data));
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            hex(b"")
+        );
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            hex(b"abc")
+        );
+        assert_eq!(
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")
+        );
+        assert_eq!(
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0",
+            hex(&vec![b'a'; 1_000_000])
+        );
+    }
+
+    #[test]
+    fn test_files_hash() {
+        let dir = std::env::temp_dir().join("incredible_test_files_hash");
+        fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<PathBuf> = (0..16).map(|i| dir.join(format!("{}.h", i))).collect();
+        for (i, path) in paths.iter().enumerate() {
+            fs::write(path, i.to_string()).unwrap();
+        }
+        let mut with_missing = paths.clone();
+        with_missing.push(dir.join("missing.h"));
+
+        let hashes = files_hash(&with_missing, 4);
+        assert_eq!(17, hashes.len());
+        for (i, (path, hash)) in hashes.iter().take(16).enumerate() {
+            assert_eq!(&paths[i], path);
+            assert_eq!(sha256(i.to_string().as_bytes()), *hash.as_ref().unwrap());
+        }
+        assert!(hashes[16].1.is_err());
+
+        let a = (String::from("a.h"), sha256(b"a"));
+        let b = (String::from("b.h"), sha256(b"b"));
+        let text = hashes_format(&[b.clone(), a.clone()]);
+        let lines = format!("{}\ta.h\n{}\tb.h\n", sha256_hex(&a.1), sha256_hex(&b.1));
+        assert_eq!(
+            format!(
+                "closure\t{}\n{}",
+                sha256_hex(&sha256(lines.as_bytes())),
+                lines
+            ),
+            text
+        );
+        assert_eq!(text, hashes_format(&[a.clone(), b, a]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Inputs
+//
+//	Inputs given as globs or directories, expanded to the files they name so
+//	build rules don't have to list the sources themselves:
+//
+//	src/main.cpp		the file
+//	src			the files below src with a source extension
+//	src/**/*.cpp		the files below src matching the glob (* ** ?)
+//
+//	globs match files whatever their extension, the extensions only filter
+//	directories. Files are listed in the order of the inputs, those of a
+//	directory or glob sorted, each once. Symbolic links to directories aren't
+//	followed
+//-----------------------------------------------------------------------------
+
+use crate::exclude::{glob_match, glob_normalise};
+use crate::{IncError, IncResult};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// extensions of the files of directory inputs, when not given (--extensions)
+
+pub const SOURCE_EXTENSIONS: &[&str] = &["c", "cc", "cpp", "cxx", "c++", "m", "mm"];
+
+fn glob_is(pattern: &str) -> bool {
+    pattern.contains(&['*', '?'][..])
+}
+
+//-----------------------------------------------------------------------------
+// files below a directory, recursively, sorted
+//-----------------------------------------------------------------------------
+
+fn files_walk(dir: &Path, files: &mut Vec<PathBuf>) -> IncResult<()> {
+    let mut entries: Vec<_> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            files_walk(&entry.path(), files)?;
+        } else if !entry.path().is_dir() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+//-----------------------------------------------------------------------------
+// files matching a glob, walking from the directory before its first wildcard
+//-----------------------------------------------------------------------------
+
+fn glob_expand(glob: &str) -> IncResult<Vec<PathBuf>> {
+    let pattern = glob_normalise(glob);
+    let base: Vec<&str> = pattern
+        .split('/')
+        .take_while(|component| !glob_is(component))
+        .collect();
+    let base = base.join("/");
+
+    let walked = if base.is_empty() { "." } else { &base };
+    let mut files = Vec::new();
+    if Path::new(walked).is_dir() {
+        files_walk(Path::new(walked), &mut files)?;
+    }
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    Ok(files
+        .into_iter()
+        .map(|f| match f.strip_prefix(".") {
+            Ok(relative) if base.is_empty() => relative.to_path_buf(),
+            _ => f,
+        })
+        .filter(|f| {
+            let text: Vec<char> = glob_normalise(&f.to_string_lossy()).chars().collect();
+            glob_match(&pattern, &text)
+        })
+        .collect())
+}
+
+//-----------------------------------------------------------------------------
+// the files of input files, directories and globs, a glob matching no file fails
+//-----------------------------------------------------------------------------
+
+pub fn inputs_expand(inputs: &[String], extensions: &[String]) -> IncResult<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    for input in inputs {
+        if glob_is(input) {
+            let matched = glob_expand(input)?;
+            if matched.is_empty() {
+                return Err(IncError::StdErr(
+                    format!("no file matches {}", input).into(),
+                ));
+            }
+            files.extend(matched);
+        } else if Path::new(input).is_dir() {
+            let mut walked = Vec::new();
+            files_walk(Path::new(input), &mut walked)?;
+            files.extend(walked.into_iter().filter(|f| {
+                let extension = f.extension().map(|e| e.to_string_lossy().to_lowercase());
+                extensions
+                    .iter()
+                    .any(|e| Some(e.trim_start_matches('.').to_lowercase()) == extension)
+            }));
+        } else {
+            // missing files fail the scan
+            files.push(PathBuf::from(input));
+        }
+    }
+
+    // the first of the same file
+    let mut listed = HashSet::new();
+    files.retain(|f| listed.insert(f.clone()));
+    Ok(files)
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_input {
+
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_inputs_expand() {
+        let dir = std::env::temp_dir().join("incredible_test_input");
+        fs::create_dir_all(dir.join("src/sub")).unwrap();
+        for file in &["src/a.cpp", "src/a.h", "src/sub/b.CC", "src/sub/c.cpp"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let root = dir.to_string_lossy().to_string();
+        let expand = |inputs: &[&str], extensions: &[&str]| {
+            inputs_expand(
+                &inputs
+                    .iter()
+                    .map(|i| i.replace("$", &root))
+                    .collect::<Vec<_>>(),
+                &extensions.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+            )
+        };
+
+        assert_eq!(
+            vec![
+                dir.join("src/a.cpp"),
+                dir.join("src/sub/b.CC"),
+                dir.join("src/sub/c.cpp")
+            ],
+            expand(&["$/src"], &["cpp", ".cc"]).unwrap()
+        );
+        assert_eq!(
+            vec![dir.join("src/a.cpp"), dir.join("src/sub/c.cpp")],
+            expand(&["$/src/**/*.cpp"], &[]).unwrap()
+        );
+        assert_eq!(
+            vec![dir.join("src/a.h"), dir.join("src/a.cpp")],
+            expand(&["$/src/a.h", "$/src/a.*"], &[]).unwrap()
+        );
+        assert_eq!(
+            vec![dir.join("missing.cpp")],
+            expand(&["$/missing.cpp"], &[]).unwrap()
+        );
+        assert!(expand(&["$/src/*.mm"], &[]).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
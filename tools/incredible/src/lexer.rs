@@ -0,0 +1,290 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Lexer
+//
+//	Prepares source for directive detection, so #include in comments or
+//	string literals doesn't become a dependency:
+//
+//	- comments are blanked
+//	- string, character and raw string literals are blanked, except on
+//	  directive lines where they are header names or define values
+//	- a hash not starting a line (after whitespace) is blanked
+//
+//	blanked bytes become spaces, newlines are kept, so offsets and line numbers
+//	are those of the original source. Backslash newlines continue lines
+//
+//	Sources are decoded first: utf-8 byte order marks are dropped, utf-16
+//	(little or big endian, with a byte order mark) is transcoded to utf-8.
+//	utf-32 and wide sources without a byte order mark aren't supported
+//-----------------------------------------------------------------------------
+
+fn ident_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b'_' == b
+}
+
+// true at a backslash newline (or backslash cr lf), which continues the line
+fn line_continued(data: &[u8], i: usize) -> bool {
+    b'\\' == data[i]
+        && (Some(&b'\n') == data.get(i + 1)
+            || (Some(&b'\r') == data.get(i + 1) && Some(&b'\n') == data.get(i + 2)))
+}
+
+// index past a backslash newline
+fn continuation_skip(data: &[u8], i: usize) -> usize {
+    if b'\r' == data[i + 1] {
+        i + 3
+    } else {
+        i + 2
+    }
+}
+
+//-----------------------------------------------------------------------------
+// length of the raw string prefix (R, LR, uR, UR, u8R) ending before a quote at i,
+// none when the quote doesn't open a raw string
+//-----------------------------------------------------------------------------
+
+fn raw_prefix(data: &[u8], i: usize) -> Option<usize> {
+    if 0 == i || b'R' != data[i - 1] {
+        return None;
+    }
+    let mut start = i - 1;
+    while start > 0 && ident_byte(data[start - 1]) {
+        start -= 1;
+    }
+    match &data[start..i] {
+        b"R" | b"LR" | b"uR" | b"UR" | b"u8R" => Some(i - start),
+        _ => None,
+    }
+}
+
+//-----------------------------------------------------------------------------
+// source as utf-8 (or a single byte encoding), an error for encodings that aren't supported
+//-----------------------------------------------------------------------------
+
+pub fn source_decode(data: Vec<u8>) -> Result<Vec<u8>, &'static str> {
+    let utf16 = |bytes: &[u8], unit: fn([u8; 2]) -> u16| {
+        let chunks = bytes.chunks_exact(2);
+        if !chunks.remainder().is_empty() {
+            return Err("malformed utf-16, odd number of bytes");
+        }
+        let units: Vec<u16> = chunks.map(|c| unit([c[0], c[1]])).collect();
+        Ok(String::from_utf16_lossy(&units).into_bytes())
+    };
+
+    if data.starts_with(&[0, 0, 0xfe, 0xff]) || data.starts_with(&[0xff, 0xfe, 0, 0]) {
+        Err("unsupported encoding, utf-32")
+    } else if data.starts_with(&[0xef, 0xbb, 0xbf]) {
+        Ok(data[3..].to_vec())
+    } else if data.starts_with(&[0xff, 0xfe]) {
+        utf16(&data[2..], u16::from_le_bytes)
+    } else if data.starts_with(&[0xfe, 0xff]) {
+        utf16(&data[2..], u16::from_be_bytes)
+    } else if data.contains(&0) {
+        // nul characters are what a wide encoding looks like byte wise
+        Err("unsupported encoding, nul bytes without a byte order mark")
+    } else {
+        Ok(data)
+    }
+}
+
+//-----------------------------------------------------------------------------
+// blank comments, literals outside of directives and hashes not starting a line
+//-----------------------------------------------------------------------------
+
+pub fn source_clean(data: &[u8]) -> Vec<u8> {
+    let mut cleaned = data.to_vec();
+    let blank = |cleaned: &mut Vec<u8>, from: usize, to: usize| {
+        for b in cleaned[from..to.min(data.len())].iter_mut() {
+            if b'\n' != *b && b'\r' != *b {
+                *b = b' ';
+            }
+        }
+    };
+
+    let mut line_start = true;
+    let mut directive = false;
+    let mut i = 0;
+
+    while i < data.len() {
+        let b = data[i];
+        let next = data.get(i + 1).cloned();
+
+        if line_continued(data, i) {
+            i = continuation_skip(data, i);
+            continue;
+        }
+        match b {
+            b'\n' => {
+                line_start = true;
+                directive = false;
+                i += 1;
+            }
+            b'/' if Some(b'/') == next => {
+                // line comments run to the end of the line, continuations included
+                let start = i;
+                while i < data.len() && b'\n' != data[i] {
+                    i = if line_continued(data, i) {
+                        continuation_skip(data, i)
+                    } else {
+                        i + 1
+                    };
+                }
+                blank(&mut cleaned, start, i);
+            }
+            b'/' if Some(b'*') == next => {
+                let start = i;
+                i += 2;
+                while i < data.len() && !(b'*' == data[i - 1] && b'/' == data[i] && i > start + 2) {
+                    i += 1;
+                }
+                i += 1;
+                blank(&mut cleaned, start, i);
+            }
+            b'"' if !directive && raw_prefix(data, i).is_some() => {
+                // R"delimiter( ... )delimiter", which may span lines
+                let open = i + 1;
+                let mut j = open;
+                while j < data.len() && b'(' != data[j] && b'\n' != data[j] {
+                    j += 1;
+                }
+                let mut end = vec![b')'];
+                end.extend_from_slice(&data[open..j]);
+                end.push(b'"');
+                while j < data.len() && !data[j..].starts_with(&end) {
+                    j += 1;
+                }
+                blank(&mut cleaned, (open + end.len() - 1).min(j), j);
+                i = j + end.len();
+                line_start = false;
+            }
+            // a quote after a digit or letter separates digits (1'000)
+            b'\'' if i > 0 && data[i - 1].is_ascii_alphanumeric() => {
+                i += 1;
+            }
+            b'"' | b'\'' => {
+                let start = i + 1;
+                i += 1;
+                while i < data.len() && b != data[i] && b'\n' != data[i] {
+                    if b'\\' == data[i] {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                if !directive {
+                    blank(&mut cleaned, start, i);
+                }
+                i += 1;
+                line_start = false;
+            }
+            b'#' => {
+                if line_start {
+                    directive = true;
+                } else if !directive {
+                    cleaned[i] = b' ';
+                }
+                line_start = false;
+                i += 1;
+            }
+            b' ' | b'\t' | b'\r' | b'\x0b' | b'\x0c' => i += 1,
+            _ => {
+                line_start = false;
+                i += 1;
+            }
+        }
+    }
+
+    cleaned
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_lexer {
+
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_source_decode() {
+        let text = "#include \"\u{e9}.h\"\n";
+        let utf16 = |unit: fn(u16) -> [u8; 2], bom: [u8; 2]| {
+            let mut bytes = bom.to_vec();
+            for u in text.encode_utf16() {
+                bytes.extend_from_slice(&unit(u));
+            }
+            bytes
+        };
+
+        assert_eq!(Ok(text.as_bytes().to_vec()), source_decode(text.into()));
+        let mut bom = vec![0xef, 0xbb, 0xbf];
+        bom.extend_from_slice(text.as_bytes());
+        assert_eq!(Ok(text.as_bytes().to_vec()), source_decode(bom));
+        assert_eq!(
+            Ok(text.as_bytes().to_vec()),
+            source_decode(utf16(u16::to_le_bytes, [0xff, 0xfe]))
+        );
+        assert_eq!(
+            Ok(text.as_bytes().to_vec()),
+            source_decode(utf16(u16::to_be_bytes, [0xfe, 0xff]))
+        );
+
+        assert!(source_decode(vec![0xff, 0xfe, b'#']).is_err());
+        assert!(source_decode(vec![0xff, 0xfe, 0, 0, b'#', 0, 0, 0]).is_err());
+        assert!(source_decode(utf16(u16::to_le_bytes, [b'#', 0])).is_err());
+        assert_eq!(Ok(Vec::new()), source_decode(Vec::new()));
+    }
+
+    #[test]
+    fn test_source_clean() {
+        let clean = |s: &str| String::from_utf8(source_clean(s.as_bytes())).unwrap();
+
+        assert_eq!("#include \"a.h\" ", clean("#include \"a.h\" "));
+        assert_eq!("  #  include <a.h>\n", clean("  #  include <a.h>\n"));
+        assert_eq!("#define A \"a.h\"     ", clean("#define A \"a.h\" // x"));
+        assert_eq!(
+            "                 \n#include \"b.h\"",
+            clean("// #include \"a.h\"\n#include \"b.h\"")
+        );
+        assert_eq!(
+            "  \n              \n  \n#include <b.h>",
+            clean("/*\n#include <a.h>\n*/\n#include <b.h>")
+        );
+        assert_eq!(
+            "const char* s = \"              \";",
+            clean("const char* s = \"#include <a.h>\";")
+        );
+        assert_eq!(
+            "s = R\"x(              \n   )x\";",
+            clean("s = R\"x(#include <a.h>\n\")\")x\";")
+        );
+        assert_eq!("c = ' '; n = 1'000;", clean("c = '#'; n = 1'000;"));
+        assert_eq!("x =   y;", clean("x = # y;"));
+        assert_eq!("#define S(x) #x", clean("#define S(x) #x"));
+
+        // continued lines stay comments, or directives
+        assert_eq!(
+            "            \n              \n#include <b.h>",
+            clean("// comment \\\n#include <a.h>\n#include <b.h>")
+        );
+        assert_eq!(
+            "#define A \\\n  \"#x\"\nx = \"  \"",
+            clean("#define A \\\n  \"#x\"\nx = \"#x\"")
+        );
+        assert_eq!("    \r\n  \r\n#a", clean("// \\\r\n#i\r\n#a"));
+    }
+}
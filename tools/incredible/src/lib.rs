@@ -0,0 +1,1804 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Incredible - Include Scanner library
+//
+//	The scanner behind the incredible command line, for tools embedding include
+//	scanning rather than running incredible and parsing its output:
+//
+//	let options = ScanOptions { include_dirs: vec!["inc".into()], ..Default::default() };
+//	let graph = incredible::scan(&["main.cpp".into()], &options)?;
+//
+//	the graph lists the dependencies of the inputs and who includes whom.
+//	inputs_expand turns directories and globs into the files to scan.
+//	compile_commands_load reads the translation units of a compilation
+//	database, each with the options to scan it with. unused_includes flags
+//	the includes of a graph none of whose names are referenced. A Watcher
+//	scans again when the scanned files change, reading only the changed ones,
+//	a Scanner scans any inputs, reading only the files its earlier scans didn't.
+//	Inputs that can't be read fail the scan, includes that can't be resolved
+//	are left unresolved in the graph, nothing is printed: warnings are returned
+//	as diagnostics of the graph, with the file and line they are about. The
+//	graph is the same whatever the worker count, files are scanned a level of
+//	nesting at a time and what each level reaches is settled in file order
+//-----------------------------------------------------------------------------
+
+//-----------------------------------------------------------------------------
+//	Using
+//-----------------------------------------------------------------------------
+
+mod cache;
+mod compdb;
+mod condition;
+mod diagnostic;
+mod exclude;
+mod guard;
+mod hash;
+mod input;
+mod lexer;
+mod pool;
+mod profile;
+mod tree;
+mod unused;
+mod watch;
+
+use cache::{defines_key, CacheEntry, FileStamp, ScanCache};
+use condition::{comment_strip, ConditionStack};
+use diagnostic::Diagnostics;
+use exclude::ExcludeSet;
+use guard::{guard_detect, GuardedFiles};
+use lexer::{source_clean, source_decode};
+use pool::{pool_run, WorkQueue, WORKER_COUNT_DEFAULT};
+use profile::Profiler;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tree::IncludeTree;
+
+pub use compdb::{compile_commands_load, compile_commands_parse, CompileCommand};
+pub use diagnostic::{Diagnostic, Severity};
+pub use hash::{files_hash, hashes_format, sha256, sha256_hex, Sha256};
+pub use input::{inputs_expand, SOURCE_EXTENSIONS};
+pub use profile::WorkerProfile;
+pub use tree::{DependencyGraph, Include};
+pub use unused::{unused_includes, UnusedInclude};
+pub use watch::{Scanner, Watcher};
+
+//	Generic Eror Type
+
+#[derive(Debug)]
+pub enum IncError {
+    IO(std::io::Error),
+    StdErr(Box<dyn std::error::Error>),
+    Literal(&'static str),
+}
+
+pub type IncResult<T> = Result<T, IncError>;
+
+impl From<std::io::Error> for IncError {
+    fn from(e: std::io::Error) -> Self {
+        IncError::IO(e)
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for IncError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        IncError::StdErr(e)
+    }
+}
+
+impl From<&'static str> for IncError {
+    fn from(e: &'static str) -> Self {
+        IncError::Literal(e)
+    }
+}
+
+impl fmt::Display for IncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IncError::IO(e) => write!(f, "{}", e),
+            IncError::StdErr(e) => write!(f, "{}", e),
+            IncError::Literal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for IncError {}
+
+//	Enum for include types (quote vs angle brackets)
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IncludeSearch {
+    Local,
+    System,
+}
+
+//	Where includes are searched for, as each compiler does
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchOrder {
+    // like gcc, then the directory of the includer for any include not found
+    Lenient,
+    // quoted includes in the directory of the includer, then the include directories
+    Gcc,
+    // quoted includes in the directory of the includer, then those of the files
+    // including it up to the input, then the include directories
+    Msvc,
+}
+
+//	Options of a scan, the library side of the command line
+
+#[derive(Clone, Debug)]
+pub struct ScanOptions {
+    // directories searched for includes, in order (-i)
+    pub include_dirs: Vec<PathBuf>,
+    // defines the inputs are scanned with (-d)
+    pub defines: HashMap<String, String>,
+    // threads scanning files, 1 scans on the calling thread (-j, -st)
+    pub worker_count: usize,
+    // file keeping the includes of each file between scans (-cache)
+    pub cache: Option<PathBuf>,
+    // globs of paths that are neither scanned nor dependencies (--exclude)
+    pub excludes: Vec<String>,
+    // headers included at the top of every input, found like quoted includes (-FI, -include)
+    pub forced_includes: Vec<String>,
+    // rules of the compiler the includes are resolved with (--search-order)
+    pub search_order: SearchOrder,
+    // system includes are recorded unresolved, neither searched for nor scanned (--no-system)
+    pub skip_system: bool,
+    // files nested deeper than this below an input are dependencies, but aren't scanned (--max-depth)
+    pub max_depth: Option<usize>,
+    // time spent reading, parsing and resolving is kept per worker in the graph (--profile)
+    pub profile: bool,
+    // precompiled header the inputs are compiled with, as included, its files aren't dependencies (--pch)
+    pub pch: Option<String>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            include_dirs: Vec::new(),
+            defines: HashMap::new(),
+            worker_count: WORKER_COUNT_DEFAULT,
+            cache: None,
+            excludes: Vec::new(),
+            forced_includes: Vec::new(),
+            search_order: SearchOrder::Lenient,
+            skip_system: false,
+            max_depth: None,
+            profile: false,
+            pch: None,
+        }
+    }
+}
+
+struct ResolvedPathCollection {
+    resolved: Arc<Mutex<HashMap<PathBuf, HashMap<String, PathBuf>>>>,
+}
+
+impl ResolvedPathCollection {
+    pub fn new() -> Self {
+        ResolvedPathCollection {
+            resolved: Arc::new(Mutex::new(
+                HashMap::<PathBuf, HashMap<String, PathBuf>>::new(),
+            )),
+        }
+    }
+}
+
+//	first path of each physical file, so hard links to a header are scanned once
+
+#[derive(Clone)]
+struct FileAliases {
+    first: Arc<Mutex<HashMap<(u64, u64), PathBuf>>>,
+}
+
+impl FileAliases {
+    fn new() -> Self {
+        FileAliases {
+            first: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // the path the file was first resolved to
+    fn first(&self, path: PathBuf) -> PathBuf {
+        match (file_id(&path), self.first.lock()) {
+            (Some(id), Ok(mut first)) => first.entry(id).or_insert(path).clone(),
+            _ => path,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+// no stable file index elsewhere, hard links stay distinct files
+#[cfg(not(unix))]
+fn file_id(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+struct ResolvedPaths {
+    local: ResolvedPathCollection,
+    system: ResolvedPathCollection,
+    // include tree to record resolved includes in
+    tree: IncludeTree,
+    diagnostics: Diagnostics,
+    // includes found in earlier scans, when requested (-cache)
+    cache: Option<ScanCache>,
+    // includes resolved to these paths aren't followed
+    excludes: Arc<ExcludeSet>,
+    search_order: SearchOrder,
+    skip_system: bool,
+    // directories of the files including each file, nearest first (msvc)
+    chains: Arc<Mutex<HashMap<PathBuf, Vec<PathBuf>>>>,
+    // files reached by the level being scanned, with the position of the file in
+    // the level and the number of includes it resolved so far
+    reaches: Arc<Mutex<Vec<Reach>>>,
+    position: usize,
+    sequence: usize,
+    // macros conditions used without them being defined, with the first file and line using them
+    unknown: Arc<Mutex<HashMap<String, (String, usize)>>>,
+    // time spent on the file being scanned
+    timings: WorkerProfile,
+}
+
+//	a file not scanned yet reached by an include of the level being scanned
+
+struct Reach {
+    // position of the includer in the level, then of the include in the includer
+    rank: (usize, usize),
+    path: PathBuf,
+    // directories of the includer and its chain, for the quoted includes of the file (msvc)
+    quote_dirs: Vec<PathBuf>,
+    // file and line of the include
+    at: (String, usize),
+    // defines of the includer at the include, the file is scanned with them
+    defines: Arc<HashMap<String, String>>,
+}
+
+impl ResolvedPaths {
+    // directories quoted includes of a file are searched first, its own first
+    fn quote_dirs(&self, path: &Path) -> Vec<PathBuf> {
+        let mut dirs = vec![path.parent().unwrap_or(Path::new("")).to_path_buf()];
+        if SearchOrder::Msvc == self.search_order {
+            if let Ok(chains) = self.chains.lock() {
+                dirs.extend(chains.get(path).cloned().unwrap_or_default());
+            }
+        }
+        dirs
+    }
+}
+
+//-----------------------------------------------------------------------------
+// helper to ensure path is formatted correctly for platform
+//-----------------------------------------------------------------------------
+
+fn path_sanitise(src: &str) -> PathBuf {
+    let alt_seperator = match MAIN_SEPARATOR {
+        '/' => '\\',
+        _ => '/',
+    };
+
+    let cleaned = &src.replace(alt_seperator, &MAIN_SEPARATOR.to_string());
+    Path::new(cleaned).to_path_buf()
+}
+
+//-----------------------------------------------------------------------------
+// Create absolute path from relative
+//-----------------------------------------------------------------------------
+
+fn path_absolute(src: &Path) -> PathBuf {
+    let mut pb = PathBuf::new();
+    let mut v = Vec::new();
+
+    for c in src.components() {
+        if ".." == c.as_os_str() {
+            v.pop();
+        } else {
+            v.push(c);
+        }
+    }
+
+    for cv in v {
+        pb.push(cv);
+    }
+
+    pb
+}
+
+//-----------------------------------------------------------------------------
+// path with symbolic links resolved, the same header through any include root
+//-----------------------------------------------------------------------------
+
+fn path_canonical(path: PathBuf) -> PathBuf {
+    let canonical = match fs::canonicalize(&path) {
+        Ok(canonical) => canonical,
+        Err(_) => return path,
+    };
+    // without the verbatim prefix of windows (\\?\C:\), which tools don't expect
+    let verbatim = canonical
+        .to_str()
+        .and_then(|c| c.strip_prefix(r"\\?\"))
+        .filter(|c| !c.starts_with("UNC"))
+        .map(PathBuf::from);
+    verbatim.unwrap_or(canonical)
+}
+
+//-----------------------------------------------------------------------------
+// path as written to outputs, stable across machines: absolute without . or ..
+// with forward slashes (lower case on windows), relative to root when below it
+//-----------------------------------------------------------------------------
+
+pub fn path_normalise(path: &Path, root: Option<&Path>) -> PathBuf {
+    let text = |path: &Path| {
+        let full = match std::env::current_dir() {
+            Ok(cwd) if path.is_relative() => cwd.join(path),
+            _ => path.to_path_buf(),
+        };
+        let full = path_absolute(&full).to_string_lossy().replace('\\', "/");
+        if cfg!(windows) {
+            full.to_lowercase()
+        } else {
+            full
+        }
+    };
+
+    let normalised = text(path);
+    if let Some(root) = root {
+        let root = text(root);
+        if let Some(relative) = normalised
+            .strip_prefix(root.trim_end_matches('/'))
+            .and_then(|r| r.strip_prefix('/'))
+        {
+            return PathBuf::from(relative);
+        }
+    }
+    PathBuf::from(normalised)
+}
+
+//-----------------------------------------------------------------------------
+//	Resolve path of include file, none when it can't be found
+//-----------------------------------------------------------------------------
+
+fn include_resolve_path(
+    quote_dirs: &[PathBuf],
+    filename: &str,
+    search_type: IncludeSearch,
+    includes: &[PathBuf],
+    search_order: SearchOrder,
+) -> Option<PathBuf> {
+    let pfname = path_sanitise(filename);
+    let file_found = |path: PathBuf| {
+        let abs_path = path_absolute(&path);
+        match fs::metadata(&abs_path) {
+            Ok(md) if md.is_file() => Some(path_canonical(abs_path)),
+            _ => None,
+        }
+    };
+
+    // if include is quoted, start by searching relative
+    if IncludeSearch::Local == search_type {
+        for dir in quote_dirs.iter() {
+            if let Some(abs_path) = file_found(dir.join(&pfname)) {
+                return Some(abs_path);
+            }
+        }
+    }
+
+    // search by prepending include paths
+    for inc_path in includes.iter() {
+        if let Some(abs_path) = file_found(Path::new(&inc_path).join(filename)) {
+            return Some(abs_path);
+        }
+    }
+
+    match (search_order, quote_dirs.first()) {
+        (SearchOrder::Lenient, Some(base_dir)) => file_found(base_dir.join(&pfname)),
+        _ => None,
+    }
+}
+
+//-----------------------------------------------------------------------------
+//	resolve an include, returns the resolved path and whether it is to be
+//	scanned, when it wasn't reached by an earlier level of the scan
+//-----------------------------------------------------------------------------
+
+#[allow(clippy::too_many_arguments)]
+fn file_add(
+    quote_dirs: &[PathBuf],
+    filename: &str,
+    search_type: IncludeSearch,
+    includes: &[PathBuf],
+    search_order: SearchOrder,
+    processsed: &Arc<Mutex<HashSet<String>>>,
+    rp: &mut ResolvedPathCollection,
+    excludes: &ExcludeSet,
+) -> Option<(PathBuf, bool)> {
+    let base_dir = quote_dirs
+        .first()
+        .map(PathBuf::as_path)
+        .unwrap_or(Path::new(""));
+
+    // we want to minimise the amount of times we need to hit file system. lets see if base path+include filename has already been resolved
+    {
+        let rp_guard = rp.resolved.lock();
+        if let Ok(rp_c) = rp_guard {
+            if let Some(rp_k) = rp_c.get(base_dir.into()) {
+                if let Some(resolved) = rp_k.get(filename) {
+                    return Some((resolved.clone(), false));
+                }
+            }
+        }
+    }
+
+    let inc_result =
+        include_resolve_path(quote_dirs, filename, search_type, includes, search_order)?;
+
+    // excluded files are resolved, but neither scanned nor dependencies
+    if excludes.matches(&inc_result) {
+        return Some((inc_result, false));
+    }
+
+    {
+        let rp_guard = rp.resolved.lock();
+        if let Ok(mut rp_c) = rp_guard {
+            rp_c.entry(base_dir.to_path_buf())
+                .or_insert_with(HashMap::new)
+                .insert(filename.to_string(), inc_result.clone());
+        }
+    }
+
+    // if we haven't already processed this path, it is to be scanned
+    let abs_path = inc_result.to_str().unwrap_or_default();
+    let scanned = match processsed.lock() {
+        Ok(p) => p.contains(abs_path),
+        Err(_) => true,
+    };
+    Some((inc_result, !scanned))
+}
+
+//-----------------------------------------------------------------------------
+// queue an include of a file and record it in the include tree
+//-----------------------------------------------------------------------------
+
+#[allow(clippy::too_many_arguments)]
+fn include_queue(
+    full_path: &Path,
+    filename: &str,
+    search_type: IncludeSearch,
+    line: usize,
+    line_file: Option<&str>,
+    defines: &Arc<HashMap<String, String>>,
+    includes: &[PathBuf],
+    processsed: &Arc<Mutex<HashSet<String>>>,
+    rp: &mut ResolvedPaths,
+) {
+    // toolchain headers are inputs of the toolchain rather than dependencies
+    if rp.skip_system && IncludeSearch::System == search_type {
+        rp.tree
+            .edge_add(full_path, filename, search_type, line, line_file, None);
+        return;
+    }
+
+    let quote_dirs = rp.quote_dirs(full_path);
+    let search_order = rp.search_order;
+    let excludes = rp.excludes.clone();
+    let collection = match search_type {
+        IncludeSearch::Local => &mut rp.local,
+        IncludeSearch::System => &mut rp.system,
+    };
+    let started = Instant::now();
+    let resolved = file_add(
+        &quote_dirs,
+        filename,
+        search_type,
+        includes,
+        search_order,
+        processsed,
+        collection,
+        &excludes,
+    );
+    rp.timings.resolve += started.elapsed();
+
+    // which of the includes reaching a file is first is settled once the level is scanned
+    let resolved = resolved.map(|(path, reached)| {
+        if reached {
+            if let Ok(mut reaches) = rp.reaches.lock() {
+                reaches.push(Reach {
+                    rank: (rp.position, rp.sequence),
+                    path: path.clone(),
+                    quote_dirs,
+                    at: (
+                        line_file
+                            .map(str::to_string)
+                            .unwrap_or_else(|| full_path.to_string_lossy().into_owned()),
+                        line,
+                    ),
+                    defines: defines.clone(),
+                });
+            }
+        }
+        path
+    });
+    rp.sequence += 1;
+    rp.tree
+        .edge_add(full_path, filename, search_type, line, line_file, resolved);
+}
+
+//-----------------------------------------------------------------------------
+// true at the newline ending a directive, newlines escaped by a backslash continue it
+//-----------------------------------------------------------------------------
+
+fn directive_end(data: &[u8], cursor: usize) -> bool {
+    if b'\n' != data[cursor] {
+        return false;
+    }
+    let mut i = cursor;
+    if i > 0 && b'\r' == data[i - 1] {
+        i -= 1;
+    }
+    !(i > 0 && b'\\' == data[i - 1])
+}
+
+//-----------------------------------------------------------------------------
+// line and file of a #line directive (12 "file.y"), without the directive
+//-----------------------------------------------------------------------------
+
+fn line_directive_parse(text: &str) -> Option<(usize, Option<String>)> {
+    let mut parts = text.trim().splitn(2, char::is_whitespace);
+    let line = parts.next()?.parse().ok()?;
+    let file = parts
+        .next()
+        .map(str::trim)
+        .and_then(|f| f.strip_prefix('"'))
+        .and_then(|f| f.find('"').map(|end| f[..end].replace("\\\\", "\\")));
+    Some((line, file))
+}
+
+//	defines of a file being scanned, those of its includer then its own
+
+struct FileDefines {
+    defines: HashMap<String, String>,
+    // the defines includes get, shared until the next change
+    shared: Option<Arc<HashMap<String, String>>>,
+    includes: usize,
+    // defines and undefs, none for an undef, after the number of includes before them
+    changes: Vec<(usize, String, Option<String>)>,
+}
+
+impl FileDefines {
+    fn new(defines: &Arc<HashMap<String, String>>) -> Self {
+        FileDefines {
+            defines: (**defines).clone(),
+            shared: Some(defines.clone()),
+            includes: 0,
+            changes: Vec::new(),
+        }
+    }
+
+    fn define(&mut self, key: &str, value: String) {
+        self.defines.insert(key.to_string(), value.clone());
+        self.changes
+            .push((self.includes, key.to_string(), Some(value)));
+        self.shared = None;
+    }
+
+    fn undef(&mut self, key: &str) {
+        if self.defines.remove(key).is_some() {
+            self.changes.push((self.includes, key.to_string(), None));
+            self.shared = None;
+        }
+    }
+
+    // the defines of the next include of the file
+    fn include(&mut self) -> Arc<HashMap<String, String>> {
+        self.includes += 1;
+        let defines = &self.defines;
+        self.shared
+            .get_or_insert_with(|| Arc::new(defines.clone()))
+            .clone()
+    }
+}
+
+//-----------------------------------------------------------------------------
+// process file and find includes, with the defines of the file including it
+//-----------------------------------------------------------------------------
+
+fn file_process(
+    full_path: &Path,
+    includes: &[PathBuf],
+    processsed: Arc<Mutex<HashSet<String>>>,
+    defines: &Arc<HashMap<String, String>>,
+    rp: &mut ResolvedPaths,
+    guarded: &GuardedFiles,
+) -> IncResult<()> {
+    // a header scanned through another path is skipped when its guard excludes it again
+    if guarded.contains(full_path, defines) {
+        return Ok(());
+    }
+
+    let quote_dirs = rp.quote_dirs(full_path);
+    let search_order = rp.search_order;
+
+    rp.tree.file_add(full_path);
+
+    // a file unchanged since it was cached isn't read, its includes are queued again
+    // its defines and undefs are applied again, for the defines of each include
+    let started = Instant::now();
+    let stamp = FileStamp::read(full_path);
+    rp.timings.io += started.elapsed();
+    let defines_scanned = match rp.cache {
+        Some(_) => defines_key(defines),
+        None => 0,
+    };
+    let cached = match (&rp.cache, stamp) {
+        (Some(cache), Some(stamp)) => cache.lookup(full_path, stamp, defines_scanned),
+        _ => None,
+    };
+    let mut defines = FileDefines::new(defines);
+    if let Some(entry) = cached {
+        if let Some(guard) = &entry.guard {
+            guarded.insert(full_path, guard.clone());
+        }
+        let mut changes = entry.changes.iter().peekable();
+        for (index, (filename, search_type, line, line_file)) in entry.includes.iter().enumerate() {
+            while let Some(&(_, key, value)) = changes.peek().filter(|c| c.0 <= index) {
+                match value {
+                    Some(value) => defines.define(key, value.clone()),
+                    None => defines.undef(key),
+                }
+                changes.next();
+            }
+            include_queue(
+                full_path,
+                filename,
+                *search_type,
+                *line,
+                line_file.as_deref(),
+                &defines.include(),
+                includes,
+                &processsed,
+                rp,
+            );
+        }
+        return Ok(());
+    }
+
+    let filename_string = full_path.to_str().ok_or("")?;
+    // utf-8 with comments and literals blanked, so only real directives are seen
+    let started = Instant::now();
+    let source = fs::read(filename_string)?;
+    rp.timings.io += started.elapsed();
+    let data = source_clean(&source_decode(source)?);
+
+    let guard = guard_detect(&data);
+    if let Some(guard) = &guard {
+        guarded.insert(full_path, guard.clone());
+    }
+
+    enum SearchMode {
+        Hash,
+        Directive,
+        WhiteSpace,
+        DefineKey,
+        DefineValue,
+        UndefKey,
+        Condition,
+        Line,
+        Quote,
+        Arrow,
+        Macro,
+    }
+
+    enum DirectiveType {
+        DefineKey,
+        DefineValue,
+        UndefKey,
+        Include,
+    }
+
+    let mut directive_type = DirectiveType::Include;
+    let mut search_mode = SearchMode::Hash;
+    let mut start_index = 0;
+    let mut define_key = "";
+    let mut condition_directive = "";
+    // branches of the enclosing #if blocks, includes and defines in inactive branches are skipped
+    let mut conditions = ConditionStack::new();
+    // __has_include probes, header names come with their delimiters ("file" or <file>)
+    let probed = Cell::new(false);
+    let probe_time = Cell::new(Duration::default());
+    let has_include = |header: &str| {
+        probed.set(true);
+        let started = Instant::now();
+        let search_type = if header.starts_with('<') {
+            IncludeSearch::System
+        } else {
+            IncludeSearch::Local
+        };
+        let found = include_resolve_path(
+            &quote_dirs,
+            &header[1..header.len() - 1],
+            search_type,
+            includes,
+            search_order,
+        )
+        .is_some();
+        probe_time.set(probe_time.get() + started.elapsed());
+        found
+    };
+    // queue an include, keeping it for the cache
+    let diagnostics = rp.diagnostics.clone();
+    let unknown = rp.unknown.clone();
+    let mut found = Vec::new();
+    let mut include_add = |filename: &str,
+                           search_type: IncludeSearch,
+                           line: usize,
+                           line_file: Option<&str>,
+                           defines: Arc<HashMap<String, String>>| {
+        include_queue(
+            full_path,
+            filename,
+            search_type,
+            line,
+            line_file,
+            &defines,
+            includes,
+            &processsed,
+            rp,
+        );
+        found.push((
+            filename.to_string(),
+            search_type,
+            line,
+            line_file.map(str::to_string),
+        ));
+    };
+    // warnings at a line of the file, or of the file a #line names
+    let full_path_text = full_path.to_string_lossy();
+    let warn = |line_file: &Option<String>, line: usize, message: String| {
+        diagnostics.add(
+            Diagnostic::new(Severity::Warning, message)
+                .at(line_file.as_deref().unwrap_or(&full_path_text), line),
+        )
+    };
+    // macros used undefined, each is noted once by the scan, at the first file and line using it
+    let unknown_add = |line_file: &Option<String>, line: usize, names: Vec<String>| {
+        if let Ok(mut unknown) = unknown.lock() {
+            let at = (
+                line_file.as_deref().unwrap_or(&full_path_text).to_string(),
+                line,
+            );
+            for name in names {
+                let first = match unknown.get(&name) {
+                    Some(first) => *first <= at,
+                    None => false,
+                };
+                if !first {
+                    unknown.insert(name, at.clone());
+                }
+            }
+        }
+    };
+    // line of the cursor, counted after each character so a directive ended by a newline is on its own line
+    let mut line_index = 1;
+    // a #line directive numbers the line after it (physical, presumed), and may name the file
+    let mut line_base = (1, 1);
+    let mut line_file: Option<String> = None;
+
+    for (cursor, cc) in data.iter().enumerate() {
+        let character = *cc as char;
+        let line = line_base.1 + line_index - line_base.0;
+        match search_mode {
+            SearchMode::Hash => {
+                if '#' == character {
+                    search_mode = SearchMode::Directive;
+                    start_index = cursor;
+                }
+            }
+            SearchMode::Directive => match character {
+                ' ' | '\t' | '\r' | '\n' => {
+                    let directive = std::str::from_utf8(&data[start_index + 1..cursor])
+                        .unwrap_or("")
+                        .trim();
+                    let line_end = '\r' == character || '\n' == character;
+                    let active = conditions.active();
+                    search_mode = SearchMode::Hash;
+                    match directive {
+                        // whitespace between the hash and the directive
+                        "" if !line_end => search_mode = SearchMode::Directive,
+                        "include" if active && !line_end => {
+                            directive_type = DirectiveType::Include;
+                            search_mode = SearchMode::WhiteSpace;
+                        }
+                        "define" if active && !line_end => {
+                            directive_type = DirectiveType::DefineKey;
+                            search_mode = SearchMode::WhiteSpace;
+                        }
+                        "undef" if active && !line_end => {
+                            directive_type = DirectiveType::UndefKey;
+                            search_mode = SearchMode::WhiteSpace;
+                        }
+                        // #line 12 "file", or the # 12 "file" line markers of preprocessed files
+                        "line" if active && !line_end => {
+                            start_index = cursor;
+                            search_mode = SearchMode::Line;
+                        }
+                        d if active && !d.is_empty() && d.bytes().all(|b| b.is_ascii_digit()) => {
+                            if line_end {
+                                line_base = (line_index + 1, d.parse().unwrap_or(line));
+                            } else {
+                                start_index = cursor - d.len();
+                                search_mode = SearchMode::Line;
+                            }
+                        }
+                        "if" | "ifdef" | "ifndef" | "elif" => {
+                            if '\n' == character {
+                                if let Some(warning) = conditions.directive_apply(
+                                    directive,
+                                    "",
+                                    &defines.defines,
+                                    &has_include,
+                                ) {
+                                    warn(&line_file, line, warning);
+                                }
+                            } else {
+                                condition_directive = directive;
+                                start_index = cursor;
+                                search_mode = SearchMode::Condition;
+                            }
+                        }
+                        "else" | "endif" => {
+                            conditions.directive_apply(
+                                directive,
+                                "",
+                                &defines.defines,
+                                &has_include,
+                            );
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            },
+            SearchMode::Condition => {
+                if directive_end(&data, cursor) {
+                    let expression = std::str::from_utf8(&data[start_index..cursor]).unwrap_or("");
+                    if let Some(warning) = conditions.directive_apply(
+                        condition_directive,
+                        expression,
+                        &defines.defines,
+                        &has_include,
+                    ) {
+                        warn(&line_file, line, warning);
+                    }
+                    unknown_add(&line_file, line, conditions.unknown_take());
+                    search_mode = SearchMode::Hash;
+                }
+            }
+            SearchMode::Line => {
+                if directive_end(&data, cursor) {
+                    let text = std::str::from_utf8(&data[start_index..cursor]).unwrap_or("");
+                    if let Some((presumed, file)) = line_directive_parse(text) {
+                        line_base = (line_index + 1, presumed);
+                        if file.is_some() {
+                            line_file = file;
+                        }
+                    }
+                    search_mode = SearchMode::Hash;
+                }
+            }
+            SearchMode::WhiteSpace => {
+                start_index = cursor;
+
+                match character {
+                    ' ' | '\t' => {}
+                    '\r' | '\n' => {
+                        // a define without a value
+                        if let DirectiveType::DefineValue = directive_type {
+                            defines.define(define_key, String::new());
+                        }
+                        search_mode = SearchMode::Hash
+                    }
+                    _ => match directive_type {
+                        DirectiveType::Include => {
+                            search_mode = match character {
+                                ' ' | '\t' => SearchMode::WhiteSpace,
+                                '"' => SearchMode::Quote,
+                                '<' => SearchMode::Arrow,
+                                _ => SearchMode::Macro,
+                            };
+                        }
+                        DirectiveType::DefineKey => {
+                            search_mode = SearchMode::DefineKey;
+                        }
+                        DirectiveType::DefineValue => {
+                            search_mode = SearchMode::DefineValue;
+                        }
+                        DirectiveType::UndefKey => {
+                            search_mode = SearchMode::UndefKey;
+                        }
+                    },
+                }
+            }
+            SearchMode::DefineKey => match character {
+                ' ' | '\t' | '\r' | '\n' => {
+                    let key = std::str::from_utf8(&data[start_index..cursor]).unwrap();
+                    // parameters of function like macros aren't part of the key
+                    define_key = key.split('(').next().unwrap_or(key);
+                    if '\r' == character || '\n' == character {
+                        defines.define(define_key, String::new());
+                        search_mode = SearchMode::Hash;
+                    } else {
+                        directive_type = DirectiveType::DefineValue;
+                        search_mode = SearchMode::WhiteSpace;
+                    }
+                }
+                _ => {}
+            },
+            SearchMode::DefineValue => {
+                // values run to the end of the line, so expressions can be evaluated in conditions
+                if directive_end(&data, cursor) {
+                    let define_value = std::str::from_utf8(&data[start_index..cursor]).unwrap();
+                    // continued lines are joined, values are kept a line each in the cache
+                    let define_value = comment_strip(define_value)
+                        .replace("\\\r\n", " ")
+                        .replace("\\\n", " ");
+                    defines.define(define_key, define_value.trim().to_string());
+                    search_mode = SearchMode::Hash;
+                }
+            }
+            SearchMode::UndefKey => match character {
+                ' ' | '\t' | '\r' | '\n' => {
+                    let key = std::str::from_utf8(&data[start_index..cursor]).unwrap();
+                    defines.undef(key);
+                    search_mode = SearchMode::Hash;
+                }
+                _ => {}
+            },
+            SearchMode::Quote => {
+                if '"' == character {
+                    include_add(
+                        std::str::from_utf8(&data[start_index + 1..cursor]).unwrap(),
+                        IncludeSearch::Local,
+                        line,
+                        line_file.as_deref(),
+                        defines.include(),
+                    );
+                    search_mode = SearchMode::Hash
+                }
+            }
+            SearchMode::Arrow => {
+                if '>' == character {
+                    include_add(
+                        std::str::from_utf8(&data[start_index + 1..cursor]).unwrap(),
+                        IncludeSearch::System,
+                        line,
+                        line_file.as_deref(),
+                        defines.include(),
+                    );
+                    search_mode = SearchMode::Hash
+                }
+            }
+            SearchMode::Macro => match character {
+                ' ' | '\t' | '\r' | '\n' => {
+                    let macro_key = std::str::from_utf8(&data[start_index..cursor]).unwrap();
+                    if let Some(mv) = defines.defines.get(macro_key).cloned() {
+                        if mv.len() > 1 {
+                            let stripped = &mv[1..mv.len() - 1];
+                            match mv.chars().next().unwrap() {
+                                '"' => {
+                                    include_add(
+                                        stripped,
+                                        IncludeSearch::Local,
+                                        line,
+                                        line_file.as_deref(),
+                                        defines.include(),
+                                    );
+                                }
+                                '<' => {
+                                    include_add(
+                                        stripped,
+                                        IncludeSearch::System,
+                                        line,
+                                        line_file.as_deref(),
+                                        defines.include(),
+                                    );
+                                }
+                                _ => {
+                                    warn(&line_file, line, format!("malformed filename : {}", mv));
+                                }
+                            }
+                        }
+                    } else {
+                        warn(
+                            &line_file,
+                            line,
+                            format!("couldn't find macro: {}", macro_key),
+                        );
+                    }
+                    search_mode = SearchMode::Hash
+                }
+                _ => {}
+            },
+        }
+
+        if b'\n' == *cc {
+            line_index += 1;
+        }
+    }
+    rp.timings.resolve += probe_time.get();
+
+    if let (Some(cache), Some(stamp)) = (&rp.cache, stamp) {
+        if !probed.get() {
+            cache.insert(
+                full_path,
+                CacheEntry {
+                    stamp,
+                    guard,
+                    defines: defines_scanned,
+                    includes: found,
+                    changes: defines.changes,
+                },
+            );
+        }
+    }
+
+    Ok(())
+}
+
+//-----------------------------------------------------------------------------
+// the files first reached by a level of the scan, the next level, in the order
+// a single worker scanning the level in order reaches them. Each is processed
+// from then on, with the defines of its first include, hard links to a file
+// reached before are renamed to it, and files nested deeper than the limit
+// are dependencies that aren't scanned
+//-----------------------------------------------------------------------------
+
+#[allow(clippy::too_many_arguments)]
+fn level_next(
+    mut reaches: Vec<Reach>,
+    depth: usize,
+    max_depth: Option<usize>,
+    search_order: SearchOrder,
+    processed: &Arc<Mutex<HashSet<String>>>,
+    aliases: &FileAliases,
+    chains: &Arc<Mutex<HashMap<PathBuf, Vec<PathBuf>>>>,
+    renamed: &mut HashMap<PathBuf, PathBuf>,
+    diagnostics: &Diagnostics,
+) -> Vec<(PathBuf, Arc<HashMap<String, String>>)> {
+    reaches.sort_by_key(|r| r.rank);
+
+    let mut level = Vec::new();
+    for reach in reaches {
+        let first = aliases.first(reach.path.clone());
+        if first != reach.path {
+            renamed.insert(reach.path, first);
+            continue;
+        }
+        let first_reach = match processed.lock() {
+            Ok(mut p) => p.insert(reach.path.to_string_lossy().into_owned()),
+            Err(_) => false,
+        };
+        if !first_reach {
+            continue;
+        }
+
+        match max_depth {
+            // a dependency still, but what it includes isn't
+            Some(max_depth) if depth > max_depth => diagnostics.add(
+                Diagnostic::new(
+                    Severity::Warning,
+                    format!(
+                        "include depth limit of {} reached, {} isn't scanned",
+                        max_depth,
+                        reach.path.display()
+                    ),
+                )
+                .at(&reach.at.0, reach.at.1),
+            ),
+            _ => {
+                if SearchOrder::Msvc == search_order {
+                    if let Ok(mut chains) = chains.lock() {
+                        chains.insert(reach.path.clone(), reach.quote_dirs);
+                    }
+                }
+                level.push((reach.path, reach.defines));
+            }
+        }
+    }
+    level
+}
+
+//-----------------------------------------------------------------------------
+// scan the inputs and all files they include, directly or not, with the
+// includes of files unchanged since they were cached
+//-----------------------------------------------------------------------------
+
+fn scan_cached(
+    inputs: &[PathBuf],
+    options: &ScanOptions,
+    cache: Option<ScanCache>,
+) -> IncResult<DependencyGraph> {
+    // missing inputs fail the scan, missing includes are only warnings
+    for input in inputs {
+        if !fs::metadata(input)?.is_file() {
+            return Err(IncError::Literal("input is not a file"));
+        }
+    }
+
+    let includes: Vec<PathBuf> = options
+        .include_dirs
+        .iter()
+        .map(|i| path_sanitise(&i.to_string_lossy()))
+        .collect();
+    let arc_includes = Arc::new(includes);
+
+    // markers to ensure each file is only processed once
+    let processed = Arc::new(Mutex::new(HashSet::new()));
+
+    // inputs get the forced includes
+    let input_set: Arc<HashSet<PathBuf>> = Arc::new(inputs.iter().cloned().collect());
+    let forced_includes = Arc::new(options.forced_includes.clone());
+
+    // guarded headers already scanned, by file rather than path
+    let guarded = GuardedFiles::new();
+
+    // who includes whom
+    let tree = IncludeTree::new();
+    let excludes = Arc::new(ExcludeSet::new(&options.excludes));
+    let search_order = options.search_order;
+    let skip_system = options.skip_system;
+    let chains = Arc::new(Mutex::new(HashMap::new()));
+    let aliases = FileAliases::new();
+    // hard links to a file, by the path it was first reached by
+    let mut renamed = HashMap::new();
+    let diagnostics = Diagnostics::new();
+    let profiler = Profiler::new();
+
+    // macros used undefined, by the scan
+    let unknown = Arc::new(Mutex::new(HashMap::new()));
+
+    // files are scanned a level of nesting at a time, the inputs first, so the
+    // files each level reaches, their paths and chains, don't depend on which
+    // worker is faster. inputs have the -d defines, includes those of their includer
+    let input_defines = Arc::new(options.defines.clone());
+    let mut level: Vec<_> = inputs
+        .iter()
+        .map(|f| (f.clone(), input_defines.clone()))
+        .collect();
+    let mut depth = 0;
+    while !level.is_empty() {
+        let work = Arc::new(WorkQueue::new());
+        for (f, _) in level.iter() {
+            work.push(f.clone());
+        }
+        let positions: Arc<HashMap<_, _>> = Arc::new(
+            level
+                .drain(..)
+                .enumerate()
+                .map(|(i, (f, defines))| (f, (i, defines)))
+                .collect(),
+        );
+        let reaches = Arc::new(Mutex::new(Vec::new()));
+
+        {
+            let processed = processed.clone();
+            let guarded = guarded.clone();
+            let tree = tree.clone();
+            let diagnostics = diagnostics.clone();
+            let unknown = unknown.clone();
+            let input_defines = input_defines.clone();
+            let cache = cache.clone();
+            let excludes = excludes.clone();
+            let chains = chains.clone();
+            let input_set = input_set.clone();
+            let forced_includes = forced_includes.clone();
+            let arc_includes = arc_includes.clone();
+            let level_reaches = reaches.clone();
+            let profiler = profiler.clone();
+            pool_run(&work, options.worker_count, move |worker, f| {
+                let started = Instant::now();
+                let (position, defines) = match positions.get(&f) {
+                    Some((position, defines)) => (*position, defines.clone()),
+                    None => (0, input_defines.clone()),
+                };
+                let mut rp = ResolvedPaths {
+                    local: ResolvedPathCollection::new(),
+                    system: ResolvedPathCollection::new(),
+                    tree: tree.clone(),
+                    diagnostics: diagnostics.clone(),
+                    cache: cache.clone(),
+                    excludes: excludes.clone(),
+                    search_order,
+                    skip_system,
+                    chains: chains.clone(),
+                    reaches: level_reaches.clone(),
+                    position,
+                    sequence: 0,
+                    unknown: unknown.clone(),
+                    timings: WorkerProfile::default(),
+                };
+
+                // as if included on a line before the first, line 0
+                if input_set.contains(&f) {
+                    for forced in forced_includes.iter() {
+                        include_queue(
+                            &f,
+                            forced,
+                            IncludeSearch::Local,
+                            0,
+                            None,
+                            &defines,
+                            &arc_includes,
+                            &processed,
+                            &mut rp,
+                        );
+                    }
+                }
+
+                if let Err(e) = file_process(
+                    &f,
+                    &arc_includes,
+                    processed.clone(),
+                    &defines,
+                    &mut rp,
+                    &guarded,
+                ) {
+                    diagnostics.add(
+                        Diagnostic::new(Severity::Warning, format!("couldn't scan: {}", e))
+                            .at(&f.to_string_lossy(), 0),
+                    );
+                }
+
+                // parsing is what isn't spent reading or resolving
+                let timings = WorkerProfile {
+                    files: 1,
+                    parse: started
+                        .elapsed()
+                        .checked_sub(rp.timings.io + rp.timings.resolve)
+                        .unwrap_or_default(),
+                    ..rp.timings
+                };
+                profiler.add(worker, &timings);
+            });
+        }
+
+        depth += 1;
+        let reaches = match reaches.lock() {
+            Ok(mut reaches) => std::mem::take(&mut *reaches),
+            Err(_) => Vec::new(),
+        };
+        level = level_next(
+            reaches,
+            depth,
+            options.max_depth,
+            search_order,
+            &processed,
+            &aliases,
+            &chains,
+            &mut renamed,
+            &diagnostics,
+        );
+    }
+
+    // conditions take undefined macros as 0, which is worth a note rather than a warning as
+    // system and compiler macros (_WIN32, __cplusplus) are seldom given
+    if let Ok(unknown) = unknown.lock() {
+        for (name, (file, line)) in unknown.iter() {
+            diagnostics.add(
+                Diagnostic::new(
+                    Severity::Note,
+                    format!("macro {} isn't defined, taken as 0", name),
+                )
+                .at(file, *line),
+            );
+        }
+    }
+
+    // create sorted list of includes
+    let mut sorted = Vec::new();
+    if let Ok(p) = processed.lock() {
+        for pi in p.iter() {
+            sorted.push(pi.clone());
+        }
+    }
+    sorted.sort();
+
+    // includes of hard links name the path the file was first reached by
+    let mut files = tree.files();
+    for edge in files.values_mut().flatten() {
+        if let Some(first) = edge.path.as_ref().and_then(|p| renamed.get(p)) {
+            edge.path = Some(first.clone());
+        }
+    }
+
+    let graph = DependencyGraph {
+        inputs: inputs.to_vec(),
+        files,
+        dependencies: sorted.into_iter().map(PathBuf::from).collect(),
+        diagnostics: diagnostics.sorted(),
+        profile: if options.profile {
+            profiler.workers()
+        } else {
+            Vec::new()
+        },
+        ..Default::default()
+    };
+
+    // what the compiler reads from the pch isn't a dependency (--pch=stdafx.h)
+    Ok(match &options.pch {
+        Some(pch) => graph.precompiled_split(pch),
+        None => graph,
+    })
+}
+
+//-----------------------------------------------------------------------------
+// scan the inputs and all files they include, directly or not
+//-----------------------------------------------------------------------------
+
+pub fn scan(inputs: &[PathBuf], options: &ScanOptions) -> IncResult<DependencyGraph> {
+    // includes of earlier scans, for the defines of this one
+    let cache = options
+        .cache
+        .as_ref()
+        .map(|c| ScanCache::load(c, defines_key(&options.defines)));
+    let mut graph = scan_cached(inputs, options, cache.clone())?;
+
+    // the cache only saves time, the scan stands without it
+    if let (Some(cache_file), Some(cache)) = (&options.cache, &cache) {
+        if cache.save(cache_file).is_err() {
+            graph.diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                format!("couldn't write to cache file: {}", cache_file.display()),
+            ));
+        }
+    }
+
+    Ok(graph)
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_incredible {
+
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_absolute_path() {
+        let a = path_absolute(Path::new("C:\test"));
+        assert_eq!(Path::new("C:\test"), a);
+
+        let a = path_absolute(Path::new("test"));
+        assert_eq!(Path::new("test"), a);
+
+        let a = path_absolute(Path::new("boo/bar"));
+        assert_eq!(Path::new("boo/bar"), a);
+
+        let a = path_absolute(Path::new(r#"go\fish"#));
+        assert_eq!(Path::new(r#"go\fish"#), a);
+
+        let a = path_absolute(Path::new(r#"first\second\.."#));
+        assert_eq!(Path::new(r#"first"#), a);
+
+        let a = path_absolute(Path::new(r#"first\second\..\third"#));
+        assert_eq!(Path::new(r#"first\third"#), a);
+    }
+
+    #[test]
+    fn test_path_normalise() {
+        let root = Some(Path::new("/src/project/"));
+        assert_eq!(
+            Path::new("inc/a.h"),
+            path_normalise(Path::new("/src/project/lib/../inc/a.h"), root)
+        );
+        assert_eq!(
+            Path::new("/src/other/a.h"),
+            path_normalise(Path::new("/src/other/a.h"), root)
+        );
+        assert_eq!(
+            Path::new("/src/project_b/a.h"),
+            path_normalise(Path::new("/src/project_b/a.h"), root)
+        );
+        assert_eq!(
+            Path::new("/src/a.h"),
+            path_normalise(Path::new("/src/./a.h"), None)
+        );
+
+        // relative paths are relative to the working directory
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            Path::new("a.h"),
+            path_normalise(Path::new("a.h"), Some(&cwd))
+        );
+    }
+
+    #[test]
+    fn test_scan() {
+        let dir = std::env::temp_dir().join("incredible_test_scan");
+        fs::create_dir_all(dir.join("inc")).unwrap();
+        fs::write(
+            dir.join("main.cpp"),
+            "#include \"a.h\"\n#include <b.h>\n#include <missing.h>\n#ifdef USE_C\n#include \"c.h\"\n#endif\n",
+        )
+        .unwrap();
+        fs::write(dir.join("a.h"), "#pragma once\n#include <b.h>\n").unwrap();
+        fs::write(dir.join("inc").join("b.h"), "").unwrap();
+        fs::write(dir.join("c.h"), "").unwrap();
+
+        let mut options = ScanOptions {
+            include_dirs: vec![dir.join("inc")],
+            worker_count: 1,
+            ..Default::default()
+        };
+        let inputs = vec![dir.join("main.cpp")];
+        let graph = scan(&inputs, &options).unwrap();
+        assert_eq!(inputs, graph.inputs);
+        assert_eq!(
+            vec![dir.join("a.h"), dir.join("inc/b.h")],
+            graph.dependencies
+        );
+        assert!(graph.diagnostics.is_empty());
+
+        let main_includes = &graph.files[&dir.join("main.cpp")];
+        assert_eq!(3, main_includes.len());
+        assert_eq!(Some(dir.join("inc/b.h")), main_includes[1].path);
+        assert_eq!(IncludeSearch::System, main_includes[2].search_type);
+        assert_eq!(None, main_includes[2].path);
+        assert_eq!(3, main_includes[2].line);
+        assert_eq!(
+            vec![(dir.join("main.cpp").as_path(), &main_includes[2])],
+            graph.unresolved()
+        );
+
+        options.defines.insert("USE_C".to_string(), "1".to_string());
+        let graph = scan(&inputs, &options).unwrap();
+        assert!(graph.dependencies.contains(&dir.join("c.h")));
+
+        // excluded headers are resolved but not followed
+        options.excludes.push("inc".to_string());
+        let graph = scan(&inputs, &options).unwrap();
+        assert!(!graph.dependencies.contains(&dir.join("inc/b.h")));
+        assert!(!graph.files.contains_key(&dir.join("inc/b.h")));
+        assert_eq!(
+            Some(dir.join("inc/b.h")),
+            graph.files[&dir.join("main.cpp")][1].path
+        );
+
+        // forced includes come before the includes of each input, on line 0
+        fs::write(dir.join("inc").join("pch.h"), "#include \"d.h\"\n").unwrap();
+        fs::write(dir.join("inc").join("d.h"), "").unwrap();
+        options.excludes.clear();
+        options.forced_includes.push("pch.h".to_string());
+        let graph = scan(&inputs, &options).unwrap();
+        let main_includes = &graph.files[&dir.join("main.cpp")];
+        assert_eq!("pch.h", main_includes[0].include);
+        assert_eq!(0, main_includes[0].line);
+        assert_eq!(Some(dir.join("inc/pch.h")), main_includes[0].path);
+        assert!(graph.dependencies.contains(&dir.join("inc/d.h")));
+        assert!(graph.files[&dir.join("a.h")]
+            .iter()
+            .all(|i| "pch.h" != i.include));
+
+        // system includes are recorded, but neither resolved nor scanned
+        options.forced_includes.clear();
+        options.skip_system = true;
+        let graph = scan(&inputs, &options).unwrap();
+        assert_eq!(vec![dir.join("a.h"), dir.join("c.h")], graph.dependencies);
+        let main_includes = &graph.files[&dir.join("main.cpp")];
+        assert_eq!(IncludeSearch::System, main_includes[1].search_type);
+        assert_eq!(None, main_includes[1].path);
+        assert_eq!(None, graph.files[&dir.join("a.h")][0].path);
+        options.skip_system = false;
+
+        assert!(scan(&[dir.join("missing.cpp")], &options).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let dir = std::env::temp_dir().join("incredible_test_max_depth");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("main.cpp"), "#include \"a.h\"\n").unwrap();
+        fs::write(dir.join("a.h"), "#include \"b.h\"\n").unwrap();
+        fs::write(dir.join("b.h"), "\n#include \"c.h\"\n").unwrap();
+        fs::write(dir.join("c.h"), "#include \"d.h\"\n").unwrap();
+        fs::write(dir.join("d.h"), "").unwrap();
+
+        let mut options = ScanOptions {
+            worker_count: 1,
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        let inputs = vec![dir.join("main.cpp")];
+        let graph = scan(&inputs, &options).unwrap();
+        assert_eq!(
+            vec![dir.join("a.h"), dir.join("b.h"), dir.join("c.h")],
+            graph.dependencies
+        );
+        assert!(!graph.files.contains_key(&dir.join("c.h")));
+        assert_eq!(
+            vec![Diagnostic::new(
+                Severity::Warning,
+                format!(
+                    "include depth limit of 2 reached, {} isn't scanned",
+                    dir.join("c.h").display()
+                )
+            )
+            .at(&dir.join("b.h").to_string_lossy(), 2)],
+            graph.diagnostics
+        );
+
+        options.max_depth = None;
+        let graph = scan(&inputs, &options).unwrap();
+        assert_eq!(4, graph.dependencies.len());
+        assert_eq!(4, graph.chains_deepest(1)[0].len() - 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_search_order() {
+        let dir = std::env::temp_dir().join("incredible_test_search_order");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(
+            dir.join("main.cpp"),
+            "#include \"sub/a.h\"\n#include <c.h>\n",
+        )
+        .unwrap();
+        fs::write(dir.join("sub").join("a.h"), "#include \"b.h\"\n").unwrap();
+        fs::write(dir.join("b.h"), "").unwrap();
+        fs::write(dir.join("c.h"), "").unwrap();
+
+        let inputs = vec![dir.join("main.cpp")];
+        let dependencies = |search_order| {
+            let options = ScanOptions {
+                worker_count: 1,
+                search_order,
+                ..Default::default()
+            };
+            scan(&inputs, &options).unwrap().dependencies
+        };
+
+        // b.h is next to main.cpp, including sub/a.h
+        assert_eq!(
+            vec![dir.join("b.h"), dir.join("sub/a.h")],
+            dependencies(SearchOrder::Msvc)
+        );
+        assert_eq!(vec![dir.join("sub/a.h")], dependencies(SearchOrder::Gcc));
+        assert_eq!(
+            vec![dir.join("c.h"), dir.join("sub/a.h")],
+            dependencies(SearchOrder::Lenient)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_aliases() {
+        let dir = std::env::temp_dir().join("incredible_test_aliases");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("real")).unwrap();
+        let dir = fs::canonicalize(&dir).unwrap();
+        fs::write(
+            dir.join("main.cpp"),
+            "#include \"real/a.h\"\n#include \"link/a.h\"\n#include \"real/b.h\"\n#include \"b_hard.h\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("real/a.h"), "").unwrap();
+        fs::write(dir.join("real/b.h"), "").unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+        fs::hard_link(dir.join("real/b.h"), dir.join("b_hard.h")).unwrap();
+
+        let options = ScanOptions {
+            worker_count: 1,
+            ..Default::default()
+        };
+        let graph = scan(&[dir.join("main.cpp")], &options).unwrap();
+        assert_eq!(
+            vec![dir.join("real/a.h"), dir.join("real/b.h")],
+            graph.dependencies
+        );
+        let main_includes = &graph.files[&dir.join("main.cpp")];
+        assert_eq!(Some(dir.join("real/a.h")), main_includes[1].path);
+        assert_eq!(Some(dir.join("real/b.h")), main_includes[3].path);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_deterministic() {
+        // headers reached by many includers, which one is first decides the hard
+        // link scanned, the chain a quoted include is resolved with, where the
+        // depth limit is reported
+        let dir = std::env::temp_dir().join("incredible_test_scan_deterministic");
+        let _ = fs::remove_dir_all(&dir);
+        for sub in ["a", "b", "shared"].iter() {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let dir = fs::canonicalize(&dir).unwrap();
+        let mut main = String::new();
+        for i in 0..16 {
+            main.push_str(&format!(
+                "#include \"b/h{}.h\"\n#include \"a/h{}.h\"\n",
+                i,
+                15 - i
+            ));
+            for sub in ["a", "b"].iter() {
+                fs::write(
+                    dir.join(sub).join(format!("h{}.h", i)),
+                    format!(
+                        "#include \"h{}.h\"\n#include \"../shared/s{}.h\"\n",
+                        (i + 1) % 16,
+                        i % 4
+                    ),
+                )
+                .unwrap();
+            }
+        }
+        fs::write(dir.join("main.cpp"), &main).unwrap();
+        fs::write(dir.join("other.cpp"), "#include \"b/h3.h\"\n").unwrap();
+        for i in 0..4 {
+            fs::write(
+                dir.join("shared").join(format!("s{}.h", i)),
+                "#include \"local.h\"\n",
+            )
+            .unwrap();
+        }
+        fs::write(dir.join("a/local.h"), "").unwrap();
+        fs::write(dir.join("b/local.h"), "").unwrap();
+        fs::hard_link(dir.join("a/h7.h"), dir.join("shared/s3.h.link")).unwrap();
+        fs::write(dir.join("a/h7.h"), "#include \"../shared/s3.h.link\"\n").unwrap();
+
+        let inputs = vec![dir.join("main.cpp"), dir.join("other.cpp")];
+        let mut options = ScanOptions {
+            worker_count: 1,
+            search_order: SearchOrder::Msvc,
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        let expected = scan(&inputs, &options).unwrap();
+        assert!(expected.dependencies.contains(&dir.join("b/local.h")));
+        assert!(!expected.diagnostics.is_empty());
+
+        options.worker_count = 8;
+        for _ in 0..20 {
+            assert_eq!(expected, scan(&inputs, &options).unwrap());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_line_directive() {
+        assert_eq!(Some((12, None)), line_directive_parse(" 12"));
+        assert_eq!(
+            Some((7, Some("gen\\parser.y".to_string()))),
+            line_directive_parse("7 \"gen\\\\parser.y\" 1 3")
+        );
+        assert_eq!(None, line_directive_parse("x"));
+
+        let dir = std::env::temp_dir().join("incredible_test_line_directive");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("main.cpp"),
+            "#include <a.h>\n#line 100 \"parser.y\"\n\n#include <b.h>\n# 7\n#include MISSING\n#include <c.h>\n",
+        )
+        .unwrap();
+
+        let options = ScanOptions {
+            worker_count: 1,
+            ..Default::default()
+        };
+        let graph = scan(&[dir.join("main.cpp")], &options).unwrap();
+        let main_includes = &graph.files[&dir.join("main.cpp")];
+        let locations: Vec<String> = main_includes
+            .iter()
+            .map(|i| i.location(&dir.join("main.cpp")))
+            .collect();
+        assert_eq!(
+            vec![
+                format!("{}:1", dir.join("main.cpp").display()),
+                "parser.y:101".to_string(),
+                "parser.y:8".to_string(),
+            ],
+            locations
+        );
+        assert_eq!(
+            vec![
+                Diagnostic::new(Severity::Warning, "couldn't find macro: MISSING".into())
+                    .at("parser.y", 7)
+            ],
+            graph.diagnostics
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_defines_included() {
+        let dir = std::env::temp_dir().join("incredible_test_defines_included");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("main.cpp"),
+            "#define USE_FOO 1\n#include \"a.h\"\n#undef USE_FOO\n#include \"b.h\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("a.h"),
+            "#if USE_FOO\n#include \"foo.h\"\n#endif\n#define USE_BAR\n#include \"c.h\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("b.h"),
+            "#if defined(USE_FOO) || _MSC_VER\n#include \"not.h\"\n#endif\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("c.h"),
+            "#ifdef USE_BAR\n#include \"bar.h\"\n#endif\n#if _MSC_VER\n#include \"msvc.h\"\n#endif\n",
+        )
+        .unwrap();
+        for header in ["foo.h", "bar.h", "msvc.h", "not.h"].iter() {
+            fs::write(dir.join(header), "").unwrap();
+        }
+
+        // includes get the defines of their includer at the include
+        let mut options = ScanOptions {
+            worker_count: 1,
+            ..Default::default()
+        };
+        let inputs = vec![dir.join("main.cpp")];
+        let expected: Vec<PathBuf> = ["a.h", "b.h", "bar.h", "c.h", "foo.h"]
+            .iter()
+            .map(|h| dir.join(h))
+            .collect();
+        let graph = scan(&inputs, &options).unwrap();
+        assert_eq!(expected, graph.dependencies);
+
+        // an undefined macro is noted once, where it is first used
+        assert_eq!(
+            vec![Diagnostic::new(
+                Severity::Note,
+                "macro _MSC_VER isn't defined, taken as 0".into()
+            )
+            .at(&dir.join("b.h").to_string_lossy(), 1)],
+            graph.diagnostics
+        );
+
+        // cached files pass the same defines to their includes
+        options.cache = Some(dir.join("incredible.cache"));
+        scan(&inputs, &options).unwrap();
+        assert_eq!(expected, scan(&inputs, &options).unwrap().dependencies);
+
+        options
+            .defines
+            .insert("_MSC_VER".to_string(), "1900".to_string());
+        let graph = scan(&inputs, &options).unwrap();
+        assert!(graph.dependencies.contains(&dir.join("msvc.h")));
+        assert!(graph.dependencies.contains(&dir.join("not.h")));
+        assert!(graph.diagnostics.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -19,10 +19,62 @@
 //	Resolved paths using supplied inclued directories
 //	Recurses through all includes and builds full list of dependents
 //	Resolves basic macros
-//	Able to run single or multi-threaded
+//	Skips includes in disabled #if/#ifdef/#else branches (with __has_include probes), a file sees
+//	the defines of the file including it, undefined macros are 0 and noted once (--verbose)
+//	Skips headers already scanned under #pragma once or an include guard
+//	Honours #line directives (and # 12 "file" line markers), warnings and the json tree give the lines they set
+//	Resolves symbolic links (and hard links on unix), a header reached through several include roots is listed once
+//	Able to run single or multi-threaded (-st, or -j=N workers)
 //
 //	Syntax
 //	incredible -f=input_file -o=output_file <-i=include_dir0 ... -i=include_dirN>  <-dDEFINE_KEY_1=DEFINE_VALUE_1 .... -dDEFINE_KEY_N-=DEFINE_VALUE_N>
+//
+//	-f also takes directories (their files with a source extension) and globs (-f=src/**/*.cpp, * ** ?)
+//	--extensions=cpp,cc sets the extensions of the files of directories (c cc cpp cxx c++ m mm by default)
+//
+//	-MD (or --depfile) writes the output files as makefile rules (gcc style .d files) for ninja and bazel,
+//	-MT=target sets the target of the rule (defaults to the first input file)
+//	--pair-outputs writes the dependencies of the Nth -f to the Nth -o instead of those of all inputs to each -o
+//	-o-dir=dir writes the dependencies of each input to dir/<input file name>.d (.txt without -MD)
+//	the rules of outputs of a single input are for the input, -MT only sets those of outputs of all inputs
+//	-cache=file keeps the includes of each file between runs, only modified files are read again
+//	-o-json=file writes the include tree as json (who includes whom, local or system, resolved path)
+//	-o-chains=file writes the deepest include chains, innermost file first (where compile times blow up)
+//	--max-depth=N doesn't scan files nested more than N includes below an input, they are still dependencies
+//	-o-unused=file lists the includes plausibly unused, none of the names their header declares are referenced
+//	-o-includers=file writes each header followed by the files including it, directly or transitively
+//	-o-hashes=file writes the sha-256 of the inputs and every file they include (those of the pch too), after the hash
+//	of them all (closure<tab>hash, then hash<tab>path sorted by path), for build caches to fingerprint units with
+//	--exclude=glob stops at includes matching the glob, neither scanned nor listed (* ** ?, repeatable)
+//	-FI=file (or -include=file) includes the header at the top of every input, as msvc /FI and gcc -include do
+//	for precompiled headers. Found in the working directory, or like a quoted include of the input (repeatable)
+//	--search-order=msvc|gcc resolves includes as the compiler does, quoted includes are searched in the directory
+//	of the includer, then (msvc) in those of the files including it, then in the include directories. Without it,
+//	includes not found in the include directories are also searched next to the includer
+//	--no-system records <...> includes without searching for or scanning them, nor listing them as dependencies
+//	(system and sdk headers are inputs of the toolchain), nor reporting them unresolved
+//	--pch=stdafx.h leaves the precompiled header, as included, and the files it includes out of the dependencies, the
+//	compiler reads them from the pch (every input is taken to use it). -o-pch=file lists them, the pch's dependencies
+//	-compdb=compile_commands.json scans every translation unit of a compilation database with its own
+//	include directories and defines, writing <output>.d next to each object file (<file>.d without one)
+//	-i and -d options are added to those of each unit, -f -o and -cache are ignored
+//	--normalise-paths writes absolute paths without . or .., with forward slashes (lower case on windows)
+//	--relative-to=root writes them relative to root when below it (the monorepo root or bazel execroot)
+//	--strict reports unresolved includes as errors and exits with 2 when there are any (1 when the scan fails)
+//	--quiet prints only errors, --verbose adds notes (include directories, defines, what was scanned)
+//	-o-diagnostics=file writes the warnings and errors as json (severity, file, line, message), for tools
+//	--serve=address keeps running, scanning the requests of clients with the headers of earlier requests in memory
+//	(a unix socket path, or 127.0.0.1:port), --connect=address has the server scan as the rest of the command line asks
+//	and write the outputs, for build systems scanning thousands of units. Requests are scanned one at a time
+//	--incremental leaves outputs newer than their inputs and the dependencies they list as they are, and only scans
+//	the inputs of the others (all of them for -o-json -o-includers -o-chains -o-unused -o-pch -o-hashes), exiting
+//	early when none changed. Changes of options or a header newly shadowing another aren't noticed, nor are warnings
+//	repeated
+//	--profile shows the time each worker spent reading files, parsing them and resolving includes, and the wall time
+//	of the scan (cargo run --release --example bench scans a generated header tree the same way every time)
+//	--watch keeps running, writing the outputs again whenever a scanned file changes (only changed files are read)
+//	@file reads more arguments from a response file, for argument lists longer than command lines allow
+//	environment variables are expanded in -i and response files, %NAME% $NAME or ${NAME} (-i=$VULKAN_SDK/include)
 
 //-----------------------------------------------------------------------------
 
@@ -30,555 +82,1421 @@
 //	Using
 //-----------------------------------------------------------------------------
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use incredible::{
+    compile_commands_load, files_hash, hashes_format, inputs_expand, path_normalise,
+    unused_includes, DependencyGraph, Diagnostic, IncludeSearch, ScanOptions, Scanner, SearchOrder,
+    Severity, Watcher, WorkerProfile, SOURCE_EXTENSIONS,
+};
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
-use std::io::Write;
-use std::path::{Path, PathBuf, MAIN_SEPARATOR};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::thread;
+use std::time::{Duration, Instant};
 
-//	Generic Eror Type
+// limit of response files referring to others, guards against cycles
 
-#[derive(Debug)]
-pub enum IncError {
-    IO(std::io::Error),
-    StdErr(Box<dyn std::error::Error>),
-    Literal(&'static str),
-}
+const RESPONSE_FILE_DEPTH_MAX: usize = 16;
 
-pub type IncResult<T> = Result<T, IncError>;
+// include chains written by -o-chains, the deepest ones
 
-impl From<std::io::Error> for IncError {
-    fn from(e: std::io::Error) -> Self {
-        IncError::IO(e)
-    }
+const CHAINS_REPORTED: usize = 10;
+
+// delay between checks of the scanned files for changes (--watch)
+
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+//	how paths are written to outputs (--normalise-paths, --relative-to=root)
+
+struct PathStyle {
+    normalise: bool,
+    root: Option<PathBuf>,
 }
 
-impl From<Box<dyn std::error::Error>> for IncError {
-    fn from(e: Box<dyn std::error::Error>) -> Self {
-        IncError::StdErr(e)
+impl PathStyle {
+    fn new(command_line: &HashMap<String, Vec<Option<String>>>) -> Self {
+        let root = command_line
+            .get("relative-to")
+            .and_then(|r| r.last().cloned().flatten())
+            .map(PathBuf::from);
+        PathStyle {
+            normalise: root.is_some() || command_line.contains_key("normalise-paths"),
+            root,
+        }
     }
-}
 
-impl From<&'static str> for IncError {
-    fn from(e: &'static str) -> Self {
-        IncError::Literal(e)
+    fn graph(&self, graph: &DependencyGraph) -> DependencyGraph {
+        if self.normalise {
+            graph.paths_normalise(self.root.as_deref())
+        } else {
+            graph.clone()
+        }
+    }
+
+    fn path(&self, path: &str) -> String {
+        if self.normalise {
+            path_normalise(Path::new(path), self.root.as_deref())
+                .to_string_lossy()
+                .into_owned()
+        } else {
+            path.to_string()
+        }
     }
 }
 
-//	Enum for include types (quote vs angle brackets)
+//	diagnostics of the run, rendered when it ends (--quiet, --verbose, -o-diagnostics=file)
 
-#[derive(PartialEq)]
-enum IncludeSearch {
-    Local,
-    System,
+struct Report {
+    // least severe diagnostics shown, errors (--quiet), warnings or notes (--verbose)
+    level: Severity,
+    json_files: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+    // time of the scans per worker and their wall time, shown after the diagnostics (--profile)
+    profile: Option<(Vec<WorkerProfile>, Duration)>,
 }
 
-struct ResolvedPathCollection {
-    resolved: Arc<Mutex<HashMap<PathBuf, HashSet<String>>>>,
+impl Report {
+    fn new(
+        command_line: &HashMap<String, Vec<Option<String>>>,
+        diagnostics: Vec<Diagnostic>,
+    ) -> Self {
+        let level = if command_line.contains_key("quiet") {
+            Severity::Error
+        } else if command_line.contains_key("verbose") {
+            Severity::Note
+        } else {
+            Severity::Warning
+        };
+        Report {
+            level,
+            json_files: command_line
+                .get("o-diagnostics")
+                .into_iter()
+                .flatten()
+                .flatten()
+                .cloned()
+                .collect(),
+            diagnostics,
+            profile: if command_line.contains_key("profile") {
+                Some((Vec::new(), Duration::default()))
+            } else {
+                None
+            },
+        }
+    }
+
+    // add the profile of a scan to those shown, the units of a compilation database add up
+    fn profile_add(&mut self, workers: &[WorkerProfile], wall: Duration) {
+        if let Some((total, total_wall)) = self.profile.as_mut() {
+            if total.len() < workers.len() {
+                total.resize(workers.len(), WorkerProfile::default());
+            }
+            for (t, w) in total.iter_mut().zip(workers.iter()) {
+                t.add(w);
+            }
+            *total_wall += wall;
+        }
+    }
+
+    fn add(&mut self, severity: Severity, message: String) {
+        self.diagnostics.push(Diagnostic::new(severity, message));
+    }
+
+    fn render(&mut self) {
+        self.render_to(&mut io::stdout());
+    }
+
+    // print the diagnostics shown, and write them as json, warnings included when quiet
+    fn render_to(&mut self, out: &mut dyn Write) {
+        let diagnostics = std::mem::take(&mut self.diagnostics);
+        for diagnostic in diagnostics.iter().filter(|d| d.severity <= self.level) {
+            let _ = writeln!(out, "{}", diagnostic);
+        }
+        if let Some((workers, wall)) = self.profile.as_mut() {
+            let _ = write!(out, "{}", WorkerProfile::format(workers, *wall));
+            *workers = Vec::new();
+            *wall = Duration::default();
+        }
+
+        let level = self.level.max(Severity::Warning);
+        let written: Vec<Diagnostic> = diagnostics
+            .into_iter()
+            .filter(|d| d.severity <= level)
+            .collect();
+        let json = Diagnostic::json_format(&written);
+        for jf in self.json_files.iter() {
+            if fs::write(jf, &json).is_err() {
+                let _ = writeln!(out, "error: couldn't write to output file: {}", jf);
+            }
+        }
+    }
+
+    fn exit(mut self, code: i32) -> ! {
+        self.render();
+        std::process::exit(code)
+    }
 }
 
-impl ResolvedPathCollection {
-    pub fn new() -> Self {
-        ResolvedPathCollection {
-            resolved: Arc::new(Mutex::new(HashMap::<PathBuf, HashSet<String>>::new())),
+//-----------------------------------------------------------------------------
+// escape a path for a makefile rule, as gcc does for -MD
+//-----------------------------------------------------------------------------
+
+fn depfile_escape(path: &str) -> String {
+    let mut escaped = String::new();
+    let chars: Vec<char> = path.chars().collect();
+    for (i, c) in chars.iter().enumerate() {
+        match c {
+            ' ' | '\t' => {
+                // backslashes before a space would escape the escaping backslash
+                let mut j = i;
+                while j > 0 && chars[j - 1] == '\\' {
+                    escaped.push('\\');
+                    j -= 1;
+                }
+                escaped.push('\\');
+            }
+            '#' => escaped.push('\\'),
+            '$' => escaped.push('$'),
+            _ => {}
         }
+        escaped.push(*c);
     }
+    escaped
 }
 
-struct ResolvedPaths {
-    local: ResolvedPathCollection,
-    system: ResolvedPathCollection,
+//-----------------------------------------------------------------------------
+// format dependencies as a makefile rule, one dependency per line
+//-----------------------------------------------------------------------------
+
+fn depfile_format(target: &str, dependencies: &[String]) -> String {
+    let mut rule = format!("{}:", depfile_escape(target));
+    for dep in dependencies {
+        rule.push_str(" \\\n  ");
+        rule.push_str(&depfile_escape(dep));
+    }
+    rule.push('\n');
+    rule
 }
 
-// counter for amount of active job threads
+//-----------------------------------------------------------------------------
+// dependencies of a makefile rule, unescaped, the target left out
+//-----------------------------------------------------------------------------
 
-static GLOBAL_JOB_COUNT: AtomicUsize = AtomicUsize::new(0);
+fn depfile_parse(text: &str) -> Vec<String> {
+    // the target ends at the first colon followed by whitespace, c:\dir is a path
+    let chars: Vec<char> = text.chars().collect();
+    let start = (0..chars.len())
+        .find(|&i| chars[i] == ':' && chars.get(i + 1).filter(|c| !c.is_whitespace()).is_none())
+        .map_or(chars.len(), |i| i + 1);
+
+    let mut dependencies = Vec::new();
+    let mut dep = String::new();
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                let mut run = 0;
+                while i < chars.len() && chars[i] == '\\' {
+                    run += 1;
+                    i += 1;
+                }
+                match chars.get(i) {
+                    // an odd run escapes the space, the rest are escaped backslashes
+                    Some(' ') | Some('\t') => {
+                        dep.push_str(&"\\".repeat(run / 2));
+                        if run % 2 == 1 {
+                            dep.push(chars[i]);
+                            i += 1;
+                        }
+                    }
+                    Some('#') => dep.push_str(&"\\".repeat(run - 1)),
+                    // continued on the next line
+                    Some('\n') | Some('\r') => dep.push_str(&"\\".repeat(run - 1)),
+                    _ => dep.push_str(&"\\".repeat(run)),
+                }
+            }
+            '$' if chars.get(i + 1) == Some(&'$') => {
+                dep.push('$');
+                i += 2;
+            }
+            c if c.is_whitespace() => {
+                if !dep.is_empty() {
+                    dependencies.push(std::mem::take(&mut dep));
+                }
+                i += 1;
+            }
+            c => {
+                dep.push(c);
+                i += 1;
+            }
+        }
+    }
+    if !dep.is_empty() {
+        dependencies.push(dep);
+    }
+    dependencies
+}
 
 //-----------------------------------------------------------------------------
-// helper to ensure path is formatted correctly for platform
+// split response file contents into arguments, separated by whitespace
+// double quotes group arguments containing whitespace
 //-----------------------------------------------------------------------------
 
-fn path_sanitise(src: &str) -> PathBuf {
-    let alt_seperator = match MAIN_SEPARATOR {
-        '/' => '\\',
-        _ => '/',
-    };
+fn response_file_parse(text: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut quoted = false;
+    let mut started = false;
+
+    for c in text.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                started = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if started {
+                    args.push(std::mem::take(&mut arg));
+                    started = false;
+                }
+            }
+            c => {
+                arg.push(c);
+                started = true;
+            }
+        }
+    }
+    if started {
+        args.push(arg);
+    }
 
-    let cleaned = &src.replace(alt_seperator, &MAIN_SEPARATOR.to_string());
-    Path::new(cleaned).to_path_buf()
+    args
 }
 
 //-----------------------------------------------------------------------------
-// Create absolute path from relative
+// expand environment variables, %NAME% as windows does, $NAME and ${NAME} as
+// shells do. Variables that aren't set are left as written, with a warning
 //-----------------------------------------------------------------------------
 
-fn path_absolute(src: &Path) -> PathBuf {
-    let mut pb = PathBuf::new();
-    let mut v = Vec::new();
-
-    for c in src.components() {
-        if ".." == c.as_os_str() {
-            v.pop();
+fn env_expand(
+    text: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> String {
+    let mut expanded = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find(&['%', '$'][..]) {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        // the name, and the length of the reference
+        let reference = if rest[start..].starts_with('%') {
+            after
+                .find('%')
+                .map(|end| &after[..end])
+                .filter(|name| !name.is_empty() && !name.contains(char::is_whitespace))
+                .map(|name| (name, name.len() + 2))
+        } else if after.starts_with('{') {
+            after
+                .find('}')
+                .map(|end| (&after[1..end], end + 2))
+                .filter(|(name, _)| !name.is_empty())
         } else {
-            v.push(c);
+            let end = after
+                .find(|c: char| !c.is_ascii_alphanumeric() && '_' != c)
+                .unwrap_or(after.len());
+            Some((&after[..end], end + 1))
+                .filter(|(name, _)| name.starts_with(|c: char| c.is_ascii_alphabetic() || '_' == c))
+        };
+
+        match reference {
+            Some((name, length)) => {
+                match lookup(name) {
+                    Some(value) => expanded.push_str(&value),
+                    None => {
+                        diagnostics.push(Diagnostic::new(
+                            Severity::Warning,
+                            format!("environment variable not set: {}", name),
+                        ));
+                        expanded.push_str(&rest[start..start + length]);
+                    }
+                }
+                rest = &rest[start + length..];
+            }
+            None => {
+                expanded.push_str(&rest[start..start + 1]);
+                rest = after;
+            }
         }
     }
+    expanded.push_str(rest);
+    expanded
+}
 
-    for cv in v {
-        pb.push(cv);
-    }
-
-    pb
+fn env_lookup(name: &str) -> Option<String> {
+    std::env::var(name).ok()
 }
 
 //-----------------------------------------------------------------------------
-//	Resolve path of include file
+// replace @file arguments by the arguments of the response file, which may refer to others
+// arguments of response files have their environment variables expanded
 //-----------------------------------------------------------------------------
 
-fn include_resolve_path(
-    base_dir: &Path,
-    filename: &str,
-    search_type: IncludeSearch,
-    includes: &[PathBuf],
-) -> Option<PathBuf> {
-    let pfname = path_sanitise(filename);
+fn args_expand(args: Vec<String>, depth: usize, diagnostics: &mut Vec<Diagnostic>) -> Vec<String> {
+    let mut expanded = Vec::new();
 
-    // if include is quoted, start by searching relative
-    if IncludeSearch::Local == search_type {
-        let abs_path = base_dir.join(&pfname);
-        let abs_path = path_absolute(&abs_path);
-        if let Ok(md) = fs::metadata(&abs_path) {
-            if md.is_file() {
-                return Some(abs_path);
+    for arg in args {
+        if !arg.starts_with('@') {
+            expanded.push(arg);
+            continue;
+        }
+        if depth >= RESPONSE_FILE_DEPTH_MAX {
+            diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                format!("response files nested too deep: {}", arg),
+            ));
+            continue;
+        }
+        match fs::read_to_string(&arg[1..]) {
+            Ok(text) => {
+                let args = response_file_parse(&text)
+                    .iter()
+                    .map(|a| env_expand(a, &env_lookup, diagnostics))
+                    .collect();
+                expanded.extend(args_expand(args, depth + 1, diagnostics));
             }
+            Err(_) => diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                format!("couldn't read response file: {}", &arg[1..]),
+            )),
         }
     }
 
-    // search by prepending include paths
-    for inc_path in includes.iter() {
-        let abs_path = Path::new(&inc_path).join(filename);
-        let abs_path = path_absolute(&abs_path);
-        if let Ok(md) = fs::metadata(&abs_path) {
-            if md.is_file() {
-                return Some(abs_path);
-            }
+    expanded
+}
+
+//-----------------------------------------------------------------------------
+// parse all command line options into map (options should be in the form of ikey=value)
+//-----------------------------------------------------------------------------
+
+pub fn command_line_parse(
+    args: Vec<String>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> HashMap<String, Vec<Option<String>>> {
+    let mut hm = HashMap::<String, Vec<Option<String>>>::new();
+
+    for arg in args_expand(args, 0, diagnostics) {
+        let sp: Vec<&str> = arg.split('=').collect();
+        if !sp.is_empty() {
+            // trim whitespace and leading hyphens
+            let k = sp[0].trim().trim_start_matches('-');
+
+            let value = if sp.len() > 1 {
+                Some(sp[1].to_string())
+            } else {
+                None
+            };
+
+            hm.entry(k.into()).or_insert_with(Vec::new).push(value);
         }
     }
+    hm
+}
+
+//-----------------------------------------------------------------------------
+// report the diagnostics of a scan and the includes that couldn't be resolved,
+// errors in strict mode, system includes aren't when skipped. true when there are any
+//-----------------------------------------------------------------------------
+
+fn unresolved_report(
+    graph: &DependencyGraph,
+    strict: bool,
+    skip_system: bool,
+    report: &mut Report,
+) -> bool {
+    let mut diagnostics = graph.diagnostics.clone();
+
+    let mut unresolved = graph.unresolved();
+    unresolved.retain(|(_, include)| !skip_system || IncludeSearch::Local == include.search_type);
+    for (path, include) in unresolved.iter() {
+        let header = match include.search_type {
+            IncludeSearch::Local => format!("\"{}\"", include.include),
+            IncludeSearch::System => format!("<{}>", include.include),
+        };
+        let file = match &include.file {
+            Some(file) => file.clone(),
+            None => path.to_string_lossy().into_owned(),
+        };
+        diagnostics.push(
+            Diagnostic::new(
+                if strict {
+                    Severity::Error
+                } else {
+                    Severity::Warning
+                },
+                format!("file not found {}", header),
+            )
+            .at(&file, include.line),
+        );
+    }
+    // in file order, with the other diagnostics of the scan
+    diagnostics.sort();
+    report.diagnostics.extend(diagnostics);
+    !unresolved.is_empty()
+}
+
+//-----------------------------------------------------------------------------
+// scan every translation unit of a compilation database, one depfile each
+// returns the exit code of the run
+//-----------------------------------------------------------------------------
 
-    let abs_path = base_dir.join(&pfname);
-    let abs_path = path_absolute(&abs_path);
-    if let Ok(md) = fs::metadata(&abs_path) {
-        if md.is_file() {
-            return Some(abs_path);
+fn compdb_scan(
+    compdb: &str,
+    options: &ScanOptions,
+    style: &PathStyle,
+    strict: bool,
+    report: &mut Report,
+    scanner: &mut Scanner,
+) -> i32 {
+    let commands = match compile_commands_load(Path::new(compdb)) {
+        Ok(commands) => commands,
+        Err(e) => {
+            report.add(
+                Severity::Error,
+                format!("couldn't read compilation database {} : {}", compdb, e),
+            );
+            return 1;
+        }
+    };
+    let mut unresolved = false;
+
+    // the cache is for the defines of a single scan
+    let mut base = options.clone();
+    base.cache = None;
+
+    for command in commands.iter() {
+        let target = command.output.as_ref().unwrap_or(&command.file);
+        let mut depfile = target.clone().into_os_string();
+        depfile.push(".d");
+        let target = style.path(&target.to_string_lossy());
+
+        // units with the same defines share the headers they include
+        let started = Instant::now();
+        let graph = match scanner.scan(
+            std::slice::from_ref(&command.file),
+            &command.scan_options(&base),
+        ) {
+            Ok(graph) => {
+                report.profile_add(&graph.profile, started.elapsed());
+                style.graph(&graph)
+            }
+            Err(e) => {
+                report.add(
+                    Severity::Warning,
+                    format!("couldn't scan {} : {}", command.file.display(), e),
+                );
+                continue;
+            }
+        };
+        unresolved |= unresolved_report(&graph, strict, options.skip_system, report);
+
+        let mut dependencies = vec![style.path(&command.file.to_string_lossy())];
+        dependencies.extend(
+            graph
+                .dependencies
+                .iter()
+                .map(|d| d.to_string_lossy().into_owned()),
+        );
+        let rule = depfile_format(&target, &dependencies);
+        if fs::write(&depfile, rule).is_err() {
+            report.add(
+                Severity::Error,
+                format!(
+                    "couldn't write to output file: {}",
+                    Path::new(&depfile).display()
+                ),
+            );
         }
     }
 
-    println!("warning: file not found {}", filename);
-    None
+    if strict && unresolved {
+        2
+    } else {
+        0
+    }
 }
 
 //-----------------------------------------------------------------------------
-//	add file to list to be processed if not processed already
+// the dependency files to write, each with the inputs it covers. Every -o
+// covers all inputs, unless paired with one (--pair-outputs). -o-dir adds a
+// file per input named after it
 //-----------------------------------------------------------------------------
 
-fn file_add(
-    base_dir: &Path,
-    filename: &str,
-    search_type: IncludeSearch,
-    includes: &[PathBuf],
-    processsed: &Arc<Mutex<HashSet<String>>>,
-    queued: &Arc<Mutex<VecDeque<PathBuf>>>,
-    rp: &mut ResolvedPathCollection,
-) {
-    // we want to minimise the amount of times we need to hit file system. lets see if base path+include filename has already been resolved
+fn outputs_plan(
+    command_line: &HashMap<String, Vec<Option<String>>>,
+    inputs: &[String],
+) -> Result<Vec<(String, Vec<String>)>, String> {
+    let outputs: Vec<String> = command_line
+        .get("o")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .cloned()
+        .collect();
+
+    let mut plan: Vec<(String, Vec<String>)> = if command_line.contains_key("pair-outputs") {
+        if outputs.len() != inputs.len() {
+            return Err(format!(
+                "--pair-outputs needs an -o for each input, {} inputs and {} outputs",
+                inputs.len(),
+                outputs.len()
+            ));
+        }
+        outputs
+            .into_iter()
+            .zip(inputs.iter().map(|i| vec![i.clone()]))
+            .collect()
+    } else {
+        outputs.into_iter().map(|o| (o, inputs.to_vec())).collect()
+    };
+
+    // <dir>/<input file name>.d for depfiles, .txt for lists (-o-dir="deps")
+    if let Some(dir) = command_line
+        .get("o-dir")
+        .and_then(|d| d.last().cloned().flatten())
     {
-        let rp_guard = rp.resolved.lock();
-        if let Ok(rp_c) = rp_guard {
-            if let Some(rp_k) = rp_c.get(base_dir.into()) {
-                if rp_k.contains(filename) {
-                    return;
-                }
+        let depfile = command_line.contains_key("MD") || command_line.contains_key("depfile");
+        let mut named: HashMap<PathBuf, &String> = HashMap::new();
+        for input in inputs.iter() {
+            let mut name = Path::new(input)
+                .file_name()
+                .unwrap_or_default()
+                .to_os_string();
+            name.push(if depfile { ".d" } else { ".txt" });
+            let output = Path::new(&dir).join(name);
+            if let Some(other) = named.insert(output.clone(), input) {
+                return Err(format!(
+                    "{} and {} would both write {}",
+                    other,
+                    input,
+                    output.display()
+                ));
             }
+            plan.push((output.to_string_lossy().into_owned(), vec![input.clone()]));
         }
     }
 
-    if let Some(inc_result) = include_resolve_path(base_dir, filename, search_type, includes) {
-        let abs_path = inc_result.to_str().unwrap_or_default();
+    Ok(plan)
+}
 
-        // if we haven't already processed this path, add it to queue to process
-        let proc_guard = processsed.lock();
-        if let Ok(mut p) = proc_guard {
-            if !p.contains(abs_path) {
-                p.insert(abs_path.into());
-                let q_guard = queued.lock();
-                if let Ok(mut q) = q_guard {
-                    q.push_back(inc_result);
-                }
+//-----------------------------------------------------------------------------
+// files an earlier run wrote an output from, the inputs it covers and the
+// dependencies it lists. None when it can't be read
+//-----------------------------------------------------------------------------
 
-                {
-                    let rp_guard = rp.resolved.lock();
-                    if let Ok(mut rp_c) = rp_guard {
-                        rp_c.entry(base_dir.to_path_buf())
-                            .or_insert_with(HashSet::new)
-                            .insert(filename.to_string());
-                    }
-                }
-            }
-        }
+fn output_sources(
+    output: &str,
+    covered: &[String],
+    depfile: bool,
+    root: Option<&Path>,
+) -> Option<Vec<PathBuf>> {
+    let text = fs::read_to_string(output).ok()?;
+    let listed: Vec<String> = if depfile {
+        depfile_parse(&text)
+    } else {
+        text.lines()
+            .filter(|l| !l.is_empty())
+            .map(str::to_string)
+            .collect()
+    };
+
+    // listed paths are relative to the root they were written relative to
+    let mut sources: Vec<PathBuf> = covered.iter().map(PathBuf::from).collect();
+    sources.extend(listed.iter().map(|l| match root {
+        Some(root) => root.join(l),
+        None => PathBuf::from(l),
+    }));
+    Some(sources)
+}
+
+// true when no source was modified after the output was written, as make decides, none missing
+fn output_newer(output: &str, sources: &[PathBuf]) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    match modified(Path::new(output)) {
+        Some(written) => sources
+            .iter()
+            .all(|s| matches!(modified(s), Some(m) if m <= written)),
+        None => false,
     }
 }
 
 //-----------------------------------------------------------------------------
-// process file and find includes
+// the outputs to write again (--incremental), those missing, or older than an
+// input they cover or a dependency they list. Every output when a file of the
+// whole graph (-o-json, -o-chains, ...) is to be written again
 //-----------------------------------------------------------------------------
 
-fn file_process(
-    full_path: &Path,
-    includes: &[PathBuf],
-    processsed: Arc<Mutex<HashSet<String>>>,
-    queued: Arc<Mutex<VecDeque<PathBuf>>>,
-    defines: &mut HashMap<String, String>,
-    rp: &mut ResolvedPaths,
-) -> IncResult<()> {
-    let base_dir = full_path.parent().unwrap_or(Path::new(""));
+fn outputs_stale(
+    command_line: &HashMap<String, Vec<Option<String>>>,
+    plan: &[(String, Vec<String>)],
+) -> Vec<(String, Vec<String>)> {
+    let depfile = command_line.contains_key("MD") || command_line.contains_key("depfile");
+    let root = PathStyle::new(command_line).root;
+    let sources: Vec<Option<Vec<PathBuf>>> = plan
+        .iter()
+        .map(|(output, covered)| output_sources(output, covered, depfile, root.as_deref()))
+        .collect();
+
+    let stale: Vec<(String, Vec<String>)> = plan
+        .iter()
+        .zip(sources.iter())
+        .filter(|((output, _), sources)| match sources {
+            Some(sources) => !output_newer(output, sources),
+            None => true,
+        })
+        .map(|(p, _)| p.clone())
+        .collect();
+
+    // files of the whole graph are fresh when newer than what every output lists
+    let graph_files: Vec<&String> = [
+        "o-json",
+        "o-includers",
+        "o-chains",
+        "o-unused",
+        "o-pch",
+        "o-hashes",
+    ]
+    .iter()
+    .filter_map(|k| command_line.get(*k))
+    .flatten()
+    .flatten()
+    .collect();
+    let graph_fresh = graph_files
+        .iter()
+        .all(|g| sources.iter().flatten().all(|s| output_newer(g, s)));
+
+    let graph_stale = !graph_files.is_empty() && (!graph_fresh || !stale.is_empty());
+    if plan.is_empty() || graph_stale {
+        plan.to_vec()
+    } else {
+        stale
+    }
+}
 
-    let filename_string = full_path.to_str().ok_or("")?;
-    let data = fs::read(filename_string)?;
+//-----------------------------------------------------------------------------
+// report the diagnostics of a scan and write the requested outputs, true when
+// includes were unresolved
+//-----------------------------------------------------------------------------
 
-    enum SearchMode {
-        Hash,
-        Directive,
-        WhiteSpace,
-        DefineKey,
-        DefineValue,
-        Quote,
-        Arrow,
-        Macro,
+fn outputs_write(
+    command_line: &HashMap<String, Vec<Option<String>>>,
+    graph: &DependencyGraph,
+    inputs: &[String],
+    plan: &[(String, Vec<String>)],
+    strict: bool,
+    worker_count: usize,
+    report: &mut Report,
+) -> bool {
+    // paths as written (--normalise-paths, --relative-to=root)
+    let style = PathStyle::new(command_line);
+
+    // write the hash of every file the inputs are compiled from, and of them
+    // all, for build caches (-o-hashes="hashes.txt"). Files are read where
+    // they are, before their paths are written relative to a root
+    if let Some(hash_files) = command_line.get("o-hashes") {
+        let mut files: Vec<PathBuf> = inputs.iter().map(PathBuf::from).collect();
+        files.extend(graph.dependencies.iter().cloned());
+        files.extend(graph.precompiled.iter().cloned());
+        let mut hashes = Vec::new();
+        for (path, hash) in files_hash(&files, worker_count) {
+            match hash {
+                Ok(hash) => hashes.push((style.path(&path.to_string_lossy()), hash)),
+                Err(e) => report.add(
+                    Severity::Warning,
+                    format!("couldn't hash {}: {}", path.display(), e),
+                ),
+            }
+        }
+        let text = hashes_format(&hashes);
+        for hf in hash_files.iter().flatten() {
+            if fs::write(hf, &text).is_err() {
+                report.add(
+                    Severity::Error,
+                    format!("couldn't write to output file: {}", hf),
+                );
+            }
+        }
     }
 
-    enum DirectiveType {
-        DefineKey,
-        DefineValue,
-        Include,
+    let graph = &style.graph(graph);
+    let inputs: Vec<String> = inputs.iter().map(|i| style.path(i)).collect();
+
+    report.add(
+        Severity::Note,
+        format!(
+            "scanned {} files, {} dependencies",
+            graph.files.len(),
+            graph.dependencies.len()
+        ),
+    );
+    let skip_system = command_line.contains_key("no-system");
+    let unresolved = unresolved_report(graph, strict, skip_system, report);
+
+    let depfile = command_line.contains_key("MD") || command_line.contains_key("depfile");
+
+    // write dependencies to specified output files (-o="output_file.txt")
+    for (o_file, covered) in plan.iter() {
+        let covered: Vec<String> = covered.iter().map(|c| style.path(c)).collect();
+        // sorted list of includes, of the inputs the file covers
+        let sorted: Vec<String> = if covered.len() == inputs.len() {
+            graph
+                .dependencies
+                .iter()
+                .map(|d| d.to_string_lossy().into_owned())
+                .collect()
+        } else {
+            let mut of_covered: Vec<PathBuf> = covered
+                .iter()
+                .flat_map(|c| graph.dependencies_of(Path::new(c)))
+                .collect();
+            of_covered.sort();
+            of_covered.dedup();
+            of_covered
+                .iter()
+                .map(|d| d.to_string_lossy().into_owned())
+                .collect()
+        };
+
+        // makefile rule of the inputs and their includes, when writing depfiles (-MD)
+        // the rule of a single input is for that input
+        let text = if depfile {
+            let target = match command_line
+                .get("MT")
+                .and_then(|t| t.last().cloned().flatten())
+            {
+                Some(t) if covered.len() == inputs.len() => t,
+                _ => covered.first().cloned().unwrap_or_default(),
+            };
+            let mut dependencies = covered.to_vec();
+            dependencies.extend(sorted.iter().cloned());
+            depfile_format(&target, &dependencies)
+        } else {
+            sorted.iter().map(|inc| format!("{}\n", inc)).collect()
+        };
+
+        if let Ok(mut f) = File::create(o_file) {
+            if write!(f, "{}", text).is_err() {
+                report.add(
+                    Severity::Error,
+                    format!("couldn't write to output file: {}", o_file),
+                );
+            }
+        } else {
+            report.add(
+                Severity::Error,
+                format!("couldn't create output file: {}", o_file),
+            );
+        }
     }
 
-    let mut directive_type = DirectiveType::Include;
-    let mut search_mode = SearchMode::Hash;
-    let mut start_index = 0;
-    let mut define_key = "";
-    //	let mut line_index = 1;
+    // write include tree to specified json files (-o-json="tree.json")
+    if let Some(json_files) = command_line.get("o-json") {
+        let json = graph.json_format();
+        for jf in json_files.iter().flatten() {
+            if fs::write(jf, &json).is_err() {
+                report.add(
+                    Severity::Error,
+                    format!("couldn't write to output file: {}", jf),
+                );
+            }
+        }
+    }
 
-    for (cursor, cc) in data.iter().enumerate() {
-        let character = *cc as char;
-        /*
-                if 10 == *cc {
-                    line_index += 1;
-                }
-        */
-        match search_mode {
-            SearchMode::Hash => {
-                if '#' == character {
-                    search_mode = SearchMode::Directive;
-                    start_index = cursor;
-                }
+    // write the includers of each header (-o-includers="includers.txt")
+    if let Some(includers_files) = command_line.get("o-includers") {
+        let text = graph.includers_format();
+        for inf in includers_files.iter().flatten() {
+            if fs::write(inf, &text).is_err() {
+                report.add(
+                    Severity::Error,
+                    format!("couldn't write to output file: {}", inf),
+                );
             }
-            SearchMode::Directive => match character {
-                ' ' | '\t' => {
-                    let directive = std::str::from_utf8(&data[start_index..cursor]).unwrap();
-                    match directive {
-                        "#include" => {
-                            directive_type = DirectiveType::Include;
-                            search_mode = SearchMode::WhiteSpace;
-                        }
-                        "#define" => {
-                            directive_type = DirectiveType::DefineKey;
-                            search_mode = SearchMode::WhiteSpace;
-                        }
-                        _ => {
-                            search_mode = SearchMode::Hash;
-                        }
-                    }
-                }
-                _ => {}
-            },
-            SearchMode::WhiteSpace => {
-                start_index = cursor;
-
-                match character {
-                    ' ' | '\t' => {}
-                    '\r' | '\n' => search_mode = SearchMode::Hash,
-                    _ => match directive_type {
-                        DirectiveType::Include => {
-                            search_mode = match character {
-                                ' ' | '\t' => SearchMode::WhiteSpace,
-                                '"' => SearchMode::Quote,
-                                '<' => SearchMode::Arrow,
-                                _ => SearchMode::Macro,
-                            };
-                        }
-                        DirectiveType::DefineKey => {
-                            search_mode = SearchMode::DefineKey;
-                        }
-                        DirectiveType::DefineValue => {
-                            search_mode = SearchMode::DefineValue;
-                        }
-                    },
-                }
+        }
+    }
+
+    // write the files read from the precompiled header, its dependencies (-o-pch="pch.txt")
+    if let Some(pch_files) = command_line.get("o-pch") {
+        let text: String = graph
+            .precompiled
+            .iter()
+            .map(|p| format!("{}\n", p.display()))
+            .collect();
+        for pf in pch_files.iter().flatten() {
+            if fs::write(pf, &text).is_err() {
+                report.add(
+                    Severity::Error,
+                    format!("couldn't write to output file: {}", pf),
+                );
             }
-            SearchMode::DefineKey => match character {
-                ' ' | '\t' | '\r' | '\n' => {
-                    define_key = std::str::from_utf8(&data[start_index..cursor]).unwrap();
-                    directive_type = DirectiveType::DefineValue;
-                    search_mode = SearchMode::WhiteSpace;
-                }
-                _ => {}
-            },
-            SearchMode::DefineValue => match character {
-                ' ' | '\t' | '\r' | '\n' => {
-                    let define_value = std::str::from_utf8(&data[start_index..cursor]).unwrap();
-                    defines.insert(define_key.to_string(), define_value.to_string());
-                    search_mode = SearchMode::Hash;
-                }
-                _ => {}
-            },
-            SearchMode::Quote => {
-                if '"' == character {
-                    file_add(
-                        base_dir,
-                        std::str::from_utf8(&data[start_index + 1..cursor]).unwrap(),
-                        IncludeSearch::Local,
-                        includes,
-                        &processsed,
-                        &queued,
-                        &mut rp.local,
-                    );
-                    search_mode = SearchMode::Hash
-                }
+        }
+    }
+
+    // write the deepest include chains, for compile time investigations (-o-chains="chains.txt")
+    if let Some(chains_files) = command_line.get("o-chains") {
+        let text = DependencyGraph::chains_format(&graph.chains_deepest(CHAINS_REPORTED));
+        for cf in chains_files.iter().flatten() {
+            if fs::write(cf, &text).is_err() {
+                report.add(
+                    Severity::Error,
+                    format!("couldn't write to output file: {}", cf),
+                );
             }
-            SearchMode::Arrow => {
-                if '>' == character {
-                    file_add(
-                        base_dir,
-                        std::str::from_utf8(&data[start_index + 1..cursor]).unwrap(),
-                        IncludeSearch::System,
-                        includes,
-                        &processsed,
-                        &queued,
-                        &mut rp.system,
-                    );
-                    search_mode = SearchMode::Hash
-                }
+        }
+    }
+
+    // write the includes that look unused, for cleanups (-o-unused="unused.txt")
+    if let Some(unused_files) = command_line.get("o-unused") {
+        let mut text = String::new();
+        for unused in unused_includes(graph).iter() {
+            text.push_str(&format!(
+                "{}: {} looks unused, none of its {} names are referenced\n",
+                unused.include.location(&unused.includer),
+                unused.include.include,
+                unused.name_count
+            ));
+        }
+        for uf in unused_files.iter().flatten() {
+            if fs::write(uf, &text).is_err() {
+                report.add(
+                    Severity::Error,
+                    format!("couldn't write to output file: {}", uf),
+                );
             }
-            SearchMode::Macro => match character {
-                ' ' | '\t' | '\r' | '\n' => {
-                    let macro_key = std::str::from_utf8(&data[start_index..cursor]).unwrap();
-                    if let Some(mv) = defines.get(macro_key) {
-                        if mv.len() > 1 {
-                            let stripped = &mv[1..mv.len() - 1];
-                            match mv.chars().next().unwrap() {
-                                '"' => {
-                                    file_add(
-                                        base_dir,
-                                        &stripped,
-                                        IncludeSearch::Local,
-                                        includes,
-                                        &processsed,
-                                        &queued,
-                                        &mut rp.local,
-                                    );
-                                }
-                                '<' => {
-                                    file_add(
-                                        base_dir,
-                                        &stripped,
-                                        IncludeSearch::System,
-                                        includes,
-                                        &processsed,
-                                        &queued,
-                                        &mut rp.system,
-                                    );
-                                }
-                                _ => {
-                                    println!("malformed filename : {}", mv);
-                                }
-                            }
-                        }
-                    } else {
-                        println!("couldn't find macro: {}", macro_key);
-                    }
-                    search_mode = SearchMode::Hash
-                }
-                _ => {}
-            },
         }
     }
 
-    Ok(())
+    unresolved
+}
+
+//	a connection between a server and a client (--serve, --connect), over a unix
+//	socket, or tcp on the loopback as windows named pipes aren't in std
+
+trait Connection: Read + Write {}
+
+impl<T: Read + Write> Connection for T {}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+// a tcp address (127.0.0.1:7341), only loopback ones as requests write files
+fn address_tcp(address: &str) -> io::Result<Option<SocketAddr>> {
+    match address.parse::<SocketAddr>() {
+        Ok(socket) if !socket.ip().is_loopback() => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only loopback addresses are served",
+        )),
+        Ok(socket) => Ok(Some(socket)),
+        Err(_) => Ok(None),
+    }
+}
+
+impl Listener {
+    // host:port listens on tcp, anything else is the path of a unix socket
+    fn bind(address: &str) -> io::Result<Self> {
+        if let Some(socket) = address_tcp(address)? {
+            return Ok(Listener::Tcp(TcpListener::bind(socket)?));
+        }
+        Listener::bind_socket(address)
+    }
+
+    #[cfg(unix)]
+    fn bind_socket(path: &str) -> io::Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+        // the socket of a server that didn't shut down, never another file
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(Listener::Unix(UnixListener::bind(path)?))
+    }
+
+    #[cfg(not(unix))]
+    fn bind_socket(_path: &str) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "named pipes aren't supported, serve on host:port",
+        ))
+    }
+
+    fn accept(&self) -> io::Result<Box<dyn Connection>> {
+        match self {
+            Listener::Tcp(listener) => Ok(Box::new(listener.accept()?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(Box::new(listener.accept()?.0)),
+        }
+    }
+}
+
+fn connection_open(address: &str) -> io::Result<Box<dyn Connection>> {
+    if let Some(socket) = address_tcp(address)? {
+        return Ok(Box::new(TcpStream::connect(socket)?));
+    }
+    #[cfg(unix)]
+    return Ok(Box::new(UnixStream::connect(address)?));
+    #[cfg(not(unix))]
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "named pipes aren't supported, connect to host:port",
+    ))
 }
 
 //-----------------------------------------------------------------------------
-// parse all command line options into map (options should be in the form of ikey=value)
+// serve a scan request: the working directory of the client and its
+// arguments, a line each, ended by an empty line. Answered with what the
+// run prints and the exit code, exit<tab>code, outputs are written by the server
 //-----------------------------------------------------------------------------
 
-pub fn command_line_parse() -> HashMap<String, Vec<Option<String>>> {
-    let mut hm = HashMap::<String, Vec<Option<String>>>::new();
+fn request_serve(connection: &mut dyn Connection, scanner: &mut Scanner) -> io::Result<()> {
+    let mut lines = Vec::new();
+    for line in BufReader::new(&mut *connection).lines() {
+        let line = line?;
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+    let (cwd, args) = match lines.split_first() {
+        Some((cwd, args)) => (cwd, args.to_vec()),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "empty request")),
+    };
 
-    // first argument is executable name, so we skip this
-    for arg in std::env::args().skip(1) {
-        let sp: Vec<&str> = arg.split('=').collect();
-        if !sp.is_empty() {
-            // trim whitespace and leading hyphen
-            let mut k = sp[0].trim();
-            if k.starts_with('-') {
-                k = &k[1..];
-            }
+    let mut diagnostics = Vec::new();
+    let command_line = command_line_parse(args, &mut diagnostics);
+    let mut report = Report::new(&command_line, diagnostics);
+    let code = if ["watch", "serve", "connect"]
+        .iter()
+        .any(|k| command_line.contains_key(*k))
+    {
+        report.add(
+            Severity::Error,
+            "--watch --serve and --connect aren't served".to_string(),
+        );
+        1
+    } else if let Err(e) = std::env::set_current_dir(cwd) {
+        report.add(
+            Severity::Error,
+            format!("couldn't enter directory {} : {}", cwd, e),
+        );
+        1
+    } else {
+        run(&command_line, &mut report, scanner)
+    };
 
-            let value = if sp.len() > 1 {
-                Some(sp[1].to_string())
-            } else {
-                None
-            };
+    let mut output = Vec::new();
+    report.render_to(&mut output);
+    connection.write_all(&output)?;
+    writeln!(connection, "exit\t{}", code)?;
+    connection.flush()
+}
 
-            hm.entry(k.into()).or_insert_with(Vec::new).push(value);
+//-----------------------------------------------------------------------------
+// serve scan requests one after the other, until killed
+//-----------------------------------------------------------------------------
+
+fn serve(address: &str, report: &mut Report) -> i32 {
+    let listener = match Listener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            report.add(
+                Severity::Error,
+                format!("couldn't serve on {} : {}", address, e),
+            );
+            return 1;
+        }
+    };
+    report.add(Severity::Note, format!("serving on {}", address));
+    report.render();
+
+    // the headers of every request, read once until modified
+    let mut scanner = Scanner::new();
+    loop {
+        let served = listener
+            .accept()
+            .and_then(|mut connection| request_serve(&mut *connection, &mut scanner));
+        if let Err(e) = served {
+            report.add(
+                Severity::Warning,
+                format!("couldn't serve a request : {}", e),
+            );
         }
+        report.render();
     }
-    hm
 }
 
 //-----------------------------------------------------------------------------
-//	main - entry point
+// have a server scan as the command line asks, printing what it answers,
+// returns its exit code. Include directories are expanded with the
+// environment of the client
 //-----------------------------------------------------------------------------
 
-fn main() {
-    println!("Incredible: Include Scanner");
+fn connect(
+    address: &str,
+    command_line: &HashMap<String, Vec<Option<String>>>,
+    report: &mut Report,
+) -> i32 {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let mut request = format!("{}\n", cwd.to_string_lossy());
+    for (key, values) in command_line.iter().filter(|(k, _)| "connect" != *k) {
+        for value in values.iter() {
+            match value {
+                Some(v) if "i" == key => request.push_str(&format!(
+                    "-i={}\n",
+                    env_expand(v, &env_lookup, &mut report.diagnostics)
+                )),
+                Some(v) => request.push_str(&format!("-{}={}\n", key, v)),
+                None => request.push_str(&format!("-{}\n", key)),
+            }
+        }
+    }
+    request.push('\n');
+
+    let answered = connection_open(address).and_then(|mut connection| {
+        connection.write_all(request.as_bytes())?;
+        for line in BufReader::new(connection).lines() {
+            let line = line?;
+            match line.strip_prefix("exit\t") {
+                Some(code) => return Ok(code.parse().unwrap_or(1)),
+                None => println!("{}", line),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the server closed the connection",
+        ))
+    });
+    match answered {
+        Ok(code) => code,
+        Err(e) => {
+            report.add(
+                Severity::Error,
+                format!("couldn't scan in server {} : {}", address, e),
+            );
+            1
+        }
+    }
+}
 
-    // parse command line
-    let command_line = command_line_parse();
+//-----------------------------------------------------------------------------
+// scan as the command line asks, returns the exit code of the run
+//-----------------------------------------------------------------------------
+
+fn run(
+    command_line: &HashMap<String, Vec<Option<String>>>,
+    report: &mut Report,
+    scanner: &mut Scanner,
+) -> i32 {
+    let mut options = ScanOptions::default();
 
     // parse all includes and collect into vector
-    let mut includes = Vec::<PathBuf>::new();
     if let Some(incs) = command_line.get("i") {
         for val in incs.iter() {
             if let Some(v) = val {
-                includes.push(path_sanitise(v));
+                // sdk roots differ between machines (-i=%VULKAN_SDK%/include)
+                let v = env_expand(v, &env_lookup, &mut report.diagnostics);
+                options.include_dirs.push(PathBuf::from(v));
             }
         }
     }
-    dbg!(&includes);
-    let arc_includes = Arc::new(includes);
-
-    // a deque for work jobs, to be consumed by job system
-    let work = Arc::new(Mutex::new(VecDeque::<PathBuf>::new()));
-
-    // markers to ensure each file is only processed once
-    let processed = Arc::new(Mutex::new(HashSet::new()));
+    report.add(
+        Severity::Note,
+        format!("include directories: {:?}", options.include_dirs),
+    );
 
-    // queue all input files for processing
+    // collect all input files for processing
+    let mut inputs = Vec::new();
     if let Some(input_files) = command_line.get("f") {
         for i in input_files {
             if let Some(i_file) = i {
-                let maybe_work = work.lock();
-                if let Ok(mut q) = maybe_work {
-                    q.push_back(Path::new(i_file).to_path_buf());
-                }
+                inputs.push(i_file.clone());
             }
         }
     }
 
     // parse defines
-    let mut defines = HashMap::<String, String>::new();
     for (cl_key, cl_values) in command_line.iter() {
-        if cl_key.starts_with("d") {
+        if cl_key.starts_with('d') && cl_key != "depfile" {
             if let Some(cl_last) = cl_values.last() {
                 if let Some(cl_last_value) = cl_last {
                     let def_key = &cl_key[1..];
-                    defines.insert(def_key.to_string(), cl_last_value.to_string());
+                    options
+                        .defines
+                        .insert(def_key.to_string(), cl_last_value.to_string());
                 }
             }
         }
     }
-    dbg!(&defines);
+    report.add(Severity::Note, format!("defines: {:?}", options.defines));
 
-    // vector to contain all thread handles
-    let mut threads = Vec::new();
+    // includes of earlier scans, for the defines of this one (-cache="incredible.cache")
+    options.cache = command_line
+        .get("cache")
+        .and_then(|c| c.last().cloned().flatten())
+        .map(PathBuf::from);
 
-    // optional single threaded mode, useful for debugging
-    let single_threaded = command_line.contains_key("st");
+    // paths that aren't followed (--exclude="third_party")
+    if let Some(excludes) = command_line.get("exclude") {
+        options.excludes = excludes.iter().flatten().cloned().collect();
+    }
 
-    loop {
-        let f_path = work.lock().unwrap().pop_front();
-        match f_path {
-            Some(f) => {
-                GLOBAL_JOB_COUNT.fetch_add(1, Ordering::SeqCst);
-
-                let processed = processed.clone();
-                let work = work.clone();
-                let includes = arc_includes.clone();
-                let mut rp = ResolvedPaths {
-                    local: ResolvedPathCollection::new(),
-                    system: ResolvedPathCollection::new(),
-                };
-
-                let mut defines2 = defines.clone();
-                if single_threaded {
-                    let _ = file_process(&f, &includes, processed, work, &mut defines2, &mut rp);
-                    GLOBAL_JOB_COUNT.fetch_sub(1, Ordering::SeqCst);
-                } else {
-                    let handle = thread::spawn(move || {
-                        let _ =
-                            file_process(&f, &includes, processed, work, &mut defines2, &mut rp);
-                        GLOBAL_JOB_COUNT.fetch_sub(1, Ordering::SeqCst);
-                    });
-                    threads.push(handle);
+    // headers included before every input (-FI="pch.h"), those of the working directory first
+    for forced in ["FI", "include"]
+        .iter()
+        .filter_map(|k| command_line.get(*k))
+    {
+        for f in forced.iter().flatten() {
+            let path = Path::new(f);
+            options.forced_includes.push(match std::env::current_dir() {
+                Ok(cwd) if path.is_relative() && path.is_file() => {
+                    cwd.join(path).to_string_lossy().to_string()
                 }
+                _ => f.clone(),
+            });
+        }
+    }
+
+    // resolve includes as the compiler does (--search-order=msvc)
+    if let Some(order) = command_line
+        .get("search-order")
+        .and_then(|o| o.last().cloned().flatten())
+    {
+        options.search_order = match order.as_str() {
+            "gcc" => SearchOrder::Gcc,
+            "msvc" => SearchOrder::Msvc,
+            _ => {
+                report.add(
+                    Severity::Error,
+                    format!("unknown search order {}, msvc or gcc", order),
+                );
+                return 1;
             }
-            None => {
-                let jobs = GLOBAL_JOB_COUNT.load(Ordering::SeqCst);
-                if 0 == jobs {
-                    break;
-                } else {
-                    //std::thread::yield_now();
-                }
+        };
+    }
+
+    // files of the precompiled header are read from the pch rather than again (--pch=stdafx.h)
+    options.pch = command_line
+        .get("pch")
+        .and_then(|p| p.last().cloned().flatten());
+
+    // system includes are only recorded, the toolchain provides them (--no-system)
+    options.skip_system = command_line.contains_key("no-system");
+
+    // files nested deeper aren't scanned, against runaway nesting (--max-depth=64)
+    if let Some(depth) = command_line
+        .get("max-depth")
+        .and_then(|d| d.last().cloned().flatten())
+    {
+        match depth.parse::<usize>() {
+            Ok(depth) => options.max_depth = Some(depth),
+            Err(_) => {
+                report.add(Severity::Error, format!("invalid include depth {}", depth));
+                return 1;
             }
         }
     }
 
-    // wait for all threads to complete
-    for handle in threads {
-        handle.join().unwrap();
+    // optional single threaded mode, useful for debugging
+    if command_line.contains_key("st") {
+        options.worker_count = 1;
+    } else if let Some(j) = command_line
+        .get("j")
+        .and_then(|j| j.last().cloned().flatten())
+    {
+        options.worker_count = j.parse::<usize>().unwrap_or(options.worker_count).max(1);
     }
 
-    // create sorted list of includes
-    let mut sorted = Vec::new();
-    let pro = processed.lock();
-    if let Ok(p) = pro {
-        for pi in p.iter() {
-            sorted.push(pi.clone());
-        }
+    // time spent reading, parsing and resolving, per worker (--profile)
+    options.profile = command_line.contains_key("profile");
+
+    // unresolved includes fail the run, for ci to gate on missing headers (--strict)
+    let strict = command_line.contains_key("strict");
+
+    // scan a whole project instead of the inputs (-compdb="compile_commands.json")
+    if let Some(compdb) = command_line
+        .get("compdb")
+        .and_then(|c| c.last().cloned().flatten())
+    {
+        return compdb_scan(
+            &compdb,
+            &options,
+            &PathStyle::new(command_line),
+            strict,
+            report,
+            scanner,
+        );
     }
-    sorted.sort();
 
-    //	dbg!(&sorted);
+    // directories and globs stand for the files they name (-f=src/**/*.cpp --extensions=cpp,h)
+    let extensions: Vec<String> = match command_line.get("extensions") {
+        Some(extensions) => extensions
+            .iter()
+            .flatten()
+            .flat_map(|e| e.split(','))
+            .map(str::to_string)
+            .collect(),
+        None => SOURCE_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+    };
+    let input_paths = match inputs_expand(&inputs, &extensions) {
+        Ok(input_paths) => input_paths,
+        Err(e) => {
+            report.add(Severity::Error, format!("couldn't expand inputs: {}", e));
+            return 1;
+        }
+    };
+    let inputs: Vec<String> = input_paths
+        .iter()
+        .map(|i| i.to_string_lossy().to_string())
+        .collect();
+
+    // the outputs are known before scanning, a bad pairing fails right away
+    let plan = match outputs_plan(command_line, &inputs) {
+        Ok(plan) => plan,
+        Err(e) => {
+            report.add(Severity::Error, e);
+            return 1;
+        }
+    };
 
-    // write dependencies to specified output files (-o="output_file.txt")
-    if let Some(output_files) = command_line.get("o") {
-        for of in output_files {
-            if let Some(o_file) = of {
-                if let Ok(mut f) = File::create(o_file) {
-                    for inc in sorted.iter() {
-                        if !writeln!(f, "{}", inc).is_ok() {
-                            println!("coudln't write to output file: {}", o_file);
-                        }
-                    }
-                } else {
-                    println!("coudln't create output file: {}", o_file);
+    // scan again whenever a scanned file changes, for editor integrations (--watch)
+    if command_line.contains_key("watch") {
+        let mut watcher = Watcher::new(&input_paths, &options);
+        loop {
+            let started = Instant::now();
+            match watcher.scan() {
+                Ok(graph) => {
+                    report.profile_add(&graph.profile, started.elapsed());
+                    outputs_write(
+                        command_line,
+                        &graph,
+                        &inputs,
+                        &plan,
+                        strict,
+                        options.worker_count,
+                        report,
+                    );
                 }
+                Err(e) => report.add(Severity::Error, format!("couldn't scan inputs: {}", e)),
+            }
+            report.render();
+            while !watcher.changed() {
+                thread::sleep(WATCH_INTERVAL);
             }
         }
     }
+
+    // outputs newer than all they list are left as they are, only the inputs of
+    // the others are scanned, for cheap no-op builds (--incremental)
+    let (plan, input_paths) = if command_line.contains_key("incremental") {
+        let stale = outputs_stale(command_line, &plan);
+        report.add(
+            Severity::Note,
+            format!(
+                "{} of {} outputs up to date",
+                plan.len() - stale.len(),
+                plan.len()
+            ),
+        );
+        if stale.is_empty() {
+            return 0;
+        }
+        let stale_inputs: Vec<PathBuf> = input_paths
+            .into_iter()
+            .filter(|i| {
+                let i = i.to_string_lossy();
+                stale
+                    .iter()
+                    .any(|(_, covered)| covered.iter().any(|c| *c == i))
+            })
+            .collect();
+        (stale, stale_inputs)
+    } else {
+        (plan, input_paths)
+    };
+
+    let started = Instant::now();
+    let graph = match scanner.scan(&input_paths, &options) {
+        Ok(graph) => {
+            report.profile_add(&graph.profile, started.elapsed());
+            graph
+        }
+        Err(e) => {
+            report.add(Severity::Error, format!("couldn't scan inputs: {}", e));
+            return 1;
+        }
+    };
+
+    // outputs are written either way, the exit code is for ci
+    if outputs_write(
+        command_line,
+        &graph,
+        &inputs,
+        &plan,
+        strict,
+        options.worker_count,
+        report,
+    ) && strict
+    {
+        2
+    } else {
+        0
+    }
+}
+
+//-----------------------------------------------------------------------------
+//	main - entry point
+//-----------------------------------------------------------------------------
+
+fn main() {
+    // parse command line, the executable name first
+    let mut diagnostics = Vec::new();
+    let command_line = command_line_parse(std::env::args().skip(1).collect(), &mut diagnostics);
+    let mut report = Report::new(&command_line, diagnostics);
+
+    if report.level > Severity::Error {
+        println!("Incredible: Include Scanner");
+    }
+
+    // scans served by a long running process, from the includes of earlier scans (--serve=address)
+    if let Some(address) = command_line
+        .get("serve")
+        .and_then(|s| s.last().cloned().flatten())
+    {
+        let code = serve(&address, &mut report);
+        report.exit(code);
+    }
+
+    // scan in a server instead, which writes the outputs (--connect=address)
+    if let Some(address) = command_line
+        .get("connect")
+        .and_then(|c| c.last().cloned().flatten())
+    {
+        // the diagnostics file is the server's to write
+        report.json_files.clear();
+        let code = connect(&address, &command_line, &mut report);
+        report.exit(code);
+    }
+
+    let code = run(&command_line, &mut report, &mut Scanner::new());
+    report.exit(code);
 }
 
 //-----------------------------------------------------------------------------
@@ -592,23 +1510,121 @@ mod test_incredible {
     use super::*;
 
     #[test]
-    fn test_absolute_path() {
-        let a = path_absolute(Path::new("C:\test"));
-        assert_eq!(Path::new("C:\test"), a);
-
-        let a = path_absolute(Path::new("test"));
-        assert_eq!(Path::new("test"), a);
-
-        let a = path_absolute(Path::new("boo/bar"));
-        assert_eq!(Path::new("boo/bar"), a);
+    fn test_response_file_parse() {
+        assert_eq!(
+            vec!["-f=a.cpp", "-i=c:\\program files\\sdk", "-dX=1", ""],
+            response_file_parse("-f=a.cpp\r\n  -i=\"c:\\program files\\sdk\"\n-dX=1 \"\"\n")
+        );
+        assert!(response_file_parse(" \n\t").is_empty());
+
+        let dir = std::env::temp_dir().join("incredible_test_response_file");
+        fs::create_dir_all(&dir).unwrap();
+        let outer = dir.join("outer.rsp");
+        let inner = dir.join("inner.rsp");
+        fs::write(&outer, format!("-f=a.cpp @{}\n-st", inner.display())).unwrap();
+        fs::write(&inner, format!("-i=inc @{}", inner.display())).unwrap();
+
+        // the inner file refers to itself until the depth limit
+        let mut diagnostics = Vec::new();
+        let args = args_expand(
+            vec![format!("@{}", outer.display()), "-o=out.txt".into()],
+            0,
+            &mut diagnostics,
+        );
+        assert_eq!("-f=a.cpp", args[0]);
+        assert_eq!(
+            RESPONSE_FILE_DEPTH_MAX - 1,
+            args.iter().filter(|a| *a == "-i=inc").count()
+        );
+        assert_eq!(vec!["-st", "-o=out.txt"], args[args.len() - 2..].to_vec());
+
+        assert_eq!(1, diagnostics.len());
+        assert!(args_expand(vec!["@missing.rsp".into()], 0, &mut diagnostics).is_empty());
+        assert_eq!(
+            "warning: couldn't read response file: missing.rsp",
+            diagnostics[1].to_string()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-        let a = path_absolute(Path::new(r#"go\fish"#));
-        assert_eq!(Path::new(r#"go\fish"#), a);
+    #[test]
+    fn test_env_expand() {
+        let lookup = |name: &str| match name {
+            "VULKAN_SDK" => Some("c:\\vulkan".to_string()),
+            "ProgramFiles(x86)" => Some("c:\\program files (x86)".to_string()),
+            _ => None,
+        };
+        let mut diagnostics = Vec::new();
+        let mut expand = |text: &str| env_expand(text, &lookup, &mut diagnostics);
+
+        assert_eq!("c:\\vulkan/include", expand("%VULKAN_SDK%/include"));
+        assert_eq!("c:\\vulkan/include", expand("$VULKAN_SDK/include"));
+        assert_eq!("c:\\vulkanx", expand("${VULKAN_SDK}x"));
+        assert_eq!(
+            "c:\\program files (x86)\\sdk",
+            expand("%ProgramFiles(x86)%\\sdk")
+        );
+        assert_eq!("100% sure, $5 or 50%", expand("100% sure, $5 or 50%"));
+        assert_eq!("$", expand("$"));
+        assert_eq!("%MISSING%/$MISSING", expand("%MISSING%/$MISSING"));
+        assert_eq!(2, diagnostics.len());
+        assert_eq!(
+            "warning: environment variable not set: MISSING",
+            diagnostics[0].to_string()
+        );
+    }
 
-        let a = path_absolute(Path::new(r#"first\second\.."#));
-        assert_eq!(Path::new(r#"first"#), a);
+    #[test]
+    fn test_depfile_format() {
+        assert_eq!("a.o:\n", depfile_format("a.o", &[]));
+
+        let deps = vec!["src/a.cpp".to_string(), "inc/b.h".to_string()];
+        assert_eq!(
+            "a.o: \\\n  src/a.cpp \\\n  inc/b.h\n",
+            depfile_format("a.o", &deps)
+        );
+
+        assert_eq!(r#"my\ dir/a.h"#, depfile_escape("my dir/a.h"));
+        assert_eq!(r#"a\\\ b"#, depfile_escape(r#"a\ b"#));
+        assert_eq!(r#"\#a$$b"#, depfile_escape("#a$b"));
+        assert_eq!(r#"c:\dir\a.h"#, depfile_escape(r#"c:\dir\a.h"#));
+
+        let deps: Vec<String> = vec!["c:\\src\\a.cpp", "my dir/a.h", r#"a\ b"#, "#a$b"]
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        assert_eq!(deps, depfile_parse(&depfile_format("c:\\out\\a.o", &deps)));
+        assert_eq!(vec!["b.h", "c.h"], depfile_parse("a.o: b.h \\\r\n c.h\r\n"));
+    }
 
-        let a = path_absolute(Path::new(r#"first\second\..\third"#));
-        assert_eq!(Path::new(r#"first\third"#), a);
+    #[test]
+    fn test_outputs_stale() {
+        let dir = std::env::temp_dir().join("incredible_test_outputs_stale");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        for name in ["a.cpp", "b.cpp", "a.h"].iter() {
+            fs::write(path(name), "").unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+        fs::write(path("a.txt"), format!("{}\n", path("a.h"))).unwrap();
+        fs::write(path("b.txt"), "").unwrap();
+
+        let mut command_line: HashMap<String, Vec<Option<String>>> = HashMap::new();
+        let plan = vec![
+            (path("a.txt"), vec![path("a.cpp")]),
+            (path("b.txt"), vec![path("b.cpp")]),
+        ];
+        assert!(outputs_stale(&command_line, &plan).is_empty());
+
+        // only the output listing the modified header
+        thread::sleep(Duration::from_millis(50));
+        fs::write(path("a.h"), "#pragma once\n").unwrap();
+        assert_eq!(plan[..1].to_vec(), outputs_stale(&command_line, &plan));
+
+        // every output for a missing file of the whole graph
+        command_line.insert("o-json".into(), vec![Some(path("tree.json"))]);
+        assert_eq!(plan, outputs_stale(&command_line, &plan));
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,162 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Work queue
+//
+//	Files waiting to be scanned by a fixed number of workers. Idle workers
+//	sleep on a condition variable until a file is queued, the scan is done
+//	once the queue is empty and no worker is scanning a file that could
+//	still queue more
+//-----------------------------------------------------------------------------
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+// workers when not set on the command line (-j=N)
+
+pub const WORKER_COUNT_DEFAULT: usize = 8;
+
+struct WorkState {
+    files: VecDeque<PathBuf>,
+    // files popped by workers and not done yet
+    active: usize,
+}
+
+pub struct WorkQueue {
+    state: Mutex<WorkState>,
+    changed: Condvar,
+}
+
+impl WorkQueue {
+    pub fn new() -> Self {
+        WorkQueue {
+            state: Mutex::new(WorkState {
+                files: VecDeque::new(),
+                active: 0,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn push(&self, path: PathBuf) {
+        if let Ok(mut state) = self.state.lock() {
+            state.files.push_back(path);
+            self.changed.notify_one();
+        }
+    }
+
+    // wait for the next file to scan, none once the scan is done
+    // every file popped must be followed by a call to done
+    pub fn pop(&self) -> Option<PathBuf> {
+        let mut state = self.state.lock().ok()?;
+        loop {
+            if let Some(path) = state.files.pop_front() {
+                state.active += 1;
+                return Some(path);
+            }
+            if 0 == state.active {
+                return None;
+            }
+            state = self.changed.wait(state).ok()?;
+        }
+    }
+
+    // a popped file was scanned, wakes the waiting workers when it was the last one
+    pub fn done(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.active -= 1;
+            if 0 == state.active && state.files.is_empty() {
+                self.changed.notify_all();
+            }
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+// run worker on worker_count threads until the queue is done, worker is given
+// its index and each file, and returns when it is scanned
+//-----------------------------------------------------------------------------
+
+pub fn pool_run<F>(queue: &Arc<WorkQueue>, worker_count: usize, worker: F)
+where
+    F: Fn(usize, PathBuf) + Clone + Send + 'static,
+{
+    let work = |index: usize, queue: &WorkQueue, worker: &F| {
+        while let Some(path) = queue.pop() {
+            worker(index, path);
+            queue.done();
+        }
+    };
+
+    // single threaded mode scans on the calling thread, useful for debugging
+    if worker_count <= 1 {
+        work(0, queue, &worker);
+        return;
+    }
+
+    let threads: Vec<_> = (0..worker_count)
+        .map(|index| {
+            let queue = queue.clone();
+            let worker = worker.clone();
+            thread::spawn(move || work(index, &queue, &worker))
+        })
+        .collect();
+
+    // wait for all threads to complete
+    for handle in threads {
+        handle.join().unwrap();
+    }
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_pool {
+
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_pool_run() {
+        for worker_count in &[1, 4] {
+            // each file n queues the files 2n+1 and 2n+2 below 100, a tree of all of them
+            let queue = Arc::new(WorkQueue::new());
+            queue.push(PathBuf::from("0"));
+            let scanned = Arc::new(Mutex::new(Vec::new()));
+
+            let worker_queue = queue.clone();
+            let worker_scanned = scanned.clone();
+            pool_run(&queue, *worker_count, move |index, path| {
+                assert!(index < *worker_count);
+                let n: usize = path.to_str().unwrap().parse().unwrap();
+                for child in &[2 * n + 1, 2 * n + 2] {
+                    if *child < 100 {
+                        worker_queue.push(PathBuf::from(child.to_string()));
+                    }
+                }
+                worker_scanned.lock().unwrap().push(n);
+            });
+
+            let mut scanned = scanned.lock().unwrap().clone();
+            scanned.sort();
+            assert_eq!((0..100).collect::<Vec<_>>(), scanned);
+            assert_eq!(None, queue.pop());
+        }
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Profile
+//
+//	Where the time of a scan goes, per worker (--profile), to weigh changes to
+//	the parser against the rest of the scan:
+//
+//	io		reading files and their modification times
+//	parse		decoding, blanking comments and literals, finding directives
+//	resolve		searching the include directories for includes
+//
+//	time spent waiting for files to scan isn't counted, the wall time of the
+//	scan is the time the busiest worker took and then some
+//-----------------------------------------------------------------------------
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WorkerProfile {
+    pub files: usize,
+    pub io: Duration,
+    pub parse: Duration,
+    pub resolve: Duration,
+}
+
+fn milliseconds(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
+
+impl WorkerProfile {
+    pub fn add(&mut self, other: &WorkerProfile) {
+        self.files += other.files;
+        self.io += other.io;
+        self.parse += other.parse;
+        self.resolve += other.resolve;
+    }
+
+    //-----------------------------------------------------------------------------
+    // the profiles of the workers as a table, with their total and the wall time
+    //-----------------------------------------------------------------------------
+
+    pub fn format(profiles: &[WorkerProfile], wall: Duration) -> String {
+        let row = |name: &str, p: &WorkerProfile| {
+            format!(
+                "{:<8}{:>8}{:>12}{:>12}{:>12}\n",
+                name,
+                p.files,
+                milliseconds(p.io),
+                milliseconds(p.parse),
+                milliseconds(p.resolve)
+            )
+        };
+        let mut text = format!(
+            "{:<8}{:>8}{:>12}{:>12}{:>12}\n",
+            "worker", "files", "io", "parse", "resolve"
+        );
+        let mut total = WorkerProfile::default();
+        for (worker, profile) in profiles.iter().enumerate() {
+            text.push_str(&row(&worker.to_string(), profile));
+            total.add(profile);
+        }
+        text.push_str(&row("total", &total));
+        text.push_str(&format!("wall {}\n", milliseconds(wall)));
+        text
+    }
+}
+
+//	profiles of all workers, indexed by worker
+
+#[derive(Clone)]
+pub(crate) struct Profiler {
+    workers: Arc<Mutex<Vec<WorkerProfile>>>,
+}
+
+impl Profiler {
+    pub(crate) fn new() -> Self {
+        Profiler {
+            workers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub(crate) fn add(&self, worker: usize, profile: &WorkerProfile) {
+        if let Ok(mut workers) = self.workers.lock() {
+            if workers.len() <= worker {
+                workers.resize(worker + 1, WorkerProfile::default());
+            }
+            workers[worker].add(profile);
+        }
+    }
+
+    pub(crate) fn workers(&self) -> Vec<WorkerProfile> {
+        match self.workers.lock() {
+            Ok(workers) => workers.clone(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_profile {
+
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_profile_format() {
+        let profiler = Profiler::new();
+        let file = WorkerProfile {
+            files: 1,
+            io: Duration::from_micros(250),
+            parse: Duration::from_millis(2),
+            resolve: Duration::from_micros(50),
+        };
+        profiler.add(1, &file);
+        profiler.add(1, &file);
+        profiler.add(0, &file);
+
+        let workers = profiler.workers();
+        assert_eq!(2, workers.len());
+        assert_eq!(2, workers[1].files);
+        assert_eq!(Duration::from_millis(4), workers[1].parse);
+        assert_eq!(
+            "worker     files          io       parse     resolve\n\
+             0              1       0.2ms       2.0ms       0.1ms\n\
+             1              2       0.5ms       4.0ms       0.1ms\n\
+             total          3       0.8ms       6.0ms       0.1ms\n\
+             wall 5.0ms\n",
+            WorkerProfile::format(&workers, Duration::from_millis(5))
+        );
+    }
+}
@@ -0,0 +1,710 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Include tree
+//
+//	Records who includes whom while scanning, returned in the dependency graph
+//	of a scan and written by -o-json as:
+//
+//	{
+//	  "inputs": ["/src/main.cpp"],
+//	  "files": {
+//	    "/src/main.cpp": [
+//	      {"include": "a.h", "kind": "local", "line": 1, "path": "/src/a.h"},
+//	      {"include": "missing.h", "kind": "system", "line": 2, "path": null}
+//	    ],
+//	    "/src/a.h": []
+//	  }
+//	}
+//
+//	every scanned file is listed with its includes in file order, unresolved
+//	includes have a null path. Includes after a #line naming a file have its
+//	"file", their line is the one #line sets
+//-----------------------------------------------------------------------------
+
+use crate::{path_absolute, path_normalise, Diagnostic, IncludeSearch, Severity, WorkerProfile};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+//	an include of a scanned file
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Include {
+    // as written, without quotes or angle brackets
+    pub include: String,
+    pub search_type: IncludeSearch,
+    // first line including it, 0 for forced includes
+    pub line: usize,
+    // file named by a #line directive before the include, diagnostics are for
+    // it rather than the includer, and line is its line
+    pub file: Option<String>,
+    // none when it couldn't be resolved
+    pub path: Option<PathBuf>,
+}
+
+impl Include {
+    // file:line of the include as compilers report it, in the file of a #line
+    pub fn location(&self, includer: &Path) -> String {
+        match &self.file {
+            Some(file) => format!("{}:{}", file, self.line),
+            None => format!("{}:{}", includer.display(), self.line),
+        }
+    }
+}
+
+//	result of a scan
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DependencyGraph {
+    pub inputs: Vec<PathBuf>,
+    // every scanned file with its includes, in file order
+    pub files: BTreeMap<PathBuf, Vec<Include>>,
+    // every file the inputs include, directly or not, sorted
+    pub dependencies: Vec<PathBuf>,
+    // the precompiled header and the files it includes, read from the pch rather
+    // than again, sorted and not among the dependencies (ScanOptions::pch)
+    pub precompiled: Vec<PathBuf>,
+    // unknown macros, conditions that couldn't be evaluated and files that couldn't be read
+    pub diagnostics: Vec<Diagnostic>,
+    // time each worker spent reading, parsing and resolving, when profiled
+    pub profile: Vec<WorkerProfile>,
+}
+
+#[derive(Clone)]
+pub struct IncludeTree {
+    files: Arc<Mutex<HashMap<PathBuf, Vec<Include>>>>,
+}
+
+//-----------------------------------------------------------------------------
+// quote and escape a json string
+//-----------------------------------------------------------------------------
+
+pub(crate) fn json_string(s: &str) -> String {
+    // a string always serializes
+    serde_json::to_string(s).unwrap_or_default()
+}
+
+//-----------------------------------------------------------------------------
+// absolute form of a scanned path, relative ones are relative to the working directory
+//-----------------------------------------------------------------------------
+
+fn path_full(path: &Path) -> String {
+    let full = match std::env::current_dir() {
+        Ok(cwd) => path_absolute(&cwd.join(path)),
+        Err(_) => path.to_path_buf(),
+    };
+    full.to_string_lossy().into_owned()
+}
+
+impl IncludeTree {
+    pub fn new() -> Self {
+        IncludeTree {
+            files: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // add a scanned file, before its includes
+    pub fn file_add(&self, path: &Path) {
+        if let Ok(mut files) = self.files.lock() {
+            files.entry(path.to_path_buf()).or_insert_with(Vec::new);
+        }
+    }
+
+    // add an include of a scanned file, the same include twice is recorded once
+    pub fn edge_add(
+        &self,
+        includer: &Path,
+        include: &str,
+        search_type: IncludeSearch,
+        line: usize,
+        file: Option<&str>,
+        path: Option<PathBuf>,
+    ) {
+        if let Ok(mut files) = self.files.lock() {
+            let edges = files.entry(includer.to_path_buf()).or_insert_with(Vec::new);
+            if !edges
+                .iter()
+                .any(|e| e.include == include && e.search_type == search_type)
+            {
+                edges.push(Include {
+                    include: include.to_string(),
+                    search_type,
+                    line,
+                    file: file.map(str::to_string),
+                    path,
+                });
+            }
+        }
+    }
+
+    // the scanned files, sorted by path
+    pub fn files(&self) -> BTreeMap<PathBuf, Vec<Include>> {
+        match self.files.lock() {
+            Ok(files) => files.iter().map(|(p, e)| (p.clone(), e.clone())).collect(),
+            Err(_) => BTreeMap::new(),
+        }
+    }
+}
+
+impl DependencyGraph {
+    // includes that couldn't be resolved with the file including them, by file and line
+    pub fn unresolved(&self) -> Vec<(&Path, &Include)> {
+        let mut unresolved: Vec<(&Path, &Include)> = self
+            .files
+            .iter()
+            .flat_map(|(path, edges)| edges.iter().map(move |e| (path.as_path(), e)))
+            .filter(|(_, e)| e.path.is_none())
+            .collect();
+        unresolved.sort_by_key(|(path, e)| (*path, e.line));
+        unresolved
+    }
+
+    //-----------------------------------------------------------------------------
+    // the files including each header, directly or through other headers
+    //-----------------------------------------------------------------------------
+
+    pub fn includers(&self) -> BTreeMap<PathBuf, BTreeSet<PathBuf>> {
+        let mut direct: HashMap<&Path, Vec<&Path>> = HashMap::new();
+        for (path, edges) in self.files.iter() {
+            for path_included in edges.iter().filter_map(|e| e.path.as_ref()) {
+                direct
+                    .entry(path_included.as_path())
+                    .or_default()
+                    .push(path.as_path());
+            }
+        }
+
+        let mut includers = BTreeMap::new();
+        for header in direct.keys() {
+            let mut found = BTreeSet::new();
+            let mut pending = vec![*header];
+            while let Some(p) = pending.pop() {
+                for includer in direct.get(p).into_iter().flatten() {
+                    if found.insert(includer.to_path_buf()) {
+                        pending.push(includer);
+                    }
+                }
+            }
+            // guarded headers including each other aren't their own includers
+            found.remove(*header);
+            includers.insert(header.to_path_buf(), found);
+        }
+        includers
+    }
+
+    //-----------------------------------------------------------------------------
+    // the graph with every path normalised for outputs, relative to root when below it
+    //-----------------------------------------------------------------------------
+
+    pub fn paths_normalise(&self, root: Option<&Path>) -> DependencyGraph {
+        let normalise = |path: &PathBuf| path_normalise(path, root);
+        let mut dependencies: Vec<PathBuf> = self.dependencies.iter().map(normalise).collect();
+        dependencies.sort();
+        dependencies.dedup();
+
+        DependencyGraph {
+            inputs: self.inputs.iter().map(normalise).collect(),
+            files: self
+                .files
+                .iter()
+                .map(|(path, includes)| {
+                    let includes = includes
+                        .iter()
+                        .map(|i| Include {
+                            path: i.path.as_ref().map(normalise),
+                            ..i.clone()
+                        })
+                        .collect();
+                    (normalise(path), includes)
+                })
+                .collect(),
+            dependencies,
+            precompiled: self.precompiled.iter().map(normalise).collect(),
+            diagnostics: self.diagnostics.clone(),
+            profile: self.profile.clone(),
+        }
+    }
+
+    //-----------------------------------------------------------------------------
+    // the graph of inputs compiled with a precompiled header, named as it is
+    // included (stdafx.h). The header and the files it includes move from the
+    // dependencies to the precompiled files, the compiler reads them from the
+    // pch. Every input is taken to be compiled with it, as /Yu applies to all
+    //-----------------------------------------------------------------------------
+
+    pub fn precompiled_split(&self, pch: &str) -> DependencyGraph {
+        let name = pch.replace('\\', "/");
+        let headers: BTreeSet<PathBuf> = self
+            .files
+            .values()
+            .flatten()
+            .filter(|i| i.include.replace('\\', "/") == name)
+            .filter_map(|i| i.path.clone())
+            .collect();
+
+        let mut split = self.clone();
+        if headers.is_empty() {
+            split.diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                format!("precompiled header {} isn't included", pch),
+            ));
+            split.diagnostics.sort();
+            return split;
+        }
+
+        let mut closure = headers.clone();
+        for header in headers.iter() {
+            closure.extend(self.dependencies_of(header));
+        }
+        let (precompiled, dependencies) = self
+            .dependencies
+            .iter()
+            .cloned()
+            .partition(|d| closure.contains(d));
+        split.precompiled = precompiled;
+        split.dependencies = dependencies;
+        split
+    }
+
+    //-----------------------------------------------------------------------------
+    // the dependencies of a single input, the files it includes directly or not,
+    // sorted. Files are scanned with the same defines whoever includes them, so
+    // their includes are the same for every input
+    //-----------------------------------------------------------------------------
+
+    pub fn dependencies_of(&self, input: &Path) -> Vec<PathBuf> {
+        let dependencies: HashSet<&Path> = self.dependencies.iter().map(PathBuf::as_path).collect();
+        let mut found = BTreeSet::new();
+        let mut pending = vec![input];
+        while let Some(p) = pending.pop() {
+            for included in self.files.get(p).into_iter().flatten() {
+                if let Some(path) = &included.path {
+                    if dependencies.contains(path.as_path()) && found.insert(path.clone()) {
+                        pending.push(path);
+                    }
+                }
+            }
+        }
+        found.remove(input);
+        found.into_iter().collect()
+    }
+
+    //-----------------------------------------------------------------------------
+    // the include chains of the most deeply nested files, deepest first, each
+    // from an input with the line including the next file (0 for the last).
+    // Files are entered where a compiler first includes them, depth first in
+    // file order, each once as include guards would have it
+    //-----------------------------------------------------------------------------
+
+    pub fn chains_deepest(&self, count: usize) -> Vec<Vec<(PathBuf, usize)>> {
+        let mut chains = Vec::new();
+        for input in self.inputs.iter() {
+            let mut entered: HashSet<&Path> = HashSet::new();
+            entered.insert(input);
+            // files being included, with the index of their next include
+            let mut stack: Vec<(&Path, usize)> = vec![(input, 0)];
+            // the file on top of the stack entered no other
+            let mut innermost = true;
+
+            while let Some(&(file, index)) = stack.last() {
+                let includes = self.files.get(file).map(Vec::as_slice).unwrap_or(&[]);
+                if index < includes.len() {
+                    stack.last_mut().unwrap().1 += 1;
+                    if let Some(path) = &includes[index].path {
+                        if entered.insert(path) {
+                            stack.push((path, 0));
+                            innermost = true;
+                        }
+                    }
+                    continue;
+                }
+                if innermost {
+                    // the includer of each file is past the include it entered
+                    let last = stack.len() - 1;
+                    let chain: Vec<(PathBuf, usize)> = stack
+                        .iter()
+                        .enumerate()
+                        .map(|(depth, &(f, i))| match depth {
+                            d if d < last => (f.to_path_buf(), self.files[f][i - 1].line),
+                            _ => (f.to_path_buf(), 0),
+                        })
+                        .collect();
+                    chains.push(chain);
+                }
+                innermost = false;
+                stack.pop();
+            }
+        }
+
+        chains.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        chains.truncate(count);
+        chains
+    }
+
+    //-----------------------------------------------------------------------------
+    // format include chains innermost file first, as compilers report them
+    //-----------------------------------------------------------------------------
+
+    pub fn chains_format(chains: &[Vec<(PathBuf, usize)>]) -> String {
+        let mut text = String::new();
+        for chain in chains.iter() {
+            let mut files = chain.iter().rev();
+            if let Some((innermost, _)) = files.next() {
+                text.push_str(&format!(
+                    "depth {}: {}
+",
+                    chain.len() - 1,
+                    innermost.to_string_lossy()
+                ));
+            }
+            for (includer, line) in files {
+                text.push_str(&format!(
+                    "  included from {}:{}\n",
+                    includer.to_string_lossy(),
+                    line
+                ));
+            }
+        }
+        text
+    }
+
+    //-----------------------------------------------------------------------------
+    // format the includers of each header, a header per line followed by its
+    // includers indented
+    //-----------------------------------------------------------------------------
+
+    pub fn includers_format(&self) -> String {
+        let mut text = String::new();
+        for (header, includers) in self.includers().iter() {
+            text.push_str(&format!("{}:\n", header.to_string_lossy()));
+            for includer in includers.iter() {
+                text.push_str(&format!("  {}\n", includer.to_string_lossy()));
+            }
+        }
+        text
+    }
+
+    //-----------------------------------------------------------------------------
+    // format the graph as json, files sorted by absolute path
+    //-----------------------------------------------------------------------------
+
+    pub fn json_format(&self) -> String {
+        let mut sorted = BTreeMap::new();
+        for (path, edges) in self.files.iter() {
+            let edges: Vec<String> = edges
+                .iter()
+                .map(|e| {
+                    format!(
+                        "{{\"include\": {}, \"kind\": \"{}\", \"line\": {}{}, \"path\": {}}}",
+                        json_string(&e.include),
+                        match e.search_type {
+                            IncludeSearch::Local => "local",
+                            IncludeSearch::System => "system",
+                        },
+                        e.line,
+                        match &e.file {
+                            Some(f) => format!(", \"file\": {}", json_string(f)),
+                            None => String::new(),
+                        },
+                        match &e.path {
+                            Some(p) => json_string(&path_full(p)),
+                            None => "null".to_string(),
+                        }
+                    )
+                })
+                .collect();
+            sorted.insert(path_full(path), edges);
+        }
+
+        let inputs: Vec<String> = self
+            .inputs
+            .iter()
+            .map(|i| format!("    {}", json_string(&path_full(i))))
+            .collect();
+        let files: Vec<String> = sorted
+            .iter()
+            .map(|(path, edges)| {
+                if edges.is_empty() {
+                    format!("    {}: []", json_string(path))
+                } else {
+                    format!(
+                        "    {}: [\n      {}\n    ]",
+                        json_string(path),
+                        edges.join(",\n      ")
+                    )
+                }
+            })
+            .collect();
+
+        format!(
+            "{{\n  \"inputs\": [\n{}\n  ],\n  \"files\": {{\n{}\n  }}\n}}\n",
+            inputs.join(",\n"),
+            files.join(",\n")
+        )
+    }
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_tree {
+
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_json_format() {
+        assert_eq!(r#""a \"b\" c\\d\n""#, json_string("a \"b\" c\\d\n"));
+
+        let root = Path::new("/src");
+        let tree = IncludeTree::new();
+        tree.file_add(&root.join("main.cpp"));
+        tree.edge_add(
+            &root.join("main.cpp"),
+            "a.h",
+            IncludeSearch::Local,
+            1,
+            None,
+            Some(root.join("inc/../a.h")),
+        );
+        tree.edge_add(
+            &root.join("main.cpp"),
+            "missing.h",
+            IncludeSearch::System,
+            2,
+            Some("gen.y"),
+            None,
+        );
+        tree.edge_add(
+            &root.join("main.cpp"),
+            "a.h",
+            IncludeSearch::Local,
+            3,
+            None,
+            Some(root.join("a.h")),
+        );
+        tree.file_add(&root.join("a.h"));
+
+        let expected = r#"{
+  "inputs": [
+    "/src/main.cpp"
+  ],
+  "files": {
+    "/src/a.h": [],
+    "/src/main.cpp": [
+      {"include": "a.h", "kind": "local", "line": 1, "path": "/src/a.h"},
+      {"include": "missing.h", "kind": "system", "line": 2, "file": "gen.y", "path": null}
+    ]
+  }
+}
+"#;
+        let graph = DependencyGraph {
+            inputs: vec![root.join("main.cpp")],
+            files: tree.files(),
+            ..Default::default()
+        };
+        assert_eq!(expected, graph.json_format());
+    }
+    #[test]
+    fn test_includers() {
+        let root = Path::new("/src");
+        let tree = IncludeTree::new();
+        let include = |includer: &str, header: &str| {
+            tree.edge_add(
+                &root.join(includer),
+                header,
+                IncludeSearch::Local,
+                1,
+                None,
+                Some(root.join(header)),
+            )
+        };
+        include("main.cpp", "a.h");
+        include("other.cpp", "b.h");
+        include("a.h", "b.h");
+        include("b.h", "c.h");
+        include("c.h", "a.h");
+        tree.edge_add(
+            &root.join("main.cpp"),
+            "missing.h",
+            IncludeSearch::System,
+            2,
+            None,
+            None,
+        );
+
+        let graph = DependencyGraph {
+            files: tree.files(),
+            dependencies: ["a.h", "b.h", "c.h"].iter().map(|n| root.join(n)).collect(),
+            ..Default::default()
+        };
+        let includers = graph.includers();
+        assert_eq!(3, includers.len());
+        let paths = |names: &[&str]| names.iter().map(|n| root.join(n)).collect::<BTreeSet<_>>();
+        assert_eq!(
+            paths(&["b.h", "c.h", "main.cpp", "other.cpp"]),
+            includers[&root.join("a.h")]
+        );
+        assert_eq!(
+            paths(&["a.h", "c.h", "main.cpp", "other.cpp"]),
+            includers[&root.join("b.h")]
+        );
+
+        let dependencies_of = |graph: &DependencyGraph, input: &str| -> BTreeSet<PathBuf> {
+            graph
+                .dependencies_of(&root.join(input))
+                .into_iter()
+                .collect()
+        };
+        assert_eq!(
+            paths(&["a.h", "b.h", "c.h"]),
+            dependencies_of(&graph, "main.cpp")
+        );
+        assert_eq!(paths(&["b.h", "c.h"]), dependencies_of(&graph, "a.h"));
+        // excluded headers aren't dependencies, nor followed
+        let mut excluded = graph.clone();
+        excluded.dependencies.retain(|d| d.ends_with("a.h"));
+        assert_eq!(paths(&["a.h"]), dependencies_of(&excluded, "main.cpp"));
+
+        let normalised = graph.paths_normalise(Some(root));
+        assert_eq!(
+            vec![Path::new("a.h"), Path::new("b.h"), Path::new("c.h")],
+            normalised.dependencies
+        );
+        assert_eq!(
+            Some(PathBuf::from("b.h")),
+            normalised.files[Path::new("a.h")][0].path
+        );
+
+        let graph = DependencyGraph {
+            files: {
+                let tree = IncludeTree::new();
+                tree.edge_add(
+                    &root.join("main.cpp"),
+                    "a.h",
+                    IncludeSearch::Local,
+                    1,
+                    None,
+                    Some(root.join("a.h")),
+                );
+                tree.files()
+            },
+            ..Default::default()
+        };
+        assert_eq!("/src/a.h:\n  /src/main.cpp\n", graph.includers_format());
+    }
+
+    #[test]
+    fn test_chains_deepest() {
+        let root = Path::new("/src");
+        let tree = IncludeTree::new();
+        let include = |includer: &str, header: &str, line: usize| {
+            tree.edge_add(
+                &root.join(includer),
+                header,
+                IncludeSearch::Local,
+                line,
+                None,
+                Some(root.join(header)),
+            )
+        };
+        // a.h is entered from main.cpp before c.h reaches it
+        include("main.cpp", "a.h", 1);
+        include("main.cpp", "c.h", 2);
+        include("a.h", "b.h", 3);
+        include("b.h", "a.h", 1);
+        include("c.h", "a.h", 5);
+        include("c.h", "d.h", 6);
+        tree.file_add(&root.join("b.h"));
+        tree.file_add(&root.join("d.h"));
+
+        let graph = DependencyGraph {
+            inputs: vec![root.join("main.cpp")],
+            files: tree.files(),
+            ..Default::default()
+        };
+        let chain = |files: &[(&str, usize)]| -> Vec<(PathBuf, usize)> {
+            files.iter().map(|(f, l)| (root.join(f), *l)).collect()
+        };
+        assert_eq!(
+            vec![
+                chain(&[("main.cpp", 1), ("a.h", 3), ("b.h", 0)]),
+                chain(&[("main.cpp", 2), ("c.h", 6), ("d.h", 0)]),
+            ],
+            graph.chains_deepest(10)
+        );
+        assert_eq!(1, graph.chains_deepest(1).len());
+
+        let text = DependencyGraph::chains_format(&graph.chains_deepest(1));
+        assert_eq!(
+            format!(
+                "depth 2: {}\n  included from {}:3\n  included from {}:1\n",
+                root.join("b.h").display(),
+                root.join("a.h").display(),
+                root.join("main.cpp").display()
+            ),
+            text
+        );
+    }
+
+    #[test]
+    fn test_precompiled_split() {
+        let root = Path::new("/src");
+        let tree = IncludeTree::new();
+        let include = |includer: &str, line: usize, header: &str| {
+            tree.edge_add(
+                &root.join(includer),
+                header,
+                IncludeSearch::Local,
+                line,
+                None,
+                Some(root.join(header)),
+            )
+        };
+        include("main.cpp", 1, "stdafx.h");
+        include("main.cpp", 2, "a.h");
+        include("stdafx.h", 1, "windows.h");
+        include("a.h", 1, "windows.h");
+        include("a.h", 2, "b.h");
+
+        let paths =
+            |names: &[&str]| -> Vec<PathBuf> { names.iter().map(|n| root.join(n)).collect() };
+        let graph = DependencyGraph {
+            inputs: paths(&["main.cpp"]),
+            files: tree.files(),
+            dependencies: paths(&["a.h", "b.h", "stdafx.h", "windows.h"]),
+            ..Default::default()
+        };
+
+        // headers the pch includes aren't read again, whoever else includes them
+        let split = graph.precompiled_split("stdafx.h");
+        assert_eq!(paths(&["a.h", "b.h"]), split.dependencies);
+        assert_eq!(paths(&["stdafx.h", "windows.h"]), split.precompiled);
+        assert_eq!(
+            paths(&["a.h", "b.h"]),
+            split.dependencies_of(&root.join("main.cpp"))
+        );
+
+        let missing = graph.precompiled_split("pch.h");
+        assert_eq!(graph.dependencies, missing.dependencies);
+        assert_eq!(
+            "warning: precompiled header pch.h isn't included",
+            missing.diagnostics[0].to_string()
+        );
+    }
+}
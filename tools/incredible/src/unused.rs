@@ -0,0 +1,461 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Unused includes
+//
+//	A lightweight include-what-you-use signal: an include is plausibly unused
+//	when the including file references none of the names the header declares.
+//	Names declared are guessed from the tokens of the header:
+//
+//	#define NAME
+//	class|struct|union|enum|namespace|typedef|using NAME
+//	NAME followed by ( ; = [ { , or }	(functions, variables, enumerators)
+//
+//	headers declaring nothing themselves (umbrella headers) stand for the names
+//	of the headers they include, their own includes are never reported. Nor
+//	are headers without any name, forced includes and includes that weren't
+//	resolved. Comments, string and character literals and include lines
+//	aren't tokens
+//-----------------------------------------------------------------------------
+
+use crate::lexer::source_decode;
+use crate::{DependencyGraph, Include};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Punct(char),
+}
+
+//	an include none of whose names is referenced
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnusedInclude {
+    pub includer: PathBuf,
+    pub include: Include,
+    // names of the header, none of them referenced by the includer
+    pub name_count: usize,
+}
+
+const KEYWORDS: &[&str] = &[
+    "alignas",
+    "alignof",
+    "and",
+    "asm",
+    "auto",
+    "bool",
+    "break",
+    "case",
+    "catch",
+    "char",
+    "class",
+    "const",
+    "constexpr",
+    "const_cast",
+    "continue",
+    "decltype",
+    "default",
+    "define",
+    "defined",
+    "delete",
+    "do",
+    "double",
+    "dynamic_cast",
+    "elif",
+    "else",
+    "endif",
+    "enum",
+    "error",
+    "explicit",
+    "export",
+    "extern",
+    "false",
+    "final",
+    "float",
+    "for",
+    "friend",
+    "goto",
+    "if",
+    "ifdef",
+    "ifndef",
+    "inline",
+    "int",
+    "long",
+    "mutable",
+    "namespace",
+    "new",
+    "noexcept",
+    "not",
+    "nullptr",
+    "once",
+    "operator",
+    "or",
+    "override",
+    "pragma",
+    "private",
+    "protected",
+    "public",
+    "register",
+    "reinterpret_cast",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "static_assert",
+    "static_cast",
+    "struct",
+    "switch",
+    "template",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typedef",
+    "typeid",
+    "typename",
+    "undef",
+    "union",
+    "unsigned",
+    "using",
+    "virtual",
+    "void",
+    "volatile",
+    "warning",
+    "while",
+];
+
+// keywords followed by the name they declare
+const DECLARATION_KEYWORDS: &[&str] = &[
+    "class",
+    "struct",
+    "union",
+    "enum",
+    "namespace",
+    "typedef",
+    "using",
+];
+
+fn ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || '_' == c
+}
+
+//-----------------------------------------------------------------------------
+// split source text into identifiers and punctuation
+//-----------------------------------------------------------------------------
+
+fn tokens_lex(text: &str) -> Vec<Token> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut line_start = true;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).cloned();
+        if '\n' == c {
+            line_start = true;
+            i += 1;
+            continue;
+        }
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        // include lines only name the header
+        if '#' == c && line_start {
+            let mut j = i + 1;
+            while j < chars.len() && (' ' == chars[j] || '\t' == chars[j]) {
+                j += 1;
+            }
+            let start = j;
+            while j < chars.len() && ident_char(chars[j]) {
+                j += 1;
+            }
+            let directive: String = chars[start..j].iter().collect();
+            if "include" == directive || "include_next" == directive || "import" == directive {
+                while i < chars.len() && '\n' != chars[i] {
+                    i += 1;
+                }
+                continue;
+            }
+        }
+        line_start = false;
+
+        if '/' == c && Some('/') == next {
+            while i < chars.len() && '\n' != chars[i] {
+                i += 1;
+            }
+        } else if '/' == c && Some('*') == next {
+            i += 2;
+            while i < chars.len() && !('*' == chars[i] && Some(&'/') == chars.get(i + 1)) {
+                i += 1;
+            }
+            i += 2;
+        } else if '"' == c || '\'' == c {
+            i += 1;
+            while i < chars.len() && c != chars[i] && '\n' != chars[i] {
+                if '\\' == chars[i] {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i += 1;
+        } else if ident_char(c) {
+            let start = i;
+            while i < chars.len() && ident_char(chars[i]) {
+                i += 1;
+            }
+            let ident: String = chars[start..i].iter().collect();
+            // raw strings, R"delimiter( ... )delimiter"
+            if ident.ends_with('R') && Some(&'"') == chars.get(i) {
+                let open = i + 1;
+                let mut j = open;
+                while j < chars.len() && '(' != chars[j] {
+                    j += 1;
+                }
+                let end: Vec<char> = std::iter::once(')')
+                    .chain(chars[open..j].iter().cloned())
+                    .chain(std::iter::once('"'))
+                    .collect();
+                i = j;
+                while i < chars.len() && !chars[i..].starts_with(&end) {
+                    i += 1;
+                }
+                i += end.len();
+            } else if !c.is_ascii_digit() {
+                tokens.push(Token::Ident(ident));
+            }
+        } else {
+            tokens.push(Token::Punct(c));
+            i += 1;
+        }
+    }
+
+    tokens
+}
+
+//-----------------------------------------------------------------------------
+// names a header plausibly declares
+//-----------------------------------------------------------------------------
+
+fn names_declared(tokens: &[Token]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    let ident = |i: usize| match tokens.get(i) {
+        Some(Token::Ident(name)) if !KEYWORDS.contains(&name.as_str()) => Some(name.clone()),
+        _ => None,
+    };
+
+    for (i, token) in tokens.iter().enumerate() {
+        let name = match token {
+            Token::Ident(k) if "define" == k && i > 0 && Token::Punct('#') == tokens[i - 1] => {
+                ident(i + 1)
+            }
+            Token::Ident(k) if DECLARATION_KEYWORDS.contains(&k.as_str()) => {
+                // enum class NAME
+                ident(i + 1).or_else(|| ident(i + 2))
+            }
+            Token::Ident(_) => match tokens.get(i + 1) {
+                Some(Token::Punct(p)) if "(;=[{,}".contains(*p) => ident(i),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(name) = name {
+            names.insert(name);
+        }
+    }
+
+    names
+}
+
+fn names_referenced(tokens: &[Token]) -> HashSet<&str> {
+    tokens
+        .iter()
+        .filter_map(|t| match t {
+            Token::Ident(name) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+struct Analysis<'a> {
+    graph: &'a DependencyGraph,
+    tokens: HashMap<PathBuf, Vec<Token>>,
+    names: HashMap<PathBuf, HashSet<String>>,
+}
+
+impl<'a> Analysis<'a> {
+    fn tokens(&mut self, path: &Path) -> &[Token] {
+        self.tokens.entry(path.to_path_buf()).or_insert_with(|| {
+            let text = fs::read(path).unwrap_or_default();
+            let text = source_decode(text).unwrap_or_default();
+            tokens_lex(&String::from_utf8_lossy(&text))
+        })
+    }
+
+    // names of a header, those of its includes for umbrella headers
+    fn names(&mut self, path: &Path, visiting: &mut HashSet<PathBuf>) -> HashSet<String> {
+        if let Some(names) = self.names.get(path) {
+            return names.clone();
+        }
+        if !visiting.insert(path.to_path_buf()) {
+            return HashSet::new();
+        }
+
+        let mut names = names_declared(self.tokens(path));
+        if names.is_empty() {
+            let graph = self.graph;
+            for include in graph.files.get(path).into_iter().flatten() {
+                if let Some(p) = &include.path {
+                    names.extend(self.names(p, visiting));
+                }
+            }
+        }
+
+        self.names.insert(path.to_path_buf(), names.clone());
+        names
+    }
+}
+
+//-----------------------------------------------------------------------------
+// the includes of the scanned files that are plausibly unused, by file and line
+//-----------------------------------------------------------------------------
+
+pub fn unused_includes(graph: &DependencyGraph) -> Vec<UnusedInclude> {
+    let mut analysis = Analysis {
+        graph,
+        tokens: HashMap::new(),
+        names: HashMap::new(),
+    };
+    let mut unused = Vec::new();
+
+    for (includer, includes) in graph.files.iter() {
+        if names_declared(analysis.tokens(includer)).is_empty() {
+            continue;
+        }
+        let referenced: HashSet<String> = names_referenced(analysis.tokens(includer))
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        for include in includes.iter() {
+            let path = match &include.path {
+                Some(path) if include.line > 0 => path,
+                _ => continue,
+            };
+            let names = analysis.names(path, &mut HashSet::new());
+            if !names.is_empty() && names.is_disjoint(&referenced) {
+                unused.push(UnusedInclude {
+                    includer: includer.clone(),
+                    include: include.clone(),
+                    name_count: names.len(),
+                });
+            }
+        }
+    }
+
+    unused
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_unused {
+
+    #[cfg(test)]
+    use super::*;
+    use crate::IncludeSearch;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_unused_includes() {
+        let idents = |text: &str| -> Vec<String> {
+            tokens_lex(text)
+                .into_iter()
+                .filter_map(|t| match t {
+                    Token::Ident(name) => Some(name),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(
+            vec!["int", "a", "b"],
+            idents("#include <vector>\n// c\nint a /* d */ = b(\"e\", 'f', R\"x(g)x\", 10u);")
+        );
+
+        let declared = |text: &str| {
+            let mut names: Vec<_> = names_declared(&tokens_lex(text)).into_iter().collect();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            vec!["A", "B", "Color", "MAX", "Widget", "count", "draw", "ns"],
+            declared(
+                "#define MAX 4\nnamespace ns {\nclass Widget;\nenum class Color { A, B };\nint draw(Widget* w);\nextern int count;\n}"
+            )
+        );
+
+        let dir = std::env::temp_dir().join("incredible_test_unused");
+        fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, text: &str| {
+            fs::write(dir.join(name), text).unwrap();
+            dir.join(name)
+        };
+        let main = write("main.cpp", "int main() { return draw(); } // Widget\n");
+        let used = write("used.h", "int draw();\n");
+        let unused = write("unused.h", "struct Widget { int size; };\n");
+        let umbrella = write("umbrella.h", "#pragma once\n#include \"used.h\"\n");
+        let empty = write("empty.h", "#pragma once\n");
+
+        let include = |name: &str, line: usize, path: &Path| Include {
+            include: name.to_string(),
+            search_type: IncludeSearch::Local,
+            line,
+            file: None,
+            path: Some(path.to_path_buf()),
+        };
+        let mut files = BTreeMap::new();
+        files.insert(
+            main.clone(),
+            vec![
+                include("used.h", 1, &used),
+                include("unused.h", 2, &unused),
+                include("umbrella.h", 3, &umbrella),
+                include("empty.h", 4, &empty),
+            ],
+        );
+        files.insert(umbrella.clone(), vec![include("used.h", 2, &used)]);
+        let graph = DependencyGraph {
+            files,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vec![UnusedInclude {
+                includer: main,
+                include: include("unused.h", 2, &unused),
+                name_count: 2,
+            }],
+            unused_includes(&graph)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
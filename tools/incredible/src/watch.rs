@@ -0,0 +1,217 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//-----------------------------------------------------------------------------
+//	Watcher
+//
+//	Repeated scans of the same inputs (--watch), for editor integrations.
+//	Each scan keeps the includes of every file in memory, the next one only
+//	reads the files modified since. Changes are found by polling the
+//	modification time and size of the scanned files, a file created to
+//	satisfy an unresolved include isn't noticed until a scanned file changes
+//
+//	A Scanner keeps the includes of every file it read across scans of any
+//	inputs (--serve), one cache per set of defines. Files modified since are
+//	read again, the others aren't read at all
+//-----------------------------------------------------------------------------
+
+use crate::cache::{defines_key, FileStamp, ScanCache};
+use crate::{scan, scan_cached, DependencyGraph, IncResult, ScanOptions};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+pub struct Watcher {
+    inputs: Vec<PathBuf>,
+    options: ScanOptions,
+    cache: ScanCache,
+    // stamps of the files of the last scan, none for missing ones
+    stamps: HashMap<PathBuf, Option<FileStamp>>,
+}
+
+impl Watcher {
+    // a watcher of the inputs, starting from the cache file of the options when given
+    pub fn new(inputs: &[PathBuf], options: &ScanOptions) -> Self {
+        let key = defines_key(&options.defines);
+        let cache = match &options.cache {
+            Some(cache_file) => ScanCache::load(cache_file, key),
+            None => ScanCache::new(key),
+        };
+        Watcher {
+            inputs: inputs.to_vec(),
+            options: options.clone(),
+            cache,
+            stamps: HashMap::new(),
+        }
+    }
+
+    //-----------------------------------------------------------------------------
+    // scan the inputs again, reading only the files changed since the last scan
+    //-----------------------------------------------------------------------------
+
+    pub fn scan(&mut self) -> IncResult<DependencyGraph> {
+        let result = scan_cached(&self.inputs, &self.options, Some(self.cache.clone()));
+
+        // a failed scan waits for the next change as well
+        let files: Vec<PathBuf> = match &result {
+            Ok(graph) => graph.files.keys().cloned().collect(),
+            Err(_) => self
+                .stamps
+                .keys()
+                .chain(self.inputs.iter())
+                .cloned()
+                .collect(),
+        };
+        self.stamps = files
+            .into_iter()
+            .map(|f| {
+                let stamp = FileStamp::read(&f);
+                (f, stamp)
+            })
+            .collect();
+
+        if result.is_ok() {
+            if let Some(cache_file) = &self.options.cache {
+                let _ = self.cache.save(cache_file);
+            }
+            self.cache = self.cache.next();
+        }
+        result
+    }
+
+    // true when a file of the last scan was modified, created or removed since
+    pub fn changed(&self) -> bool {
+        self.stamps
+            .iter()
+            .any(|(path, stamp)| FileStamp::read(path) != *stamp)
+    }
+}
+
+#[derive(Default)]
+pub struct Scanner {
+    // includes of the files of earlier scans, by key of their defines
+    caches: HashMap<u64, ScanCache>,
+}
+
+impl Scanner {
+    pub fn new() -> Self {
+        Scanner::default()
+    }
+
+    //-----------------------------------------------------------------------------
+    // scan inputs, reading only the files that are new or modified since earlier
+    // scans with the same defines. Scans with a cache file (-cache) use it instead
+    //-----------------------------------------------------------------------------
+
+    pub fn scan(
+        &mut self,
+        inputs: &[PathBuf],
+        options: &ScanOptions,
+    ) -> IncResult<DependencyGraph> {
+        if options.cache.is_some() {
+            return scan(inputs, options);
+        }
+
+        let key = defines_key(&options.defines);
+        let cache = self
+            .caches
+            .entry(key)
+            .or_insert_with(|| ScanCache::new(key))
+            .clone();
+        let result = scan_cached(inputs, options, Some(cache.clone()));
+        if result.is_ok() {
+            self.caches.insert(key, cache.merged());
+        }
+        result
+    }
+}
+
+//-----------------------------------------------------------------------------
+//	TESTS
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test_watch {
+
+    #[cfg(test)]
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_watcher() {
+        let dir = std::env::temp_dir().join("incredible_test_watch");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("main.cpp"), "#include \"a.h\"\n").unwrap();
+        fs::write(dir.join("a.h"), "").unwrap();
+        fs::write(dir.join("b.h"), "").unwrap();
+
+        let options = ScanOptions {
+            worker_count: 1,
+            ..Default::default()
+        };
+        let mut watcher = Watcher::new(&[dir.join("main.cpp")], &options);
+        let graph = watcher.scan().unwrap();
+        assert_eq!(vec![dir.join("a.h")], graph.dependencies);
+        assert!(!watcher.changed());
+
+        // sizes differ, so the change is seen whatever the timestamp resolution
+        fs::write(dir.join("a.h"), "#include \"b.h\"\n").unwrap();
+        assert!(watcher.changed());
+        let graph = watcher.scan().unwrap();
+        assert_eq!(vec![dir.join("a.h"), dir.join("b.h")], graph.dependencies);
+        assert!(!watcher.changed());
+
+        fs::remove_file(dir.join("main.cpp")).unwrap();
+        assert!(watcher.changed());
+        assert!(watcher.scan().is_err());
+        assert!(!watcher.changed());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_scanner() {
+        let dir = std::env::temp_dir().join("incredible_test_scanner");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.cpp"), "#include \"a.h\"\n").unwrap();
+        fs::write(dir.join("b.cpp"), "#include \"b.h\"\n").unwrap();
+        fs::write(dir.join("a.h"), "").unwrap();
+        fs::write(dir.join("b.h"), "").unwrap();
+        fs::write(dir.join("c.h"), "").unwrap();
+
+        let options = ScanOptions {
+            worker_count: 1,
+            ..Default::default()
+        };
+        let mut scanner = Scanner::new();
+        let graph = scanner.scan(&[dir.join("a.cpp")], &options).unwrap();
+        assert_eq!(vec![dir.join("a.h")], graph.dependencies);
+        let graph = scanner.scan(&[dir.join("b.cpp")], &options).unwrap();
+        assert_eq!(vec![dir.join("b.h")], graph.dependencies);
+
+        // the includes of a.cpp are kept through the scan of b.cpp
+        let key = defines_key(&options.defines);
+        assert!(scanner.caches[&key]
+            .lookup(
+                &dir.join("a.cpp"),
+                FileStamp::read(&dir.join("a.cpp")).unwrap(),
+                key
+            )
+            .is_some());
+
+        // modified files are read again
+        fs::write(dir.join("a.h"), "#include \"c.h\"\n").unwrap();
+        let graph = scanner.scan(&[dir.join("a.cpp")], &options).unwrap();
+        assert_eq!(vec![dir.join("a.h"), dir.join("c.h")], graph.dependencies);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The submitted changes of users, through p4_lib so tests can hand canned
+// perforce output to a mock
+
+use crate::render::{Snippet, SnippetFile};
+use error_lib::*;
+use p4_lib::{Change, PerforceTrait};
+use std::cell::RefCell;
+
+// runs p4 through another perforce and keeps the arguments of every call, to
+// show the queries of a run
+pub struct Recorder<'a, P: PerforceTrait> {
+    p4: &'a P,
+    pub commands: RefCell<Vec<Vec<String>>>,
+}
+
+impl<'a, P: PerforceTrait> Recorder<'a, P> {
+    pub fn new(p4: &'a P) -> Self {
+        Recorder {
+            p4,
+            commands: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl<'a, P: PerforceTrait> PerforceTrait for Recorder<'a, P> {
+    fn exec(&self, args: &[&str]) -> SgeResult<String> {
+        self.commands
+            .borrow_mut()
+            .push(args.iter().map(|s| s.to_string()).collect());
+        self.p4.exec(args)
+    }
+}
+
+fn snippet_from(change: Change) -> Snippet {
+    Snippet {
+        changelist: change.changelist,
+        commit: None,
+        user: change.user,
+        date: change.date.trim().to_string(),
+        description: change
+            .description
+            .lines()
+            .map(|l| l.trim())
+            .collect::<Vec<&str>>()
+            .join("\n"),
+        files: Vec::new(),
+    }
+}
+
+// fails with what to do when p4 can't list changes, p4 changes listing
+// nothing rather than failing when not logged in or the server is down
+pub fn check_login<P: PerforceTrait>(p4: &P) -> SgeResult<()> {
+    let e = match p4.exec(&["login", "-s"]) {
+        Ok(_) => return Ok(()),
+        Err(SgeError::IO(e)) => {
+            return Err(SgeError::Message(format!(
+                "couldn't run p4, is it installed and on the PATH? {}",
+                e
+            )))
+        }
+        Err(e) => e,
+    };
+    let out = e.to_string();
+    if out.contains("invalid or unset") || out.contains("session has expired") {
+        return Err(SgeError::Literal(
+            "not logged in to perforce, run p4 login and try again",
+        ));
+    }
+    if out.contains("Connect to server failed") {
+        return Err(SgeError::Message(format!(
+            "couldn't connect to perforce, check P4PORT\n{}",
+            out.trim()
+        )));
+    }
+    Err(e)
+}
+
+// the changes of users submitted to file_specs, @range or //depot/...@range,
+// with their files when described
+pub fn fetch_snippets<P: PerforceTrait>(
+    p4: &P,
+    users: &[String],
+    file_specs: &[String],
+    described: bool,
+) -> SgeResult<Vec<Snippet>> {
+    // p4 changes takes a single user, a call per user
+    let mut snippets = Vec::new();
+    for user in users.iter() {
+        let mut args = vec!["-s", "submitted", "-u", user, "-l"];
+        args.extend(file_specs.iter().map(|s| s.as_str()));
+        snippets.extend(p4.changes(&args)?.into_iter().map(snippet_from));
+    }
+
+    // the files of all changes in one call
+    if described && !snippets.is_empty() {
+        let changelists: Vec<u32> = snippets.iter().map(|s| s.changelist).collect();
+        for description in p4.describe(&changelists)? {
+            if let Some(s) = snippets
+                .iter_mut()
+                .find(|s| s.changelist == description.changelist)
+            {
+                s.files = description
+                    .files
+                    .into_iter()
+                    .map(|f| SnippetFile {
+                        depot_file: f.depot_file,
+                        action: f.action,
+                    })
+                    .collect();
+            }
+        }
+    }
+    Ok(snippets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // hands out canned outputs in order and records the arguments of each call
+    #[derive(Default)]
+    struct PerforceMock {
+        outputs: RefCell<Vec<SgeResult<String>>>,
+        commands: RefCell<Vec<Vec<String>>>,
+    }
+
+    impl PerforceMock {
+        fn new(outputs: &[&str]) -> Self {
+            PerforceMock {
+                outputs: RefCell::new(outputs.iter().rev().map(|s| Ok(s.to_string())).collect()),
+                ..Default::default()
+            }
+        }
+
+        // a command failing, as p4 does with its stderr
+        fn failing(stderr: &str) -> Self {
+            PerforceMock {
+                outputs: RefCell::new(vec![Err(SgeError::Message(stderr.trim().to_string()))]),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl PerforceTrait for PerforceMock {
+        fn exec(&self, args: &[&str]) -> SgeResult<String> {
+            self.commands
+                .borrow_mut()
+                .push(args.iter().map(|s| s.to_string()).collect());
+            self.outputs
+                .borrow_mut()
+                .pop()
+                .unwrap_or(Err(SgeError::Literal("not enough outputs in mock")))
+        }
+    }
+
+    const ALICE_CHANGES: &str = "Change 1202 on 2021/03/02 by alice@alice-ws
+
+\tFix the shader cache
+\t  keyed on the platform too
+
+Change 1201 on 2021/03/01 by alice@alice-ws
+
+\tAdd <b>bold</b> to the \"toolbar\"
+";
+
+    const BOB_CHANGES: &str = "Change 1203 on 2021/03/01 by bob@bob-ws
+
+\tBump the engine version
+";
+
+    const DESCRIBE: &str = "Change 1202 by alice@alice-ws on 2021/03/02 10:15:00
+
+\tFix the shader cache
+
+Affected files ...
+
+... //depot/engine/shaders/cache.cpp#4 edit
+... //depot/tools/shaderc/main.rs#1 add
+
+Change 1201 by alice@alice-ws on 2021/03/01 09:00:00
+
+\tAdd <b>bold</b> to the \"toolbar\"
+
+Affected files ...
+
+... //depot/editor/toolbar.cpp#7 edit
+... //depot/editor/toolbar_old.cpp#3 delete
+";
+
+    #[test]
+    fn test_fetch_snippets() {
+        let p4 = PerforceMock::new(&[ALICE_CHANGES, DESCRIBE]);
+        let users = [String::from("alice")];
+        let specs = [String::from("//depot/...@1,@now")];
+        let snippets = fetch_snippets(&p4, &users, &specs, true).unwrap();
+        assert_eq!(
+            vec![
+                vec![
+                    "changes",
+                    "-s",
+                    "submitted",
+                    "-u",
+                    "alice",
+                    "-l",
+                    "//depot/...@1,@now"
+                ],
+                vec!["describe", "-s", "1202", "1201"],
+            ],
+            *p4.commands.borrow()
+        );
+        assert_eq!(
+            Snippet {
+                changelist: 1202,
+                commit: None,
+                user: String::from("alice"),
+                date: String::from("2021/03/02"),
+                description: String::from("Fix the shader cache\nkeyed on the platform too"),
+                files: vec![
+                    SnippetFile {
+                        depot_file: String::from("//depot/engine/shaders/cache.cpp"),
+                        action: String::from("edit"),
+                    },
+                    SnippetFile {
+                        depot_file: String::from("//depot/tools/shaderc/main.rs"),
+                        action: String::from("add"),
+                    },
+                ],
+            },
+            snippets[0]
+        );
+        assert_eq!(2, snippets[1].files.len());
+    }
+
+    #[test]
+    fn test_recorder() {
+        let p4 = PerforceMock::new(&[BOB_CHANGES]);
+        let recorder = Recorder::new(&p4);
+        let users = [String::from("bob")];
+        let snippets = fetch_snippets(&recorder, &users, &[String::from("@1,@now")], false);
+        assert_eq!(1, snippets.unwrap().len());
+        assert_eq!(*p4.commands.borrow(), *recorder.commands.borrow());
+    }
+
+    #[test]
+    fn test_fetch_snippets_users() {
+        let p4 = PerforceMock::new(&[ALICE_CHANGES, BOB_CHANGES]);
+        let users = [String::from("alice"), String::from("bob")];
+        let snippets = fetch_snippets(&p4, &users, &[String::from("@1,@now")], false).unwrap();
+        let changelists: Vec<u32> = snippets.iter().map(|s| s.changelist).collect();
+        assert_eq!(vec![1202, 1201, 1203], changelists);
+        assert_eq!("bob", snippets[2].user);
+        assert_eq!(2, p4.commands.borrow().len());
+
+        // a failing call fails the fetch
+        let p4 = PerforceMock::new(&[ALICE_CHANGES]);
+        assert!(fetch_snippets(&p4, &users, &[String::from("@1,@now")], false).is_err());
+        let p4 = PerforceMock::failing("//nope/... - must refer to client 'alice-ws'.\n");
+        assert_eq!(
+            Err(SgeError::Message(String::from(
+                "//nope/... - must refer to client 'alice-ws'."
+            ))),
+            fetch_snippets(&p4, &users, &[String::from("//nope/...")], false)
+        );
+    }
+
+    #[test]
+    fn test_check_login() {
+        let p4 = PerforceMock::new(&["User alice ticket expires in 11 hours 59 minutes.\n"]);
+        assert_eq!(Ok(()), check_login(&p4));
+        assert_eq!(vec![vec!["login", "-s"]], *p4.commands.borrow());
+
+        let p4 = PerforceMock::failing("Perforce password (P4PASSWD) invalid or unset.\n");
+        assert_eq!(
+            Err(SgeError::Literal(
+                "not logged in to perforce, run p4 login and try again"
+            )),
+            check_login(&p4)
+        );
+        let p4 = PerforceMock::failing("Your session has expired, please login again.\n");
+        assert!(check_login(&p4).is_err());
+        let p4 = PerforceMock::failing(
+            "Perforce client error:\n\tConnect to server failed; check $P4PORT.\n",
+        );
+        assert!(check_login(&p4).is_err());
+        // other failures as p4 reports them
+        let p4 = PerforceMock::failing("Access for user 'alice' has not been enabled.\n");
+        assert_eq!(
+            Err(SgeError::Message(String::from(
+                "Access for user 'alice' has not been enabled."
+            ))),
+            check_login(&p4)
+        );
+    }
+}
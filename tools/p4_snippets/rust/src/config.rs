@@ -0,0 +1,229 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Defaults of the command line, read from ~/.p4snippets.toml and overridden by
+// a .p4snippets.toml in the current directory or the closest parent, the root
+// of the repo. Only the part of toml the file needs is read, top-level keys
+// holding a string or an array of strings:
+//
+// user = "jdoe"
+// range = "last-week"
+// format = "markdown"
+// template = "snippet.tmpl"
+// review_url = "https://swarm.example.com/changes/{cl}"
+// depots = ["//depot/engine/...", "//depot/tools/..."]
+// git_repos = ["~/src/pipeline"]
+//
+// A leading ~ of git_repos is the home directory.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_NAME: &str = ".p4snippets.toml";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    pub user: Option<String>,
+    pub range: Option<String>,
+    pub format: Option<String>,
+    pub template: Option<String>,
+    pub review_url: Option<String>,
+    pub depots: Vec<String>,
+    pub git_repos: Vec<String>,
+}
+
+impl Config {
+    // the keys set in other replace those of self
+    pub fn merge(self, other: Config) -> Config {
+        Config {
+            user: other.user.or(self.user),
+            range: other.range.or(self.range),
+            format: other.format.or(self.format),
+            template: other.template.or(self.template),
+            review_url: other.review_url.or(self.review_url),
+            depots: if other.depots.is_empty() {
+                self.depots
+            } else {
+                other.depots
+            },
+            git_repos: if other.git_repos.is_empty() {
+                self.git_repos
+            } else {
+                other.git_repos
+            },
+        }
+    }
+}
+
+// a "quoted" string, with \" and \\ escapes
+fn parse_string(text: &str) -> Option<(String, &str)> {
+    let mut chars = text.char_indices();
+    if chars.next() != Some((0, '"')) {
+        return None;
+    }
+    let mut value = String::new();
+    let mut escaped = false;
+    for (i, c) in chars {
+        if escaped {
+            value.push(match c {
+                'n' => '\n',
+                't' => '\t',
+                c => c,
+            });
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            return Some((value, &text[i + 1..]));
+        } else {
+            value.push(c);
+        }
+    }
+    None
+}
+
+// ["a", "b"]
+fn parse_array(text: &str) -> Option<Vec<String>> {
+    let mut rest = text.strip_prefix('[')?.trim_start();
+    let mut values = Vec::new();
+    loop {
+        if let Some(after) = rest.strip_prefix(']') {
+            return if after.trim().is_empty() || after.trim().starts_with('#') {
+                Some(values)
+            } else {
+                None
+            };
+        }
+        let (value, after) = parse_string(rest)?;
+        values.push(value);
+        rest = after.trim_start();
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after.trim_start();
+        } else if !rest.starts_with(']') {
+            return None;
+        }
+    }
+}
+
+pub fn parse_config(text: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |what: &str| format!("line {}: {}", number + 1, what);
+        let equal = match line.find('=') {
+            Some(i) => i,
+            None => return Err(error("expected key = value")),
+        };
+        let key = line[..equal].trim();
+        let value = line[equal + 1..].trim();
+        if key == "depots" || key == "git_repos" {
+            let values = match parse_array(value) {
+                Some(values) => values,
+                None => return Err(error("expected an array of strings")),
+            };
+            match key {
+                "depots" => config.depots = values,
+                _ => config.git_repos = values,
+            }
+            continue;
+        }
+        let value = match parse_string(value) {
+            Some((s, rest)) if rest.trim().is_empty() || rest.trim().starts_with('#') => s,
+            _ => return Err(error("expected a string")),
+        };
+        match key {
+            "user" => config.user = Some(value),
+            "range" => config.range = Some(value),
+            "format" => config.format = Some(value),
+            "template" => config.template = Some(value),
+            "review_url" => config.review_url = Some(value),
+            _ => return Err(error(&format!("unknown key {}", key))),
+        }
+    }
+    Ok(config)
+}
+
+fn read_config(path: &Path) -> Result<Option<Config>, String> {
+    match fs::read_to_string(path) {
+        Ok(text) => parse_config(&text)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("USERPROFILE")
+        .or_else(|| env::var_os("HOME"))
+        .map(PathBuf::from)
+}
+
+// path with a leading ~ replaced by home, git -C doesn't expand it
+fn expand_home(path: &str, home: Option<&Path>) -> String {
+    match (path.strip_prefix('~'), home) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with(&['/', '\\'][..]) => {
+            format!("{}{}", home.display(), rest)
+        }
+        _ => path.to_string(),
+    }
+}
+
+// the config of the user, overridden by that of the repo holding dir
+pub fn load_config(dir: &Path) -> Result<Config, String> {
+    let mut config = Config::default();
+    if let Some(home) = home_dir() {
+        if let Some(user) = read_config(&home.join(CONFIG_NAME))? {
+            config = config.merge(user);
+        }
+    }
+    for parent in dir.ancestors() {
+        if let Some(repo) = read_config(&parent.join(CONFIG_NAME))? {
+            // the user config when run from home isn't a repo config
+            if Some(parent) != home_dir().as_deref() {
+                config = config.merge(repo);
+            }
+            break;
+        }
+    }
+    let home = home_dir();
+    config.git_repos = config
+        .git_repos
+        .iter()
+        .map(|r| expand_home(r, home.as_deref()))
+        .collect();
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_home() {
+        let home = Path::new("/home/alice");
+        assert_eq!(
+            "/home/alice/src/pipeline",
+            expand_home("~/src/pipeline", Some(home))
+        );
+        assert_eq!("/home/alice", expand_home("~", Some(home)));
+        // ~user isn't the home of the user running
+        assert_eq!("~bob/src", expand_home("~bob/src", Some(home)));
+        assert_eq!("src/~/a", expand_home("src/~/a", Some(home)));
+        assert_eq!("~/src", expand_home("~/src", None));
+    }
+}
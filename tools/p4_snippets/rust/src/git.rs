@@ -0,0 +1,218 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The commits of users to git repos, listed with the perforce changes of the
+// same period. The files of a commit are //<repo>/<path> so they group by
+// project like depot files do.
+
+use crate::render::{Snippet, SnippetFile};
+use error_lib::*;
+use std::path::Path;
+use std::process::Command;
+
+// a record per commit, fields apart, the files after the last field
+// \x1e<hash>\x1f<date>\x1f<message>\x1f
+// M\tpath
+const LOG_FORMAT: &str = "--format=%x1e%H%x1f%ad%x1f%B%x1f";
+
+// a bound of a p4 range as a git date, none for @now
+// @2021/03/01 is 2021-03-01 00:00:00, @2021/03/07:23:59:59 2021-03-07 23:59:59
+fn git_date(bound: &str) -> Result<Option<String>, ()> {
+    let bound = bound.trim().trim_start_matches('@');
+    if bound == "now" {
+        return Ok(None);
+    }
+    // a changelist has no date
+    if bound.is_empty() || bound.chars().all(|c| c.is_ascii_digit()) {
+        return Err(());
+    }
+    let date = bound.replace('/', "-");
+    Ok(Some(match date.find(':') {
+        Some(i) => format!("{} {}", &date[..i], &date[i + 1..]),
+        None => format!("{} 00:00:00", date),
+    }))
+}
+
+// the since and until of a p4 date range, @first,@last or @last, none for
+// ranges of changelists
+pub fn git_dates(range: &str) -> Option<(Option<String>, Option<String>)> {
+    let bounds: Vec<&str> = range.split(',').collect();
+    match bounds.len() {
+        1 => Some((None, git_date(bounds[0]).ok()?)),
+        2 => Some((git_date(bounds[0]).ok()?, git_date(bounds[1]).ok()?)),
+        _ => None,
+    }
+}
+
+fn file_action(status: &str) -> &'static str {
+    match status.chars().next() {
+        Some('A') => "add",
+        Some('C') => "branch",
+        Some('D') => "delete",
+        Some('R') => "move/add",
+        _ => "edit",
+    }
+}
+
+// the commits of git log LOG_FORMAT --name-status, as those of user
+pub fn parse_log(text: &str, repo: &str, user: &str) -> Vec<Snippet> {
+    let mut snippets = Vec::new();
+    for record in text.split('\x1e').filter(|r| !r.trim().is_empty()) {
+        let fields: Vec<&str> = record.split('\x1f').collect();
+        if fields.len() < 4 {
+            continue;
+        }
+        let files = fields[3]
+            .lines()
+            .filter_map(|line| {
+                let columns: Vec<&str> = line.split('\t').collect();
+                // renames and copies list the old path first
+                let path = columns.last().filter(|_| columns.len() > 1)?;
+                Some(SnippetFile {
+                    depot_file: format!("//{}/{}", repo, path),
+                    action: String::from(file_action(columns[0])),
+                })
+            })
+            .collect();
+        snippets.push(Snippet {
+            changelist: 0,
+            commit: Some(fields[0].trim().to_string()),
+            user: user.to_string(),
+            date: fields[1].to_string(),
+            description: fields[2]
+                .trim()
+                .lines()
+                .map(|l| l.trim())
+                .collect::<Vec<&str>>()
+                .join("\n"),
+            files,
+        });
+    }
+    snippets
+}
+
+// the arguments of git log for the commits of author between since and until,
+// dates of the local timezone
+pub fn log_args(
+    path: &Path,
+    author: &str,
+    since: Option<&str>,
+    until: Option<&str>,
+) -> Vec<String> {
+    let mut args = vec![
+        String::from("-C"),
+        path.display().to_string(),
+        String::from("log"),
+        format!("--author={}", author),
+        String::from("--date=format-local:%Y/%m/%d"),
+        String::from("--name-status"),
+        String::from(LOG_FORMAT),
+    ];
+    if let Some(since) = since {
+        args.push(format!("--since={}", since));
+    }
+    if let Some(until) = until {
+        args.push(format!("--until={}", until));
+    }
+    args
+}
+
+// the commits of author to the repo at path between since and until
+pub fn fetch_commits(
+    path: &Path,
+    author: &str,
+    since: Option<&str>,
+    until: Option<&str>,
+) -> SgeResult<Vec<Snippet>> {
+    let output = Command::new("git")
+        .args(log_args(path, author, since, until))
+        .output()?;
+    if !output.status.success() {
+        return Err(SgeError::Message(format!(
+            "git log failed in {}, {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    // the name of the repo is that of its directory
+    let repo = path
+        .canonicalize()
+        .ok()
+        .and_then(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+        .unwrap_or_else(|| path.display().to_string());
+    Ok(parse_log(
+        &String::from_utf8_lossy(&output.stdout),
+        &repo,
+        author,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_git_dates() {
+        assert_eq!(
+            Some((
+                Some(String::from("2021-03-01 00:00:00")),
+                Some(String::from("2021-03-07 23:59:59"))
+            )),
+            git_dates("@2021/03/01,@2021/03/07:23:59:59")
+        );
+        assert_eq!(
+            Some((Some(String::from("2021-01-01 00:00:00")), None)),
+            git_dates("@2021/01/01,@now")
+        );
+        assert_eq!(
+            Some((None, Some(String::from("2021-01-01 00:00:00")))),
+            git_dates("@2021/01/01")
+        );
+        assert_eq!(None, git_dates("@1,@37000"));
+    }
+
+    #[test]
+    fn test_parse_log() {
+        let log = "\x1e4f1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c\x1f2021/03/02\x1f\
+                   Fix the build\n\n  on linux too\n\x1f\n\n\
+                   M\tsrc/main.rs\nA\tdocs/build.md\nR100\told.rs\tsrc/new.rs\n\
+                   \x1e0123456789abcdef0123456789abcdef01234567\x1f2021/03/01\x1f\
+                   Remove the old tool\n\x1f\n\nD\ttools/old.sh\n";
+        let snippets = parse_log(log, "tools", "alice");
+        assert_eq!(2, snippets.len());
+        assert_eq!("4f1c2d3e4f", snippets[0].id());
+        assert_eq!("alice", snippets[0].user);
+        assert_eq!("2021/03/02", snippets[0].date);
+        assert_eq!("Fix the build\n\non linux too", snippets[0].description);
+        assert_eq!(
+            vec![
+                SnippetFile {
+                    depot_file: String::from("//tools/src/main.rs"),
+                    action: String::from("edit"),
+                },
+                SnippetFile {
+                    depot_file: String::from("//tools/docs/build.md"),
+                    action: String::from("add"),
+                },
+                SnippetFile {
+                    depot_file: String::from("//tools/src/new.rs"),
+                    action: String::from("move/add"),
+                },
+            ],
+            snippets[0].files
+        );
+        assert_eq!("delete", snippets[1].files[0].action);
+        assert!(parse_log("", "tools", "alice").is_empty());
+    }
+}
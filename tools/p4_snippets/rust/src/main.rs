@@ -13,11 +13,25 @@
 // limitations under the License.
 
 extern crate getopts;
+mod changes;
+mod config;
+mod git;
+mod period;
+mod render;
+
+use changes::{check_login, fetch_snippets, Recorder};
 use chrono::prelude::*;
-use chrono::Duration;
+use config::load_config;
+use error_lib::*;
 use getopts::Options;
+use git::{fetch_commits, git_dates, log_args};
+use p4_lib::Perforce;
+use period::{preset_range, PRESETS};
+use render::{render, Format, Grouping, Style};
 use std::env;
-use std::process::Command;
+use std::fs;
+use std::path::Path;
+use std::process;
 
 #[cfg(target_os = "windows")]
 use clipboard_win::Clipboard;
@@ -27,91 +41,264 @@ fn print_help(program: &str, opts: Options) {
     print!("{}", opts.usage(&brief));
 }
 
-fn build_p4_date(dt: DateTime<Local>) -> String {
-    format!("@{}/{}/{}", dt.year(), dt.month(), dt.day())
+// the users of a team file, one per line, # starting a comment
+fn parse_team(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|l| l.split('#').next().unwrap_or_default().trim())
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_string())
+        .collect()
+}
+
+// a command as typed in a shell, arguments with spaces quoted
+fn command_line(program: &str, args: &[String]) -> String {
+    let mut line = String::from(program);
+    for arg in args {
+        if arg.contains(char::is_whitespace) {
+            line.push_str(&format!(" \"{}\"", arg));
+        } else {
+            line.push_str(&format!(" {}", arg));
+        }
+    }
+    line
+}
+
+// exit codes, so scripts tell a bad command line from a failed run
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+// an error and the code the run exits with
+struct Failure {
+    code: i32,
+    error: SgeError,
+}
+
+fn usage_error<E: Into<SgeError>>(error: E) -> Failure {
+    Failure {
+        code: EXIT_USAGE,
+        error: error.into(),
+    }
+}
+
+fn run_error<E: Into<SgeError>>(error: E) -> Failure {
+    Failure {
+        code: EXIT_FAILURE,
+        error: error.into(),
+    }
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+    if let Err(failure) = run(&args) {
+        eprintln!("p4_snippets: {}", failure.error);
+        process::exit(failure.code);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), Failure> {
     let ref program = args[0];
 
     let mut opts = Options::new();
     opts.optopt(
         "r",
         "range",
-        "specify an optional date or CL range (else past week)",
-        "@2020/01/01,@now or @1,@37000",
+        "specify an optional date or CL range, or a period of this-week last-week \
+         this-month or last-month (else this week)",
+        "@2020/01/01,@now or @1,@37000 or last-week",
+    );
+    opts.optopt(
+        "f",
+        "format",
+        "specify the format of the snippet (else markdown)",
+        "markdown|html|plain|json",
+    );
+    opts.optmulti(
+        "d",
+        "depot",
+        "only list changes to files of a depot path (else those of the config)",
+        "//depot/project/...",
+    );
+    opts.optopt(
+        "u",
+        "users",
+        "list the changes of several users, by user (else those of the config)",
+        "alice,bob,carol",
+    );
+    opts.optopt(
+        "",
+        "team",
+        "list the changes of the users of a file, one per line, by user",
+        "team.txt",
+    );
+    opts.optopt(
+        "",
+        "review-url-template",
+        "link changes to their review, {cl} replaced by the change (else that of the config)",
+        "https://swarm/changes/{cl}",
+    );
+    opts.optopt(
+        "t",
+        "template",
+        "render each change with a template, {cl} {user} {date} {summary} \
+         {description} and {link} replaced by those of the change (else that of the config)",
+        "snippet.tmpl",
+    );
+    opts.optmulti(
+        "",
+        "git",
+        "also list the commits of the users to a git repo (else those of the config)",
+        "path/to/repo",
+    );
+    opts.optflag(
+        "s",
+        "summary",
+        "start with the number of changes and of files added edited and deleted",
+    );
+    opts.optflag(
+        "g",
+        "group",
+        "group changes by day and by top-level depot path",
+    );
+    opts.optflag(
+        "n",
+        "dry-run",
+        "print the snippet and the queries it comes from, without copying it to the clipboard",
     );
     opts.optflag("h", "help", "print this help menu");
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(f) => panic!(f.to_string()),
-    };
+    let matches = opts
+        .parse(&args[1..])
+        .map_err(|f| usage_error(format!("{}, see --help", f)))?;
     if matches.opt_present("h") {
         print_help(&program, opts);
-        return;
+        return Ok(());
     }
 
-    let username = env::var("USERNAME").unwrap_or_default();
+    let current_dir = env::current_dir().unwrap_or_default();
+    let config = load_config(&current_dir)
+        .map_err(|e| usage_error(format!("couldn't read the config, {}", e)))?;
+
+    let format = match matches.opt_str("f").or(config.format) {
+        Some(name) => Format::parse(&name).ok_or_else(|| {
+            usage_error(format!(
+                "unknown format {}, markdown html plain or json",
+                name
+            ))
+        })?,
+        None => Format::Markdown,
+    };
 
-    let now = Local::now();
-    let mut weekday_current = now.weekday().num_days_from_monday();
-    if 0 == weekday_current {
-        weekday_current = 7;
+    let mut users: Vec<String> = match matches.opt_str("u") {
+        Some(list) => list
+            .split(',')
+            .map(|u| u.trim().to_string())
+            .filter(|u| !u.is_empty())
+            .collect(),
+        None => Vec::new(),
+    };
+    if let Some(team) = matches.opt_str("team") {
+        let text = fs::read_to_string(&team)
+            .map_err(|e| usage_error(format!("couldn't read team file {}, {}", team, e)))?;
+        users.extend(parse_team(&text));
+    }
+    users.sort();
+    users.dedup();
+    let team_mode = !users.is_empty();
+    if !team_mode {
+        users.push(
+            config
+                .user
+                .unwrap_or_else(|| env::var("USERNAME").unwrap_or_default()),
+        );
     }
-    let monday = now - Duration::days(weekday_current.into());
-    let sunday = monday + Duration::days(7);
 
-    let range = match matches.opt_str("r") {
-        Some(s) => s,
-        None => format!("{},{}", build_p4_date(monday), build_p4_date(sunday)),
+    // periods are days of the local timezone
+    let today = Local::now().naive_local().date();
+    let range = matches
+        .opt_str("r")
+        .or(config.range)
+        .unwrap_or_else(|| String::from(PRESETS[0]));
+    let range = preset_range(&range, today).unwrap_or(range);
+
+    // the range applies to each depot path, //depot/project/...@range
+    let mut depots = matches.opt_strs("d");
+    if depots.is_empty() {
+        depots = config.depots;
+    }
+    let file_specs: Vec<String> = if depots.is_empty() {
+        vec![range.clone()]
+    } else {
+        depots.iter().map(|d| format!("{}{}", d, range)).collect()
+    };
+
+    let template = match matches.opt_str("t").or(config.template) {
+        Some(path) => Some(
+            fs::read_to_string(&path)
+                .map_err(|e| usage_error(format!("couldn't read template {}, {}", path, e)))?,
+        ),
+        None => None,
+    };
+    let style = Style {
+        grouping: Grouping {
+            users: team_mode,
+            days: matches.opt_present("g"),
+        },
+        review_url: matches.opt_str("review-url-template").or(config.review_url),
+        template,
+        summary: matches.opt_present("s"),
+        ..Style::new(format)
     };
+    // projects, summaries and json come from the files of the changes
+    let described = style.grouping.days || style.summary || style.format == Format::Json;
+    let p4 = Perforce {};
+    check_login(&p4).map_err(run_error)?;
+    let recorder = Recorder::new(&p4);
+    let mut snippets = fetch_snippets(&recorder, &users, &file_specs, described)
+        .map_err(|e| run_error(format!("couldn't list changes, {}", e)))?;
+    let mut queries: Vec<String> = recorder
+        .commands
+        .borrow()
+        .iter()
+        .map(|args| command_line("p4", args))
+        .collect();
 
-    let output = Command::new("p4")
-        .args(&[
-            "-C",
-            "utf8-bom",
-            "changes",
-            "-s",
-            "submitted",
-            "-u",
-            &username,
-            "-l",
-            &range,
-        ])
-        .output()
-        .expect("failed to execute process");
-
-    let cmd_stdout = String::from_utf8_lossy(&output.stdout);
-    let cmd_stderr = String::from_utf8_lossy(&output.stderr);
-
-    let lines = cmd_stdout.split("\n");
-
-    let mut details = Vec::new();
-
-    details.push(format!("Perforce Changes:\n"));
-    for line in lines {
-        if line.starts_with("Change") {
-            let words = line.split(" ").collect::<Vec<&str>>();
-            if words.len() > 1 {
-                details.push(format!(
-                    "\n* [change {}]",
-                    words[1], words[1]
-                ));
+    // commits are listed with the changes of their day, users being authors
+    let mut git_repos = matches.opt_strs("git");
+    if git_repos.is_empty() {
+        git_repos = config.git_repos;
+    }
+    if !git_repos.is_empty() {
+        match git_dates(&range) {
+            Some((since, until)) => {
+                for (repo, user) in git_repos
+                    .iter()
+                    .flat_map(|r| users.iter().map(move |u| (r, u)))
+                {
+                    let (path, since, until) =
+                        (Path::new(repo), since.as_deref(), until.as_deref());
+                    queries.push(command_line("git", &log_args(path, user, since, until)));
+                    let commits = fetch_commits(path, user, since, until)
+                        .map_err(|e| run_error(format!("couldn't list commits, {}", e)))?;
+                    snippets.extend(commits);
+                }
+                snippets.sort_by(|a, b| b.date.cmp(&a.date));
             }
-        } else {
-            details.push(format!(" {}", line.trim()));
+            None => eprintln!("commits aren't listed for a range of changelists"),
         }
     }
 
-    for d in &details {
-        print!("{}", d);
-    }
-
-    copy_to_clipboard(&details.into_iter().collect::<String>())
-        .expect("couldn't copy to clipboard");
+    let snippet = render(&snippets, &style);
+    print!("{}", snippet);
 
-    println!("{}", cmd_stderr);
+    // queries go to stderr, the snippet can still be piped
+    if matches.opt_present("n") {
+        for query in queries.iter() {
+            eprintln!("{}", query);
+        }
+        return Ok(());
+    }
+    copy_to_clipboard(&snippet)
+        .map_err(|e| run_error(format!("couldn't copy to clipboard, {}", e)))?;
+    Ok(())
 }
 
 #[cfg(target_os = "windows")]
@@ -0,0 +1,123 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Periods of --range presets as p4 date ranges, from the first day to the end
+// of the last one, weeks running monday to sunday. Days are those of the local
+// timezone, today is passed in so periods don't depend on the clock in tests.
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+pub const PRESETS: &[&str] = &["this-week", "last-week", "this-month", "last-month"];
+
+fn monday_of(day: NaiveDate) -> NaiveDate {
+    day - Duration::days(day.weekday().num_days_from_monday().into())
+}
+
+fn first_of_month(day: NaiveDate) -> Option<NaiveDate> {
+    NaiveDate::from_ymd_opt(day.year(), day.month(), 1)
+}
+
+fn first_of_next_month(day: NaiveDate) -> Option<NaiveDate> {
+    match day.month() {
+        12 => NaiveDate::from_ymd_opt(day.year() + 1, 1, 1),
+        month => NaiveDate::from_ymd_opt(day.year(), month + 1, 1),
+    }
+}
+
+// @first,@last:23:59:59, p4 dates without a time being midnight
+fn build_p4_range(first: NaiveDate, last: NaiveDate) -> String {
+    format!(
+        "@{},@{}:23:59:59",
+        first.format("%Y/%m/%d"),
+        last.format("%Y/%m/%d")
+    )
+}
+
+// the days of a preset, first and last included
+fn preset_days(name: &str, today: NaiveDate) -> Option<(NaiveDate, NaiveDate)> {
+    match name {
+        "this-week" => {
+            let monday = monday_of(today);
+            Some((monday, monday + Duration::days(6)))
+        }
+        "last-week" => {
+            let monday = monday_of(today) - Duration::weeks(1);
+            Some((monday, monday + Duration::days(6)))
+        }
+        "this-month" => Some((
+            first_of_month(today)?,
+            first_of_next_month(today)?.pred_opt()?,
+        )),
+        "last-month" => {
+            let last = first_of_month(today)?.pred_opt()?;
+            Some((first_of_month(last)?, last))
+        }
+        _ => None,
+    }
+}
+
+// the p4 range of a preset, none when name isn't one
+pub fn preset_range(name: &str, today: NaiveDate) -> Option<String> {
+    preset_days(name, today).map(|(first, last)| build_p4_range(first, last))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn test_preset_range_week() {
+        // a monday is the first day of its week, a sunday the last
+        for today in [day(2021, 3, 1), day(2021, 3, 4), day(2021, 3, 7)].iter() {
+            assert_eq!(
+                Some(String::from("@2021/03/01,@2021/03/07:23:59:59")),
+                preset_range("this-week", *today)
+            );
+        }
+        assert_eq!(
+            Some(String::from("@2021/02/22,@2021/02/28:23:59:59")),
+            preset_range("last-week", day(2021, 3, 1))
+        );
+        // weeks across years
+        assert_eq!(
+            Some(String::from("@2020/12/28,@2021/01/03:23:59:59")),
+            preset_range("this-week", day(2021, 1, 1))
+        );
+    }
+
+    #[test]
+    fn test_preset_range_month() {
+        assert_eq!(
+            Some(String::from("@2020/02/01,@2020/02/29:23:59:59")),
+            preset_range("this-month", day(2020, 2, 10))
+        );
+        assert_eq!(
+            Some(String::from("@2020/12/01,@2020/12/31:23:59:59")),
+            preset_range("this-month", day(2020, 12, 31))
+        );
+        assert_eq!(
+            Some(String::from("@2020/12/01,@2020/12/31:23:59:59")),
+            preset_range("last-month", day(2021, 1, 15))
+        );
+        assert_eq!(None, preset_range("@2021/01/01,@now", day(2021, 1, 15)));
+        for name in PRESETS {
+            assert!(preset_range(name, day(2021, 1, 15)).is_some());
+        }
+    }
+}
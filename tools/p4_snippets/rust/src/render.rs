@@ -0,0 +1,597 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Rendering of the changes of a period as a snippet, in the format of the tool
+// it gets pasted into, as a list or grouped by user, by day and by project
+
+use chrono::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+
+// a file of a change and what the change did to it, add edit delete...
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SnippetFile {
+    pub depot_file: String,
+    pub action: String,
+}
+
+// a submitted changelist, as listed by p4 changes -l, or a git commit
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snippet {
+    pub changelist: u32,
+    // the hash of a commit, changelist is 0
+    pub commit: Option<String>,
+    pub user: String,
+    // yyyy/mm/dd as perforce reports it
+    pub date: String,
+    pub description: String,
+    // the files changed, when described
+    pub files: Vec<SnippetFile>,
+}
+
+impl Snippet {
+    // the changelist number, or the short hash of a commit
+    pub fn id(&self) -> String {
+        match &self.commit {
+            Some(hash) => hash.chars().take(10).collect(),
+            None => self.changelist.to_string(),
+        }
+    }
+}
+
+// the scope of the changes of a period, a file touched by several changes is
+// counted once in files and once per change in the actions
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub changes: usize,
+    pub files: usize,
+    pub adds: usize,
+    pub edits: usize,
+    pub deletes: usize,
+}
+
+impl Summary {
+    pub fn new(snippets: &[Snippet]) -> Self {
+        let mut summary = Summary::default();
+        let mut changelists = BTreeSet::new();
+        let mut files = BTreeSet::new();
+        // a change of several users of a team is listed once
+        for s in snippets.iter().filter(|s| changelists.insert(s.id())) {
+            for f in s.files.iter() {
+                files.insert(f.depot_file.as_str());
+                match f.action.as_str() {
+                    "add" | "branch" | "move/add" | "import" => summary.adds += 1,
+                    "delete" | "move/delete" | "purge" | "archive" => summary.deletes += 1,
+                    _ => summary.edits += 1,
+                }
+            }
+        }
+        summary.changes = changelists.len();
+        summary.files = files.len();
+        summary
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    // a bullet per change, description lines continuing the bullet
+    Markdown,
+    // a table with a row per change
+    Html,
+    // a line per change, description lines indented below it
+    Plain,
+    // an array of the changes with their files, for tools rather than people
+    Json,
+}
+
+// the sections of a snippet, changes are listed by user then by day and
+// project when both are set
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Grouping {
+    pub users: bool,
+    pub days: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Style {
+    pub format: Format,
+    pub grouping: Grouping,
+    // the url of the review of a change, {cl} replaced by its number, that
+    // markdown and html link the number to
+    pub review_url: Option<String>,
+    // the text of each change in place of that of the format, see
+    // template_apply
+    pub template: Option<String>,
+    // the summary of the changes below the title, json leaves it to its
+    // readers
+    pub summary: bool,
+}
+
+impl Style {
+    pub fn new(format: Format) -> Self {
+        Style {
+            format,
+            grouping: Grouping::default(),
+            review_url: None,
+            template: None,
+            summary: false,
+        }
+    }
+
+    // commits aren't reviewed through the review tool of perforce
+    fn review_url(&self, s: &Snippet) -> Option<String> {
+        match s.commit {
+            Some(_) => None,
+            None => self
+                .review_url
+                .as_ref()
+                .map(|url| url.replace("{cl}", &s.changelist.to_string())),
+        }
+    }
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name.to_lowercase().as_str() {
+            "markdown" | "md" => Some(Format::Markdown),
+            "html" => Some(Format::Html),
+            "plain" | "text" => Some(Format::Plain),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// the top-level depot path of a file, //depot/project of //depot/project/a.cpp
+fn depot_project(path: &str) -> String {
+    let parts: Vec<&str> = path.trim_start_matches("//").splitn(3, '/').collect();
+    match parts.len() {
+        0 | 1 => path.to_string(),
+        _ => format!("//{}/{}", parts[0], parts[1]),
+    }
+}
+
+// Monday 2021/03/01 of 2021/03/01, the date alone when it doesn't parse
+fn day_name(date: &str) -> String {
+    let weekday = match NaiveDate::parse_from_str(date, "%Y/%m/%d") {
+        Ok(d) => d.weekday(),
+        Err(_) => return date.to_string(),
+    };
+    let name = match weekday {
+        Weekday::Mon => "Monday",
+        Weekday::Tue => "Tuesday",
+        Weekday::Wed => "Wednesday",
+        Weekday::Thu => "Thursday",
+        Weekday::Fri => "Friday",
+        Weekday::Sat => "Saturday",
+        Weekday::Sun => "Sunday",
+    };
+    format!("{} {}", name, date)
+}
+
+// bucket changes per day then per project, in order, a change touching several
+// projects is listed under each of them
+fn group_days<'a>(
+    snippets: &[&'a Snippet],
+) -> BTreeMap<String, BTreeMap<String, Vec<&'a Snippet>>> {
+    let mut days: BTreeMap<String, BTreeMap<String, Vec<&Snippet>>> = BTreeMap::new();
+    for &s in snippets {
+        let mut projects: Vec<String> = s
+            .files
+            .iter()
+            .map(|f| depot_project(&f.depot_file))
+            .collect();
+        projects.sort();
+        projects.dedup();
+        if projects.is_empty() {
+            projects.push(String::from("(no files)"));
+        }
+        let day = days.entry(s.date.clone()).or_default();
+        for project in projects {
+            day.entry(project).or_default().push(s);
+        }
+    }
+    days
+}
+
+fn group_users(snippets: &[Snippet]) -> BTreeMap<String, Vec<&Snippet>> {
+    let mut users: BTreeMap<String, Vec<&Snippet>> = BTreeMap::new();
+    for s in snippets {
+        users.entry(s.user.clone()).or_default().push(s);
+    }
+    users
+}
+
+fn heading(format: Format, level: usize, text: &str) -> String {
+    match format {
+        Format::Markdown => format!("{} {}\n\n", "#".repeat(level + 2), text),
+        Format::Html => format!("<h{0}>{1}</h{0}>\n", level + 2, escape_html(text)),
+        Format::Plain => match level {
+            0 => format!("{}\n\n", text),
+            _ => format!("{}{}\n", "  ".repeat(level - 1), text),
+        },
+        // json isn't sectioned
+        Format::Json => String::new(),
+    }
+}
+
+fn list_markdown(style: &Style, snippets: &[&Snippet]) -> String {
+    let mut out = String::new();
+    for s in snippets {
+        let changelist = match style.review_url(s) {
+            Some(url) => format!("[{}]({})", s.id(), url),
+            None => s.id(),
+        };
+        let mut lines = s.description.lines();
+        out.push_str(&format!(
+            "* **{}** ({}) {}\n",
+            changelist,
+            s.date,
+            lines.next().unwrap_or_default()
+        ));
+        // indented lines stay within the bullet
+        for line in lines.filter(|l| !l.is_empty()) {
+            out.push_str(&format!("  {}\n", line));
+        }
+    }
+    out.push('\n');
+    out
+}
+
+fn list_html(style: &Style, snippets: &[&Snippet]) -> String {
+    let mut out =
+        String::from("<table>\n<tr><th>Change</th><th>Date</th><th>Description</th></tr>\n");
+    for s in snippets {
+        let changelist = match style.review_url(s) {
+            Some(url) => format!("<a href=\"{}\">{}</a>", escape_html(&url), s.id()),
+            None => s.id(),
+        };
+        let lines: Vec<String> = s
+            .description
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(escape_html)
+            .collect();
+        out.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            changelist,
+            escape_html(&s.date),
+            lines.join("<br>")
+        ));
+    }
+    out.push_str("</table>\n");
+    out
+}
+
+fn list_plain(snippets: &[&Snippet]) -> String {
+    let mut out = String::new();
+    for s in snippets {
+        let mut lines = s.description.lines();
+        out.push_str(&format!(
+            "{} {} {}\n",
+            s.id(),
+            s.date,
+            lines.next().unwrap_or_default()
+        ));
+        for line in lines.filter(|l| !l.is_empty()) {
+            out.push_str(&format!("    {}\n", line));
+        }
+    }
+    out.push('\n');
+    out
+}
+
+fn escape_json(text: &str) -> String {
+    // a string always serializes
+    serde_json::to_string(text).unwrap_or_default()
+}
+
+fn list_json(style: &Style, snippets: &[&Snippet]) -> String {
+    let changes: Vec<String> = snippets
+        .iter()
+        .map(|s| {
+            let files: Vec<String> = s.files.iter().map(|f| escape_json(&f.depot_file)).collect();
+            let review_url = match style.review_url(s) {
+                Some(url) => format!(",\n    \"review_url\": {}", escape_json(&url)),
+                None => String::new(),
+            };
+            let id = match &s.commit {
+                Some(hash) => format!("\"commit\": {}", escape_json(hash)),
+                None => format!("\"changelist\": {}", s.changelist),
+            };
+            format!(
+                "  {{\n    {},\n    \"user\": {},\n    \"date\": {},\n    \
+                 \"description\": {},\n    \"files\": [{}]{}\n  }}",
+                id,
+                escape_json(&s.user),
+                escape_json(&s.date),
+                escape_json(&s.description),
+                files.join(", "),
+                review_url
+            )
+        })
+        .collect();
+    if changes.is_empty() {
+        return String::from("[]\n");
+    }
+    format!("[\n{}\n]\n", changes.join(",\n"))
+}
+
+// replace the placeholders of a template by those of a change, in one pass so
+// a description holding {cl} stays as is, unknown placeholders are kept
+// {cl} {user} {date} {summary}, the first line of the description,
+// {description} and {link}, the review url when there's one
+fn template_apply(template: &str, style: &Style, s: &Snippet) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        rest = &rest[open..];
+        let close = match rest.find('}') {
+            Some(close) => close,
+            None => break,
+        };
+        let value = match &rest[1..close] {
+            "cl" => s.id(),
+            "user" => s.user.clone(),
+            "date" => s.date.clone(),
+            "summary" => s.description.lines().next().unwrap_or_default().to_string(),
+            "description" => s.description.clone(),
+            "link" => style.review_url(s).unwrap_or_default(),
+            _ => rest[..=close].to_string(),
+        };
+        out.push_str(&value);
+        rest = &rest[close + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn list_template(template: &str, style: &Style, snippets: &[&Snippet]) -> String {
+    let mut out: String = snippets
+        .iter()
+        .map(|s| template_apply(template, style, s))
+        .collect();
+    out.push('\n');
+    out
+}
+
+fn list(style: &Style, snippets: &[&Snippet]) -> String {
+    if let Some(template) = &style.template {
+        if style.format != Format::Json {
+            return list_template(template, style, snippets);
+        }
+    }
+    match style.format {
+        Format::Markdown => list_markdown(style, snippets),
+        Format::Html => list_html(style, snippets),
+        Format::Plain => list_plain(snippets),
+        Format::Json => list_json(style, snippets),
+    }
+}
+
+// the changes as a list, or by day and project below headings of level
+fn render_changes(style: &Style, snippets: &[&Snippet], level: usize) -> String {
+    if style.format == Format::Json || !style.grouping.days {
+        return list(style, snippets);
+    }
+    let mut out = String::new();
+    for (date, projects) in group_days(snippets) {
+        out.push_str(&heading(style.format, level, &day_name(&date)));
+        for (project, changes) in projects {
+            out.push_str(&heading(style.format, level + 1, &project));
+            out.push_str(&list(style, &changes));
+        }
+    }
+    out
+}
+
+// 1 change, 2 changes
+fn plural(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("1 {}", noun),
+        _ => format!("{} {}s", count, noun),
+    }
+}
+
+fn render_summary(format: Format, summary: &Summary) -> String {
+    let text = format!(
+        "{}, {}: {} added, {} edited, {} deleted",
+        plural(summary.changes, "change"),
+        plural(summary.files, "file"),
+        summary.adds,
+        summary.edits,
+        summary.deletes
+    );
+    match format {
+        Format::Markdown => format!("**{}**\n\n", text),
+        Format::Html => format!("<p>{}</p>\n", text),
+        Format::Plain => format!("{}\n\n", text),
+        Format::Json => String::new(),
+    }
+}
+
+pub fn render(snippets: &[Snippet], style: &Style) -> String {
+    let mut out = heading(style.format, 0, "Perforce changes");
+    if style.summary {
+        out.push_str(&render_summary(style.format, &Summary::new(snippets)));
+    }
+    if style.format == Format::Json || !style.grouping.users {
+        let all: Vec<&Snippet> = snippets.iter().collect();
+        out.push_str(&render_changes(style, &all, 1));
+        return out;
+    }
+    for (user, changes) in group_users(snippets) {
+        out.push_str(&heading(style.format, 1, &user));
+        out.push_str(&render_changes(style, &changes, 2));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(depot_file: &str, action: &str) -> SnippetFile {
+        SnippetFile {
+            depot_file: String::from(depot_file),
+            action: String::from(action),
+        }
+    }
+
+    // two changes of alice, with their files when described
+    fn alice(described: bool) -> Vec<Snippet> {
+        let mut snippets = vec![
+            Snippet {
+                changelist: 1202,
+                user: String::from("alice"),
+                date: String::from("2021/03/02"),
+                description: String::from("Fix the shader cache\nkeyed on the platform too"),
+                ..Default::default()
+            },
+            Snippet {
+                changelist: 1201,
+                user: String::from("alice"),
+                date: String::from("2021/03/01"),
+                description: String::from("Add <b>bold</b> to the \"toolbar\""),
+                ..Default::default()
+            },
+        ];
+        if described {
+            snippets[0].files = vec![
+                file("//depot/engine/shaders/cache.cpp", "edit"),
+                file("//depot/tools/shaderc/main.rs", "add"),
+            ];
+            snippets[1].files = vec![
+                file("//depot/editor/toolbar.cpp", "edit"),
+                file("//depot/editor/toolbar_old.cpp", "delete"),
+            ];
+        }
+        snippets
+    }
+
+    #[test]
+    fn test_render_markdown() {
+        let style = Style {
+            review_url: Some(String::from("https://swarm/changes/{cl}")),
+            ..Style::new(Format::Markdown)
+        };
+        assert_eq!(
+            "## Perforce changes\n\n\
+             * **[1202](https://swarm/changes/1202)** (2021/03/02) Fix the shader cache\n  \
+             keyed on the platform too\n\
+             * **[1201](https://swarm/changes/1201)** (2021/03/01) Add <b>bold</b> to the \"toolbar\"\n\n",
+            render(&alice(false), &style)
+        );
+    }
+
+    #[test]
+    fn test_render_html_plain() {
+        assert_eq!(
+            "<h2>Perforce changes</h2>\n\
+             <table>\n<tr><th>Change</th><th>Date</th><th>Description</th></tr>\n\
+             <tr><td>1202</td><td>2021/03/02</td><td>Fix the shader cache<br>keyed on the platform too</td></tr>\n\
+             <tr><td>1201</td><td>2021/03/01</td><td>Add &lt;b&gt;bold&lt;/b&gt; to the &quot;toolbar&quot;</td></tr>\n\
+             </table>\n",
+            render(&alice(false), &Style::new(Format::Html))
+        );
+        assert_eq!(
+            "Perforce changes\n\n\
+             1202 2021/03/02 Fix the shader cache\n    keyed on the platform too\n\
+             1201 2021/03/01 Add <b>bold</b> to the \"toolbar\"\n\n",
+            render(&alice(false), &Style::new(Format::Plain))
+        );
+    }
+
+    #[test]
+    fn test_render_grouped() {
+        let snippets = alice(true);
+        let style = Style {
+            grouping: Grouping {
+                users: true,
+                days: true,
+            },
+            ..Style::new(Format::Markdown)
+        };
+        assert_eq!(
+            "## Perforce changes\n\n\
+             ### alice\n\n\
+             #### Monday 2021/03/01\n\n\
+             ##### //depot/editor\n\n\
+             * **1201** (2021/03/01) Add <b>bold</b> to the \"toolbar\"\n\n\
+             #### Tuesday 2021/03/02\n\n\
+             ##### //depot/engine\n\n\
+             * **1202** (2021/03/02) Fix the shader cache\n  keyed on the platform too\n\n\
+             ##### //depot/tools\n\n\
+             * **1202** (2021/03/02) Fix the shader cache\n  keyed on the platform too\n\n",
+            render(&snippets, &style)
+        );
+    }
+
+    #[test]
+    fn test_render_json_template() {
+        let mut style = Style::new(Format::Json);
+        let json = render(&alice(false)[..1], &style);
+        assert_eq!(
+            "[\n  {\n    \"changelist\": 1202,\n    \"user\": \"alice\",\n    \
+             \"date\": \"2021/03/02\",\n    \
+             \"description\": \"Fix the shader cache\\nkeyed on the platform too\",\n    \
+             \"files\": []\n  }\n]\n",
+            json
+        );
+        assert_eq!("[]\n", render(&[], &style));
+
+        style.format = Format::Plain;
+        style.template = Some(String::from("- {cl} {summary} {link}{unknown}\n"));
+        assert_eq!(
+            "Perforce changes\n\n- 1202 Fix the shader cache {unknown}\n\
+             - 1201 Add <b>bold</b> to the \"toolbar\" {unknown}\n\n",
+            render(&alice(false), &style)
+        );
+    }
+
+    #[test]
+    fn test_render_summary() {
+        let mut snippets = alice(true);
+        // the same change listed twice and a file touched twice
+        snippets.push(snippets[0].clone());
+        let touched = snippets[0].files[0].clone();
+        snippets[1].files.push(touched);
+        assert_eq!(
+            Summary {
+                changes: 2,
+                files: 4,
+                adds: 1,
+                edits: 3,
+                deletes: 1,
+            },
+            Summary::new(&snippets)
+        );
+
+        let style = Style {
+            summary: true,
+            ..Style::new(Format::Html)
+        };
+        assert!(render(&snippets[..1], &style).starts_with(
+            "<h2>Perforce changes</h2>\n\
+             <p>1 change, 2 files: 1 added, 1 edited, 0 deleted</p>\n<table>"
+        ));
+    }
+}
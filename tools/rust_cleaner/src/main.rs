@@ -15,13 +15,277 @@
 // binary rust_cleaner cleans up intermediate rust artefacts from all rust directories
 // RLS creates a directory titled "target" that accumulates gigabytes of intermediate data across
 // our repo
+//
+// usage: rust_cleaner [--dry-run] [--report-only] [--top <n>] [--older-than <days>]
+//                     [--json <file>] [--daemon [--min-free <gib>] [--interval <min>]] [path...]
+//   --dry-run            report the size of each target directory instead of cleaning it
+//   --report-only        only list the largest target directories
+//   --top <n>            how many of the largest target directories to list, 10 by default
+//   --older-than <days>  only clean target directories whose newest file is older than days,
+//                        crates being worked on keep their incremental caches
+//   --json <file>        write a report of the run as json to file, - for stdout
+//   --daemon             check the free space of the disk of the current directory every
+//                        interval and clean when it is below min-free, logging each check
+//   --min-free <gib>     the free space the daemon keeps, 20 GiB by default
+//   --interval <min>     the minutes between two checks of the daemon, 60 by default
+//   path                 directories to clean, relative to the current one
+//
+// without paths, the directories listed by clean.toml at the root of the monorepo are cleaned,
+// relative to the root:
+//
+// paths = ["build", "libs", "third_party/rust", "tools"]
+//
+// and those of DEFAULT_PATHS when there is no clean.toml
+//
+// the directories that could not be read, measured or cleaned are listed at the end, the exit
+// code is 1 when there are some and 2 when nothing could run
+//
+// the json report lists the target directories cleaned, or that dry runs would clean, and the
+// directories that failed:
+//
+// {"dry_run": false, "bytes_reclaimed": 3000000,
+//  "cleaned": [{"path": "tools/a/target", "bytes": 3000000}],
+//  "failures": [{"path": "tools/b", "error": "cargo clean exited with exit status: 101"}]}
 
 use error_lib::SgeResult;
 
+use std::cmp::Reverse;
 use std::env;
 use std::fs;
-use std::path::PathBuf;
-use std::process::Command;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const USAGE: &str = "usage: rust_cleaner [--dry-run] [--report-only] [--top <n>] \
+                     [--older-than <days>] [--json <file>] \
+                     [--daemon [--min-free <gib>] [--interval <min>]] [path...]";
+
+const CONFIG_NAME: &str = "clean.toml";
+
+// we only want to crawl a subset of the monorepo
+const DEFAULT_PATHS: &[&str] = &["build", "libs", "third_party/rust", "tools"];
+
+const DEFAULT_TOP: usize = 10;
+
+const DEFAULT_MIN_FREE_GIB: u64 = 20;
+
+const DEFAULT_INTERVAL_MINUTES: u64 = 60;
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+const EXIT_FAILURES: i32 = 1;
+
+const EXIT_ERROR: i32 = 2;
+
+#[derive(Debug)]
+struct Options {
+    dry_run: bool,
+    report_only: bool,
+    top: usize,
+    older_than: Option<u64>,
+    json: Option<String>,
+    daemon: bool,
+    min_free: u64,
+    interval: Duration,
+    paths: Vec<PathBuf>,
+}
+
+impl Options {
+    fn json_stdout(&self) -> bool {
+        self.json.as_deref() == Some("-")
+    }
+}
+
+// human output, on stderr when the json report goes to stdout
+macro_rules! out {
+    ($options:expr, $($arg:tt)*) => {
+        if $options.json_stdout() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            dry_run: false,
+            report_only: false,
+            top: DEFAULT_TOP,
+            older_than: None,
+            json: None,
+            daemon: false,
+            min_free: DEFAULT_MIN_FREE_GIB * GIB,
+            interval: Duration::from_secs(DEFAULT_INTERVAL_MINUTES * 60),
+            paths: Vec::new(),
+        }
+    }
+}
+
+// the size of a directory and when its newest file was modified, none when it holds no files
+#[derive(Debug, Default)]
+struct DirStat {
+    size: u64,
+    newest: Option<SystemTime>,
+}
+
+// the target directory of a crate
+#[derive(Debug)]
+struct Target {
+    crate_dir: PathBuf,
+    stat: DirStat,
+}
+
+// the target directories cleaned, or that a dry run would clean, with their size, and the
+// directories that failed
+#[derive(Debug, Default)]
+struct Report {
+    cleaned: Vec<(PathBuf, u64)>,
+    kept: usize,
+    failures: Vec<(PathBuf, String)>,
+}
+
+// a line on stderr redrawn as the target directories are cleaned
+#[derive(Debug)]
+struct Progress {
+    done: usize,
+    total: usize,
+    line_len: usize,
+}
+
+impl Progress {
+    const BAR_LEN: usize = 20;
+
+    fn new(total: usize) -> Progress {
+        Progress {
+            done: 0,
+            total,
+            line_len: 0,
+        }
+    }
+
+    fn show(&mut self, freed: u64, crate_dir: &Path) {
+        let filled = match self.total {
+            0 => Progress::BAR_LEN,
+            total => self.done * Progress::BAR_LEN / total,
+        };
+        let line = format!(
+            "[{}{}] {}/{}  {} freed  {}",
+            "=".repeat(filled),
+            " ".repeat(Progress::BAR_LEN - filled),
+            self.done,
+            self.total,
+            size_format(freed),
+            crate_dir.display()
+        );
+        // the rest of a longer previous line is blanked
+        let blank = self.line_len.saturating_sub(line.chars().count());
+        eprint!("\r{}{}", line, " ".repeat(blank));
+        let _ = io::stderr().flush();
+        self.line_len = line.chars().count();
+    }
+
+    // blank the line so other output starts on a clean one
+    fn clear(&mut self) {
+        if self.line_len > 0 {
+            eprint!("\r{}\r", " ".repeat(self.line_len));
+            let _ = io::stderr().flush();
+            self.line_len = 0;
+        }
+    }
+}
+
+impl Report {
+    fn bytes_reclaimed(&self) -> u64 {
+        self.cleaned.iter().map(|(_, size)| size).sum()
+    }
+}
+
+fn options_parse(args: &[String]) -> SgeResult<Options> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => options.dry_run = true,
+            "--report-only" => options.report_only = true,
+            "--top" => match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                Some(top) => options.top = top,
+                None => return Err(format!("--top takes a number\n{}", USAGE).into()),
+            },
+            "--older-than" => {
+                let days = args.next().and_then(|d| d.parse::<u64>().ok());
+                if days.is_none() {
+                    return Err(format!("--older-than takes a number of days\n{}", USAGE).into());
+                }
+                options.older_than = days;
+            }
+            "--json" => match args.next() {
+                Some(file) => options.json = Some(file.clone()),
+                None => return Err(format!("--json takes a file\n{}", USAGE).into()),
+            },
+            "--daemon" => options.daemon = true,
+            "--min-free" => match args.next().and_then(|n| n.parse::<u64>().ok()) {
+                Some(gib) => options.min_free = gib * GIB,
+                None => return Err(format!("--min-free takes a number of GiB\n{}", USAGE).into()),
+            },
+            "--interval" => match args.next().and_then(|n| n.parse::<u64>().ok()) {
+                Some(minutes) if minutes > 0 => {
+                    options.interval = Duration::from_secs(minutes * 60)
+                }
+                _ => return Err(format!("--interval takes a number of minutes\n{}", USAGE).into()),
+            },
+            _ if arg.starts_with("--") => {
+                return Err(format!("unknown argument {}\n{}", arg, USAGE).into())
+            }
+            _ => options.paths.push(PathBuf::from(arg)),
+        }
+    }
+    Ok(options)
+}
+
+// the files below path, symlinks not followed
+fn dir_scan(path: &Path) -> SgeResult<DirStat> {
+    let mut stat = DirStat::default();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = fs::symlink_metadata(entry.path())?;
+        let (size, modified) = if metadata.is_dir() {
+            let sub = dir_scan(&entry.path())?;
+            (sub.size, sub.newest)
+        } else {
+            (metadata.len(), metadata.modified().ok())
+        };
+        stat.size += size;
+        if modified > stat.newest {
+            stat.newest = modified;
+        }
+    }
+    Ok(stat)
+}
+
+fn days_since(time: SystemTime) -> u64 {
+    let age = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_else(|_| Duration::from_secs(0));
+    age.as_secs() / (24 * 60 * 60)
+}
+
+fn size_format(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, units[unit]),
+    }
+}
 
 fn get_monorepo_base_path() -> SgeResult<PathBuf> {
     let mut dir = env::current_dir()?;
@@ -36,50 +300,603 @@ fn get_monorepo_base_path() -> SgeResult<PathBuf> {
     }
 }
 
+// the paths of clean.toml, an array of strings that may span lines:
+// paths = ["libs", "tools"]
+fn config_parse(text: &str) -> SgeResult<Vec<String>> {
+    // comments end lines, paths hold no #
+    let text: Vec<&str> = text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .collect();
+    let text = text.join("\n");
+    let value = match text.trim().strip_prefix("paths") {
+        Some(rest) => rest.trim_start().strip_prefix('='),
+        None => None,
+    };
+    let array = value
+        .map(|v| v.trim())
+        .and_then(|v| v.strip_prefix('['))
+        .and_then(|v| v.strip_suffix(']'));
+    let array = match array {
+        Some(array) => array,
+        None => return Err(format!("{}: expected paths = [\"dir\", ...]", CONFIG_NAME).into()),
+    };
+    let mut paths = Vec::new();
+    for item in array.split(',').map(|i| i.trim()).filter(|i| !i.is_empty()) {
+        match item.strip_prefix('"').and_then(|i| i.strip_suffix('"')) {
+            Some(path) => paths.push(path.to_string()),
+            None => return Err(format!("{}: expected a string, not {}", CONFIG_NAME, item).into()),
+        }
+    }
+    Ok(paths)
+}
+
+// the directories to clean, those of the command line or those of the config of the monorepo
+fn paths_get(options: &Options) -> SgeResult<Vec<PathBuf>> {
+    if !options.paths.is_empty() {
+        return Ok(options.paths.clone());
+    }
+    let base = get_monorepo_base_path()?;
+    let paths = config_read(&base)?;
+    Ok(paths.iter().map(|p| base.join(p)).collect())
+}
+
+// the paths of the config in base, DEFAULT_PATHS when there is none
+fn config_read(base: &Path) -> SgeResult<Vec<String>> {
+    match fs::read_to_string(base.join(CONFIG_NAME)) {
+        Ok(text) => config_parse(&text),
+        Err(e) if io::ErrorKind::NotFound == e.kind() => {
+            Ok(DEFAULT_PATHS.iter().map(|p| p.to_string()).collect())
+        }
+        Err(e) => Err(format!("{}: {}", CONFIG_NAME, e).into()),
+    }
+}
+
 fn cargo_clean(path: &PathBuf) -> SgeResult<()> {
-    println!("cargo clean: {:#?}", path);
-    let status = Command::new("cargo")
+    // the output of cargo would break the progress line, its first line is kept for failures
+    let output = Command::new("cargo")
         .args(&["clean"])
         .current_dir(path)
-        .status()?;
-    if !status.success() {
-        println!("  FAILED");
+        .output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "cargo clean exited with {}, {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+                .lines()
+                .next()
+                .unwrap_or("")
+        )
+        .into());
     }
     Ok(())
 }
 
-fn toml_process(base_dir: PathBuf) -> SgeResult<()> {
+// the days since a target directory was built when --older-than keeps it, empty ones are cleaned
+fn age_kept(stat: &DirStat, options: &Options) -> Option<u64> {
+    let days = options.older_than?;
+    stat.newest.map(days_since).filter(|&age| age < days)
+}
+
+// clean the target directory of a crate, dry runs only report its size
+fn crate_process(
+    target: &Target,
+    options: &Options,
+    report: &mut Report,
+    progress: &mut Progress,
+) -> SgeResult<()> {
+    let crate_dir = &target.crate_dir;
+    if let Some(age) = age_kept(&target.stat, options) {
+        progress.clear();
+        report.kept += 1;
+        out!(
+            options,
+            "kept, built {} days ago: {}",
+            age,
+            crate_dir.display()
+        );
+        return Ok(());
+    }
+    if options.dry_run {
+        out!(
+            options,
+            "{:>12}  {}",
+            size_format(target.stat.size),
+            crate_dir.display()
+        );
+    } else {
+        progress.show(report.bytes_reclaimed(), crate_dir);
+        cargo_clean(crate_dir)?;
+    }
+    report
+        .cleaned
+        .push((crate_dir.join("target"), target.stat.size));
+    Ok(())
+}
+
+// the largest target directories, with when they were last built
+fn ranking_print(targets: &[Target], options: &Options) {
+    let mut ranking: Vec<&Target> = targets.iter().collect();
+    ranking.sort_by_key(|target| Reverse(target.stat.size));
+    out!(options, "largest target directories:");
+    for target in ranking.iter().take(options.top) {
+        let built = match target.stat.newest.map(days_since) {
+            Some(days) => format!("built {} days ago", days),
+            None => String::from("empty"),
+        };
+        out!(
+            options,
+            "{:>12}  {}  {}",
+            size_format(target.stat.size),
+            target.crate_dir.display(),
+            built
+        );
+    }
+}
+
+// the crates below base_dir
+fn toml_process(
+    base_dir: PathBuf,
+    crates: &mut Vec<PathBuf>,
+    report: &mut Report,
+) -> SgeResult<()> {
     let toml = base_dir.join("Cargo.toml");
-    if toml.exists() {
-        cargo_clean(&base_dir)?;
+    let is_crate = toml.exists();
+    if is_crate {
+        crates.push(base_dir.clone());
     }
-    let entries = fs::read_dir(base_dir)?;
+    let entries = fs::read_dir(&base_dir)?;
     for entry in entries {
         let entry = entry?;
+        // the target directory of a crate holds no crates of the repo
+        if is_crate && entry.file_name() == "target" {
+            continue;
+        }
         if entry.path().is_dir() {
-            if let Err(e) = toml_process(entry.path()) {
-                println!("directory process error: {:#?}", e)
+            if let Err(e) = toml_process(entry.path(), crates, report) {
+                report.failures.push((entry.path(), e.to_string()));
             }
         }
     }
     Ok(())
 }
 
-fn paths_process() -> SgeResult<()> {
-    // we only want to crawl a subset of the monorepo
-    let rust_paths = &["build", "libs", "third_party/rust", "tools"];
-    let base = get_monorepo_base_path()?;
-    for r in rust_paths {
-        let sub_dir = base.join(r);
-        if let Err(e) = toml_process(sub_dir) {
-            println!("error processing sub directory: {}", e)
+fn paths_process(options: &Options) -> SgeResult<Report> {
+    let mut crates = Vec::new();
+    let mut report = Report::default();
+    for sub_dir in paths_get(options)? {
+        if let Err(e) = toml_process(sub_dir.clone(), &mut crates, &mut report) {
+            report.failures.push((sub_dir, e.to_string()));
         }
     }
-    Ok(())
+    // every target directory is measured before any is cleaned
+    let mut targets = Vec::new();
+    for crate_dir in crates {
+        let target = crate_dir.join("target");
+        if !target.is_dir() {
+            continue;
+        }
+        match dir_scan(&target) {
+            Ok(stat) => targets.push(Target { crate_dir, stat }),
+            Err(e) => report.failures.push((target, e.to_string())),
+        }
+    }
+    ranking_print(&targets, options);
+    if !options.report_only {
+        out!(options, "");
+        let mut progress = Progress::new(targets.len());
+        for (done, target) in targets.iter().enumerate() {
+            progress.done = done;
+            if let Err(e) = crate_process(target, options, &mut report, &mut progress) {
+                report
+                    .failures
+                    .push((target.crate_dir.clone(), e.to_string()));
+            }
+        }
+        progress.clear();
+        out!(options, "{}", summary(&report, options));
+    }
+    eprint!("{}", failures_format(&report));
+    match options.json.as_deref() {
+        Some("-") => println!("{}", report_json(&report, options)),
+        Some(file) => fs::write(file, report_json(&report, options) + "\n")?,
+        None => (),
+    }
+    Ok(report)
+}
+
+// the lines listing the directories that failed, empty when none did
+fn failures_format(report: &Report) -> String {
+    if report.failures.is_empty() {
+        return String::new();
+    }
+    let mut text = format!("\n{} directories failed:\n", report.failures.len());
+    for (path, error) in &report.failures {
+        text.push_str(&format!("  {}: {}\n", path.display(), error));
+    }
+    text
+}
+
+// the bytes freed, or that a dry run would free, and what was kept or failed
+fn summary(report: &Report, options: &Options) -> String {
+    let mut summary = if options.dry_run {
+        format!(
+            "{:>12}  reclaimable in {} target directories, nothing deleted",
+            size_format(report.bytes_reclaimed()),
+            report.cleaned.len()
+        )
+    } else {
+        format!(
+            "{:>12}  freed in {} target directories",
+            size_format(report.bytes_reclaimed()),
+            report.cleaned.len()
+        )
+    };
+    if report.kept > 0 {
+        summary.push_str(&format!(", {} kept", report.kept));
+    }
+    if !report.failures.is_empty() {
+        summary.push_str(&format!(", {} failed", report.failures.len()));
+    }
+    summary
+}
+
+// text as a quoted json string
+fn json_string(text: &str) -> String {
+    // a string always serializes
+    serde_json::to_string(text).unwrap_or_default()
+}
+
+fn report_json(report: &Report, options: &Options) -> String {
+    let cleaned: Vec<String> = report
+        .cleaned
+        .iter()
+        .map(|(path, size)| {
+            format!(
+                "{{\"path\": {}, \"bytes\": {}}}",
+                json_string(&path.display().to_string()),
+                size
+            )
+        })
+        .collect();
+    let failures: Vec<String> = report
+        .failures
+        .iter()
+        .map(|(path, error)| {
+            format!(
+                "{{\"path\": {}, \"error\": {}}}",
+                json_string(&path.display().to_string()),
+                json_string(error)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"dry_run\": {}, \"bytes_reclaimed\": {}, \"cleaned\": [{}], \"failures\": [{}]}}",
+        options.dry_run,
+        report.bytes_reclaimed(),
+        cleaned.join(", "),
+        failures.join(", ")
+    )
+}
+
+// the bytes available on the disk holding path
+#[cfg(windows)]
+fn disk_free(path: &Path) -> SgeResult<u64> {
+    let script = format!(
+        "(Get-Item -LiteralPath '{}').PSDrive.Free",
+        path.display().to_string().replace('\'', "''")
+    );
+    let output = Command::new("powershell")
+        .args(&["-NoProfile", "-Command", &script])
+        .output()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    match text.trim().parse::<u64>() {
+        Ok(free) if output.status.success() => Ok(free),
+        _ => Err(format!("no free space of {}", path.display()).into()),
+    }
+}
+
+// the bytes available on the disk holding path
+#[cfg(not(windows))]
+fn disk_free(path: &Path) -> SgeResult<u64> {
+    let output = Command::new("df").arg("-Pk").arg(path).output()?;
+    match df_available(&String::from_utf8_lossy(&output.stdout)) {
+        Some(free) if output.status.success() => Ok(free),
+        _ => Err(format!("no free space of {}", path.display()).into()),
+    }
+}
+
+// the bytes available of the output of df -Pk, a header and a line per disk:
+// Filesystem 1024-blocks Used Available Capacity Mounted on
+#[cfg(not(windows))]
+fn df_available(text: &str) -> Option<u64> {
+    text.lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|kib| kib.parse::<u64>().ok())
+        .map(|kib| kib * 1024)
+}
+
+// yyyy-mm-dd hh:mm:ss of time in UTC
+fn time_format(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0))
+        .as_secs();
+    let (days, rest) = ((secs / 86400) as i64, secs % 86400);
+    // the civil date of days since 1970-01-01, years starting in march
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60
+    )
+}
+
+// lines of the daemon, timestamped
+fn daemon_log(options: &Options, line: &str) {
+    out!(options, "[{} UTC] {}", time_format(SystemTime::now()), line);
+}
+
+// cleans whenever the disk of the current directory has less than min-free available, only
+// returns when there is no current directory
+fn daemon_run(options: &Options) -> SgeResult<()> {
+    let dir = env::current_dir()?;
+    daemon_log(
+        options,
+        &format!(
+            "daemon started, keeping {} free on the disk of {}, checking every {} minutes",
+            size_format(options.min_free),
+            dir.display(),
+            options.interval.as_secs() / 60
+        ),
+    );
+    loop {
+        match disk_free(&dir) {
+            Ok(free) if free < options.min_free => {
+                daemon_log(options, &format!("{} free, cleaning", size_format(free)));
+                if let Err(e) = paths_process(options) {
+                    daemon_log(options, &format!("clean failed: {}", e));
+                }
+                if let Ok(free) = disk_free(&dir) {
+                    daemon_log(
+                        options,
+                        &format!("{} free after cleaning", size_format(free)),
+                    );
+                }
+            }
+            Ok(free) => daemon_log(
+                options,
+                &format!("{} free, nothing to clean", size_format(free)),
+            ),
+            Err(e) => daemon_log(options, &format!("error checking free space: {}", e)),
+        }
+        thread::sleep(options.interval);
+    }
 }
 
 fn main() {
-    if let Err(e) = paths_process() {
-        println!("error: {}", e);
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = options_parse(&args).and_then(|options| {
+        if options.daemon {
+            daemon_run(&options).map(|_| Report::default())
+        } else {
+            paths_process(&options)
+        }
+    });
+    match result {
+        Ok(report) if !report.failures.is_empty() => process::exit(EXIT_FAILURES),
+        Ok(_) => (),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_options_parse() {
+        let options = options_parse(&[]).unwrap();
+        assert!(!options.dry_run && !options.report_only && !options.daemon);
+        assert_eq!(DEFAULT_TOP, options.top);
+        assert_eq!(None, options.older_than);
+        assert_eq!(DEFAULT_MIN_FREE_GIB * GIB, options.min_free);
+        assert!(options.paths.is_empty());
+
+        let options = options_parse(&args(
+            "--dry-run --report-only --top 3 --older-than 30 --json - --daemon --min-free 5 \
+             --interval 10 libs tools",
+        ))
+        .unwrap();
+        assert!(options.dry_run && options.report_only && options.daemon);
+        assert_eq!(3, options.top);
+        assert_eq!(Some(30), options.older_than);
+        assert!(options.json_stdout());
+        assert_eq!(5 * GIB, options.min_free);
+        assert_eq!(Duration::from_secs(600), options.interval);
+        assert_eq!(
+            vec![PathBuf::from("libs"), PathBuf::from("tools")],
+            options.paths
+        );
+
+        for bad in &[
+            "--top",
+            "--top many",
+            "--older-than",
+            "--older-than -1",
+            "--json",
+            "--min-free 1.5",
+            "--interval 0",
+            "--force",
+        ] {
+            assert!(options_parse(&args(bad)).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_size_format() {
+        assert_eq!("0 B", size_format(0));
+        assert_eq!("1023 B", size_format(1023));
+        assert_eq!("1.5 KiB", size_format(1536));
+        assert_eq!("2.0 GiB", size_format(2 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn test_config_parse() {
+        assert_eq!(
+            Ok(vec![String::from("libs"), String::from("tools/rust")]),
+            config_parse(
+                "# crates of the repo\npaths = [\n  \"libs\", # shared\n  \"tools/rust\",\n]\n"
+            )
+        );
+        assert_eq!(Ok(Vec::new()), config_parse("paths = []"));
+        assert!(config_parse("").is_err());
+        assert!(config_parse("dirs = [\"libs\"]").is_err());
+        assert!(config_parse("paths = [libs]").is_err());
+        assert!(config_parse("paths = \"libs\"").is_err());
+
+        // only a missing config falls back to the default paths
+        let base = env::temp_dir().join(format!("rust_cleaner_config_{}", process::id()));
+        fs::create_dir_all(&base).unwrap();
+        assert_eq!(
+            Ok(DEFAULT_PATHS.iter().map(|p| p.to_string()).collect()),
+            config_read(&base)
+        );
+        fs::write(base.join(CONFIG_NAME), "paths = [\"libs\"]").unwrap();
+        assert_eq!(Ok(vec![String::from("libs")]), config_read(&base));
+        fs::remove_file(base.join(CONFIG_NAME)).unwrap();
+        fs::create_dir(base.join(CONFIG_NAME)).unwrap();
+        assert!(config_read(&base).is_err());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_report_json() {
+        assert_eq!("\"a\\\"b\\\\c\\n\\u0001\"", json_string("a\"b\\c\n\u{1}"));
+
+        let report = Report {
+            cleaned: vec![(PathBuf::from("tools/a \"b\"/target"), 5000)],
+            kept: 1,
+            failures: vec![(PathBuf::from("tools/c"), String::from("denied\nby policy"))],
+        };
+        let options = Options {
+            dry_run: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            "{\"dry_run\": true, \"bytes_reclaimed\": 5000, \
+             \"cleaned\": [{\"path\": \"tools/a \\\"b\\\"/target\", \"bytes\": 5000}], \
+             \"failures\": [{\"path\": \"tools/c\", \"error\": \"denied\\nby policy\"}]}",
+            report_json(&report, &options)
+        );
+        assert_eq!(
+            "{\"dry_run\": false, \"bytes_reclaimed\": 0, \"cleaned\": [], \"failures\": []}",
+            report_json(&Report::default(), &Options::default())
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        let report = Report {
+            cleaned: vec![(PathBuf::from("tools/a/target"), 1536)],
+            kept: 2,
+            failures: vec![(PathBuf::from("tools/b"), String::from("denied"))],
+        };
+        assert_eq!(
+            format!(
+                "{:>12}  freed in 1 target directories, 2 kept, 1 failed",
+                "1.5 KiB"
+            ),
+            summary(&report, &Options::default())
+        );
+        let options = Options {
+            dry_run: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format!(
+                "{:>12}  reclaimable in 0 target directories, nothing deleted",
+                "0 B"
+            ),
+            summary(&Report::default(), &options)
+        );
+    }
+
+    #[test]
+    fn test_failures_format() {
+        assert_eq!("", failures_format(&Report::default()));
+        let report = Report {
+            failures: vec![
+                (PathBuf::from("tools/a"), String::from("denied")),
+                (PathBuf::from("libs/b/target"), String::from("not found")),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            "\n2 directories failed:\n  tools/a: denied\n  libs/b/target: not found\n",
+            failures_format(&report)
+        );
+    }
+
+    #[test]
+    fn test_time_format() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!("1970-01-01 00:00:00", time_format(at(0)));
+        assert_eq!("2000-02-29 12:34:56", time_format(at(951_827_696)));
+        assert_eq!("2021-12-31 23:59:59", time_format(at(1_640_995_199)));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_df_available() {
+        let linux = "Filesystem     1024-blocks      Used Available Capacity Mounted on\n\
+                     /dev/nvme0n1p2   490617784 301234520 164377408      65% /\n";
+        assert_eq!(Some(164_377_408 * 1024), df_available(linux));
+        let mac = "Filesystem   1024-blocks      Used Available Capacity  Mounted on\n\
+                   /dev/disk3s5   971350180 512337036 422455296    55%    /System/Volumes/Data\n";
+        assert_eq!(Some(422_455_296 * 1024), df_available(mac));
+        assert_eq!(None, df_available(""));
+        assert_eq!(
+            None,
+            df_available("Filesystem 1024-blocks Used Available Capacity Mounted on\n")
+        );
+    }
+
+    #[test]
+    fn test_age_kept() {
+        let built = |days_ago: u64| DirStat {
+            size: 1,
+            newest: Some(SystemTime::now() - Duration::from_secs(days_ago * 24 * 60 * 60 + 60)),
+        };
+        let options = Options {
+            older_than: Some(14),
+            ..Default::default()
+        };
+        assert_eq!(Some(3), age_kept(&built(3), &options));
+        assert_eq!(None, age_kept(&built(14), &options));
+        assert_eq!(None, age_kept(&built(20), &options));
+        assert_eq!(None, age_kept(&DirStat::default(), &options));
+        // everything is cleaned without --older-than
+        assert_eq!(None, age_kept(&built(3), &Options::default()));
     }
 }
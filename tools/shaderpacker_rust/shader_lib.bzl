@@ -18,11 +18,18 @@ def _shader_library_impl(ctx):
         dst_file = ctx.label.name + "/" + f.basename.replace("." + f.extension, ".shader_pkg")
         out = ctx.actions.declare_file(dst_file)
         args = ctx.actions.args()
+        args.add("pack")
+        inputs = [f]
+        if ctx.file.config:
+            args.add("-config", ctx.file.config)
+            inputs.append(ctx.file.config)
+        if ctx.attr.platform:
+            args.add("-platform", ctx.attr.platform)
         args.add("-T", out)
         args.add(f)
         ctx.actions.run(
             executable = ctx.executable._compiler,
-            inputs = [f],
+            inputs = inputs,
             outputs = [out],
             arguments = [args],
         )
@@ -36,6 +43,9 @@ shader_library = rule(
             mandatory = True,
             allow_files = True,
         ),
+        # json file with the defines and include dirs of every platform
+        "config": attr.label(allow_single_file = [".json"]),
+        "platform": attr.string(),
         "_compiler": attr.label(
             default = "//tools/shaderpacker_rust",
             executable = True,
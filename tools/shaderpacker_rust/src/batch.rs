@@ -0,0 +1,207 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module batch compiles many hlsl files in parallel
+// inputs can be files, directories (searched recursively for .hlsl) or glob patterns
+// results are either combined into one package, or written as one package per source
+
+use crate::diagnostics::{compile_error_find, CompileError};
+use crate::signature::package_finish;
+use crate::{
+    cache_open, file_cached_compile, package_build, package_name, save_file, CompileOptions,
+    CompiledSource,
+};
+use error_lib::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const SHADER_EXTENSION: &str = "hlsl";
+pub const PACKAGE_EXTENSION: &str = "shader_pkg";
+
+// InputsExpand resolves files, directories and glob patterns into a sorted list of hlsl files
+pub fn inputs_expand(inputs: &[String]) -> SgeResult<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        if path.is_dir() {
+            dir_walk(path, &mut files)?;
+        } else if input.contains(&['*', '?', '['][..]) {
+            let paths = glob::glob(input)
+                .map_err(|e| SgeError::Message(format!("invalid pattern {}: {}", input, e)))?;
+            let before = files.len();
+            for p in paths {
+                let p = p.map_err(|e| SgeError::Message(e.to_string()))?;
+                if p.is_file() {
+                    files.push(p);
+                }
+            }
+            if files.len() == before {
+                return Err(SgeError::Message(format!("no files match {}", input)));
+            }
+        } else if path.is_file() {
+            files.push(path.to_path_buf());
+        } else {
+            return Err(SgeError::Message(format!("input {} not found", input)));
+        }
+    }
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+fn dir_walk(dir: &Path, files: &mut Vec<PathBuf>) -> SgeResult<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            dir_walk(&path, files)?;
+        } else if path.extension() == Some(SHADER_EXTENSION.as_ref()) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// ParallelMap runs f over items on up to jobs threads, results keep the order of items
+pub fn parallel_map<T, R, F>(items: Vec<T>, jobs: usize, f: F) -> Vec<R>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(T) -> R + Send + Sync + 'static,
+{
+    let count = items.len();
+    let queue = Arc::new(Mutex::new(items.into_iter().enumerate()));
+    let results = Arc::new(Mutex::new(Vec::with_capacity(count)));
+    let f = Arc::new(f);
+    let workers: Vec<_> = (0..std::cmp::max(1, std::cmp::min(jobs, count)))
+        .map(|_| {
+            let queue = queue.clone();
+            let results = results.clone();
+            let f = f.clone();
+            std::thread::spawn(move || loop {
+                let next = queue.lock().unwrap().next();
+                match next {
+                    Some((i, item)) => {
+                        let r = f(item);
+                        results.lock().unwrap().push((i, r));
+                    }
+                    None => return,
+                }
+            })
+        })
+        .collect();
+    for w in workers {
+        w.join().unwrap();
+    }
+    let mut results = std::mem::take(&mut *results.lock().unwrap());
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, r)| r).collect()
+}
+
+// CompileBatch compiles every input, failures of all inputs are reported together
+pub fn compile_batch(
+    inputs: &[PathBuf],
+    options: &CompileOptions,
+    jobs: usize,
+) -> SgeResult<Vec<(PathBuf, CompiledSource)>> {
+    let mut options = options.clone();
+    // the jobs are shared between files and the entry points within each file, so a single large
+    // file still uses every job while many files don't multiply the thread count
+    options.jobs = std::cmp::max(
+        1,
+        options.jobs / std::cmp::max(1, std::cmp::min(jobs, inputs.len())),
+    );
+    // the inputs share one cache
+    let cache = cache_open(&options)?;
+    // SgeError can't cross threads, so failures come back as diagnostics or messages
+    let results = parallel_map(inputs.to_vec(), jobs, move |input| {
+        let result = file_cached_compile(&input, &options, cache.as_ref()).map_err(|e| {
+            match compile_error_find(&e) {
+                Some(compile_error) => Ok(compile_error.clone()),
+                None => Err(e.to_string()),
+            }
+        });
+        (input, result)
+    });
+
+    let mut compiled = Vec::new();
+    let mut errors = CompileError::default();
+    let mut other = Vec::new();
+    for (input, result) in results {
+        match result {
+            Ok(source) => compiled.push((input, source)),
+            Err(Ok(compile_error)) => {
+                for d in compile_error.diagnostics {
+                    errors.push(d);
+                }
+            }
+            Err(Err(e)) => other.push(format!("{}: {}", input.display(), e)),
+        }
+    }
+    if !other.is_empty() {
+        if errors.has_errors() {
+            other.push(errors.to_string());
+        }
+        return Err(SgeError::Message(other.join("\n")));
+    }
+    if errors.has_errors() {
+        return Err(errors.into());
+    }
+    Ok(compiled)
+}
+
+// PackageSave writes all sources into a single package, named after the output file
+pub fn package_save(
+    output: &Path,
+    sources: &[CompiledSource],
+    options: &CompileOptions,
+) -> SgeResult<()> {
+    let name = output
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(package_name)
+        .unwrap_or("");
+    let builder = package_build(name, sources, options)?;
+    save_file(
+        &output.to_string_lossy(),
+        &package_finish(&builder, options)?,
+    )?;
+    Ok(())
+}
+
+// PackagesSave writes one package per source into output_dir, returning the written paths
+pub fn packages_save(
+    output_dir: &Path,
+    sources: &[CompiledSource],
+    options: &CompileOptions,
+) -> SgeResult<Vec<PathBuf>> {
+    std::fs::create_dir_all(output_dir)?;
+    let mut written: Vec<PathBuf> = Vec::new();
+    for source in sources {
+        let name = package_name(&source.name);
+        let output = output_dir.join(format!("{}.{}", name, PACKAGE_EXTENSION));
+        if written.contains(&output) {
+            return Err(SgeError::Message(format!(
+                "more than one source would be written to {}",
+                output.display()
+            )));
+        }
+        let builder = package_build(name, std::slice::from_ref(source), options)?;
+        save_file(
+            &output.to_string_lossy(),
+            &package_finish(&builder, options)?,
+        )?;
+        written.push(output);
+    }
+    Ok(written)
+}
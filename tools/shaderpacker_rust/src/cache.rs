@@ -0,0 +1,106 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module cache keeps compiled bytecode on disk, so unchanged shaders skip dxc
+// entries are keyed by a sha256 over everything that affects the output:
+// the preprocessed source, entry point, profile, arguments, defines and the compiler binary itself
+
+use error_lib::*;
+use lazy_static::*;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+// bump when the cached data or key layout changes, to invalidate old entries
+const CACHE_FORMAT: &str = "1";
+
+lazy_static! {
+    // hashing the dxc library takes a while, so it is done once per run
+    static ref COMPILER_VERSION: String = compiler_version();
+}
+
+#[derive(Clone)]
+pub struct ShaderCache {
+    dir: PathBuf,
+    compiler_version: &'static str,
+}
+
+impl ShaderCache {
+    // Open creates the cache directory if needed, and identifies the dxc in use
+    pub fn open(dir: &Path) -> SgeResult<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(ShaderCache {
+            dir: dir.to_path_buf(),
+            compiler_version: &COMPILER_VERSION,
+        })
+    }
+
+    // Key hashes the given inputs together with the compiler version
+    pub fn key(&self, inputs: &[&str]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(CACHE_FORMAT.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(self.compiler_version.as_bytes());
+        for i in inputs {
+            hasher.update(b"\0");
+            hasher.update(i.as_bytes());
+        }
+        hex_string(&hasher.finalize())
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        std::fs::read(self.path(key)).ok()
+    }
+
+    // Put stores data under key, written to a temporary file first so concurrent readers never see partial entries
+    pub fn put(&self, key: &str, data: &[u8]) -> SgeResult<()> {
+        let path = self.path(key);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[..2]).join(format!("{}.bin", key))
+    }
+}
+
+pub fn hex_string(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// CompilerVersion identifies the dxc library hassle-rs loads, by hashing its contents
+// dxc is loaded from the working directory, bazel also places it next to the binary
+// when it can't be found, a fixed value is used and cache entries survive compiler updates
+pub fn compiler_version() -> String {
+    let lib_name = if cfg!(windows) {
+        "dxcompiler.dll"
+    } else if cfg!(target_os = "macos") {
+        "libdxcompiler.dylib"
+    } else {
+        "libdxcompiler.so"
+    };
+    let exe_dir = std::env::current_exe()
+        .ok()
+        .and_then(|e| e.parent().map(|p| p.to_path_buf()));
+    let candidates = std::iter::once(PathBuf::from(lib_name))
+        .chain(exe_dir.into_iter().map(|d| d.join(lib_name)));
+    for c in candidates {
+        if let Ok(data) = std::fs::read(&c) {
+            return hex_string(&Sha256::digest(&data));
+        }
+    }
+    "unknown".into()
+}
@@ -0,0 +1,85 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module compression packs shader blobs with zstd or lz4
+// the ShaderHeader records the compression used and the uncompressed size, shader_data undoes it
+
+use error_lib::*;
+use rust_shader_headers::render::shader::{
+    Compression, ShaderHeader, ENUM_NAMES_COMPRESSION, ENUM_VALUES_COMPRESSION,
+};
+use std::borrow::Cow;
+
+// zstd level used for packages, favors ratio since packages are built once and loaded often
+const ZSTD_LEVEL: i32 = 19;
+
+// accepts the schema names case insensitively (eg none, zstd, lz4)
+pub fn compression_from_str(input: &str) -> SgeResult<Compression> {
+    for (n, v) in ENUM_NAMES_COMPRESSION
+        .iter()
+        .zip(ENUM_VALUES_COMPRESSION.iter())
+    {
+        if n.eq_ignore_ascii_case(input) {
+            return Ok(*v);
+        }
+    }
+    Err(SgeError::Message(format!(
+        "unknown compression {}, expected one of {}",
+        input,
+        ENUM_NAMES_COMPRESSION.join(", ").to_lowercase()
+    )))
+}
+
+pub fn compress(data: &[u8], compression: Compression) -> SgeResult<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Zstd => Ok(zstd::encode_all(data, ZSTD_LEVEL)?),
+        Compression::Lz4 => Ok(lz4_flex::compress(data)),
+    }
+}
+
+pub fn decompress(
+    data: &[u8],
+    compression: Compression,
+    uncompressed_size: usize,
+) -> SgeResult<Vec<u8>> {
+    let decompressed = match compression {
+        Compression::None => data.to_vec(),
+        Compression::Zstd => zstd::decode_all(data)?,
+        Compression::Lz4 => lz4_flex::decompress(data, uncompressed_size)
+            .map_err(|e| SgeError::Message(format!("lz4 decompression failed: {}", e)))?,
+    };
+    if decompressed.len() != uncompressed_size {
+        return Err(SgeError::Message(format!(
+            "decompressed shader is {} bytes, header says {}",
+            decompressed.len(),
+            uncompressed_size
+        )));
+    }
+    Ok(decompressed)
+}
+
+// ShaderData returns the shader bytecode of a header, decompressing it when needed
+// uncompressed blobs are borrowed straight from the package
+pub fn shader_data<'a>(header: &ShaderHeader<'a>) -> SgeResult<Cow<'a, [u8]>> {
+    let data = header.data().unwrap_or(&[]);
+    match header.compression() {
+        Compression::None => Ok(Cow::Borrowed(data)),
+        c => Ok(Cow::Owned(decompress(
+            data,
+            c,
+            header.uncompressed_size() as usize,
+        )?)),
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module config reads the json build configuration selected with -config, the defines and include
+// dirs every shader is compiled with, globally and per platform
+//
+// {
+//   "defines": ["SHADOW_CASCADES=4"],
+//   "include_dirs": ["shaders/common"],
+//   "platforms": {
+//     "pc": { "defines": ["PLATFORM_PC"] },
+//     "mobile": { "defines": ["PLATFORM_MOBILE", "SHADOW_CASCADES=2"], "include_dirs": ["shaders/mobile"] }
+//   }
+// }
+//
+// platform settings come after the global ones, a platform define replaces a global one of the
+// same name; include dirs are relative to the config file
+
+use crate::CompileOptions;
+use error_lib::*;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlatformConfig {
+    // NAME or NAME=VALUE
+    pub defines: Vec<String>,
+    pub include_dirs: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub defines: Vec<String>,
+    pub include_dirs: Vec<PathBuf>,
+    pub platforms: BTreeMap<String, PlatformConfig>,
+}
+
+// DefineParse splits NAME=VALUE, a define without value is defined as 1 by dxc
+pub fn define_parse(input: &str) -> SgeResult<(String, Option<String>)> {
+    let re = Regex::new(r#"^[A-Za-z_][A-Za-z0-9_]*$"#).unwrap();
+    let (name, value) = match input.find('=') {
+        Some(i) => (&input[..i], Some(input[i + 1..].to_string())),
+        None => (input, None),
+    };
+    if !re.is_match(name) {
+        return Err(SgeError::Message(format!("invalid define {}", input)));
+    }
+    Ok((name.to_string(), value))
+}
+
+impl Config {
+    pub fn from_json(text: &str) -> SgeResult<Self> {
+        serde_json::from_str(text).map_err(|e| SgeError::Message(e.to_string()))
+    }
+
+    // Load reads a config file, making its include dirs relative to the file
+    pub fn load(path: &Path) -> SgeResult<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| SgeError::Message(format!("{}: {}", path.display(), e)))?;
+        let mut config = Config::from_json(&text)
+            .map_err(|e| SgeError::Message(format!("{}: {}", path.display(), e)))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let relative = |dirs: &mut Vec<PathBuf>| {
+            for d in dirs.iter_mut() {
+                *d = dir.join(&d);
+            }
+        };
+        relative(&mut config.include_dirs);
+        for p in config.platforms.values_mut() {
+            relative(&mut p.include_dirs);
+        }
+        Ok(config)
+    }
+
+    // Apply adds the global settings and those of platform to options, after the ones it has
+    pub fn apply(&self, platform: Option<&str>, options: &mut CompileOptions) -> SgeResult<()> {
+        let mut settings = vec![(&self.defines, &self.include_dirs)];
+        if let Some(name) = platform {
+            let p = self.platforms.get(name).ok_or_else(|| {
+                let known: Vec<&str> = self.platforms.keys().map(|k| k.as_str()).collect();
+                SgeError::Message(format!(
+                    "unknown platform {}, the config has: {}",
+                    name,
+                    known.join(", ")
+                ))
+            })?;
+            settings.push((&p.defines, &p.include_dirs));
+        }
+        for (defines, include_dirs) in settings {
+            for d in defines {
+                let (name, value) = define_parse(d)?;
+                match options.defines.iter_mut().find(|d| d.0 == name) {
+                    Some(d) => d.1 = value,
+                    None => options.defines.push((name, value)),
+                }
+            }
+            options.include_dirs.extend(include_dirs.iter().cloned());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module cross translates compiled spirv to other shading languages with SPIRV-Cross
+// glsl is the GL fallback, msl is used on metal; the code of each api is stored next to the spirv
+
+use error_lib::*;
+use rust_shader_headers::render::shader::{
+    ShaderApi, ENUM_NAMES_SHADER_API, ENUM_VALUES_SHADER_API,
+};
+use spirv_cross::{glsl, msl, spirv};
+
+// accepts the schema names case insensitively (eg glsl, msl)
+pub fn shader_api_from_str(input: &str) -> SgeResult<ShaderApi> {
+    for (n, v) in ENUM_NAMES_SHADER_API
+        .iter()
+        .zip(ENUM_VALUES_SHADER_API.iter())
+    {
+        if n.eq_ignore_ascii_case(input) {
+            return Ok(*v);
+        }
+    }
+    Err(SgeError::Message(format!(
+        "unknown cross compilation target {}, expected one of {}",
+        input,
+        ENUM_NAMES_SHADER_API.join(", ").to_lowercase()
+    )))
+}
+
+// dxc emits spirv as little endian bytes, SPIRV-Cross takes words
+fn spirv_words(data: &[u8]) -> SgeResult<Vec<u32>> {
+    if data.len() % 4 != 0 {
+        return Err(SgeError::Message(format!(
+            "spirv of {} bytes is not a whole number of words",
+            data.len()
+        )));
+    }
+    Ok(data
+        .chunks(4)
+        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+        .collect())
+}
+
+// CrossCompile translates a spirv module with a single entry point to the given api
+pub fn cross_compile(data: &[u8], api: ShaderApi) -> SgeResult<String> {
+    let words = spirv_words(data)?;
+    let module = spirv::Module::from_words(&words);
+    let error = |e: spirv_cross::ErrorCode| {
+        SgeError::Message(format!("SPIRV-Cross failed to emit {:?}: {}", api, e))
+    };
+    match api {
+        ShaderApi::Glsl => spirv::Ast::<glsl::Target>::parse(&module)
+            .and_then(|mut ast| ast.compile())
+            .map_err(error),
+        ShaderApi::Msl => spirv::Ast::<msl::Target>::parse(&module)
+            .and_then(|mut ast| ast.compile())
+            .map_err(error),
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module depfile writes Makefile style dependency files, as consumed by ninja and make
+// each package depends on its hlsl sources and every header they include
+
+use crate::CompiledSource;
+use error_lib::*;
+use std::path::{Path, PathBuf};
+
+pub struct DepRule {
+    pub target: PathBuf,
+    pub dependencies: Vec<PathBuf>,
+}
+
+impl DepRule {
+    // New creates the rule of a package built from the given (path, source) pairs
+    pub fn new(target: &Path, sources: &[(&Path, &CompiledSource)]) -> Self {
+        let mut dependencies: Vec<PathBuf> = Vec::new();
+        for (path, source) in sources {
+            for d in std::iter::once(*path).chain(source.includes.iter().map(|i| i.as_path())) {
+                if !dependencies.iter().any(|e| e == d) {
+                    dependencies.push(d.to_path_buf());
+                }
+            }
+        }
+        DepRule {
+            target: target.to_path_buf(),
+            dependencies,
+        }
+    }
+}
+
+// escapes a path for make, spaces and # are special, and paths use forward slashes
+fn path_escape(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('\\', "/")
+        .replace(' ', "\\ ")
+        .replace('#', "\\#")
+        .replace('$', "$$")
+}
+
+pub fn depfile_format(rules: &[DepRule]) -> String {
+    let mut out = String::new();
+    for r in rules {
+        out += &format!("{}:", path_escape(&r.target));
+        for d in &r.dependencies {
+            out += &format!(" \\\n  {}", path_escape(d));
+        }
+        out += "\n";
+    }
+    out
+}
+
+pub fn depfile_write(path: &Path, rules: &[DepRule]) -> SgeResult<()> {
+    std::fs::write(path, depfile_format(rules))?;
+    Ok(())
+}
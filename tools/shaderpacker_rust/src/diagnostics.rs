@@ -0,0 +1,183 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module diagnostics turns dxc output into structured file/line diagnostics
+// a failed compile is returned as SgeError::StdErr holding a CompileError, which callers can downcast
+
+use error_lib::*;
+use regex::Regex;
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub file: String,
+    // 1 based, 0 when the compiler didn't report a location
+    pub line: u32,
+    pub column: u32,
+    pub severity: Severity,
+    pub message: String,
+    // entry point being compiled when the diagnostic was reported, empty for annotation errors
+    pub entry_point: String,
+    pub permutation: u32,
+}
+
+// formatted like compiler output (file:line:column: severity: message), so editors can jump to it
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}: {}",
+            self.file, self.line, self.column, self.severity, self.message
+        )?;
+        if !self.entry_point.is_empty() {
+            write!(f, " [{}", self.entry_point)?;
+            if self.permutation != 0 {
+                write!(f, " permutation {:#x}", self.permutation)?;
+            }
+            write!(f, "]")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompileError {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl CompileError {
+    // Push adds a diagnostic, unless the same one was already reported by another entry point or permutation
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        let duplicate = self.diagnostics.iter().any(|d| {
+            d.file == diagnostic.file
+                && d.line == diagnostic.line
+                && d.column == diagnostic.column
+                && d.severity == diagnostic.severity
+                && d.message == diagnostic.message
+        });
+        if !duplicate {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for d in &self.diagnostics {
+            writeln!(f, "{}", d)?;
+        }
+        write!(f, "{} error(s)", self.errors().count())
+    }
+}
+
+impl From<CompileError> for SgeError {
+    fn from(e: CompileError) -> Self {
+        SgeError::StdErr(Box::new(e))
+    }
+}
+
+// WarningsReport is the json report of the warnings of compiled sources, for CI
+pub fn warnings_report(warnings: &[Diagnostic]) -> SgeResult<String> {
+    serde_json::to_string_pretty(warnings).map_err(|e| SgeError::Message(e.to_string()))
+}
+
+// CompileErrorFind returns the structured diagnostics of a failed compile, if that is what the error holds
+pub fn compile_error_find(e: &SgeError) -> Option<&CompileError> {
+    match e {
+        SgeError::StdErr(e) => e.downcast_ref::<CompileError>(),
+        _ => None,
+    }
+}
+
+// DiagnosticsParse extracts the diagnostics from dxc (and fxc) output
+// source snippets and caret lines following each diagnostic are skipped
+pub fn diagnostics_parse(output: &str) -> Vec<Diagnostic> {
+    let located =
+        Regex::new(r#"^(.*?):(\d+):(\d+): (fatal error|error|warning|note): (.*)$"#).unwrap();
+    // fxc: file(line,column-column): error X3004: message
+    let fxc_located =
+        Regex::new(r#"^(.*?)\((\d+),(\d+)(?:-\d+)?\): (error|warning) \w+: (.*)$"#).unwrap();
+    let unlocated = Regex::new(r#"^(fatal error|error|warning|note): (.*)$"#).unwrap();
+    let mut diagnostics = Vec::new();
+    for line in output.lines() {
+        let line = line.trim_end();
+        let c = located
+            .captures(line)
+            .or_else(|| fxc_located.captures(line));
+        let (file, line, column, severity, message) = if let Some(c) = c {
+            (
+                c[1].to_string(),
+                c[2].parse().unwrap_or(0),
+                c[3].parse().unwrap_or(0),
+                c.get(4).unwrap().as_str(),
+                c[5].to_string(),
+            )
+        } else if let Some(c) = unlocated.captures(line) {
+            (
+                "".to_string(),
+                0,
+                0,
+                c.get(1).unwrap().as_str(),
+                c[2].to_string(),
+            )
+        } else {
+            continue;
+        };
+        let severity = match severity {
+            "warning" => Severity::Warning,
+            "note" => Severity::Note,
+            _ => Severity::Error,
+        };
+        diagnostics.push(Diagnostic {
+            file,
+            line,
+            column,
+            severity,
+            message,
+            entry_point: "".into(),
+            permutation: 0,
+        });
+    }
+    diagnostics
+}
@@ -0,0 +1,63 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module disasm writes spirv disassembly of every shader in a package, for debugging codegen
+// files go to a directory next to the package (lighting.shader_pkg.disasm), one per entry point
+// and permutation, so the output of two compiler versions can be diffed
+
+use crate::compression::shader_data;
+use crate::package_name;
+use error_lib::*;
+use rspirv::binary::Disassemble;
+use rust_shader_headers::render::shader::get_root_as_shader_package;
+use std::path::{Path, PathBuf};
+
+pub const DISASM_EXTENSION: &str = "spvasm";
+
+pub fn disassemble(spirv: &[u8]) -> SgeResult<String> {
+    let module = rspirv::dr::load_bytes(spirv)
+        .map_err(|e| SgeError::Message(format!("invalid spirv: {}", e)))?;
+    Ok(module.disassemble())
+}
+
+// DisasmDir is where the disassembly of a package is written
+pub fn disasm_dir(package: &Path) -> PathBuf {
+    let mut name = package.as_os_str().to_os_string();
+    name.push(".disasm");
+    PathBuf::from(name)
+}
+
+// DisasmWrite disassembles every shader of the package file, returning the written paths
+pub fn disasm_write(package: &Path) -> SgeResult<Vec<PathBuf>> {
+    let data = std::fs::read(package)?;
+    let pkg = get_root_as_shader_package(&data);
+    let dir = disasm_dir(package);
+    std::fs::create_dir_all(&dir)?;
+    let mut written = Vec::new();
+    for s in pkg.shaders().iter().flat_map(|v| v.iter()) {
+        let entry_point = s.entry_point().unwrap_or("");
+        let path = dir.join(format!(
+            "{}.{}.{}.{}",
+            package_name(s.source().unwrap_or("")),
+            entry_point,
+            s.permutation(),
+            DISASM_EXTENSION
+        ));
+        let text = disassemble(&shader_data(&s)?)
+            .map_err(|e| SgeError::Message(format!("{}: {}", entry_point, e)))?;
+        std::fs::write(&path, text)?;
+        written.push(path);
+    }
+    Ok(written)
+}
@@ -0,0 +1,102 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module dxbc compiles the legacy shader model 5_0 DXBC blobs the D3D11 min-spec path needs
+// dxc only emits DXIL and spirv, so fxc (from the windows sdk) is run as a process on the
+// preprocessed source; its blobs are stored next to the spirv ones
+
+use crate::cache::hex_string;
+use crate::{package_name, shader_hash, CompileOptions, Optimization};
+use rust_shader_headers::render::shader::ShaderType;
+use std::path::Path;
+use std::process::Command;
+
+// DxbcProfile is the shader model 5_0 profile of a stage, none for stages added since (ray
+// tracing, mesh shading)
+pub fn dxbc_profile(st: ShaderType) -> Option<&'static str> {
+    match st {
+        ShaderType::Vertex => Some("vs_5_0"),
+        ShaderType::Pixel => Some("ps_5_0"),
+        ShaderType::Geometry => Some("gs_5_0"),
+        ShaderType::Hull => Some("hs_5_0"),
+        ShaderType::Domain => Some("ds_5_0"),
+        ShaderType::Compute => Some("cs_5_0"),
+        _ => None,
+    }
+}
+
+// FxcCompile runs fxc on preprocessed text, returning the bytecode or the fxc output on failure
+// @args are dxc arguments and are not passed, optimization and debug info are
+pub fn fxc_compile(
+    fxc: &Path,
+    name: &str,
+    text: &str,
+    entry_point: &str,
+    profile: &str,
+    defines: &[(&str, Option<&str>)],
+    options: &CompileOptions,
+) -> Result<Vec<u8>, String> {
+    // jobs run in parallel, so the files are unique per process, source, entry point and defines
+    let id = format!(
+        "shaderpacker_{}_{}_{}_{}",
+        std::process::id(),
+        package_name(name),
+        entry_point,
+        hex_string(&shader_hash(format!("{}{:?}", text, defines).as_bytes())[..8])
+    );
+    let input = std::env::temp_dir().join(format!("{}.hlsl", id));
+    let output = std::env::temp_dir().join(format!("{}.dxbc", id));
+    std::fs::write(&input, text).map_err(|e| e.to_string())?;
+
+    let mut command = Command::new(fxc);
+    command
+        .arg("/nologo")
+        .arg("/T")
+        .arg(profile)
+        .arg("/E")
+        .arg(entry_point)
+        .arg("/Fo")
+        .arg(&output);
+    command.arg(match options.optimization {
+        Optimization::Disabled => "/Od",
+        Optimization::O0 => "/O0",
+        Optimization::O1 => "/O1",
+        Optimization::O2 => "/O2",
+        Optimization::O3 => "/O3",
+    });
+    if options.debug_info {
+        command.arg("/Zi");
+    }
+    for (k, v) in defines {
+        command.arg("/D").arg(format!("{}={}", k, v.unwrap_or("1")));
+    }
+    command.arg(&input);
+    let result = command.output();
+    let _ = std::fs::remove_file(&input);
+    let result = result.map_err(|e| format!("can't run {}: {}", fxc.display(), e))?;
+    let log = format!(
+        "{}{}",
+        String::from_utf8_lossy(&result.stderr),
+        String::from_utf8_lossy(&result.stdout)
+    );
+    // fxc names the temporary file in its diagnostics, they should point at the source
+    let log = log.replace(&*input.to_string_lossy(), name);
+    if !result.status.success() {
+        let _ = std::fs::remove_file(&output);
+        return Err(log);
+    }
+    let data = std::fs::read(&output).map_err(|e| e.to_string());
+    let _ = std::fs::remove_file(&output);
+    data
+}
@@ -0,0 +1,446 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module include resolves #include directives before the source is handed to dxc
+// included files are inlined with #line markers, so compiler diagnostics keep pointing at the header
+// "file" includes search the including file's directory first, <file> includes only the include dirs
+// files are found through an IncludeLoader, so sources can also be compiled from memory
+// includes in comments and in blocks known to be inactive (#if 0, the #ifndef of an include guard
+// already defined) are left to dxc, as are missing includes in blocks depending on other macros
+
+use error_lib::*;
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+pub struct Preprocessed {
+    pub text: String,
+    // every include resolved while preprocessing, in first seen order
+    pub includes: Vec<PathBuf>,
+}
+
+// IncludeLoader finds the file an include refers to and reads it, none when there is no such file
+// dir is the including file's directory (none for the source itself), system is set for <file>
+// the returned path identifies the file for #pragma once, recursion checks and depfiles
+pub trait IncludeLoader {
+    fn load(
+        &self,
+        include: &str,
+        dir: Option<&Path>,
+        system: bool,
+    ) -> SgeResult<Option<(PathBuf, String)>>;
+}
+
+impl<F> IncludeLoader for F
+where
+    F: Fn(&str, Option<&Path>, bool) -> SgeResult<Option<(PathBuf, String)>>,
+{
+    fn load(
+        &self,
+        include: &str,
+        dir: Option<&Path>,
+        system: bool,
+    ) -> SgeResult<Option<(PathBuf, String)>> {
+        self(include, dir, system)
+    }
+}
+
+// FileIncludes loads includes from disk, searching the include dirs in order
+pub struct FileIncludes {
+    pub include_dirs: Vec<PathBuf>,
+}
+
+impl FileIncludes {
+    // finds the file an include refers to, dir is the including file's directory
+    pub fn resolve(&self, include: &str, dir: Option<&Path>, system: bool) -> Option<PathBuf> {
+        let local = if system { None } else { dir };
+        local
+            .into_iter()
+            .chain(self.include_dirs.iter().map(|d| d.as_path()))
+            .map(|d| d.join(include))
+            .find(|p| p.is_file())
+    }
+}
+
+impl IncludeLoader for FileIncludes {
+    fn load(
+        &self,
+        include: &str,
+        dir: Option<&Path>,
+        system: bool,
+    ) -> SgeResult<Option<(PathBuf, String)>> {
+        match self.resolve(include, dir, system) {
+            Some(path) => {
+                let path = path.canonicalize()?;
+                let contents = std::fs::read_to_string(&path)?;
+                Ok(Some((path, contents)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+pub struct IncludeResolver<'a> {
+    loader: Box<dyn IncludeLoader + 'a>,
+    include_re: Regex,
+    pragma_once_re: Regex,
+    directive_re: Regex,
+}
+
+struct State {
+    includes: Vec<PathBuf>,
+    pragma_once: Vec<PathBuf>,
+    stack: Vec<PathBuf>,
+    // macros known defined at the current line, when it is reached, include guards among them
+    defines: Vec<String>,
+}
+
+// a conditional block, whether its current branch and any of the earlier ones are active
+// none when that depends on macros only dxc knows (-D, permutation defines, ...)
+struct Conditional {
+    active: Option<bool>,
+    taken: Option<bool>,
+    // macros known defined when the block was entered
+    defines: Vec<String>,
+}
+
+impl Conditional {
+    // macros defined in an undecided branch are only known inside it
+    fn branch_end(&self, defines: &mut Vec<String>) {
+        if self.active.is_none() {
+            defines.retain(|d| self.defines.contains(d));
+        }
+    }
+}
+
+// active when every enclosing branch is, inactive when any is not, otherwise undecided
+// certain is unset in files included from undecided branches
+fn conditionals_active(certain: bool, conditionals: &[Conditional]) -> Option<bool> {
+    if conditionals.iter().any(|c| c.active == Some(false)) {
+        Some(false)
+    } else if certain && conditionals.iter().all(|c| c.active == Some(true)) {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+// the name of #define NAME(args) value
+fn macro_name(argument: &str) -> &str {
+    argument
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .next()
+        .unwrap_or("")
+}
+
+// the code of a line without its comments, in_comment carries a /* comment over lines
+fn comments_strip(line: &str, in_comment: &mut bool) -> String {
+    let mut code = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        if *in_comment {
+            if c == '*' && chars.peek() == Some(&'/') {
+                chars.next();
+                *in_comment = false;
+                code.push(' ');
+            }
+            continue;
+        }
+        match c {
+            '"' => quoted = !quoted,
+            '/' if !quoted && chars.peek() == Some(&'/') => break,
+            '/' if !quoted && chars.peek() == Some(&'*') => {
+                chars.next();
+                *in_comment = true;
+                continue;
+            }
+            _ => {}
+        }
+        code.push(c);
+    }
+    code
+}
+
+// the value of a condition when the text decides it, #if 0, #ifndef of a macro defined above, ...
+fn condition_value(directive: &str, condition: &str, defines: &[String]) -> Option<bool> {
+    let condition = condition.trim();
+    let defined = |name: &str| {
+        if defines.iter().any(|d| d == name.trim()) {
+            Some(true)
+        } else {
+            None
+        }
+    };
+    let negate = |value: Option<bool>| value.map(|v| !v);
+    match directive {
+        "ifdef" => defined(condition),
+        "ifndef" => negate(defined(condition)),
+        _ => match condition {
+            "0" => Some(false),
+            "1" => Some(true),
+            _ => {
+                let (negated, rest) = match condition.strip_prefix('!') {
+                    Some(rest) => (true, rest.trim_start()),
+                    None => (false, condition),
+                };
+                let name = rest
+                    .strip_prefix("defined")?
+                    .trim()
+                    .trim_start_matches('(')
+                    .trim_end_matches(')');
+                let value = defined(name);
+                if negated {
+                    negate(value)
+                } else {
+                    value
+                }
+            }
+        },
+    }
+}
+
+impl<'a> IncludeResolver<'a> {
+    // New resolves includes from disk
+    pub fn new(include_dirs: &[PathBuf]) -> Self {
+        IncludeResolver::with_loader(FileIncludes {
+            include_dirs: include_dirs.to_vec(),
+        })
+    }
+
+    pub fn with_loader(loader: impl IncludeLoader + 'a) -> Self {
+        IncludeResolver {
+            loader: Box::new(loader),
+            include_re: Regex::new(r#"^\s*#\s*include\s*([<"])([^">]+)[">]"#).unwrap(),
+            pragma_once_re: Regex::new(r#"^\s*#\s*pragma\s+once\b"#).unwrap(),
+            directive_re: Regex::new(
+                r#"^\s*#\s*(if|ifdef|ifndef|elif|else|endif|define|undef)\b(.*)$"#,
+            )
+            .unwrap(),
+        }
+    }
+
+    // Preprocess inlines the includes of text, name is used for #line markers and error messages
+    // dir is the directory of the source, searched first for its "file" includes (none from memory)
+    pub fn preprocess(
+        &self,
+        text: &str,
+        name: &str,
+        dir: Option<&Path>,
+    ) -> SgeResult<Preprocessed> {
+        let mut state = State {
+            includes: Vec::new(),
+            pragma_once: Vec::new(),
+            stack: Vec::new(),
+            defines: Vec::new(),
+        };
+        let mut out = String::with_capacity(text.len());
+        self.preprocess_text(text, name, dir, None, true, &mut state, &mut out)?;
+        Ok(Preprocessed {
+            text: out,
+            includes: state.includes,
+        })
+    }
+
+    // the macro of the include guard wrapping text, #ifndef X #define X ... #endif
+    fn guard_macro(&self, text: &str) -> Option<String> {
+        let mut in_comment = false;
+        let directives: Vec<(String, String)> = text
+            .lines()
+            .map(|line| comments_strip(line, &mut in_comment))
+            .filter(|code| !code.trim().is_empty())
+            .map(|code| {
+                self.directive_re
+                    .captures(&code)
+                    .map(|groups| (groups[1].to_string(), groups[2].trim().to_string()))
+                    .unwrap_or_default()
+            })
+            .collect();
+        let guard = match directives.as_slice() {
+            [(ifndef, guard), (define, defined), ..]
+                if ifndef == "ifndef" && define == "define" && macro_name(defined) == guard =>
+            {
+                guard
+            }
+            _ => return None,
+        };
+        // the #endif closing the #ifndef is the last line
+        let mut depth = 0;
+        for (i, (directive, _)) in directives.iter().enumerate() {
+            match directive.as_str() {
+                "if" | "ifdef" | "ifndef" => depth += 1,
+                "endif" => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                return if i + 1 == directives.len() {
+                    Some(guard.clone())
+                } else {
+                    None
+                };
+            }
+        }
+        None
+    }
+
+    // guard is the include guard of text, its block is entered as the guard is not defined yet
+    #[allow(clippy::too_many_arguments)]
+    fn preprocess_text(
+        &self,
+        text: &str,
+        name: &str,
+        dir: Option<&Path>,
+        guard: Option<&str>,
+        certain: bool,
+        state: &mut State,
+        out: &mut String,
+    ) -> SgeResult<()> {
+        let mut in_comment = false;
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let code = comments_strip(line, &mut in_comment);
+            let active = conditionals_active(certain, &conditionals);
+            // the directives stay in the text, dxc evaluates them again with all the macros
+            if let Some(groups) = self.directive_re.captures(&code) {
+                let argument = groups[2].trim();
+                match &groups[1] {
+                    directive @ "if" | directive @ "ifdef" | directive @ "ifndef" => {
+                        let mut value = condition_value(directive, argument, &state.defines);
+                        if conditionals.is_empty() && guard == Some(argument) {
+                            value = Some(true);
+                        }
+                        conditionals.push(Conditional {
+                            active: value,
+                            taken: value,
+                            defines: state.defines.clone(),
+                        });
+                    }
+                    "elif" => {
+                        if let Some(c) = conditionals.last_mut() {
+                            c.branch_end(&mut state.defines);
+                            let value = condition_value("if", argument, &state.defines);
+                            c.active = match (c.taken, value) {
+                                (Some(true), _) | (_, Some(false)) => Some(false),
+                                (Some(false), value) => value,
+                                (None, _) => None,
+                            };
+                            c.taken = match (c.taken, value) {
+                                (Some(true), _) | (_, Some(true)) => Some(true),
+                                (Some(false), Some(false)) => Some(false),
+                                _ => None,
+                            };
+                        }
+                    }
+                    "else" => {
+                        if let Some(c) = conditionals.last_mut() {
+                            c.branch_end(&mut state.defines);
+                            c.active = c.taken.map(|taken| !taken);
+                            c.taken = Some(true);
+                        }
+                    }
+                    "endif" => {
+                        if let Some(c) = conditionals.pop() {
+                            c.branch_end(&mut state.defines);
+                        }
+                    }
+                    // defines of undecided branches hold until the end of the branch
+                    "define" if active != Some(false) => {
+                        state.defines.push(macro_name(argument).to_string());
+                    }
+                    "undef" if active != Some(false) => state.defines.retain(|d| d != argument),
+                    _ => {}
+                }
+                out.push_str(line);
+                out.push('\n');
+                continue;
+            }
+
+            if active != Some(false) && self.pragma_once_re.is_match(&code) {
+                // a file included from an undecided branch may still be included again
+                if let (Some(true), Some(current)) = (active, state.stack.last()) {
+                    state.pragma_once.push(current.clone());
+                }
+                out.push('\n');
+                continue;
+            }
+            let groups = match self.include_re.captures(&code) {
+                Some(groups) if active != Some(false) => groups,
+                _ => {
+                    out.push_str(line);
+                    out.push('\n');
+                    continue;
+                }
+            };
+            let include = &groups[2];
+            let (path, contents) = match self.loader.load(include, dir, &groups[1] == "<")? {
+                Some(found) => found,
+                // dxc reports it, if the block turns out to be active
+                None if active.is_none() => {
+                    out.push_str(line);
+                    out.push('\n');
+                    continue;
+                }
+                None => {
+                    return Err(SgeError::Message(format!(
+                        "{}:{}: cannot open include file {}",
+                        name,
+                        i + 1,
+                        include
+                    )))
+                }
+            };
+            if !state.includes.contains(&path) {
+                state.includes.push(path.clone());
+            }
+            let guard = self.guard_macro(&contents);
+            let guarded = match &guard {
+                Some(guard) => state.defines.contains(guard),
+                None => false,
+            };
+            if guarded || state.pragma_once.contains(&path) {
+                out.push('\n');
+                continue;
+            }
+            if state.stack.contains(&path) {
+                if active.is_none() {
+                    out.push_str(line);
+                    out.push('\n');
+                    continue;
+                }
+                return Err(SgeError::Message(format!(
+                    "{}:{}: recursive include of {}",
+                    name,
+                    i + 1,
+                    include
+                )));
+            }
+
+            let include_name = path.to_string_lossy().replace('\\', "/");
+            out.push_str(&format!("#line 1 \"{}\"\n", include_name));
+            state.stack.push(path.clone());
+            let certain = active == Some(true);
+            self.preprocess_text(
+                &contents,
+                &include_name,
+                path.parent(),
+                guard.as_deref(),
+                certain,
+                state,
+                out,
+            )?;
+            state.stack.pop();
+            out.push_str(&format!("#line {} \"{}\"\n", i + 2, name));
+        }
+        Ok(())
+    }
+}
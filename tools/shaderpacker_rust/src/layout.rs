@@ -0,0 +1,179 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module layout reflects the input signature of vertex shaders from their spirv, so runtimes can
+// build input layouts (vertex attribute descriptions) without hand written tables
+//
+// dxc only names the semantics with -fspv-reflect (UserSemantic decorations), stripped blobs fall
+// back to the in.var.<SEMANTIC> debug names dxc gives the input variables
+
+use error_lib::*;
+use rspirv::dr::{Instruction, Operand};
+use rspirv::spirv::{Decoration, ExecutionModel, Op, StorageClass};
+use rust_shader_headers::render::shader::VertexFormat;
+use std::collections::HashMap;
+
+// an attribute of a vertex shader input signature
+#[derive(Clone, Debug, PartialEq)]
+pub struct VertexAttribute {
+    // hlsl semantic with its index, eg TEXCOORD1, empty when the blob has no names
+    pub semantic: String,
+    pub location: u32,
+    pub format: VertexFormat,
+}
+
+fn id_operand(i: &Instruction, n: usize) -> Option<u32> {
+    match i.operands.get(n) {
+        Some(Operand::IdRef(id)) => Some(*id),
+        _ => None,
+    }
+}
+
+// the format of a scalar or vector type, unknown for anything else (matrices, 16 and 64 bit)
+fn vertex_format(types: &HashMap<u32, &Instruction>, id: u32) -> VertexFormat {
+    let t = match types.get(&id) {
+        Some(t) => t,
+        None => return VertexFormat::Unknown,
+    };
+    let (component, count) = match t.class.opcode {
+        Op::TypeVector => match (id_operand(t, 0), t.operands.get(1)) {
+            (Some(c), Some(Operand::LiteralBit32(n))) => match types.get(&c) {
+                Some(c) => (*c, *n),
+                None => return VertexFormat::Unknown,
+            },
+            _ => return VertexFormat::Unknown,
+        },
+        _ => (*t, 1),
+    };
+    let formats = match (component.class.opcode, component.operands.as_slice()) {
+        (Op::TypeFloat, [Operand::LiteralBit32(32)]) => [
+            VertexFormat::Float,
+            VertexFormat::Float2,
+            VertexFormat::Float3,
+            VertexFormat::Float4,
+        ],
+        (Op::TypeInt, [Operand::LiteralBit32(32), Operand::LiteralBit32(1)]) => [
+            VertexFormat::Int,
+            VertexFormat::Int2,
+            VertexFormat::Int3,
+            VertexFormat::Int4,
+        ],
+        (Op::TypeInt, [Operand::LiteralBit32(32), Operand::LiteralBit32(0)]) => [
+            VertexFormat::Uint,
+            VertexFormat::Uint2,
+            VertexFormat::Uint3,
+            VertexFormat::Uint4,
+        ],
+        _ => return VertexFormat::Unknown,
+    };
+    match count {
+        1..=4 => formats[count as usize - 1],
+        _ => VertexFormat::Unknown,
+    }
+}
+
+// VertexAttributes reflects the inputs of the vertex entry point of a spirv module, by location
+// system values (SV_VertexID, SV_InstanceID) are builtins and not part of the layout
+pub fn vertex_attributes(spirv: &[u8]) -> SgeResult<Vec<VertexAttribute>> {
+    let module = rspirv::dr::load_bytes(spirv)
+        .map_err(|e| SgeError::Message(format!("invalid spirv: {}", e)))?;
+    let entry_point = module
+        .entry_points
+        .iter()
+        .find(|e| e.operands.first() == Some(&Operand::ExecutionModel(ExecutionModel::Vertex)))
+        .ok_or_else(|| SgeError::Message("spirv has no vertex entry point".into()))?;
+    // operands are the execution model, function and name, then the interface variables
+    let interface: Vec<u32> = entry_point
+        .operands
+        .iter()
+        .skip(3)
+        .filter_map(|o| match o {
+            Operand::IdRef(id) => Some(*id),
+            _ => None,
+        })
+        .collect();
+
+    let mut locations = HashMap::new();
+    let mut semantics = HashMap::new();
+    let mut builtins = Vec::new();
+    for a in &module.annotations {
+        let target = match id_operand(a, 0) {
+            Some(target) => target,
+            None => continue,
+        };
+        match (a.class.opcode, a.operands.get(1), a.operands.get(2)) {
+            (
+                Op::Decorate,
+                Some(Operand::Decoration(Decoration::Location)),
+                Some(Operand::LiteralBit32(l)),
+            ) => {
+                locations.insert(target, *l);
+            }
+            (Op::Decorate, Some(Operand::Decoration(Decoration::BuiltIn)), _) => {
+                builtins.push(target)
+            }
+            (
+                Op::DecorateString,
+                Some(Operand::Decoration(Decoration::UserSemantic)),
+                Some(Operand::LiteralString(s)),
+            ) => {
+                semantics.insert(target, s.clone());
+            }
+            _ => {}
+        }
+    }
+    for n in &module.debug_names {
+        if let (Some(target), Some(Operand::LiteralString(name))) =
+            (id_operand(n, 0), n.operands.get(1))
+        {
+            if name.starts_with("in.var.") && !semantics.contains_key(&target) {
+                semantics.insert(target, name["in.var.".len()..].to_string());
+            }
+        }
+    }
+
+    let types: HashMap<u32, &Instruction> = module
+        .types_global_values
+        .iter()
+        .filter_map(|t| t.result_id.map(|id| (id, t)))
+        .collect();
+    let mut attributes = Vec::new();
+    for id in interface {
+        let v = match types.get(&id) {
+            Some(v) if v.class.opcode == Op::Variable => v,
+            _ => continue,
+        };
+        if v.operands.first() != Some(&Operand::StorageClass(StorageClass::Input))
+            || builtins.contains(&id)
+        {
+            continue;
+        }
+        let location = locations
+            .get(&id)
+            .copied()
+            .ok_or_else(|| SgeError::Message(format!("vertex input %{} has no location", id)))?;
+        // variables are pointers, the format is the one of the pointee
+        let pointee = v
+            .result_type
+            .and_then(|t| types.get(&t))
+            .and_then(|t| id_operand(t, 1));
+        attributes.push(VertexAttribute {
+            semantic: semantics.get(&id).cloned().unwrap_or_default(),
+            location,
+            format: pointee.map_or(VertexFormat::Unknown, |t| vertex_format(&types, t)),
+        });
+    }
+    attributes.sort_by_key(|a| a.location);
+    Ok(attributes)
+}
@@ -13,29 +13,252 @@
 // limitations under the License.
 
 use error_lib::*;
-use hassle_rs::utils::compile_hlsl;
+use hassle_rs::Dxc;
 use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufWriter;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub mod batch;
+pub mod cache;
+pub mod compression;
+pub mod config;
+pub mod cross;
+pub mod depfile;
+pub mod diagnostics;
+pub mod disasm;
+pub mod dxbc;
+pub mod include;
+pub mod layout;
+pub mod loader;
+pub mod manifest;
+pub mod runtime;
+pub mod server;
+pub mod signature;
+pub mod stats;
+pub mod variant;
+pub mod verifier;
+use diagnostics::*;
+use include::{IncludeLoader, IncludeResolver};
+use layout::VertexAttribute;
 
 // use the target name of rust_library from bazel
 // the rust_library will create a Crate with target name
 use rust_shader_headers::render::shader::{
-    get_root_as_shader_package, ShaderHeader, ShaderHeaderArgs, ShaderPackage, ShaderPackageArgs,
-    ShaderType, ENUM_NAMES_SHADER_TYPE, ENUM_VALUES_SHADER_TYPE,
+    Compression, CrossCode, CrossCodeArgs, ShaderApi, ShaderHeader, ShaderHeaderArgs,
+    ShaderPackage, ShaderPackageArgs, ShaderType, VertexInput, VertexInputArgs,
+    ENUM_NAMES_SHADER_TYPE, ENUM_VALUES_SHADER_TYPE,
 };
 
-fn get_shader_target(st: ShaderType) -> &'static str {
+// shader model the compiler targets, written as dxc does in profiles (eg 6_5 in ps_6_5)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ShaderModel {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl ShaderModel {
+    // range of shader models supported by the dxc version we ship
+    pub const MIN: ShaderModel = ShaderModel { major: 6, minor: 0 };
+    pub const MAX: ShaderModel = ShaderModel { major: 6, minor: 7 };
+    // first shader model with ray tracing libraries
+    pub const RAY_TRACING: ShaderModel = ShaderModel { major: 6, minor: 3 };
+    // first shader model with mesh and amplification shaders
+    pub const MESH_SHADING: ShaderModel = ShaderModel { major: 6, minor: 5 };
+}
+
+impl Default for ShaderModel {
+    fn default() -> Self {
+        ShaderModel::MIN
+    }
+}
+
+impl std::fmt::Display for ShaderModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}_{}", self.major, self.minor)
+    }
+}
+
+// accepts both 6_5 and 6.5
+impl std::str::FromStr for ShaderModel {
+    type Err = SgeError;
+
+    fn from_str(s: &str) -> SgeResult<Self> {
+        let unsupported = || {
+            SgeError::Message(format!(
+                "unsupported shader model {}, expected {} through {}",
+                s,
+                ShaderModel::MIN,
+                ShaderModel::MAX
+            ))
+        };
+        let mut parts = s.split(&['_', '.'][..]);
+        let major = parts.next().and_then(|p| p.parse().ok());
+        let minor = parts.next().and_then(|p| p.parse().ok());
+        match (major, minor, parts.next()) {
+            (Some(major), Some(minor), None) => {
+                let sm = ShaderModel { major, minor };
+                if sm < ShaderModel::MIN || sm > ShaderModel::MAX {
+                    return Err(unsupported());
+                }
+                Ok(sm)
+            }
+            _ => Err(unsupported()),
+        }
+    }
+}
+
+// dxc optimization level, Disabled is -Od
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Optimization {
+    Disabled,
+    O0,
+    O1,
+    O2,
+    O3,
+}
+
+impl Optimization {
+    const ALL: [Optimization; 5] = [
+        Optimization::Disabled,
+        Optimization::O0,
+        Optimization::O1,
+        Optimization::O2,
+        Optimization::O3,
+    ];
+
+    // Arg is the dxc flag of the level, eg -O3
+    pub fn arg(self) -> &'static str {
+        match self {
+            Optimization::Disabled => "-Od",
+            Optimization::O0 => "-O0",
+            Optimization::O1 => "-O1",
+            Optimization::O2 => "-O2",
+            Optimization::O3 => "-O3",
+        }
+    }
+
+    // FromArg parses a dxc optimization flag
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        Optimization::ALL.iter().find(|o| o.arg() == arg).copied()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CompileOptions {
+    pub shader_model: ShaderModel,
+    // directories searched for #include, in order
+    pub include_dirs: Vec<PathBuf>,
+    // defines every shader is compiled with, none as value defines the name as 1
+    pub defines: Vec<(String, Option<String>)>,
+    // compression applied to each shader blob
+    pub compression: Compression,
+    // directory of the incremental compilation cache, none disables caching
+    pub cache_dir: Option<PathBuf>,
+    pub optimization: Optimization,
+    // emit debug info (-Zi)
+    pub debug_info: bool,
+    // leave out reflection and debug info, for release packages
+    pub strip: bool,
+    // apis the spirv is translated to with SPIRV-Cross
+    pub cross_targets: Vec<ShaderApi>,
+    // entry points and permutations of a source compiled in parallel
+    pub jobs: usize,
+    // key packages are signed with, none leaves them unsigned
+    pub signing_key: Option<Vec<u8>>,
+    // store the preprocessed source in the package, so captures and crash dumps can show it
+    pub embed_source: bool,
+    // fail compiles dxc reports warnings for
+    pub warnings_as_errors: bool,
+    // fxc also compiling shader model 5_0 dxbc blobs, for the d3d11 path; none skips them
+    pub fxc: Option<PathBuf>,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            shader_model: ShaderModel::default(),
+            include_dirs: Vec::new(),
+            defines: Vec::new(),
+            compression: Compression::None,
+            cache_dir: None,
+            // dxc optimizes with -O3 unless told otherwise
+            optimization: Optimization::O3,
+            debug_info: false,
+            strip: false,
+            cross_targets: Vec::new(),
+            jobs: 1,
+            signing_key: None,
+            embed_source: false,
+            warnings_as_errors: false,
+            fxc: None,
+        }
+    }
+}
+
+// ray tracing stages have no profile of their own, they are compiled as dxil libraries
+pub fn shader_type_is_library(st: ShaderType) -> bool {
+    matches!(
+        st,
+        ShaderType::RayGeneration
+            | ShaderType::Miss
+            | ShaderType::ClosestHit
+            | ShaderType::AnyHit
+            | ShaderType::Intersection
+            | ShaderType::Callable
+    )
+}
+
+// profile prefix of a shader type and the first shader model supporting it
+fn shader_type_profile(st: ShaderType) -> (&'static str, ShaderModel) {
     match st {
-        ShaderType::Compute => "cs_6_0",
-        ShaderType::Domain => "ds_6_0",
-        ShaderType::Geometry => "gs_6_0",
-        ShaderType::Hull => "hs_6_0",
-        ShaderType::Pixel => "ps_6_0",
-        ShaderType::Vertex => "vs_6_0",
+        ShaderType::Compute => ("cs", ShaderModel::MIN),
+        ShaderType::Domain => ("ds", ShaderModel::MIN),
+        ShaderType::Geometry => ("gs", ShaderModel::MIN),
+        ShaderType::Hull => ("hs", ShaderModel::MIN),
+        ShaderType::Pixel => ("ps", ShaderModel::MIN),
+        ShaderType::Vertex => ("vs", ShaderModel::MIN),
+        ShaderType::RayGeneration
+        | ShaderType::Miss
+        | ShaderType::ClosestHit
+        | ShaderType::AnyHit
+        | ShaderType::Intersection
+        | ShaderType::Callable => ("lib", ShaderModel::RAY_TRACING),
+        ShaderType::Mesh => ("ms", ShaderModel::MESH_SHADING),
+        ShaderType::Amplification => ("as", ShaderModel::MESH_SHADING),
+    }
+}
+
+// libraries need at least shader model 6_3 and mesh shading 6_5, lower requested models are raised
+pub fn get_shader_target(st: ShaderType, sm: ShaderModel) -> String {
+    let (prefix, min) = shader_type_profile(st);
+    format!("{}_{}", prefix, std::cmp::max(sm, min))
+}
+
+// ProfileValidate checks a profile override (eg ps_6_6) can compile the shader type, returning its
+// shader model; unlike -sm, models too old for the stage are errors rather than raised
+pub fn profile_validate(st: ShaderType, profile: &str) -> SgeResult<ShaderModel> {
+    let (prefix, min) = shader_type_profile(st);
+    let sm: ShaderModel = match profile.find('_') {
+        Some(i) if &profile[..i] == prefix => profile[i + 1..].parse()?,
+        _ => {
+            return Err(SgeError::Message(format!(
+                "profile {} doesn't match shader type {:?}, expected {}_<model>",
+                profile, st, prefix
+            )))
+        }
+    };
+    if sm < min {
+        return Err(SgeError::Message(format!(
+            "{:?} shaders need shader model {} or later, {} is too old",
+            st, min, profile
+        )));
     }
+    Ok(sm)
 }
 
 fn read_file(file_name: &str) -> std::io::Result<Vec<u8>> {
@@ -46,7 +269,7 @@ fn read_file(file_name: &str) -> std::io::Result<Vec<u8>> {
     Ok(data)
 }
 
-fn save_file(file_name: &str, data: &[u8]) -> std::io::Result<()> {
+pub(crate) fn save_file(file_name: &str, data: &[u8]) -> std::io::Result<()> {
     let file = File::create(file_name)?;
     let mut buf_writer = BufWriter::new(file);
     buf_writer.write_all(data)?;
@@ -72,7 +295,7 @@ fn regex_collector<'a>(re: &Regex, input: &'a str) -> Option<Vec<&'a str>> {
 }
 
 // cannot use impl FromStr for ShaderType since ShaderType is from external Crate
-fn shader_type_from_str(input: &str) -> SgeResult<ShaderType> {
+pub(crate) fn shader_type_from_str(input: &str) -> SgeResult<ShaderType> {
     for (n, v) in ENUM_NAMES_SHADER_TYPE
         .iter()
         .zip(ENUM_VALUES_SHADER_TYPE.iter())
@@ -84,84 +307,960 @@ fn shader_type_from_str(input: &str) -> SgeResult<ShaderType> {
     Err(SgeError::Literal("name not found"))
 }
 
-pub fn shader_compile<'a>(
+// EntryPointsDiscover finds entry points declared with hlsl attributes, [shader("vertex")] names
+// the stage and [numthreads(..)] alone marks a compute shader, so hlsl from other projects can be
+// packed without @shader annotations
+pub fn entry_points_discover(text: &str) -> Vec<(ShaderType, String)> {
+    // one or more attributes at the start of a line, then the return type and function name
+    let function_re = Regex::new(
+        r#"(?m)^[ \t]*((?:\[[^\[\]]*\]\s*)+)(?:[A-Za-z_][A-Za-z0-9_<>,]*\s+)+([A-Za-z_][A-Za-z0-9_]*)\s*\("#,
+    )
+    .unwrap();
+    let shader_re = Regex::new(r#"\[\s*shader\s*\(\s*"([A-Za-z]+)"\s*\)\s*\]"#).unwrap();
+    let numthreads_re = Regex::new(r#"\[\s*numthreads\s*\("#).unwrap();
+    let mut entry_points = Vec::new();
+    for c in function_re.captures_iter(text) {
+        let attributes = &c[1];
+        // stage names are the schema names in lower case, eg raygeneration for RayGeneration
+        let shader_type = match shader_re.captures(attributes) {
+            Some(stage) => ENUM_NAMES_SHADER_TYPE
+                .iter()
+                .position(|n| n.eq_ignore_ascii_case(&stage[1]))
+                .map(|i| ENUM_VALUES_SHADER_TYPE[i]),
+            None if numthreads_re.is_match(attributes) => Some(ShaderType::Compute),
+            None => None,
+        };
+        if let Some(st) = shader_type {
+            entry_points.push((st, c[2].to_string()));
+        }
+    }
+    entry_points
+}
+
+// FunctionsFind lists the names of the functions defined or declared in hlsl text
+pub fn functions_find(text: &str) -> Vec<String> {
+    let re = Regex::new(
+        r#"(?m)^[ \t]*(?:\[[^\[\]]*\]\s*)*(?:[A-Za-z_][A-Za-z0-9_<>,]*\s+)+([A-Za-z_][A-Za-z0-9_]*)\s*\("#,
+    )
+    .unwrap();
+    let mut functions: Vec<String> = Vec::new();
+    for c in re.captures_iter(text) {
+        if !functions.iter().any(|f| f == &c[1]) {
+            functions.push(c[1].to_string());
+        }
+    }
+    functions
+}
+
+// levenshtein distance, to suggest the functions closest to a misspelled entry point
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + if ca == *cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = std::cmp::min(substitution, std::cmp::min(row[j], row[j + 1]) + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// number of functions suggested when an entry point isn't found
+const ENTRY_POINT_CANDIDATES: usize = 3;
+
+fn entry_point_missing(entry_point: &str, functions: &[String]) -> String {
+    let mut candidates: Vec<&String> = functions.iter().collect();
+    candidates.sort_by_key(|f| edit_distance(entry_point, f));
+    candidates.truncate(ENTRY_POINT_CANDIDATES);
+    if candidates.is_empty() {
+        return format!("entry point {} not found", entry_point);
+    }
+    let candidates: Vec<&str> = candidates.iter().map(|c| c.as_str()).collect();
+    format!(
+        "entry point {} not found, candidates: {}",
+        entry_point,
+        candidates.join(", ")
+    )
+}
+
+// every combination of permutation defines is compiled, so keep the cross product bounded
+pub const MAX_PERMUTATION_DEFINES: usize = 8;
+
+// parses the define list of a @permutation(A, B) annotation
+fn permutation_parse(input: &str) -> SgeResult<Vec<String>> {
+    let re = Regex::new(r#"^[A-Za-z_][A-Za-z0-9_]*$"#).unwrap();
+    input
+        .split(',')
+        .map(|d| d.trim())
+        .filter(|d| !d.is_empty())
+        .map(|d| {
+            if re.is_match(d) {
+                Ok(d.to_string())
+            } else {
+                Err(SgeError::Message(format!(
+                    "invalid permutation define {}",
+                    d
+                )))
+            }
+        })
+        .collect()
+}
+
+// defines for a permutation, bit i set defines permutation_defines[i] as 1
+// defines whose bit is clear are left undefined, so both #ifdef and #if work
+pub fn permutation_defines_build(
+    permutation_defines: &[String],
+    permutation: u32,
+) -> Vec<(&str, Option<&str>)> {
+    permutation_defines
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| permutation & (1 << i) != 0)
+        .map(|(_, d)| (d.as_str(), Some("1")))
+        .collect()
+}
+
+// ShaderHash is the sha256 of the uncompressed bytecode, stored in each header for dedup and cache lookups
+pub fn shader_hash(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
+
+// a shader entry point compiled for one permutation
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompiledShader {
+    pub entry_point: String,
+    pub shader_type: ShaderType,
+    // target profile the shader was compiled for, eg ps_6_0
+    pub profile: String,
+    pub permutation: u32,
+    // entry points exported by a library blob, empty for other stages
+    pub exports: Vec<String>,
+    // dxc arguments the blob was compiled with
+    pub arguments: Vec<String>,
+    // the shader translated for other apis
+    pub cross_code: Vec<(ShaderApi, String)>,
+    // uncompressed bytecode
+    pub data: Vec<u8>,
+    // shader model 5_0 bytecode compiled by fxc, empty when not compiled
+    pub dxbc: Vec<u8>,
+    // input signature of vertex shaders, by location, empty for other stages
+    pub vertex_inputs: Vec<VertexAttribute>,
+}
+
+// everything compiled from one source file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompiledSource {
+    // file name of the source, eg lighting.hlsl
+    pub name: String,
+    pub shaders: Vec<CompiledShader>,
+    // defines declared with @permutation, in bit order
+    pub permutation_defines: Vec<String>,
+    // every include resolved while preprocessing
+    pub includes: Vec<PathBuf>,
+    // preprocessed text, kept when options.embed_source is set
+    pub text: Option<String>,
+    // warnings dxc reported, each once even when several entry points or permutations hit it
+    pub warnings: Vec<Diagnostic>,
+}
+
+// one dxc invocation per permutation, of a single stage or of all ray tracing stages as a library
+struct CompileUnit {
+    entry_point: String,
+    stages: Vec<(ShaderType, String)>,
+    profile: String,
+    is_library: bool,
+    exports: Vec<String>,
+    args: Vec<String>,
+}
+
+impl CompileUnit {
+    fn new(
+        entry_point: String,
+        stages: Vec<(ShaderType, String)>,
+        extra_args: &[String],
+        shader_model: ShaderModel,
+        options: &CompileOptions,
+    ) -> Self {
+        let is_library = shader_type_is_library(stages[0].0);
+        let exports = if is_library {
+            stages.iter().map(|s| s.1.clone()).collect()
+        } else {
+            Vec::new()
+        };
+        let mut args = vec!["-spirv", options.optimization.arg()];
+        if !options.strip {
+            args.push("-fspv-reflect");
+            if options.debug_info {
+                args.push("-Zi");
+            }
+        }
+        if is_library {
+            // spirv ray tracing needs the vulkan 1.2 environment
+            args.push("-fspv-target-env=vulkan1.2");
+        }
+        // @args come last, so they can override the defaults
+        let args = args
+            .into_iter()
+            .map(|a| a.to_string())
+            .chain(extra_args.iter().cloned())
+            .collect();
+        CompileUnit {
+            entry_point,
+            profile: get_shader_target(stages[0].0, shader_model),
+            stages,
+            is_library,
+            exports,
+            args,
+        }
+    }
+}
+
+// the preprocessed source and its compile units, shared by the compile jobs of a source
+struct SourceJobs {
+    name: String,
+    text: String,
+    units: Vec<CompileUnit>,
+    permutation_defines: Vec<String>,
+    options: CompileOptions,
+    cache: Option<cache::ShaderCache>,
+}
+
+// the bytecode of a compile, the dxc output (warnings) and why the cache couldn't store it
+type Compiled = (Vec<u8>, String, Option<String>);
+
+impl SourceJobs {
+    // Compile runs dxc for one unit and permutation, or reads the result from the cache
+    // failures are returned as the dxc output, SgeError can't cross threads
+    fn compile(&self, unit: usize, permutation: u32) -> Result<Compiled, String> {
+        let unit = &self.units[unit];
+        let defines = self.defines(permutation);
+        let cache_key = self.cache.as_ref().map(|c| {
+            let defines: Vec<String> = defines
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v.unwrap_or("")))
+                .collect();
+            c.key(&[
+                &self.name,
+                &self.text,
+                &unit.entry_point,
+                &unit.exports.join(" "),
+                &unit.profile,
+                &unit.args.join(" "),
+                &defines.join(" "),
+            ])
+        });
+        if let (Some(c), Some(key)) = (&self.cache, &cache_key) {
+            if let Some(sd) = c.get(key) {
+                return Ok((sd, String::new(), None));
+            }
+        }
+        let args: Vec<&str> = unit.args.iter().map(|a| a.as_str()).collect();
+        let (sd, output) = dxc_compile(
+            &self.name,
+            &self.text,
+            &unit.entry_point,
+            &unit.profile,
+            &args,
+            &defines,
+        )?;
+        // the cache only holds bytecode, so results with warnings are recompiled to report them again
+        // a cache that can't be written only costs time, so the compile still succeeds
+        let mut cache_error = None;
+        if let (Some(c), Some(key), true) = (&self.cache, &cache_key, output.trim().is_empty()) {
+            if let Err(e) = c.put(key, &sd) {
+                cache_error = Some(format!("failed to write shader cache: {}", e));
+            }
+        }
+        Ok((sd, output, cache_error))
+    }
+
+    // CompileDxbc runs fxc for one unit and permutation, empty when there is no fxc or the stage
+    // has no shader model 5_0 profile
+    fn compile_dxbc(&self, unit: usize, permutation: u32) -> Result<Vec<u8>, String> {
+        let unit = &self.units[unit];
+        let (fxc, profile) = match (&self.options.fxc, dxbc::dxbc_profile(unit.stages[0].0)) {
+            (Some(fxc), Some(profile)) => (fxc, profile),
+            _ => return Ok(Vec::new()),
+        };
+        dxbc::fxc_compile(
+            fxc,
+            &self.name,
+            &self.text,
+            &unit.entry_point,
+            profile,
+            &self.defines(permutation),
+            &self.options,
+        )
+    }
+
+    // global defines followed by those of the permutation
+    fn defines(&self, permutation: u32) -> Vec<(&str, Option<&str>)> {
+        let mut defines: Vec<(&str, Option<&str>)> = self
+            .options
+            .defines
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_deref()))
+            .collect();
+        defines.extend(permutation_defines_build(
+            &self.permutation_defines,
+            permutation,
+        ));
+        defines
+    }
+}
+
+// runs dxc on preprocessed text, returning the bytecode and the compiler output, or the output when
+// the compile fails
+fn dxc_compile(
+    name: &str,
+    text: &str,
+    entry_point: &str,
+    profile: &str,
+    args: &[&str],
+    defines: &[(&str, Option<&str>)],
+) -> Result<(Vec<u8>, String), String> {
+    let compile = || -> hassle_rs::Result<Result<(Vec<u8>, String), String>> {
+        let dxc = Dxc::new(None)?;
+        let compiler = dxc.create_compiler()?;
+        let library = dxc.create_library()?;
+        let blob = library.create_blob_with_encoding_from_str(text)?;
+        // includes are inlined already, so dxc needs no include handler
+        match compiler.compile(&blob, name, entry_point, profile, args, None, defines) {
+            Ok(result) => {
+                let sd = result.get_result()?.to_vec();
+                let output = library.get_blob_as_string(&result.get_error_buffer()?.into())?;
+                Ok(Ok((sd, output)))
+            }
+            Err((result, _)) => {
+                let output = library.get_blob_as_string(&result.get_error_buffer()?.into())?;
+                Ok(Err(output))
+            }
+        }
+    };
+    compile().unwrap_or_else(|e| Err(e.to_string()))
+}
+
+// an hlsl source held in memory, eg an editor buffer
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Source {
+    // file name of the source, used for diagnostics and the package name
+    pub name: String,
+    pub text: String,
+}
+
+// SourceCompile compiles every annotated entry point (and permutation) of an hlsl source,
+// includes are read from options.include_dirs
+pub fn source_compile(
     data: &[u8],
     name: &str,
-) -> SgeResult<flatbuffers::FlatBufferBuilder<'a>> {
-    let contents = std::str::from_utf8(&data).unwrap();
+    options: &CompileOptions,
+) -> SgeResult<CompiledSource> {
+    let cache = cache_open(options)?;
+    source_data_compile(data, name, None, options, cache.as_ref())
+}
 
-    let re = Regex::new(r#"\s*@shader\s*\(\s*(\S+)\s*,\s*(\S+)\s*\)"#).unwrap();
+// the compilation cache of options.cache_dir, one is shared by the sources of a run
+pub(crate) fn cache_open(options: &CompileOptions) -> SgeResult<Option<cache::ShaderCache>> {
+    match &options.cache_dir {
+        Some(dir) => Ok(Some(cache::ShaderCache::open(dir)?)),
+        None => Ok(None),
+    }
+}
 
-    let mut shader_text = String::with_capacity(contents.len());
-    let mut variants = Vec::new();
+// compiles a source read from dir, its quoted includes are searched there first
+fn source_data_compile(
+    data: &[u8],
+    name: &str,
+    dir: Option<&Path>,
+    options: &CompileOptions,
+    cache: Option<&cache::ShaderCache>,
+) -> SgeResult<CompiledSource> {
+    let contents = std::str::from_utf8(&data)
+        .map_err(|_| SgeError::Message(format!("{} is not valid utf8", name)))?;
+    let resolver = IncludeResolver::new(&options.include_dirs);
+    source_text_compile(contents, name, dir, None, options, &resolver, cache)
+}
 
-    let mut builder = flatbuffers::FlatBufferBuilder::new();
+// EntryPointCompile compiles one entry point of an hlsl source (every permutation of it), the
+// other entry points the source declares are skipped; @args of the entry point still apply and
+// profile overrides the shader model, as the third @shader argument does
+// dir is the directory of the source, searched first for its quoted includes
+pub fn entry_point_compile(
+    contents: &str,
+    name: &str,
+    dir: Option<&Path>,
+    entry_point: &str,
+    shader_type: ShaderType,
+    profile: Option<&str>,
+    options: &CompileOptions,
+) -> SgeResult<CompiledSource> {
+    let resolver = IncludeResolver::new(&options.include_dirs);
+    let selected = (entry_point, shader_type, profile);
+    let cache = cache_open(options)?;
+    source_text_compile(
+        contents,
+        name,
+        dir,
+        Some(selected),
+        options,
+        &resolver,
+        cache.as_ref(),
+    )
+}
 
-    for line in contents.lines() {
+// CompileSources compiles sources without touching the filesystem, includes are loaded through
+// includes and options.include_dirs is not used (options.cache_dir still is, when set)
+pub fn compile_sources(
+    sources: &[Source],
+    options: &CompileOptions,
+    includes: &dyn IncludeLoader,
+) -> SgeResult<Vec<CompiledSource>> {
+    let resolver = IncludeResolver::with_loader(|include: &str, dir: Option<&Path>, system| {
+        includes.load(include, dir, system)
+    });
+    let cache = cache_open(options)?;
+    sources
+        .iter()
+        .map(|s| {
+            source_text_compile(
+                &s.text,
+                &s.name,
+                None,
+                None,
+                options,
+                &resolver,
+                cache.as_ref(),
+            )
+        })
+        .collect()
+}
+
+// compiles the entry points of a source, or only the selected one (name, type and profile)
+fn source_text_compile(
+    contents: &str,
+    name: &str,
+    dir: Option<&Path>,
+    selected: Option<(&str, ShaderType, Option<&str>)>,
+    options: &CompileOptions,
+    resolver: &IncludeResolver,
+    cache: Option<&cache::ShaderCache>,
+) -> SgeResult<CompiledSource> {
+    // @shader(entry point, type) or @shader(entry point, type, profile)
+    let re =
+        Regex::new(r#"\s*@shader\s*\(\s*([^,\s)]+)\s*,\s*([^,\s)]+)\s*(?:,\s*([^,\s)]+)\s*)?\)"#)
+            .unwrap();
+    let permutation_re = Regex::new(r#"\s*@permutation\s*\(([^)]*)\)"#).unwrap();
+    let args_re = Regex::new(r#"\s*@args\s*\(([^)]*)\)"#).unwrap();
+
+    let mut shader_text = String::with_capacity(contents.len());
+    let mut variants = Vec::new();
+    // annotated entry points and their lines, checked against the functions of the source
+    let mut annotated: Vec<(String, usize)> = Vec::new();
+    let mut permutation_defines = Vec::new();
+    // extra dxc arguments of the next @shader, and the line they were declared on
+    let mut pending_args: Option<(usize, Vec<String>)> = None;
+    let mut errors = CompileError::default();
+    let annotation_error = |line: usize, message: String| Diagnostic {
+        file: name.into(),
+        line: line as u32 + 1,
+        column: 0,
+        severity: Severity::Error,
+        message,
+        entry_point: "".into(),
+        permutation: 0,
+    };
+    for (i, line) in contents.lines().enumerate() {
         if let Some(groups) = regex_collector(&re, line) {
-            let st = shader_type_from_str(groups[2])?;
-            variants.push((st, groups[1].to_string()));
+            let args = pending_args.take().map(|a| a.1).unwrap_or_default();
+            annotated.push((groups[1].to_string(), i));
+            match shader_type_from_str(groups[2]) {
+                Ok(st) if groups[3].is_empty() => {
+                    variants.push((st, groups[1].to_string(), args, options.shader_model))
+                }
+                Ok(st) => match profile_validate(st, groups[3]) {
+                    Ok(sm) => variants.push((st, groups[1].to_string(), args, sm)),
+                    Err(e) => errors.push(annotation_error(i, e.to_string())),
+                },
+                Err(_) => errors.push(annotation_error(
+                    i,
+                    format!("unknown shader type {}", groups[2]),
+                )),
+            }
+        } else if let Some(groups) = regex_collector(&permutation_re, line) {
+            match permutation_parse(groups[1]) {
+                Ok(defines) => {
+                    for d in defines {
+                        if permutation_defines.contains(&d) {
+                            errors.push(annotation_error(
+                                i,
+                                format!("permutation define {} declared twice", d),
+                            ));
+                        } else if options.defines.iter().any(|g| g.0 == d) {
+                            // a global define would be set in every permutation
+                            errors.push(annotation_error(
+                                i,
+                                format!("permutation define {} is also a global define", d),
+                            ));
+                        } else {
+                            permutation_defines.push(d);
+                        }
+                    }
+                }
+                Err(e) => errors.push(annotation_error(i, e.to_string())),
+            }
+        } else if let Some(groups) = regex_collector(&args_re, line) {
+            if pending_args.is_some() {
+                errors.push(annotation_error(
+                    i,
+                    "@args declared twice before @shader".into(),
+                ));
+            }
+            let args = groups[1]
+                .split_whitespace()
+                .map(|a| a.to_string())
+                .collect();
+            pending_args = Some((i, args));
         } else {
             shader_text.push_str(line);
-            shader_text.push('\n');
         }
+        // annotation lines are kept blank, so compiler line numbers match the source
+        shader_text.push('\n');
+    }
+    if let Some((i, _)) = pending_args {
+        errors.push(annotation_error(
+            i,
+            "@args is not followed by @shader".into(),
+        ));
+    }
+    // entry points declared with attributes are added, unless also annotated
+    for (st, entry_point) in entry_points_discover(&shader_text) {
+        if !variants.iter().any(|v| v.1 == entry_point) {
+            variants.push((st, entry_point, Vec::new(), options.shader_model));
+        }
+    }
+    if let Some((entry_point, st, profile)) = selected {
+        let args = variants
+            .iter()
+            .find(|v| v.1 == entry_point)
+            .map(|v| v.2.clone())
+            .unwrap_or_default();
+        let sm = match profile {
+            Some(profile) => profile_validate(st, profile),
+            None => Ok(options.shader_model),
+        };
+        match sm {
+            Ok(sm) => variants = vec![(st, entry_point.to_string(), args, sm)],
+            Err(e) => errors.push(annotation_error(0, e.to_string())),
+        }
+        annotated = vec![(entry_point.to_string(), 0)];
+    }
+    if permutation_defines.len() > MAX_PERMUTATION_DEFINES {
+        errors.push(annotation_error(
+            0,
+            format!(
+                "{} permutation defines declared, at most {} are supported",
+                permutation_defines.len(),
+                MAX_PERMUTATION_DEFINES
+            ),
+        ));
     }
+    if errors.has_errors() {
+        return Err(errors.into());
+    }
+
+    let preprocessed = resolver.preprocess(&shader_text, name, dir)?;
+    let shader_text = preprocessed.text;
+
+    // a misspelled entry point only gets a cryptic dxc error, so check the names first
+    let functions = functions_find(&shader_text);
+    for (entry_point, line) in &annotated {
+        if !functions.contains(entry_point) {
+            errors.push(annotation_error(
+                *line,
+                entry_point_missing(entry_point, &functions),
+            ));
+        }
+    }
+    if errors.has_errors() {
+        return Err(errors.into());
+    }
+
+    // each stage is compiled on its own, except ray tracing stages which are compiled together
+    // into one library, every stage still gets a header and the headers share the library blob
+    let (library, stages): (Vec<_>, Vec<_>) = variants
+        .into_iter()
+        .partition(|v| shader_type_is_library(v.0));
+    let mut units: Vec<CompileUnit> = stages
+        .into_iter()
+        .map(|(st, entry_point, args, sm)| {
+            CompileUnit::new(
+                entry_point.clone(),
+                vec![(st, entry_point)],
+                &args,
+                sm,
+                options,
+            )
+        })
+        .collect();
+    if !library.is_empty() {
+        let args = library[0].2.clone();
+        let sm = library[0].3;
+        if library.iter().any(|v| v.2 != args || v.3 != sm) {
+            errors.push(annotation_error(
+                0,
+                "ray tracing stages are compiled into one library, their @args and profiles must match"
+                    .into(),
+            ));
+            return Err(errors.into());
+        }
+        let stages = library.into_iter().map(|v| (v.0, v.1)).collect();
+        units.push(CompileUnit::new("".into(), stages, &args, sm, options));
+    }
+
+    // every entry point and permutation is a job, jobs run in parallel and results keep the job
+    // order, so packages are deterministic; every job is attempted, so one run reports all errors
+    let permutations = 1u32 << permutation_defines.len();
+    let jobs = Arc::new(SourceJobs {
+        name: name.into(),
+        text: shader_text,
+        units,
+        permutation_defines,
+        options: options.clone(),
+        cache: cache.cloned(),
+    });
+    let job_list: Vec<(usize, u32)> = (0..jobs.units.len())
+        .flat_map(|u| (0..permutations).map(move |p| (u, p)))
+        .collect();
+    let worker_jobs = jobs.clone();
+    let results = batch::parallel_map(job_list.clone(), options.jobs, move |(u, p)| {
+        (worker_jobs.compile(u, p), worker_jobs.compile_dxbc(u, p))
+    });
 
     let mut shaders = Vec::new();
-    for s in variants.iter_mut() {
-        let target_profile = get_shader_target(s.0);
-        let entry_point = &s.1;
-        let args = &["-spirv", "-fspv-reflect"];
-        let defines = &[];
-        let compiled = compile_hlsl(
-            name,
-            &shader_text,
-            &entry_point,
-            target_profile,
-            args,
-            defines,
-        );
-
-        let ep = builder.create_string(&entry_point);
-        let sd = compiled.unwrap();
-        println!("shader size: {}", sd.len());
-        let shader_data = builder.create_vector(&sd);
-        shaders.push(ShaderHeader::create(
-            &mut builder,
-            &ShaderHeaderArgs {
-                entry_point: Some(ep),
+    let mut warnings = CompileError::default();
+    // the first cache failure, the others are likely the same
+    let mut cache_warning = None;
+    for (&(u, permutation), (compiled, dxbc)) in job_list.iter().zip(results) {
+        let unit = &jobs.units[u];
+        let unit_name = if unit.is_library {
+            unit.exports.join(",")
+        } else {
+            unit.entry_point.clone()
+        };
+        let sd = match compiled {
+            Ok((sd, output, cache_error)) => {
+                if let (None, Some(message)) = (&cache_warning, cache_error) {
+                    cache_warning = Some(Diagnostic {
+                        file: name.into(),
+                        line: 0,
+                        column: 0,
+                        severity: Severity::Warning,
+                        message,
+                        entry_point: unit_name.clone(),
+                        permutation,
+                    });
+                }
+                for mut d in diagnostics_parse(&output) {
+                    if d.severity == Severity::Warning {
+                        d.entry_point = unit_name.clone();
+                        d.permutation = permutation;
+                        warnings.push(d);
+                    }
+                }
+                sd
+            }
+            Err(output) => {
+                let mut diagnostics = diagnostics_parse(&output);
+                if diagnostics.is_empty() {
+                    diagnostics.push(Diagnostic {
+                        file: name.into(),
+                        line: 0,
+                        column: 0,
+                        severity: Severity::Error,
+                        message: format!("{} failed to compile: {}", unit.profile, output),
+                        entry_point: "".into(),
+                        permutation: 0,
+                    });
+                }
+                for mut d in diagnostics {
+                    d.entry_point = unit_name.clone();
+                    d.permutation = permutation;
+                    errors.push(d);
+                }
+                continue;
+            }
+        };
+
+        let dxbc = match dxbc {
+            Ok(dxbc) => dxbc,
+            Err(output) => {
+                let mut diagnostics = diagnostics_parse(&output);
+                if diagnostics.is_empty() {
+                    diagnostics.push(Diagnostic {
+                        file: name.into(),
+                        line: 0,
+                        column: 0,
+                        severity: Severity::Error,
+                        message: format!("fxc failed to compile: {}", output),
+                        entry_point: "".into(),
+                        permutation: 0,
+                    });
+                }
+                for mut d in diagnostics {
+                    d.entry_point = unit_name.clone();
+                    d.permutation = permutation;
+                    errors.push(d);
+                }
+                Vec::new()
+            }
+        };
+
+        // libraries have no single entry point to translate, so they stay spirv only
+        let mut cross_code = Vec::new();
+        if !unit.is_library {
+            for &api in &options.cross_targets {
+                match cross::cross_compile(&sd, api) {
+                    Ok(code) => cross_code.push((api, code)),
+                    Err(e) => errors.push(Diagnostic {
+                        file: name.into(),
+                        line: 0,
+                        column: 0,
+                        severity: Severity::Error,
+                        message: e.to_string(),
+                        entry_point: unit.entry_point.clone(),
+                        permutation,
+                    }),
+                }
+            }
+        }
+
+        let mut vertex_inputs = Vec::new();
+        if unit.stages[0].0 == ShaderType::Vertex {
+            match layout::vertex_attributes(&sd) {
+                Ok(attributes) => vertex_inputs = attributes,
+                Err(e) => errors.push(Diagnostic {
+                    file: name.into(),
+                    line: 0,
+                    column: 0,
+                    severity: Severity::Error,
+                    message: e.to_string(),
+                    entry_point: unit.entry_point.clone(),
+                    permutation,
+                }),
+            }
+        }
+
+        for s in &unit.stages {
+            shaders.push(CompiledShader {
+                entry_point: s.1.clone(),
                 shader_type: s.0,
-                data: Some(shader_data),
-                ..Default::default()
-            },
-        ));
+                profile: unit.profile.clone(),
+                permutation,
+                exports: unit.exports.clone(),
+                arguments: unit.args.clone(),
+                cross_code: cross_code.clone(),
+                data: sd.clone(),
+                dxbc: dxbc.clone(),
+                vertex_inputs: vertex_inputs.clone(),
+            });
+        }
+    }
+    if options.warnings_as_errors {
+        for w in &warnings.diagnostics {
+            errors.push(Diagnostic {
+                severity: Severity::Error,
+                ..w.clone()
+            });
+        }
+    }
+    if errors.has_errors() {
+        return Err(errors.into());
+    }
+    // not a warning of the source, so -Werror doesn't fail the compile for it
+    warnings.diagnostics.extend(cache_warning);
+
+    Ok(CompiledSource {
+        name: name.into(),
+        shaders,
+        permutation_defines: jobs.permutation_defines.clone(),
+        includes: preprocessed.includes,
+        text: if options.embed_source {
+            Some(jobs.text.clone())
+        } else {
+            None
+        },
+        warnings: warnings.diagnostics,
+    })
+}
+
+// PackageBuild serializes compiled sources into a ShaderPackage
+pub fn package_build<'a>(
+    name: &str,
+    sources: &[CompiledSource],
+    options: &CompileOptions,
+) -> SgeResult<flatbuffers::FlatBufferBuilder<'a>> {
+    let mut builder = flatbuffers::FlatBufferBuilder::new();
+    let mut shaders = Vec::new();
+    // identical bytecode (eg permutations a shader ignores) is stored once and shared by its headers
+    let mut blobs = HashMap::new();
+    for source in sources {
+        let source_name = builder.create_string(&source.name);
+        let pd: Vec<_> = source
+            .permutation_defines
+            .iter()
+            .map(|d| builder.create_string(d))
+            .collect();
+        let pdv = builder.create_vector(&pd);
+        let source_text = source.text.as_ref().map(|t| builder.create_string(t));
+        for s in &source.shaders {
+            let ep = builder.create_string(&s.entry_point);
+            let profile = builder.create_string(&s.profile);
+            let exports: Vec<_> = s.exports.iter().map(|e| builder.create_string(e)).collect();
+            let exports = builder.create_vector(&exports);
+            let arguments: Vec<_> = s
+                .arguments
+                .iter()
+                .map(|a| builder.create_string(a))
+                .collect();
+            let arguments = builder.create_vector(&arguments);
+            let cross_code: Vec<_> = s
+                .cross_code
+                .iter()
+                .map(|(api, code)| {
+                    let code = builder.create_string(code);
+                    CrossCode::create(
+                        &mut builder,
+                        &CrossCodeArgs {
+                            api: *api,
+                            code: Some(code),
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect();
+            let cross_code = builder.create_vector(&cross_code);
+            let dxbc = if s.dxbc.is_empty() {
+                None
+            } else {
+                Some(builder.create_vector(&s.dxbc))
+            };
+            let vertex_inputs: Vec<_> = s
+                .vertex_inputs
+                .iter()
+                .map(|a| {
+                    let semantic = builder.create_string(&a.semantic);
+                    VertexInput::create(
+                        &mut builder,
+                        &VertexInputArgs {
+                            semantic: Some(semantic),
+                            location: a.location,
+                            format: a.format,
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect();
+            let vertex_inputs = if vertex_inputs.is_empty() {
+                None
+            } else {
+                Some(builder.create_vector(&vertex_inputs))
+            };
+            let hash = shader_hash(&s.data);
+            let (shader_data, shader_hash) = match blobs.get(&hash) {
+                Some(&offsets) => offsets,
+                None => {
+                    let packed = compression::compress(&s.data, options.compression)?;
+                    let offsets = (builder.create_vector(&packed), builder.create_vector(&hash));
+                    blobs.insert(hash, offsets);
+                    offsets
+                }
+            };
+            shaders.push(ShaderHeader::create(
+                &mut builder,
+                &ShaderHeaderArgs {
+                    entry_point: Some(ep),
+                    shader_type: s.shader_type,
+                    data: Some(shader_data),
+                    permutation: s.permutation,
+                    compression: options.compression,
+                    uncompressed_size: s.data.len() as u32,
+                    hash: Some(shader_hash),
+                    source: Some(source_name),
+                    permutation_defines: Some(pdv),
+                    profile: Some(profile),
+                    exports: Some(exports),
+                    arguments: Some(arguments),
+                    cross_code: Some(cross_code),
+                    source_text,
+                    dxbc,
+                    vertex_inputs,
+                    ..Default::default()
+                },
+            ));
+        }
     }
     let sv = builder.create_vector(&shaders);
-    let name_vec: Vec<&str> = name.split(".").collect();
-    let package_name = builder.create_string(&name_vec[0]);
+    let package_name = builder.create_string(name);
+    // reserved here, the signature is written once the package is serialized
+    let signature = options
+        .signing_key
+        .as_ref()
+        .map(|_| builder.create_vector(&[0u8; signature::SIGNATURE_SIZE]));
     let package = ShaderPackage::create(
         &mut builder,
         &ShaderPackageArgs {
             name: Some(package_name),
             shaders: Some(sv),
+            format_version: loader::PACKAGE_FORMAT_VERSION,
+            signature,
             ..Default::default()
         },
     );
     builder.finish(package, None);
+    Ok(builder)
+}
 
-    let pkg = get_root_as_shader_package(builder.finished_data());
-    let sads = pkg.shaders().unwrap();
-    for s in sads.iter() {
-        println!("entry point: {}", s.entry_point().unwrap());
-    }
+// package name of a source, its file name up to the first dot
+pub(crate) fn package_name(name: &str) -> &str {
+    name.split('.').next().unwrap_or(name)
+}
 
-    Ok(builder)
+pub fn shader_compile<'a>(
+    data: &[u8],
+    name: &str,
+    options: &CompileOptions,
+) -> SgeResult<flatbuffers::FlatBufferBuilder<'a>> {
+    let source = source_compile(data, name, options)?;
+    package_build(package_name(name), &[source], options)
+}
+
+// FileCompile compiles one hlsl file, quoted includes resolve next to it first
+pub fn file_compile(input: &Path, options: &CompileOptions) -> SgeResult<CompiledSource> {
+    file_cached_compile(input, options, cache_open(options)?.as_ref())
+}
+
+// compiles one hlsl file with the cache of the run
+pub(crate) fn file_cached_compile(
+    input: &Path,
+    options: &CompileOptions,
+    cache: Option<&cache::ShaderCache>,
+) -> SgeResult<CompiledSource> {
+    let invalid = || SgeError::Message(format!("invalid input {}", input.display()));
+    let data = read_file(input.to_str().ok_or_else(invalid)?)?;
+    let name = input
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(invalid)?;
+    source_data_compile(&data, name, input.parent(), options, cache)
 }
 
-pub fn compile_and_save(intput: &str, output: &str) -> SgeResult<()> {
-    let data = read_file(intput)?;
-    let name = Path::new(intput).file_name().unwrap();
-    let shaders = shader_compile(&data, name.to_str().unwrap())?;
-    save_file(output, shaders.finished_data())?;
+pub fn compile_and_save(intput: &str, output: &str, options: &CompileOptions) -> SgeResult<()> {
+    let source = file_compile(Path::new(intput), options)?;
+    let name = package_name(&source.name).to_string();
+    let shaders = package_build(&name, &[source], options)?;
+    save_file(output, &signature::package_finish(&shaders, options)?)?;
     Ok(())
 }
@@ -0,0 +1,154 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module loader reads packages written by any supported version of the packer
+// every package records its format version, packages from newer packers are rejected and packages
+// from older ones are upgraded to the current layout, so runtimes only deal with one format
+//
+// format versions:
+//   1: entry_point, shader_type and data only, compiled for shader model 6_0 (packages without
+//      a format_version field)
+//   2: adds permutations, compression, hashes, sources, profiles, library exports, dxc arguments
+//      and cross compiled code
+
+use crate::compression::shader_data;
+use crate::layout::VertexAttribute;
+use crate::signature::package_verify;
+use crate::verifier::package_validate;
+use crate::{
+    get_shader_target, package_build, CompileOptions, CompiledShader, CompiledSource, ShaderModel,
+};
+use error_lib::*;
+use rust_shader_headers::render::shader::get_root_as_shader_package;
+use std::borrow::Cow;
+
+// version written by package_build, bump when the schema changes and add the upgrade below
+// (optional fields older loaders can ignore, eg source_text, don't need a bump)
+pub const PACKAGE_FORMAT_VERSION: u32 = 2;
+// oldest version that can still be upgraded
+pub const MIN_PACKAGE_FORMAT_VERSION: u32 = 1;
+
+// PackageLoad checks the format version of a serialized package, upgrading it when it is older
+// packages already in the current format are borrowed as is, malformed packages are rejected
+pub fn package_load<'a>(data: &'a [u8]) -> SgeResult<Cow<'a, [u8]>> {
+    package_validate(data)?;
+    let version = get_root_as_shader_package(data).format_version();
+    if version > PACKAGE_FORMAT_VERSION {
+        return Err(SgeError::Message(format!(
+            "package format version {} is newer than the supported version {}",
+            version, PACKAGE_FORMAT_VERSION
+        )));
+    }
+    if version < MIN_PACKAGE_FORMAT_VERSION {
+        return Err(SgeError::Message(format!(
+            "package format version {} is older than the oldest supported version {}",
+            version, MIN_PACKAGE_FORMAT_VERSION
+        )));
+    }
+    match version {
+        PACKAGE_FORMAT_VERSION => Ok(Cow::Borrowed(data)),
+        _ => Ok(Cow::Owned(upgrade_v1(data)?)),
+    }
+}
+
+// PackageLoadVerified loads a package after checking it is signed with key and unchanged since
+pub fn package_load_verified<'a>(data: &'a [u8], key: &[u8]) -> SgeResult<Cow<'a, [u8]>> {
+    package_verify(data, key)?;
+    package_load(data)
+}
+
+// PackageSources reads the compiled sources back from a package in the current format, with
+// decompressed bytecode, so packages can be rebuilt (eg merged)
+pub fn package_sources(data: &[u8]) -> SgeResult<Vec<CompiledSource>> {
+    let strings =
+        |v: Option<flatbuffers::Vector<flatbuffers::ForwardsUOffset<&str>>>| -> Vec<String> {
+            v.iter()
+                .flat_map(|v| v.iter())
+                .map(|s| s.to_string())
+                .collect()
+        };
+    let pkg = get_root_as_shader_package(data);
+    let mut sources: Vec<CompiledSource> = Vec::new();
+    for s in pkg.shaders().iter().flat_map(|v| v.iter()) {
+        let shader = CompiledShader {
+            entry_point: s.entry_point().unwrap_or("").into(),
+            shader_type: s.shader_type(),
+            profile: s.profile().unwrap_or("").into(),
+            permutation: s.permutation(),
+            exports: strings(s.exports()),
+            arguments: strings(s.arguments()),
+            cross_code: s
+                .cross_code()
+                .iter()
+                .flat_map(|v| v.iter())
+                .map(|c| (c.api(), c.code().unwrap_or("").to_string()))
+                .collect(),
+            data: shader_data(&s)?.into_owned(),
+            dxbc: s.dxbc().unwrap_or(&[]).to_vec(),
+            vertex_inputs: s
+                .vertex_inputs()
+                .iter()
+                .flat_map(|v| v.iter())
+                .map(|a| VertexAttribute {
+                    semantic: a.semantic().unwrap_or("").into(),
+                    location: a.location(),
+                    format: a.format(),
+                })
+                .collect(),
+        };
+        let name = s.source().unwrap_or("");
+        match sources.iter_mut().find(|c| c.name == name) {
+            Some(source) => source.shaders.push(shader),
+            None => sources.push(CompiledSource {
+                name: name.into(),
+                shaders: vec![shader],
+                permutation_defines: strings(s.permutation_defines()),
+                includes: Vec::new(),
+                text: s.source_text().map(|t| t.to_string()),
+                warnings: Vec::new(),
+            }),
+        }
+    }
+    Ok(sources)
+}
+
+// version 1 headers only have bytecode, the fields added since are derived from it
+fn upgrade_v1(data: &[u8]) -> SgeResult<Vec<u8>> {
+    let pkg = get_root_as_shader_package(data);
+    let name = pkg.name().unwrap_or("");
+    let shaders = pkg
+        .shaders()
+        .iter()
+        .flat_map(|v| v.iter())
+        .map(|s| CompiledShader {
+            entry_point: s.entry_point().unwrap_or("").into(),
+            shader_type: s.shader_type(),
+            profile: get_shader_target(s.shader_type(), ShaderModel::MIN),
+            permutation: 0,
+            exports: Vec::new(),
+            arguments: Vec::new(),
+            cross_code: Vec::new(),
+            data: s.data().unwrap_or(&[]).to_vec(),
+            dxbc: Vec::new(),
+            vertex_inputs: Vec::new(),
+        })
+        .collect();
+    let source = CompiledSource {
+        name: name.into(),
+        shaders,
+        ..Default::default()
+    };
+    let builder = package_build(name, &[source], &CompileOptions::default())?;
+    Ok(builder.finished_data().to_vec())
+}
@@ -12,18 +12,592 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use error_lib::*;
+use shaderpacker_rust_lib::batch::*;
+use shaderpacker_rust_lib::config::*;
+use shaderpacker_rust_lib::depfile::*;
+use shaderpacker_rust_lib::diagnostics::*;
+use shaderpacker_rust_lib::disasm::*;
+use shaderpacker_rust_lib::loader::*;
+use shaderpacker_rust_lib::manifest::*;
+use shaderpacker_rust_lib::server::*;
+use shaderpacker_rust_lib::stats::*;
+use shaderpacker_rust_lib::variant::*;
 use shaderpacker_rust_lib::*;
+use std::borrow::Cow;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
 
-fn main() {
-    if std::env::args().len() != 4 {
+const USAGE: &str = "usage: shaderpacker_rust <command> [options]
+
+commands:
+  compile  compile hlsl inputs, one package per input
+  pack     compile hlsl inputs into a single package
+  inspect  print the shaders of a package
+  merge    combine packages into one
+  serve    compile requests of editors and builds on a local port (also --serve)
+  help     print the options of a command
+
+run `shaderpacker_rust help <command>` for the options of a command";
+
+// loopback port the serve command listens on by default
+const SERVE_ADDR: &str = "127.0.0.1:7878";
+
+// options shared by compile and pack, flags follow dxc where it has one (eg -T, -I, -Zi)
+fn compile_options() -> String {
+    format!(
+        "  inputs are hlsl files, directories (searched for .hlsl) or glob patterns
+  -sm <model>  shader model to target, {} through {} (default {})
+  -I <dir>  directory searched for #include files, can be repeated
+  -config <file>  json config with the defines and include dirs of every platform
+  -platform <name>  platform of the config to compile for, only global settings without it
+  -compress <none|zstd|lz4>  compression of the shader blobs (default none)
+  -cross <glsl,msl>  apis the spirv is also translated to, comma separated
+  -dxbc <fxc>  also compile shader model 5_0 dxbc with the fxc executable, for d3d11
+  -cache <dir>  directory of the incremental compilation cache
+  -Od|-O0|-O1|-O2|-O3  optimization level (default -O3)
+  -Zi  include debug info
+  -strip  leave out reflection and debug info, for release packages
+  -embed-source  store the preprocessed source in the package, for debug packages
+  -j <n>  number of parallel compile jobs (default cpu count)
+  -MF <file>  write a Makefile style dependency file of the packages
+  -manifest  write a json manifest next to each package, with the warnings of its sources
+  --warnings-as-errors  fail when dxc reports warnings
+  -warnings-report <file>  write the warnings of every input as json
+  -sign <keyfile>  sign packages with an hmac-sha256 of the key file
+  --disasm  write spirv disassembly of each shader next to each package
+  -stats  print the size, instruction and resource counts of every shader
+  -budget <file>  json limits of those counts, exceeding them fails the compile
+  -variant <name:settings>  also write the packages of a variant into a <name> directory, with
+    the settings strip, embed-source and compress=<compression>, eg ship:strip,compress=zstd;
+    sources are compiled once for every variant, can be repeated",
+        ShaderModel::MIN,
+        ShaderModel::MAX,
+        ShaderModel::default()
+    )
+}
+
+fn command_usage(command: &str) -> Option<String> {
+    match command {
+        "compile" => Some(format!(
+            "usage: shaderpacker_rust compile [options] -outdir <dir> <input>...\n  \
+             -outdir <dir>  directory the packages are written to, <name>.shader_pkg per input\n{}",
+            compile_options()
+        )),
         // using "-T" to be consistent with dxc cmd
-        println!("usage: shaderpacker_rust -T <output_compiled_shader_file> <input_hlsl_file>");
-        std::process::exit(1);
+        "pack" => Some(format!(
+            "usage: shaderpacker_rust pack [options] -T <package> <input>...\n  \
+             -T <package>  package every input is written to\n{}",
+            compile_options()
+        )),
+        "inspect" => Some(
+            "usage: shaderpacker_rust inspect [options] <package>\n  \
+             -key <keyfile>  verify the package is signed with the key\n  \
+             -json  print the json manifest instead of a table"
+                .into(),
+        ),
+        "merge" => Some(
+            "usage: shaderpacker_rust merge [options] -T <package> <package>...\n  \
+             -T <package>  package the inputs are merged into\n  \
+             -compress <none|zstd|lz4>  compression of the merged package (default none)\n  \
+             -sign <keyfile>  sign the merged package with an hmac-sha256 of the key file"
+                .into(),
+        ),
+        "serve" => Some(format!(
+            "usage: shaderpacker_rust serve [options]\n  \
+             -addr <address>  loopback address and port to listen on (default {})\n  \
+             -sm <model>  shader model to target, {} through {} (default {})\n  \
+             -I <dir>  directory searched for #include files, can be repeated\n  \
+             -config <file>  json config with the defines and include dirs of every platform\n  \
+             -platform <name>  platform of the config to compile for\n  \
+             -cache <dir>  directory of the incremental compilation cache, shared with builds\n  \
+             -Od|-O0|-O1|-O2|-O3  optimization level (default -O3)\n  \
+             -Zi  include debug info\n  \
+             -strip  leave out reflection and debug info\n  \
+             -j <n>  number of parallel compile jobs per request (default cpu count)\n  \
+             --warnings-as-errors  fail when dxc reports warnings",
+            SERVE_ADDR,
+            ShaderModel::MIN,
+            ShaderModel::MAX,
+            ShaderModel::default()
+        )),
+        _ => None,
+    }
+}
+
+// prints the error and how to get help, then exits
+fn fail(command: &str, message: &str) -> ! {
+    println!("error: {}", message);
+    match command_usage(command) {
+        Some(_) => println!("run `shaderpacker_rust help {}` for usage", command),
+        None => println!("{}", USAGE),
+    }
+    std::process::exit(1);
+}
+
+// command line of a command, flags are checked and values taken in order
+struct Args {
+    command: String,
+    args: std::vec::IntoIter<String>,
+}
+
+impl Args {
+    // Value returns the value following flag
+    fn value(&mut self, flag: &str) -> String {
+        match self.args.next() {
+            Some(v) => v,
+            None => fail(&self.command, &format!("{} needs a value", flag)),
+        }
+    }
+
+    // Parsed returns the value following flag, parsed with parse
+    fn parsed<T, E: std::fmt::Display>(
+        &mut self,
+        flag: &str,
+        parse: impl FnOnce(&str) -> Result<T, E>,
+    ) -> T {
+        let value = self.value(flag);
+        match parse(&value) {
+            Ok(v) => v,
+            Err(e) => fail(&self.command, &format!("{} {}: {}", flag, value, e)),
+        }
     }
 
-    let input = std::env::args().nth(3).unwrap();
-    let output = std::env::args().nth(2).unwrap();
-    if let Err(e) = compile_and_save(&input, &output) {
-        println!("error: {}", e);
+    // Input rejects unknown flags, anything else is an input
+    fn input(&self, arg: String) -> String {
+        if arg.starts_with('-') {
+            fail(&self.command, &format!("unknown option {}", arg));
+        }
+        arg
+    }
+}
+
+fn signing_key(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("can't read signing key: {}", e))
+}
+
+fn package_read(path: &str) -> SgeResult<Vec<u8>> {
+    std::fs::read(path).map_err(|e| SgeError::Message(format!("{}: {}", path, e)))
+}
+
+// prints the shader sizes and entry points of a written package, bytecode shared with an earlier
+// shader is stored once
+fn package_print(path: &Path) -> SgeResult<()> {
+    let manifest = Manifest::from_package(&std::fs::read(path)?);
+    let mut hashes: Vec<&str> = Vec::new();
+    for s in &manifest.shaders {
+        if hashes.contains(&s.hash.as_str()) {
+            println!("shader size: {} (shared)", s.size);
+        } else {
+            println!("shader size: {} (packed {})", s.size, s.packed_size);
+            hashes.push(&s.hash);
+        }
+    }
+    for s in &manifest.shaders {
+        println!("entry point: {}", s.entry_point);
+    }
+    Ok(())
+}
+
+// compile and pack, which differ only in how packages are written
+fn compile_command(mut args: Args) -> SgeResult<()> {
+    let mut options = CompileOptions::default();
+    let mut output: Option<PathBuf> = None;
+    let mut depfile: Option<PathBuf> = None;
+    let mut manifest = false;
+    let mut disasm = false;
+    let mut jobs = num_cpus::get();
+    let mut warnings_report_path: Option<PathBuf> = None;
+    let mut stats = false;
+    let mut budget: Option<PathBuf> = None;
+    let mut config: Option<PathBuf> = None;
+    let mut platform: Option<String> = None;
+    let mut variants: Vec<PackageVariant> = Vec::new();
+    let mut inputs = Vec::new();
+    let output_flag = if args.command == "pack" {
+        "-T"
+    } else {
+        "-outdir"
+    };
+    while let Some(arg) = args.args.next() {
+        if arg == output_flag {
+            output = Some(args.value(&arg).into());
+        } else if arg == "-manifest" {
+            manifest = true;
+        } else if arg == "--disasm" || arg == "-disasm" {
+            disasm = true;
+        } else if arg == "-config" || arg == "--config" {
+            config = Some(args.value(&arg).into());
+        } else if arg == "-platform" || arg == "--platform" {
+            platform = Some(args.value(&arg));
+        } else if arg == "-warnings-as-errors" || arg == "--warnings-as-errors" {
+            options.warnings_as_errors = true;
+        } else if arg == "-warnings-report" {
+            warnings_report_path = Some(args.value(&arg).into());
+        } else if arg == "-dxbc" {
+            options.fxc = Some(args.value(&arg).into());
+        } else if arg == "-stats" {
+            stats = true;
+        } else if arg == "-budget" || arg == "--budget" {
+            budget = Some(args.value(&arg).into());
+        } else if arg == "-variant" {
+            let variant: PackageVariant = args.parsed(&arg, |v| v.parse());
+            if variants.iter().any(|v| v.name == variant.name) {
+                fail(
+                    &args.command,
+                    &format!("variant {} declared twice", variant.name),
+                );
+            }
+            variants.push(variant);
+        } else if arg == "-MF" {
+            depfile = Some(args.value(&arg).into());
+        } else if arg == "-j" {
+            jobs = args.parsed(&arg, |v| match v.parse::<usize>() {
+                Ok(j) if j > 0 => Ok(j),
+                _ => Err("expected a positive number"),
+            });
+        } else if arg == "-sm" {
+            options.shader_model = args.parsed(&arg, |v| v.parse());
+        } else if arg == "-compress" {
+            options.compression = args.parsed(&arg, compression::compression_from_str);
+        } else if arg == "-cross" {
+            let targets = args.parsed(&arg, |v| {
+                v.split(',')
+                    .map(|t| cross::shader_api_from_str(t.trim()))
+                    .collect::<Result<Vec<_>, _>>()
+            });
+            for api in targets {
+                if !options.cross_targets.contains(&api) {
+                    options.cross_targets.push(api);
+                }
+            }
+        } else if arg == "-sign" {
+            options.signing_key = Some(args.parsed(&arg, signing_key));
+        } else if arg == "-cache" {
+            options.cache_dir = Some(args.value(&arg).into());
+        } else if let Some(o) = Optimization::from_arg(&arg) {
+            options.optimization = o;
+        } else if arg == "-Zi" {
+            options.debug_info = true;
+        } else if arg == "-strip" {
+            options.strip = true;
+        } else if arg == "-embed-source" {
+            options.embed_source = true;
+        } else if arg == "-I" {
+            options.include_dirs.push(args.value(&arg).into());
+        } else if let Some(dir) = arg.strip_prefix("-I") {
+            options.include_dirs.push(dir.into());
+        } else {
+            inputs.push(args.input(arg));
+        }
+    }
+    let output = match output {
+        Some(o) => o,
+        None => fail(&args.command, &format!("{} is required", output_flag)),
+    };
+    let budget = match &budget {
+        Some(b) => Some(Budget::load(b)?),
+        None => None,
+    };
+    if inputs.is_empty() {
+        fail(&args.command, "no inputs");
+    }
+    if options.strip && options.debug_info {
+        fail(&args.command, "-strip and -Zi can't be combined");
+    }
+    if options.strip && options.embed_source {
+        fail(&args.command, "-strip and -embed-source can't be combined");
+    }
+    if !variants.is_empty()
+        && (options.strip
+            || options.embed_source
+            || options.compression != CompileOptions::default().compression)
+    {
+        fail(
+            &args.command,
+            "-strip, -embed-source and -compress are set per -variant",
+        );
+    }
+    options.jobs = jobs;
+    match &config {
+        Some(c) => Config::load(c)?.apply(platform.as_deref(), &mut options)?,
+        None if platform.is_some() => fail(&args.command, "-platform needs -config"),
+        None => {}
+    }
+
+    let files = inputs_expand(&inputs)?;
+    let compile_options = if variants.is_empty() {
+        options.clone()
+    } else {
+        variants_options(&options, &variants)
+    };
+    let compiled = compile_batch(&files, &compile_options, jobs)?;
+    let (paths, sources): (Vec<PathBuf>, Vec<CompiledSource>) = compiled.into_iter().unzip();
+    let warnings: Vec<Diagnostic> = sources
+        .iter()
+        .flat_map(|s| s.warnings.iter().cloned())
+        .collect();
+    for w in &warnings {
+        println!("{}", w);
+    }
+    if let Some(path) = &warnings_report_path {
+        std::fs::write(path, warnings_report(&warnings)?)?;
+    }
+    let pairs: Vec<_> = paths
+        .iter()
+        .map(|p| p.as_path())
+        .zip(sources.iter())
+        .collect();
+    // without variants the packages are written as such, each variant into a directory of its name
+    let packages: Vec<(PathBuf, Cow<[CompiledSource]>, CompileOptions)> = if variants.is_empty() {
+        vec![(output.clone(), Cow::Borrowed(&sources[..]), options.clone())]
+    } else {
+        let mut packages = Vec::new();
+        for v in &variants {
+            let target = match output.file_name() {
+                Some(file) if args.command == "pack" => output.with_file_name(&v.name).join(file),
+                _ => output.join(&v.name),
+            };
+            let (variant_sources, variant_options) = variant_apply(&sources, v, &compile_options)?;
+            packages.push((target, Cow::Owned(variant_sources), variant_options));
+        }
+        packages
+    };
+    let mut rules = Vec::new();
+    let mut rule_warnings: Vec<&[Diagnostic]> = Vec::new();
+    for (target, package_sources, package_options) in &packages {
+        if args.command == "pack" {
+            if let Some(dir) = target.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            package_save(target, package_sources, package_options)?;
+            rules.push(DepRule::new(target, &pairs));
+            rule_warnings.push(&warnings[..]);
+        } else {
+            let written = packages_save(target, package_sources, package_options)?;
+            for ((t, pair), source) in written.iter().zip(pairs.iter()).zip(sources.iter()) {
+                rules.push(DepRule::new(t, std::slice::from_ref(pair)));
+                rule_warnings.push(&source.warnings[..]);
+            }
+        }
+    }
+    let mut exceeded = Vec::new();
+    for (r, package_warnings) in rules.iter().zip(rule_warnings) {
+        package_print(&r.target)?;
+        if manifest {
+            manifest_write(&r.target, package_warnings)?;
+        }
+        if disasm {
+            disasm_write(&r.target)?;
+        }
+        if stats || budget.is_some() {
+            let package_stats = package_stats(&std::fs::read(&r.target)?)?;
+            if stats {
+                println!("{}", r.target.display());
+                print!("{}", stats_table(&package_stats));
+            }
+            if let Some(b) = &budget {
+                exceeded.extend(b.check(&package_stats));
+            }
+        }
+    }
+    if !exceeded.is_empty() {
+        return Err(SgeError::Message(format!(
+            "shaders over budget:\n{}",
+            exceeded.join("\n")
+        )));
+    }
+    match &depfile {
+        Some(d) => depfile_write(d, &rules),
+        None => Ok(()),
+    }
+}
+
+fn inspect_command(mut args: Args) -> SgeResult<()> {
+    let mut key: Option<Vec<u8>> = None;
+    let mut json = false;
+    let mut inputs = Vec::new();
+    while let Some(arg) = args.args.next() {
+        if arg == "-key" {
+            key = Some(args.parsed(&arg, signing_key));
+        } else if arg == "-json" {
+            json = true;
+        } else {
+            inputs.push(args.input(arg));
+        }
+    }
+    if inputs.len() != 1 {
+        fail(&args.command, "expected a single package");
+    }
+    let data = package_read(&inputs[0])?;
+    let data = match &key {
+        Some(key) => package_load_verified(&data, key)?,
+        None => package_load(&data)?,
+    };
+    let manifest = Manifest::from_package(&data);
+    if json {
+        println!("{}", manifest.to_json()?);
+        return Ok(());
+    }
+    println!(
+        "package {}, {} shaders",
+        manifest.name,
+        manifest.shaders.len()
+    );
+    println!(
+        "{:<24} {:<24} {:<14} {:<10} {:>11} {:>10} {:>10}",
+        "source", "entry point", "type", "profile", "permutation", "size", "packed"
+    );
+    for s in &manifest.shaders {
+        println!(
+            "{:<24} {:<24} {:<14} {:<10} {:>#11x} {:>10} {:>10}",
+            s.source, s.entry_point, s.shader_type, s.profile, s.permutation, s.size, s.packed_size
+        );
+    }
+    Ok(())
+}
+
+fn merge_command(mut args: Args) -> SgeResult<()> {
+    let mut options = CompileOptions::default();
+    let mut output: Option<PathBuf> = None;
+    let mut inputs = Vec::new();
+    while let Some(arg) = args.args.next() {
+        if arg == "-T" {
+            output = Some(args.value(&arg).into());
+        } else if arg == "-compress" {
+            options.compression = args.parsed(&arg, compression::compression_from_str);
+        } else if arg == "-sign" {
+            options.signing_key = Some(args.parsed(&arg, signing_key));
+        } else {
+            inputs.push(args.input(arg));
+        }
+    }
+    let output = match output {
+        Some(o) => o,
+        None => fail(&args.command, "-T is required"),
+    };
+    if inputs.is_empty() {
+        fail(&args.command, "no packages to merge");
+    }
+
+    let mut sources: Vec<CompiledSource> = Vec::new();
+    for input in &inputs {
+        let data = package_read(input)?;
+        for source in package_sources(&package_load(&data)?)? {
+            if sources.iter().any(|s| s.name == source.name) {
+                return Err(SgeError::Message(format!(
+                    "{} is in more than one package",
+                    source.name
+                )));
+            }
+            sources.push(source);
+        }
+    }
+    package_save(&output, &sources, &options)?;
+    package_print(&output)
+}
+
+fn serve_command(mut args: Args) -> SgeResult<()> {
+    let mut options = CompileOptions::default();
+    let mut addr: SocketAddr = SERVE_ADDR.parse().unwrap();
+    let mut config: Option<PathBuf> = None;
+    let mut platform: Option<String> = None;
+    options.jobs = num_cpus::get();
+    while let Some(arg) = args.args.next() {
+        if arg == "-addr" {
+            addr = args.parsed(&arg, |v| v.parse::<SocketAddr>());
+        } else if arg == "-config" || arg == "--config" {
+            config = Some(args.value(&arg).into());
+        } else if arg == "-platform" || arg == "--platform" {
+            platform = Some(args.value(&arg));
+        } else if arg == "-warnings-as-errors" || arg == "--warnings-as-errors" {
+            options.warnings_as_errors = true;
+        } else if arg == "-j" {
+            options.jobs = args.parsed(&arg, |v| match v.parse::<usize>() {
+                Ok(j) if j > 0 => Ok(j),
+                _ => Err("expected a positive number"),
+            });
+        } else if arg == "-sm" {
+            options.shader_model = args.parsed(&arg, |v| v.parse());
+        } else if arg == "-cache" {
+            options.cache_dir = Some(args.value(&arg).into());
+        } else if let Some(o) = Optimization::from_arg(&arg) {
+            options.optimization = o;
+        } else if arg == "-Zi" {
+            options.debug_info = true;
+        } else if arg == "-strip" {
+            options.strip = true;
+        } else if arg == "-I" {
+            options.include_dirs.push(args.value(&arg).into());
+        } else if let Some(dir) = arg.strip_prefix("-I") {
+            options.include_dirs.push(dir.into());
+        } else {
+            fail(&args.command, &format!("unknown option {}", arg));
+        }
+    }
+    // requests carry sources and return blobs, so only local clients are served
+    if !addr.ip().is_loopback() {
+        fail(
+            &args.command,
+            &format!("{} is not a loopback address", addr),
+        );
+    }
+    if options.strip && options.debug_info {
+        fail(&args.command, "-strip and -Zi can't be combined");
+    }
+    match &config {
+        Some(c) => Config::load(c)?.apply(platform.as_deref(), &mut options)?,
+        None if platform.is_some() => fail(&args.command, "-platform needs -config"),
+        None => {}
+    }
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| SgeError::Message(format!("can't listen on {}: {}", addr, e)))?;
+    println!("serving compile requests on {}", addr);
+    serve(listener, &options)
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let command = match args.next() {
+        // --serve is the daemon mode of editors and build wrappers
+        Some(c) if c == "--serve" => "serve".to_string(),
+        Some(c) => c,
+        None => fail("", "no command"),
+    };
+    let args: Vec<String> = args.collect();
+    let help = args.iter().any(|a| a == "--help" || a == "-h");
+    if command == "help" || command == "--help" || command == "-h" {
+        match args.first().and_then(|c| command_usage(c)) {
+            Some(usage) => println!("{}", usage),
+            None => println!("{}", USAGE),
+        }
+        return;
+    }
+    match command_usage(&command) {
+        Some(usage) if help => {
+            println!("{}", usage);
+            return;
+        }
+        Some(_) => {}
+        None => fail("", &format!("unknown command {}", command)),
+    }
+
+    let args = Args {
+        command: command.clone(),
+        args: args.into_iter(),
+    };
+    let result = match command.as_str() {
+        "compile" | "pack" => compile_command(args),
+        "inspect" => inspect_command(args),
+        "merge" => merge_command(args),
+        "serve" => serve_command(args),
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        match diagnostics::compile_error_find(&e) {
+            Some(compile_error) => println!("{}", compile_error),
+            None => println!("error: {}", e),
+        }
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,167 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module manifest describes a shader package as json
+// the manifest is written next to the package (lighting.shader_pkg.json), so pipeline steps
+// and people can inspect packages without flatbuffer tooling
+
+use crate::cache::hex_string;
+use crate::diagnostics::Diagnostic;
+use error_lib::*;
+use rust_shader_headers::render::shader::{
+    get_root_as_shader_package, Compression, ShaderApi, ShaderType, VertexFormat,
+    ENUM_NAMES_COMPRESSION, ENUM_NAMES_SHADER_API, ENUM_NAMES_SHADER_TYPE,
+    ENUM_NAMES_VERTEX_FORMAT, ENUM_VALUES_COMPRESSION, ENUM_VALUES_SHADER_API,
+    ENUM_VALUES_SHADER_TYPE, ENUM_VALUES_VERTEX_FORMAT,
+};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ManifestShader {
+    pub source: String,
+    pub entry_point: String,
+    pub shader_type: String,
+    pub profile: String,
+    pub permutation: u32,
+    pub permutation_defines: Vec<String>,
+    // entry points exported by a library blob (ray tracing stages)
+    pub exports: Vec<String>,
+    pub arguments: Vec<String>,
+    // apis the shader was translated to
+    pub cross_code: Vec<String>,
+    pub compression: String,
+    // size of the bytecode, and of the blob stored in the package
+    pub size: u32,
+    pub packed_size: u32,
+    // size of the shader model 5_0 dxbc blob, 0 without one
+    pub dxbc_size: u32,
+    // input signature of vertex shaders, as location:semantic:format
+    pub vertex_inputs: Vec<String>,
+    pub hash: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Manifest {
+    pub name: String,
+    pub shaders: Vec<ManifestShader>,
+    // warnings dxc reported while compiling the package, packages don't store them
+    pub warnings: Vec<Diagnostic>,
+}
+
+pub(crate) fn shader_type_name(st: ShaderType) -> String {
+    let i = ENUM_VALUES_SHADER_TYPE.iter().position(|v| *v == st);
+    i.map(|i| ENUM_NAMES_SHADER_TYPE[i]).unwrap_or("").into()
+}
+
+fn shader_api_name(api: ShaderApi) -> String {
+    let i = ENUM_VALUES_SHADER_API.iter().position(|v| *v == api);
+    i.map(|i| ENUM_NAMES_SHADER_API[i]).unwrap_or("").into()
+}
+
+fn compression_name(c: Compression) -> String {
+    let i = ENUM_VALUES_COMPRESSION.iter().position(|v| *v == c);
+    i.map(|i| ENUM_NAMES_COMPRESSION[i]).unwrap_or("").into()
+}
+
+fn vertex_format_name(f: VertexFormat) -> String {
+    let i = ENUM_VALUES_VERTEX_FORMAT.iter().position(|v| *v == f);
+    i.map(|i| ENUM_NAMES_VERTEX_FORMAT[i]).unwrap_or("").into()
+}
+
+impl Manifest {
+    // FromPackage reads the manifest of a serialized package
+    pub fn from_package(data: &[u8]) -> Self {
+        let pkg = get_root_as_shader_package(data);
+        let mut shaders = Vec::new();
+        for s in pkg.shaders().iter().flat_map(|v| v.iter()) {
+            shaders.push(ManifestShader {
+                source: s.source().unwrap_or("").into(),
+                entry_point: s.entry_point().unwrap_or("").into(),
+                shader_type: shader_type_name(s.shader_type()),
+                profile: s.profile().unwrap_or("").into(),
+                permutation: s.permutation(),
+                permutation_defines: s
+                    .permutation_defines()
+                    .iter()
+                    .flat_map(|v| v.iter())
+                    .map(|d| d.to_string())
+                    .collect(),
+                exports: s
+                    .exports()
+                    .iter()
+                    .flat_map(|v| v.iter())
+                    .map(|e| e.to_string())
+                    .collect(),
+                arguments: s
+                    .arguments()
+                    .iter()
+                    .flat_map(|v| v.iter())
+                    .map(|a| a.to_string())
+                    .collect(),
+                cross_code: s
+                    .cross_code()
+                    .iter()
+                    .flat_map(|v| v.iter())
+                    .map(|c| shader_api_name(c.api()))
+                    .collect(),
+                compression: compression_name(s.compression()),
+                size: s.uncompressed_size(),
+                packed_size: s.data().map_or(0, |d| d.len() as u32),
+                dxbc_size: s.dxbc().map_or(0, |d| d.len() as u32),
+                vertex_inputs: s
+                    .vertex_inputs()
+                    .iter()
+                    .flat_map(|v| v.iter())
+                    .map(|a| {
+                        format!(
+                            "{}:{}:{}",
+                            a.location(),
+                            a.semantic().unwrap_or(""),
+                            vertex_format_name(a.format())
+                        )
+                    })
+                    .collect(),
+                hash: hex_string(s.hash().unwrap_or(&[])),
+            });
+        }
+        Manifest {
+            name: pkg.name().unwrap_or("").into(),
+            shaders,
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> SgeResult<String> {
+        serde_json::to_string_pretty(self).map_err(|e| SgeError::Message(e.to_string()))
+    }
+}
+
+// ManifestPath is where the manifest of a package is written
+pub fn manifest_path(package: &Path) -> PathBuf {
+    let mut name = package.as_os_str().to_os_string();
+    name.push(".json");
+    PathBuf::from(name)
+}
+
+// ManifestWrite writes the manifest of the package file next to it, with the warnings reported
+// while compiling it
+pub fn manifest_write(package: &Path, warnings: &[Diagnostic]) -> SgeResult<PathBuf> {
+    let data = std::fs::read(package)?;
+    let path = manifest_path(package);
+    let mut manifest = Manifest::from_package(&data);
+    manifest.warnings = warnings.to_vec();
+    std::fs::write(&path, manifest.to_json()?)?;
+    Ok(path)
+}
@@ -0,0 +1,144 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module runtime is what engines load packages with: a package file is memory mapped and validated
+// once, then headers and bytecode are borrowed from the mapping without copies
+// packages in an older format are upgraded into memory (see loader), compressed blobs still go
+// through compression::shader_data
+
+use crate::loader::{package_load, PACKAGE_FORMAT_VERSION};
+use crate::signature::package_verify;
+use crate::verifier::package_validate;
+use error_lib::*;
+use memmap2::Mmap;
+use rust_shader_headers::render::shader::{
+    get_root_as_shader_package, ShaderHeader, ShaderPackage,
+};
+use std::borrow::Cow;
+use std::fs::File;
+use std::path::Path;
+
+enum PackageData {
+    Mapped(Mmap),
+    Upgraded(Vec<u8>),
+}
+
+// a package file opened for reading, validated and in the current format
+pub struct PackageFile {
+    data: PackageData,
+}
+
+impl PackageFile {
+    // Open maps and validates a package file
+    pub fn open(path: &Path) -> SgeResult<Self> {
+        PackageFile::map(path, None)
+    }
+
+    // OpenVerified also checks the package is signed with key and unchanged since
+    pub fn open_verified(path: &Path, key: &[u8]) -> SgeResult<Self> {
+        PackageFile::map(path, Some(key))
+    }
+
+    fn map(path: &Path, key: Option<&[u8]>) -> SgeResult<Self> {
+        let error = |e: String| SgeError::Message(format!("{}: {}", path.display(), e));
+        let file = File::open(path).map_err(|e| error(e.to_string()))?;
+        // safe as long as the file isn't truncated or rewritten while it is open, packages are
+        // written once by the build and only read by the engine
+        let map = unsafe { Mmap::map(&file) }.map_err(|e| error(e.to_string()))?;
+        // package_verify and package_load validate the package before reading it
+        if let Some(key) = key {
+            package_verify(&map, key).map_err(|e| error(e.to_string()))?;
+        }
+        let upgraded = match package_load(&map).map_err(|e| error(e.to_string()))? {
+            Cow::Borrowed(_) => None,
+            Cow::Owned(data) => Some(data),
+        };
+        let data = match upgraded {
+            Some(data) => PackageData::Upgraded(data),
+            None => PackageData::Mapped(map),
+        };
+        Ok(PackageFile { data })
+    }
+
+    // Data is the serialized package
+    pub fn data(&self) -> &[u8] {
+        match &self.data {
+            PackageData::Mapped(map) => map,
+            PackageData::Upgraded(data) => data,
+        }
+    }
+
+    pub fn view(&self) -> PackageView<'_> {
+        PackageView {
+            package: get_root_as_shader_package(self.data()),
+        }
+    }
+}
+
+// borrowed access to a validated package
+#[derive(Clone, Copy)]
+pub struct PackageView<'a> {
+    package: ShaderPackage<'a>,
+}
+
+impl<'a> PackageView<'a> {
+    // New validates a package already in memory (eg embedded in the executable), it must be in the
+    // current format, older ones go through loader::package_load first
+    pub fn new(data: &'a [u8]) -> SgeResult<Self> {
+        package_validate(data)?;
+        let package = get_root_as_shader_package(data);
+        if package.format_version() != PACKAGE_FORMAT_VERSION {
+            return Err(SgeError::Message(format!(
+                "package format version {} is not the current version {}",
+                package.format_version(),
+                PACKAGE_FORMAT_VERSION
+            )));
+        }
+        Ok(PackageView { package })
+    }
+
+    pub fn package(&self) -> ShaderPackage<'a> {
+        self.package
+    }
+
+    pub fn name(&self) -> &'a str {
+        self.package.name().unwrap_or("")
+    }
+
+    // Shaders iterates the headers in package order
+    pub fn shaders(&self) -> impl Iterator<Item = ShaderHeader<'a>> {
+        self.package.shaders().into_iter().flat_map(|v| v.iter())
+    }
+
+    // Shader finds the header of an entry point and permutation, in the first source that has it
+    pub fn shader(&self, entry_point: &str, permutation: u32) -> Option<ShaderHeader<'a>> {
+        self.shaders()
+            .find(|s| s.entry_point() == Some(entry_point) && s.permutation() == permutation)
+    }
+
+    // SourceShader finds the header of an entry point and permutation of one source, for
+    // packages combining sources with the same entry point names
+    pub fn source_shader(
+        &self,
+        source: &str,
+        entry_point: &str,
+        permutation: u32,
+    ) -> Option<ShaderHeader<'a>> {
+        self.shaders().find(|s| {
+            s.source() == Some(source)
+                && s.entry_point() == Some(entry_point)
+                && s.permutation() == permutation
+        })
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module server is the compile daemon of the serve command: it listens on a local port and
+// compiles entry points for editors and builds, one json request per line answered by one json
+// response per line
+//
+// {"name": "shaders/lighting.hlsl", "source": "...", "entry_point": "PSMain",
+//  "shader_type": "Pixel", "defines": ["USE_SHADOWS=1"]}
+//
+// the process keeps dxc loaded between requests, and requests share the cache of options, so a
+// shader compiled by any client (or a build using the same -cache) is read back from it
+
+use crate::config::define_parse;
+use crate::diagnostics::{compile_error_find, Diagnostic};
+use crate::{entry_point_compile, shader_type_from_str, CompileOptions, CompiledSource};
+use error_lib::*;
+use hassle_rs::Dxc;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompileRequest {
+    // path of the source, quoted includes are searched next to it first
+    pub name: String,
+    pub source: String,
+    pub entry_point: String,
+    // as in @shader, eg Pixel
+    pub shader_type: String,
+    // NAME or NAME=VALUE, added to the defines of the server
+    pub defines: Vec<String>,
+    // profile override, as the third @shader argument
+    pub profile: Option<String>,
+}
+
+// the blob of one permutation of the requested entry point
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ServedShader {
+    pub profile: String,
+    pub permutation: u32,
+    pub permutation_defines: Vec<String>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CompileResponse {
+    pub shaders: Vec<ServedShader>,
+    pub warnings: Vec<Diagnostic>,
+    // set when the compile failed, with the diagnostics dxc reported
+    pub error: Option<String>,
+    pub errors: Vec<Diagnostic>,
+}
+
+fn request_compile(
+    request: &CompileRequest,
+    options: &CompileOptions,
+) -> SgeResult<CompiledSource> {
+    let shader_type = shader_type_from_str(&request.shader_type)
+        .map_err(|_| SgeError::Message(format!("unknown shader type {}", request.shader_type)))?;
+    let mut options = options.clone();
+    for d in &request.defines {
+        let (name, value) = define_parse(d)?;
+        match options.defines.iter_mut().find(|d| d.0 == name) {
+            Some(d) => d.1 = value,
+            None => options.defines.push((name, value)),
+        }
+    }
+    let path = Path::new(&request.name);
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or(&request.name);
+    entry_point_compile(
+        &request.source,
+        name,
+        path.parent(),
+        &request.entry_point,
+        shader_type,
+        request.profile.as_deref(),
+        &options,
+    )
+}
+
+// RequestHandle compiles the entry point of a request with the options of the server
+pub fn request_handle(request: &CompileRequest, options: &CompileOptions) -> CompileResponse {
+    match request_compile(request, options) {
+        Ok(source) => {
+            let permutation_defines = source.permutation_defines;
+            CompileResponse {
+                shaders: source
+                    .shaders
+                    .into_iter()
+                    .map(|s| ServedShader {
+                        profile: s.profile,
+                        permutation: s.permutation,
+                        permutation_defines: permutation_defines.clone(),
+                        data: s.data,
+                    })
+                    .collect(),
+                warnings: source.warnings,
+                ..Default::default()
+            }
+        }
+        Err(e) => CompileResponse {
+            errors: compile_error_find(&e)
+                .map(|c| c.diagnostics.clone())
+                .unwrap_or_default(),
+            error: Some(e.to_string()),
+            ..Default::default()
+        },
+    }
+}
+
+// answers the requests of one client until it disconnects
+fn connection_serve(stream: TcpStream, options: &CompileOptions) -> std::io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<CompileRequest>(&line) {
+            Ok(request) => request_handle(&request, options),
+            Err(e) => CompileResponse {
+                error: Some(format!("invalid request: {}", e)),
+                ..Default::default()
+            },
+        };
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+// Serve answers the requests of every client of listener, each on its own thread, until the
+// process is stopped
+pub fn serve(listener: TcpListener, options: &CompileOptions) -> SgeResult<()> {
+    // an instance held for the lifetime of the server keeps the dxc library loaded, so compiles
+    // don't load it again; without dxc every request reports the error
+    let _dxc = Dxc::new(None).ok();
+    let options = Arc::new(options.clone());
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("warning: failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let options = options.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = connection_serve(stream, &options) {
+                println!("warning: connection closed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module signature signs packages with an hmac-sha256, so runtimes can detect corrupted or
+// tampered packages
+// package_build reserves the signature bytes when a key is set, the mac is computed over the
+// whole serialized package with those bytes zeroed and then written into them
+
+use crate::verifier::package_validate;
+use crate::CompileOptions;
+use error_lib::*;
+use hmac::{Hmac, Mac, NewMac};
+use rust_shader_headers::render::shader::get_root_as_shader_package;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const SIGNATURE_SIZE: usize = 32;
+
+// offset of the signature bytes within the package, none for unsigned packages
+fn signature_offset(data: &[u8]) -> Option<usize> {
+    let signature = get_root_as_shader_package(data).signature()?;
+    if signature.len() != SIGNATURE_SIZE {
+        return None;
+    }
+    Some(signature.as_ptr() as usize - data.as_ptr() as usize)
+}
+
+fn package_mac(data: &[u8], offset: usize, key: &[u8]) -> SgeResult<HmacSha256> {
+    let mut mac =
+        HmacSha256::new_from_slice(key).map_err(|_| SgeError::Literal("invalid signing key"))?;
+    mac.update(&data[..offset]);
+    mac.update(&[0; SIGNATURE_SIZE]);
+    mac.update(&data[offset + SIGNATURE_SIZE..]);
+    Ok(mac)
+}
+
+// PackageSign writes the signature of a package built with a signing key
+pub fn package_sign(data: &mut [u8], key: &[u8]) -> SgeResult<()> {
+    let offset = signature_offset(data).ok_or(SgeError::Literal(
+        "package has no space reserved for a signature",
+    ))?;
+    let signature = package_mac(data, offset, key)?.finalize().into_bytes();
+    data[offset..offset + SIGNATURE_SIZE].copy_from_slice(&signature);
+    Ok(())
+}
+
+// PackageVerify fails unless the package is signed with key and unchanged since
+pub fn package_verify(data: &[u8], key: &[u8]) -> SgeResult<()> {
+    package_validate(data)?;
+    let offset = signature_offset(data).ok_or(SgeError::Literal("package is not signed"))?;
+    package_mac(data, offset, key)?
+        .verify(&data[offset..offset + SIGNATURE_SIZE])
+        .map_err(|_| SgeError::Literal("package signature does not match"))
+}
+
+// PackageFinish returns the serialized package, signed when options has a signing key
+pub fn package_finish(
+    builder: &flatbuffers::FlatBufferBuilder,
+    options: &CompileOptions,
+) -> SgeResult<Vec<u8>> {
+    let mut data = builder.finished_data().to_vec();
+    if let Some(key) = &options.signing_key {
+        package_sign(&mut data, key)?;
+    }
+    Ok(data)
+}
@@ -0,0 +1,244 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module stats measures the shaders of a package (sizes, instruction and resource counts read from
+// the spirv) and checks them against a json budget, so shader growth fails the build
+//
+// {
+//   "default": { "size": 65536, "instructions": 4000 },
+//   "shaders": { "PSMain": { "instructions": 8000 }, "blur.hlsl:CSMain": { "storage_buffers": 2 } }
+// }
+//
+// shaders are matched by source:entry_point first, then entry_point, their limits replace the
+// default ones they set
+
+use crate::compression::shader_data;
+use crate::manifest::shader_type_name;
+use error_lib::*;
+use rspirv::dr::Operand;
+use rspirv::spirv::{Decoration, Op, StorageClass};
+use rust_shader_headers::render::shader::get_root_as_shader_package;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ShaderStats {
+    pub source: String,
+    pub entry_point: String,
+    pub shader_type: String,
+    pub permutation: u32,
+    // bytecode size, before and after compression
+    pub size: u32,
+    pub packed_size: u32,
+    // instructions in function bodies, none when the blob isn't readable spirv
+    pub instructions: Option<u32>,
+    // constant buffers
+    pub uniform_buffers: u32,
+    // structured and byte address buffers
+    pub storage_buffers: u32,
+    // textures, samplers and acceleration structures
+    pub resources: u32,
+}
+
+// counts the instructions and resource variables of a spirv module
+fn spirv_stats(spirv: &[u8], stats: &mut ShaderStats) {
+    let module = match rspirv::dr::load_bytes(spirv) {
+        Ok(module) => module,
+        Err(_) => return,
+    };
+    let instructions = module
+        .functions
+        .iter()
+        .flat_map(|f| f.blocks.iter())
+        .map(|b| b.instructions.len() as u32)
+        .sum();
+    stats.instructions = Some(instructions);
+
+    // before spirv 1.3, storage buffers are uniform blocks decorated BufferBlock
+    let buffer_blocks: Vec<u32> = module
+        .annotations
+        .iter()
+        .filter(|a| a.class.opcode == Op::Decorate)
+        .filter(|a| a.operands.get(1) == Some(&Operand::Decoration(Decoration::BufferBlock)))
+        .filter_map(|a| match a.operands.first() {
+            Some(Operand::IdRef(id)) => Some(*id),
+            _ => None,
+        })
+        .collect();
+    let types: HashMap<u32, &rspirv::dr::Instruction> = module
+        .types_global_values
+        .iter()
+        .filter_map(|t| t.result_id.map(|id| (id, t)))
+        .collect();
+    // the struct or resource a variable points to, looking through arrays of resources
+    let pointee = |mut id: u32| loop {
+        let t = match types.get(&id) {
+            Some(t) => t,
+            None => return id,
+        };
+        let next = match t.class.opcode {
+            Op::TypePointer => t.operands.get(1),
+            Op::TypeArray | Op::TypeRuntimeArray => t.operands.first(),
+            _ => return id,
+        };
+        match next {
+            Some(Operand::IdRef(next)) => id = *next,
+            _ => return id,
+        }
+    };
+    for v in &module.types_global_values {
+        if v.class.opcode != Op::Variable {
+            continue;
+        }
+        match v.operands.first() {
+            Some(Operand::StorageClass(StorageClass::Uniform)) => match v.result_type {
+                Some(t) if buffer_blocks.contains(&pointee(t)) => stats.storage_buffers += 1,
+                _ => stats.uniform_buffers += 1,
+            },
+            Some(Operand::StorageClass(StorageClass::StorageBuffer)) => stats.storage_buffers += 1,
+            Some(Operand::StorageClass(StorageClass::UniformConstant)) => stats.resources += 1,
+            _ => {}
+        }
+    }
+}
+
+// PackageStats measures every shader of a serialized package
+pub fn package_stats(data: &[u8]) -> SgeResult<Vec<ShaderStats>> {
+    let pkg = get_root_as_shader_package(data);
+    let mut stats = Vec::new();
+    for s in pkg.shaders().iter().flat_map(|v| v.iter()) {
+        let mut shader = ShaderStats {
+            source: s.source().unwrap_or("").into(),
+            entry_point: s.entry_point().unwrap_or("").into(),
+            shader_type: shader_type_name(s.shader_type()),
+            permutation: s.permutation(),
+            size: s.uncompressed_size(),
+            packed_size: s.data().map_or(0, |d| d.len() as u32),
+            ..Default::default()
+        };
+        spirv_stats(&shader_data(&s)?, &mut shader);
+        stats.push(shader);
+    }
+    Ok(stats)
+}
+
+// StatsTable formats stats as a text table, one row per shader
+pub fn stats_table(stats: &[ShaderStats]) -> String {
+    let mut table = format!(
+        "{:<24} {:<24} {:>11} {:>10} {:>10} {:>12} {:>8} {:>8} {:>9}\n",
+        "source",
+        "entry point",
+        "permutation",
+        "size",
+        "packed",
+        "instructions",
+        "uniform",
+        "storage",
+        "resources"
+    );
+    for s in stats {
+        let instructions = s
+            .instructions
+            .map_or_else(|| "-".to_string(), |i| i.to_string());
+        table.push_str(&format!(
+            "{:<24} {:<24} {:>#11x} {:>10} {:>10} {:>12} {:>8} {:>8} {:>9}\n",
+            s.source,
+            s.entry_point,
+            s.permutation,
+            s.size,
+            s.packed_size,
+            instructions,
+            s.uniform_buffers,
+            s.storage_buffers,
+            s.resources
+        ));
+    }
+    table
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    // uncompressed bytecode size
+    pub size: Option<u32>,
+    pub instructions: Option<u32>,
+    pub uniform_buffers: Option<u32>,
+    pub storage_buffers: Option<u32>,
+    pub resources: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Budget {
+    // limits of every shader
+    pub default: Limits,
+    // limits of single shaders, by source:entry_point or entry_point
+    pub shaders: BTreeMap<String, Limits>,
+}
+
+impl Budget {
+    pub fn from_json(text: &str) -> SgeResult<Self> {
+        serde_json::from_str(text).map_err(|e| SgeError::Message(e.to_string()))
+    }
+
+    pub fn load(path: &Path) -> SgeResult<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| SgeError::Message(format!("{}: {}", path.display(), e)))?;
+        Budget::from_json(&text)
+            .map_err(|e| SgeError::Message(format!("{}: {}", path.display(), e)))
+    }
+
+    // Check returns a message for every limit a shader exceeds
+    pub fn check(&self, stats: &[ShaderStats]) -> Vec<String> {
+        let mut exceeded = Vec::new();
+        for s in stats {
+            let specific = self
+                .shaders
+                .get(&format!("{}:{}", s.source, s.entry_point))
+                .or_else(|| self.shaders.get(&s.entry_point));
+            let limit =
+                |f: fn(&Limits) -> Option<u32>| specific.and_then(f).or_else(|| f(&self.default));
+            let values = [
+                ("bytes", s.size, limit(|l| l.size)),
+                (
+                    "instructions",
+                    s.instructions.unwrap_or(0),
+                    limit(|l| l.instructions),
+                ),
+                (
+                    "uniform buffers",
+                    s.uniform_buffers,
+                    limit(|l| l.uniform_buffers),
+                ),
+                (
+                    "storage buffers",
+                    s.storage_buffers,
+                    limit(|l| l.storage_buffers),
+                ),
+                ("resources", s.resources, limit(|l| l.resources)),
+            ];
+            for (what, value, limit) in values.iter() {
+                match limit {
+                    Some(limit) if value > limit => exceeded.push(format!(
+                        "{}:{} permutation {:#x}: {} {}, budget {}",
+                        s.source, s.entry_point, s.permutation, value, what, limit
+                    )),
+                    _ => {}
+                }
+            }
+        }
+        exceeded
+    }
+}
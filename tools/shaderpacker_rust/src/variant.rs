@@ -0,0 +1,188 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module variant derives several packages from one compile, eg a debug package with reflection
+// and source and a stripped, compressed ship package, so pipelines don't compile twice
+//
+// -variant debug:embed-source -variant ship:strip,compress=zstd
+//
+// sources are compiled once with everything a variant keeps, stripped variants then remove the
+// reflection and debug info from the spirv, as dxc does without -fspv-reflect and -Zi
+
+use crate::compression::compression_from_str;
+use crate::{CompileOptions, CompiledSource};
+use error_lib::*;
+use rspirv::binary::Assemble;
+use rspirv::dr::Operand;
+use rspirv::spirv::{Decoration, Op};
+use rust_shader_headers::render::shader::Compression;
+
+// extensions dxc enables for the reflection data of -fspv-reflect
+const REFLECTION_EXTENSIONS: [&str; 3] = [
+    "SPV_GOOGLE_hlsl_functionality1",
+    "SPV_GOOGLE_user_type",
+    "SPV_GOOGLE_decorate_string",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackageVariant {
+    pub name: String,
+    // leave out reflection and debug info
+    pub strip: bool,
+    // store the preprocessed source
+    pub embed_source: bool,
+    pub compression: Compression,
+}
+
+impl std::str::FromStr for PackageVariant {
+    type Err = SgeError;
+
+    // name:setting,... with the settings strip, embed-source and compress=<none|zstd|lz4>
+    fn from_str(input: &str) -> SgeResult<Self> {
+        let (name, settings) = match input.find(':') {
+            Some(i) => (&input[..i], &input[i + 1..]),
+            None => (input, ""),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(SgeError::Message(format!("invalid variant name {}", name)));
+        }
+        let mut variant = PackageVariant {
+            name: name.into(),
+            strip: false,
+            embed_source: false,
+            compression: Compression::None,
+        };
+        for setting in settings.split(',').filter(|s| !s.is_empty()) {
+            if setting == "strip" {
+                variant.strip = true;
+            } else if setting == "embed-source" {
+                variant.embed_source = true;
+            } else if let Some(c) = setting.strip_prefix("compress=") {
+                variant.compression = compression_from_str(c)?;
+            } else {
+                return Err(SgeError::Message(format!(
+                    "unknown variant setting {}, expected strip, embed-source or compress=<compression>",
+                    setting
+                )));
+            }
+        }
+        if variant.strip && variant.embed_source {
+            return Err(SgeError::Message(format!(
+                "variant {} can't both strip and embed the source",
+                name
+            )));
+        }
+        Ok(variant)
+    }
+}
+
+// VariantsOptions are the options sources are compiled with for variants: stripped only when every
+// variant is, with the source kept when a variant embeds it
+pub fn variants_options(options: &CompileOptions, variants: &[PackageVariant]) -> CompileOptions {
+    CompileOptions {
+        strip: variants.iter().all(|v| v.strip),
+        embed_source: variants.iter().any(|v| v.embed_source),
+        ..options.clone()
+    }
+}
+
+// SpirvStrip removes the debug info (names, sources, lines, non semantic instructions) and the
+// hlsl reflection decorations from a spirv module
+pub fn spirv_strip(spirv: &[u8]) -> SgeResult<Vec<u8>> {
+    let mut module = rspirv::dr::load_bytes(spirv)
+        .map_err(|e| SgeError::Message(format!("invalid spirv: {}", e)))?;
+    module.debug_string_source.clear();
+    module.debug_names.clear();
+    module.debug_module_processed.clear();
+    module.annotations.retain(|a| match a.class.opcode {
+        Op::DecorateString | Op::MemberDecorateString => false,
+        Op::DecorateId => {
+            a.operands.get(1) != Some(&Operand::Decoration(Decoration::HlslCounterBufferGOOGLE))
+        }
+        _ => true,
+    });
+    module.extensions.retain(|e| match e.operands.first() {
+        Some(Operand::LiteralString(s)) => !REFLECTION_EXTENSIONS.contains(&s.as_str()),
+        _ => true,
+    });
+
+    // debug info of -fspv-debug is made of extended instructions of NonSemantic sets
+    let non_semantic: Vec<u32> = module
+        .ext_inst_imports
+        .iter()
+        .filter(|i| matches!(i.operands.first(), Some(Operand::LiteralString(s)) if s.starts_with("NonSemantic.")))
+        .filter_map(|i| i.result_id)
+        .collect();
+    module.ext_inst_imports.retain(|i| match i.result_id {
+        Some(id) => !non_semantic.contains(&id),
+        None => true,
+    });
+    let debug = |i: &rspirv::dr::Instruction| match i.class.opcode {
+        Op::Line | Op::NoLine => true,
+        Op::ExtInst => match i.operands.first() {
+            Some(Operand::IdRef(set)) => non_semantic.contains(set),
+            _ => false,
+        },
+        _ => false,
+    };
+    module.types_global_values.retain(|i| !debug(i));
+    for f in &mut module.functions {
+        for b in &mut f.blocks {
+            b.instructions.retain(|i| !debug(i));
+        }
+    }
+    if !non_semantic.is_empty() {
+        // the extension only enabled the debug info
+        module.extensions.retain(|e| {
+            e.operands.first() != Some(&Operand::LiteralString("SPV_KHR_non_semantic_info".into()))
+        });
+    }
+
+    Ok(module
+        .assemble()
+        .iter()
+        .flat_map(|w| w.to_le_bytes().to_vec())
+        .collect())
+}
+
+// VariantApply derives the sources and package options of a variant from sources compiled with
+// variants_options
+pub fn variant_apply(
+    sources: &[CompiledSource],
+    variant: &PackageVariant,
+    options: &CompileOptions,
+) -> SgeResult<(Vec<CompiledSource>, CompileOptions)> {
+    let mut derived = sources.to_vec();
+    // already stripped when every variant is
+    let strip = variant.strip && !options.strip;
+    for source in &mut derived {
+        if !variant.embed_source {
+            source.text = None;
+        }
+        if !strip {
+            continue;
+        }
+        for s in &mut source.shaders {
+            s.data = spirv_strip(&s.data)?;
+            s.arguments.retain(|a| a != "-fspv-reflect" && a != "-Zi");
+        }
+    }
+    let options = CompileOptions {
+        strip: variant.strip,
+        embed_source: variant.embed_source,
+        compression: variant.compression,
+        ..options.clone()
+    };
+    Ok((derived, options))
+}
@@ -0,0 +1,258 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Module verifier checks that a buffer is a well formed ShaderPackage before its generated
+// accessors read it: they follow offsets, read strings and enums unchecked, so a truncated or
+// corrupted file would read out of bounds
+// the flatbuffers version we use has no generated verifier, this one walks every field of the
+// schema (offsets and lengths in bounds, scalars aligned, strings terminated utf-8, known enums)
+
+use error_lib::*;
+use rust_shader_headers::render::shader::{
+    CrossCode, ShaderHeader, ShaderPackage, VertexInput, ENUM_MAX_COMPRESSION, ENUM_MAX_SHADER_API,
+    ENUM_MAX_SHADER_TYPE, ENUM_MAX_VERTEX_FORMAT,
+};
+
+fn invalid(what: &str) -> SgeError {
+    SgeError::Message(format!("invalid package: {}", what))
+}
+
+// a table and its vtable, positions in the buffer
+struct Table {
+    pos: usize,
+    vtable: usize,
+    vtable_size: usize,
+    table_size: usize,
+}
+
+struct Verifier<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Verifier<'a> {
+    fn range(&self, pos: usize, len: usize, what: &str) -> SgeResult<()> {
+        match pos.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(()),
+            _ => Err(invalid(&format!("{} out of bounds", what))),
+        }
+    }
+
+    fn scalar(&self, pos: usize, size: usize, what: &str) -> SgeResult<()> {
+        self.range(pos, size, what)?;
+        if pos % size != 0 {
+            return Err(invalid(&format!("{} misaligned", what)));
+        }
+        Ok(())
+    }
+
+    fn u16_at(&self, pos: usize, what: &str) -> SgeResult<usize> {
+        self.scalar(pos, 2, what)?;
+        Ok(u16::from_le_bytes([self.data[pos], self.data[pos + 1]]) as usize)
+    }
+
+    fn u32_at(&self, pos: usize, what: &str) -> SgeResult<u32> {
+        self.scalar(pos, 4, what)?;
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.data[pos..pos + 4]);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    // follows the offset stored at pos, offsets are relative to where they are stored
+    fn offset(&self, pos: usize, what: &str) -> SgeResult<usize> {
+        let offset = self.u32_at(pos, what)? as usize;
+        pos.checked_add(offset)
+            .ok_or_else(|| invalid(&format!("{} out of bounds", what)))
+    }
+
+    fn table(&self, pos: usize, what: &str) -> SgeResult<Table> {
+        // tables start with the signed offset back to their vtable
+        let soffset = self.u32_at(pos, what)? as i32 as i64;
+        let vtable = pos as i64 - soffset;
+        if vtable < 0 || vtable as u64 > self.data.len() as u64 {
+            return Err(invalid(&format!("{} vtable out of bounds", what)));
+        }
+        let vtable = vtable as usize;
+        let vtable_size = self.u16_at(vtable, what)?;
+        let table_size = self.u16_at(vtable + 2, what)?;
+        if vtable_size < 4 || vtable_size % 2 != 0 {
+            return Err(invalid(&format!("{} vtable malformed", what)));
+        }
+        self.range(vtable, vtable_size, what)?;
+        self.range(pos, table_size, what)?;
+        Ok(Table {
+            pos,
+            vtable,
+            vtable_size,
+            table_size,
+        })
+    }
+
+    // position of a field of t, none when absent
+    fn field(&self, t: &Table, field: u16, size: usize, what: &str) -> SgeResult<Option<usize>> {
+        let field = field as usize;
+        if field + 2 > t.vtable_size {
+            return Ok(None);
+        }
+        let offset = self.u16_at(t.vtable + field, what)?;
+        if offset == 0 {
+            return Ok(None);
+        }
+        if offset + size > t.table_size {
+            return Err(invalid(&format!("{} out of its table", what)));
+        }
+        self.scalar(t.pos + offset, size, what)?;
+        Ok(Some(t.pos + offset))
+    }
+
+    fn enum_field(&self, t: &Table, field: u16, max: u8, what: &str) -> SgeResult<()> {
+        if let Some(pos) = self.field(t, field, 1, what)? {
+            if self.data[pos] > max {
+                return Err(invalid(&format!("unknown {} {}", what, self.data[pos])));
+            }
+        }
+        Ok(())
+    }
+
+    // start and length of a vector of elements of size bytes
+    fn vector_at(&self, pos: usize, size: usize, what: &str) -> SgeResult<(usize, usize)> {
+        let len = self.u32_at(pos, what)? as usize;
+        let bytes = len
+            .checked_mul(size)
+            .ok_or_else(|| invalid(&format!("{} out of bounds", what)))?;
+        self.range(pos + 4, bytes, what)?;
+        Ok((pos + 4, len))
+    }
+
+    fn string_at(&self, pos: usize, what: &str) -> SgeResult<()> {
+        let (start, len) = self.vector_at(pos, 1, what)?;
+        // strings are followed by a terminating zero
+        self.range(start + len, 1, what)?;
+        if self.data[start + len] != 0 {
+            return Err(invalid(&format!("{} not terminated", what)));
+        }
+        std::str::from_utf8(&self.data[start..start + len])
+            .map_err(|_| invalid(&format!("{} not utf-8", what)))?;
+        Ok(())
+    }
+
+    fn string(&self, t: &Table, field: u16, what: &str) -> SgeResult<()> {
+        if let Some(pos) = self.field(t, field, 4, what)? {
+            self.string_at(self.offset(pos, what)?, what)?;
+        }
+        Ok(())
+    }
+
+    fn bytes(&self, t: &Table, field: u16, what: &str) -> SgeResult<()> {
+        if let Some(pos) = self.field(t, field, 4, what)? {
+            self.vector_at(self.offset(pos, what)?, 1, what)?;
+        }
+        Ok(())
+    }
+
+    // calls f with the position every element of a vector of offsets points to
+    fn offsets(
+        &self,
+        t: &Table,
+        field: u16,
+        what: &str,
+        f: impl Fn(usize) -> SgeResult<()>,
+    ) -> SgeResult<()> {
+        if let Some(pos) = self.field(t, field, 4, what)? {
+            let (start, len) = self.vector_at(self.offset(pos, what)?, 4, what)?;
+            for i in 0..len {
+                f(self.offset(start + 4 * i, what)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn strings(&self, t: &Table, field: u16, what: &str) -> SgeResult<()> {
+        self.offsets(t, field, what, |pos| self.string_at(pos, what))
+    }
+
+    fn cross_code(&self, pos: usize) -> SgeResult<()> {
+        let t = self.table(pos, "cross code")?;
+        self.enum_field(&t, CrossCode::VT_API, ENUM_MAX_SHADER_API, "shader api")?;
+        self.string(&t, CrossCode::VT_CODE, "cross code")
+    }
+
+    fn vertex_input(&self, pos: usize) -> SgeResult<()> {
+        let t = self.table(pos, "vertex input")?;
+        self.string(&t, VertexInput::VT_SEMANTIC, "vertex input semantic")?;
+        self.field(&t, VertexInput::VT_LOCATION, 4, "vertex input location")?;
+        self.enum_field(
+            &t,
+            VertexInput::VT_FORMAT,
+            ENUM_MAX_VERTEX_FORMAT,
+            "vertex format",
+        )
+    }
+
+    fn shader(&self, pos: usize) -> SgeResult<()> {
+        let t = self.table(pos, "shader header")?;
+        self.string(&t, ShaderHeader::VT_ENTRY_POINT, "entry point")?;
+        self.enum_field(
+            &t,
+            ShaderHeader::VT_SHADER_TYPE,
+            ENUM_MAX_SHADER_TYPE,
+            "shader type",
+        )?;
+        self.bytes(&t, ShaderHeader::VT_DATA, "shader data")?;
+        self.field(&t, ShaderHeader::VT_PERMUTATION, 4, "permutation")?;
+        self.enum_field(
+            &t,
+            ShaderHeader::VT_COMPRESSION,
+            ENUM_MAX_COMPRESSION,
+            "compression",
+        )?;
+        self.field(
+            &t,
+            ShaderHeader::VT_UNCOMPRESSED_SIZE,
+            4,
+            "uncompressed size",
+        )?;
+        self.bytes(&t, ShaderHeader::VT_HASH, "hash")?;
+        self.string(&t, ShaderHeader::VT_SOURCE, "source")?;
+        self.strings(
+            &t,
+            ShaderHeader::VT_PERMUTATION_DEFINES,
+            "permutation define",
+        )?;
+        self.string(&t, ShaderHeader::VT_PROFILE, "profile")?;
+        self.strings(&t, ShaderHeader::VT_EXPORTS, "export")?;
+        self.strings(&t, ShaderHeader::VT_ARGUMENTS, "argument")?;
+        self.offsets(&t, ShaderHeader::VT_CROSS_CODE, "cross code", |pos| {
+            self.cross_code(pos)
+        })?;
+        self.string(&t, ShaderHeader::VT_SOURCE_TEXT, "source text")?;
+        self.bytes(&t, ShaderHeader::VT_DXBC, "dxbc")?;
+        self.offsets(&t, ShaderHeader::VT_VERTEX_INPUTS, "vertex inputs", |pos| {
+            self.vertex_input(pos)
+        })
+    }
+}
+
+// PackageValidate checks that data is a well formed package, so its accessors can be used on it
+// it checks the layout, not the contents (use signature::package_verify for that)
+pub fn package_validate(data: &[u8]) -> SgeResult<()> {
+    let v = Verifier { data };
+    let root = v.offset(0, "root")?;
+    let t = v.table(root, "package")?;
+    v.string(&t, ShaderPackage::VT_NAME, "name")?;
+    v.offsets(&t, ShaderPackage::VT_SHADERS, "shaders", |pos| {
+        v.shader(pos)
+    })?;
+    v.field(&t, ShaderPackage::VT_FORMAT_VERSION, 4, "format version")?;
+    v.bytes(&t, ShaderPackage::VT_SIGNATURE, "signature")
+}
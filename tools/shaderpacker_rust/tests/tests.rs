@@ -12,9 +12,1283 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use rust_shade_fb_lib::shader_generated::get_root_as_shader_package_flat;
-use rust_shade_fb_lib::shader_generated::ShaderTypeFlat;
-use rust_shade_fb_lib::*;
+use rust_shader_headers::render::shader::{
+    get_root_as_shader_package, Compression, ShaderApi, ShaderHeader, ShaderHeaderArgs,
+    ShaderPackage, ShaderPackageArgs, ShaderType, VertexFormat,
+};
+use shaderpacker_rust_lib::batch::*;
+use shaderpacker_rust_lib::cache::*;
+use shaderpacker_rust_lib::compression::*;
+use shaderpacker_rust_lib::config::*;
+use shaderpacker_rust_lib::cross::*;
+use shaderpacker_rust_lib::depfile::*;
+use shaderpacker_rust_lib::diagnostics::*;
+use shaderpacker_rust_lib::disasm::*;
+use shaderpacker_rust_lib::dxbc::*;
+use shaderpacker_rust_lib::include::IncludeResolver;
+use shaderpacker_rust_lib::layout::*;
+use shaderpacker_rust_lib::loader::*;
+use shaderpacker_rust_lib::manifest::*;
+use shaderpacker_rust_lib::runtime::*;
+use shaderpacker_rust_lib::server::*;
+use shaderpacker_rust_lib::signature::*;
+use shaderpacker_rust_lib::stats::*;
+use shaderpacker_rust_lib::variant::*;
+use shaderpacker_rust_lib::verifier::*;
+use shaderpacker_rust_lib::*;
+use std::path::{Path, PathBuf};
+
+// creates an empty scratch directory under the system temp dir
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join("shaderpacker_rust_tests")
+        .join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_shader_model() {
+    assert_eq!(ShaderModel::default(), ShaderModel { major: 6, minor: 0 });
+    assert_eq!(
+        "6_5".parse::<ShaderModel>(),
+        Ok(ShaderModel { major: 6, minor: 5 })
+    );
+    assert_eq!(
+        "6.7".parse::<ShaderModel>(),
+        Ok(ShaderModel { major: 6, minor: 7 })
+    );
+    assert_eq!(ShaderModel { major: 6, minor: 6 }.to_string(), "6_6");
+    assert!("5_1".parse::<ShaderModel>().is_err());
+    assert!("6_8".parse::<ShaderModel>().is_err());
+    assert!("6".parse::<ShaderModel>().is_err());
+    assert!("6_0_1".parse::<ShaderModel>().is_err());
+}
+
+#[test]
+fn test_profile_override() {
+    assert_eq!(
+        profile_validate(ShaderType::Pixel, "ps_6_6"),
+        Ok(ShaderModel { major: 6, minor: 6 })
+    );
+    assert_eq!(
+        profile_validate(ShaderType::Miss, "lib_6_5"),
+        Ok(ShaderModel { major: 6, minor: 5 })
+    );
+    assert!(profile_validate(ShaderType::Pixel, "vs_6_6").is_err());
+    assert!(profile_validate(ShaderType::Pixel, "ps_6_9").is_err());
+    assert!(profile_validate(ShaderType::Mesh, "ms_6_4").is_err());
+
+    let source = r#"//@shader(VSMain, Vertex, ps_6_6)
+//@shader(MSMain, Mesh, ms_6_0)
+//@shader(PSMain, Pixel, ps_6_6)
+void VSMain() {}
+void MSMain() {}
+"#;
+    let result = shader_compile(source.as_bytes(), "test.hlsl", &CompileOptions::default());
+    let e = result.err().unwrap();
+    let errors = compile_error_find(&e).unwrap();
+    assert_eq!(errors.diagnostics.len(), 2);
+    assert_eq!(errors.diagnostics[0].line, 1);
+    assert_eq!(
+        errors.diagnostics[0].message,
+        "profile ps_6_6 doesn't match shader type Vertex, expected vs_<model>"
+    );
+    assert_eq!(errors.diagnostics[1].line, 2);
+    assert_eq!(
+        errors.diagnostics[1].message,
+        "Mesh shaders need shader model 6_5 or later, ms_6_0 is too old"
+    );
+}
+
+#[test]
+fn test_include() {
+    let dir = test_dir("include");
+    std::fs::create_dir_all(dir.join("common")).unwrap();
+    std::fs::write(
+        dir.join("common/lighting.hlsli"),
+        "#pragma once\n#include \"math.hlsli\"\nfloat4 Light();\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("common/math.hlsli"), "float Square(float x);\n").unwrap();
+
+    let resolver = IncludeResolver::new(&[dir.join("common")]);
+    let source = "#include <lighting.hlsli>\n#include \"lighting.hlsli\"\nfloat4 PSMain();\n";
+    let result = resolver.preprocess(source, "main.hlsl", None).unwrap();
+    assert_eq!(result.includes.len(), 2);
+    assert!(result.includes[0].ends_with("lighting.hlsli"));
+    assert!(result.includes[1].ends_with("math.hlsli"));
+    assert_eq!(result.text.matches("float4 Light();").count(), 1);
+    assert!(result.text.contains("float Square(float x);"));
+    assert!(result.text.contains("#line 2 \"main.hlsl\""));
+    assert!(result.text.ends_with("float4 PSMain();\n"));
+
+    let missing = resolver.preprocess("#include \"missing.hlsli\"\n", "main.hlsl", None);
+    assert!(missing.is_err());
+
+    std::fs::write(dir.join("common/a.hlsli"), "#include \"b.hlsli\"\n").unwrap();
+    std::fs::write(dir.join("common/b.hlsli"), "#include \"a.hlsli\"\n").unwrap();
+    assert!(resolver
+        .preprocess("#include <a.hlsli>\n", "main.hlsl", None)
+        .is_err());
+
+    // headers including each other behind include guards
+    std::fs::write(
+        dir.join("common/c.hlsli"),
+        "#ifndef C_H\n#define C_H\n#include \"d.hlsli\"\nfloat C();\n#endif\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("common/d.hlsli"),
+        "#ifndef D_H // guard\n#define D_H\n#include \"c.hlsli\"\nfloat D();\n#endif\n",
+    )
+    .unwrap();
+    let result = resolver
+        .preprocess(
+            "#include <c.hlsli>\n#include <d.hlsli>\n",
+            "main.hlsl",
+            None,
+        )
+        .unwrap();
+    assert_eq!(result.includes.len(), 2);
+    assert_eq!(result.text.matches("float C();").count(), 1);
+    assert_eq!(result.text.matches("float D();").count(), 1);
+
+    // includes in comments and inactive blocks are not followed
+    let source = "// #include \"missing.hlsli\"\n/*\n#include \"missing.hlsli\"\n*/\n\
+                  #if 0\n#include \"missing.hlsli\"\n#else\n#include <math.hlsli>\n#endif\n";
+    let result = resolver.preprocess(source, "main.hlsl", None).unwrap();
+    assert_eq!(result.includes.len(), 1);
+    assert!(result.text.contains("float Square(float x);"));
+    // a missing include depending on a -D define is left to dxc
+    let source = "#ifdef USE_MISSING\n#include \"missing.hlsli\"\n#endif\n";
+    let result = resolver.preprocess(source, "main.hlsl", None).unwrap();
+    assert!(result.text.contains("#include \"missing.hlsli\""));
+    let source = "#define USE_MISSING\n#ifdef USE_MISSING\n#include \"missing.hlsli\"\n#endif\n";
+    assert!(resolver.preprocess(source, "main.hlsl", None).is_err());
+
+    // the directory of the source is searched for "file" only
+    std::fs::write(dir.join("local.hlsli"), "float Local();\n").unwrap();
+    let result = resolver
+        .preprocess("#include \"local.hlsli\"\n", "main.hlsl", Some(&dir))
+        .unwrap();
+    assert!(result.text.contains("float Local();"));
+    assert!(resolver
+        .preprocess("#include <local.hlsli>\n", "main.hlsl", Some(&dir))
+        .is_err());
+}
+
+#[test]
+fn test_permutation_defines() {
+    let defines = vec!["USE_SHADOWS".to_string(), "USE_FOG".to_string()];
+    assert!(permutation_defines_build(&defines, 0).is_empty());
+    assert_eq!(
+        permutation_defines_build(&defines, 2),
+        vec![("USE_FOG", Some("1"))]
+    );
+    assert_eq!(
+        permutation_defines_build(&defines, 3),
+        vec![("USE_SHADOWS", Some("1")), ("USE_FOG", Some("1"))]
+    );
+}
+
+#[test]
+fn test_config() {
+    let dir = test_dir("config");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("shaders.json"),
+        r#"{
+            "defines": ["SHADOW_CASCADES=4", "USE_FOG"],
+            "include_dirs": ["common"],
+            "platforms": {
+                "pc": { "defines": ["PLATFORM_PC"] },
+                "mobile": { "defines": ["SHADOW_CASCADES=2"], "include_dirs": ["mobile"] }
+            }
+        }"#,
+    )
+    .unwrap();
+    let config = Config::load(&dir.join("shaders.json")).unwrap();
+
+    let mut options = CompileOptions {
+        include_dirs: vec!["cmdline".into()],
+        ..Default::default()
+    };
+    config.apply(Some("mobile"), &mut options).unwrap();
+    assert_eq!(
+        options.defines,
+        vec![
+            ("SHADOW_CASCADES".to_string(), Some("2".to_string())),
+            ("USE_FOG".to_string(), None)
+        ]
+    );
+    assert_eq!(
+        options.include_dirs,
+        vec![
+            PathBuf::from("cmdline"),
+            dir.join("common"),
+            dir.join("mobile")
+        ]
+    );
+
+    let mut options = CompileOptions::default();
+    config.apply(None, &mut options).unwrap();
+    assert_eq!(options.defines.len(), 2);
+    assert!(config
+        .apply(Some("console"), &mut options)
+        .unwrap_err()
+        .to_string()
+        .contains("unknown platform console, the config has: mobile, pc"));
+
+    assert!(Config::from_json(r#"{ "define": [] }"#).is_err());
+    assert!(define_parse("1ST=2").is_err());
+    assert_eq!(define_parse("A=").unwrap(), ("A".into(), Some("".into())));
+
+    let source = "//@permutation(USE_FOG)\n//@shader(PSMain, Pixel)\nvoid PSMain() {}\n";
+    let e = shader_compile(source.as_bytes(), "test.hlsl", &options)
+        .err()
+        .unwrap();
+    assert_eq!(
+        compile_error_find(&e).unwrap().diagnostics[0].message,
+        "permutation define USE_FOG is also a global define"
+    );
+}
+
+#[test]
+fn test_optimization() {
+    assert_eq!(CompileOptions::default().optimization, Optimization::O3);
+    assert_eq!(Optimization::from_arg("-Od"), Some(Optimization::Disabled));
+    assert_eq!(Optimization::from_arg("-O1"), Some(Optimization::O1));
+    assert_eq!(Optimization::from_arg("-O4"), None);
+    assert_eq!(Optimization::O2.arg(), "-O2");
+}
+
+#[test]
+fn test_entry_points_discover() {
+    let source = r#"
+// [shader("pixel")] in a comment is ignored
+[shader("vertex")]
+float4 VSMain(float3 position : POSITION) : SV_POSITION { return float4(position, 1); }
+
+[numthreads(8, 8, 1)]
+void CSMain(uint3 id : SV_DispatchThreadID)
+{
+    [unroll]
+    for (int i = 0; i < 4; i++) {}
+}
+
+[shader("raygeneration")]
+void RayGen() {}
+
+[shader("mesh")] [outputtopology("triangle")] [numthreads(32, 1, 1)]
+void MSMain() {}
+
+[shader("unknown")]
+void Unknown() {}
+"#;
+    assert_eq!(
+        entry_points_discover(source),
+        vec![
+            (ShaderType::Vertex, "VSMain".to_string()),
+            (ShaderType::Compute, "CSMain".to_string()),
+            (ShaderType::RayGeneration, "RayGen".to_string()),
+            (ShaderType::Mesh, "MSMain".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_diagnostics() {
+    let output = r#"lighting.hlsl:12:5: error: use of undeclared identifier 'foo'
+    foo = 1;
+    ^
+common/math.hlsli:3:1: warning: implicit truncation of vector type
+error: validation failed
+"#;
+    let diagnostics = diagnostics_parse(output);
+    assert_eq!(diagnostics.len(), 3);
+    assert_eq!(diagnostics[0].file, "lighting.hlsl");
+    assert_eq!(diagnostics[0].line, 12);
+    assert_eq!(diagnostics[0].column, 5);
+    assert_eq!(diagnostics[0].severity, Severity::Error);
+    assert_eq!(diagnostics[0].message, "use of undeclared identifier 'foo'");
+    assert_eq!(diagnostics[1].severity, Severity::Warning);
+    assert_eq!(diagnostics[2].file, "");
+    assert_eq!(diagnostics[2].line, 0);
+
+    let mut errors = CompileError::default();
+    for d in diagnostics.iter().chain(diagnostics.iter()) {
+        errors.push(d.clone());
+    }
+    assert_eq!(errors.diagnostics.len(), 3);
+    assert_eq!(errors.errors().count(), 2);
+
+    let source = "//@shader(VSMain, Vertex)\n//@shader(PSMain, Pixle)\n//@permutation(A, A)\nvoid VSMain() {}\n";
+    let result = shader_compile(source.as_bytes(), "test.hlsl", &CompileOptions::default());
+    let e = result.err().unwrap();
+    let errors = compile_error_find(&e).unwrap();
+    assert_eq!(errors.diagnostics.len(), 2);
+    assert_eq!(errors.diagnostics[0].line, 2);
+    assert_eq!(errors.diagnostics[0].message, "unknown shader type Pixle");
+    assert_eq!(
+        errors.diagnostics[0].to_string(),
+        "test.hlsl:2:0: error: unknown shader type Pixle"
+    );
+    assert_eq!(errors.diagnostics[1].line, 3);
+
+    let source =
+        "//@args(-HV 2021)\n//@args(-O0)\n//@shader(VSMain, Vertex)\n//@args(-Zi)\nvoid VSMain() {}\n";
+    let result = shader_compile(source.as_bytes(), "test.hlsl", &CompileOptions::default());
+    let e = result.err().unwrap();
+    let errors = compile_error_find(&e).unwrap();
+    assert_eq!(errors.diagnostics.len(), 2);
+    assert_eq!(errors.diagnostics[0].line, 2);
+    assert_eq!(
+        errors.diagnostics[0].message,
+        "@args declared twice before @shader"
+    );
+    assert_eq!(errors.diagnostics[1].line, 4);
+    assert_eq!(
+        errors.diagnostics[1].message,
+        "@args is not followed by @shader"
+    );
+}
+
+#[test]
+fn test_entry_point_validation() {
+    let source = r#"//@shader(VSMian, Vertex)
+//@shader(PSMain, Pixel)
+//@shader(CSMain, Compute)
+
+struct PSInput { float4 position : SV_POSITION; };
+
+PSInput VSMain(float3 position : POSITION) { PSInput result; return result; }
+
+[earlydepthstencil]
+float4 PSMain(PSInput input) : SV_TARGET { return input.position; }
+
+float4 Shade(float4 color) { return color; }
+"#;
+    let functions = functions_find(source);
+    assert_eq!(functions, vec!["VSMain", "PSMain", "Shade"]);
+
+    let result = shader_compile(source.as_bytes(), "test.hlsl", &CompileOptions::default());
+    let e = result.err().unwrap();
+    let errors = compile_error_find(&e).unwrap();
+    assert_eq!(errors.diagnostics.len(), 2);
+    assert_eq!(errors.diagnostics[0].line, 1);
+    assert_eq!(
+        errors.diagnostics[0].message,
+        "entry point VSMian not found, candidates: VSMain, PSMain, Shade"
+    );
+    assert_eq!(errors.diagnostics[1].line, 3);
+    assert!(errors.diagnostics[1]
+        .message
+        .starts_with("entry point CSMain not found, candidates: "));
+}
+
+#[test]
+fn test_compile_sources() {
+    let headers = [(
+        "shaders/lighting.hlsli",
+        "#pragma once\nfloat4 PSMain() : SV_TARGET { return 1; }\n",
+    )];
+    let includes = |include: &str, _: Option<&Path>, _: bool| {
+        Ok(headers
+            .iter()
+            .find(|h| h.0.ends_with(include))
+            .map(|h| (PathBuf::from(h.0), h.1.to_string())))
+    };
+
+    let resolver = IncludeResolver::with_loader(includes);
+    let result = resolver
+        .preprocess("#include \"lighting.hlsli\"\n", "main.hlsl", None)
+        .unwrap();
+    assert_eq!(
+        result.includes,
+        vec![PathBuf::from("shaders/lighting.hlsli")]
+    );
+    assert!(result.text.contains("return 1;"));
+
+    // entry points found in the include list it as a candidate
+    let sources = vec![
+        Source {
+            name: "lighting.hlsl".into(),
+            text: "//@shader(PSMian, Pixel)\n#include <lighting.hlsli>\n".into(),
+        },
+        Source {
+            name: "blur.hlsl".into(),
+            text: "#include \"missing.hlsli\"\n".into(),
+        },
+    ];
+    let e = compile_sources(&sources, &CompileOptions::default(), &includes)
+        .err()
+        .unwrap();
+    let errors = compile_error_find(&e).unwrap();
+    assert_eq!(
+        errors.diagnostics[0].message,
+        "entry point PSMian not found, candidates: PSMain"
+    );
+    let e = compile_sources(&sources[1..], &CompileOptions::default(), &includes)
+        .err()
+        .unwrap();
+    assert!(e
+        .to_string()
+        .contains("blur.hlsl:1: cannot open include file missing.hlsli"));
+}
+
+#[test]
+fn test_compression() {
+    assert_eq!(compression_from_str("zstd"), Ok(Compression::Zstd));
+    assert_eq!(compression_from_str("LZ4"), Ok(Compression::Lz4));
+    assert!(compression_from_str("gzip").is_err());
+
+    let data: Vec<u8> = (0..4096u32).map(|i| (i % 7) as u8).collect();
+    for &c in &[Compression::None, Compression::Zstd, Compression::Lz4] {
+        let packed = compress(&data, c).unwrap();
+        if c != Compression::None {
+            assert!(packed.len() < data.len());
+        }
+        assert_eq!(decompress(&packed, c, data.len()).unwrap(), data);
+        assert!(decompress(&packed, c, data.len() + 1).is_err());
+
+        let mut builder = flatbuffers::FlatBufferBuilder::new();
+        let blob = builder.create_vector(&packed);
+        let header = ShaderHeader::create(
+            &mut builder,
+            &ShaderHeaderArgs {
+                data: Some(blob),
+                compression: c,
+                uncompressed_size: data.len() as u32,
+                ..Default::default()
+            },
+        );
+        let shaders = builder.create_vector(&[header]);
+        let package = ShaderPackage::create(
+            &mut builder,
+            &ShaderPackageArgs {
+                shaders: Some(shaders),
+                ..Default::default()
+            },
+        );
+        builder.finish(package, None);
+        let package = get_root_as_shader_package(builder.finished_data());
+        let header = package.shaders().unwrap().get(0);
+        assert_eq!(shader_data(&header).unwrap().as_ref(), &data[..]);
+    }
+}
+
+// spirv of an empty compute shader, void main() {} with a 1x1x1 work group
+#[rustfmt::skip]
+const EMPTY_COMPUTE_SPIRV: &[u32] = &[
+    0x07230203, 0x00010000, 0, 5, 0,
+    0x00020011, 1,
+    0x0003000e, 0, 1,
+    0x0005000f, 5, 1, 0x6e69616d, 0,
+    0x00060010, 1, 17, 1, 1, 1,
+    0x00020013, 2,
+    0x00030021, 3, 2,
+    0x00050036, 2, 1, 0, 3,
+    0x000200f8, 4,
+    0x000100fd,
+    0x00010038,
+];
+
+#[test]
+fn test_cross_compile() {
+    assert_eq!(shader_api_from_str("GLSL"), Ok(ShaderApi::Glsl));
+    assert_eq!(shader_api_from_str("msl"), Ok(ShaderApi::Msl));
+    assert!(shader_api_from_str("wgsl").is_err());
+
+    let spirv: Vec<u8> = EMPTY_COMPUTE_SPIRV
+        .iter()
+        .flat_map(|w| w.to_le_bytes().to_vec())
+        .collect();
+    let glsl = cross_compile(&spirv, ShaderApi::Glsl).unwrap();
+    assert!(glsl.contains("#version"));
+    assert!(glsl.contains("void main()"));
+    let msl = cross_compile(&spirv, ShaderApi::Msl).unwrap();
+    assert!(msl.contains("kernel void"));
+    assert!(cross_compile(&spirv[1..], ShaderApi::Glsl).is_err());
+
+    let mut sources = compiled_sources();
+    sources[1].shaders[0].cross_code = vec![(ShaderApi::Msl, msl.clone())];
+    let builder = package_build("materials", &sources, &CompileOptions::default()).unwrap();
+    let pkg = get_root_as_shader_package(builder.finished_data());
+    let cross = pkg.shaders().unwrap().get(4).cross_code().unwrap();
+    assert_eq!(cross.len(), 1);
+    assert_eq!(cross.get(0).api(), ShaderApi::Msl);
+    assert_eq!(cross.get(0).code(), Some(msl.as_str()));
+}
+
+#[test]
+fn test_disasm() {
+    let spirv: Vec<u8> = EMPTY_COMPUTE_SPIRV
+        .iter()
+        .flat_map(|w| w.to_le_bytes().to_vec())
+        .collect();
+    let text = disassemble(&spirv).unwrap();
+    assert!(text.contains("OpEntryPoint GLCompute"));
+    assert!(disassemble(&[1, 2, 3, 4]).is_err());
+
+    let mut sources = compiled_sources();
+    sources.truncate(1);
+    for s in sources[0].shaders.iter_mut() {
+        s.data = spirv.clone();
+    }
+    let options = CompileOptions {
+        compression: Compression::Zstd,
+        ..Default::default()
+    };
+    let dir = test_dir("disasm");
+    let package = dir.join("lighting.shader_pkg");
+    package_save(&package, &sources, &options).unwrap();
+    let written = disasm_write(&package).unwrap();
+    assert_eq!(written.len(), 4);
+    assert!(written[1].ends_with("lighting.shader_pkg.disasm/lighting.VSMain.1.spvasm"));
+    assert_eq!(std::fs::read_to_string(&written[1]).unwrap(), text);
+}
+
+// a compute shader using a constant buffer, a structured buffer (BufferBlock) and a texture
+#[rustfmt::skip]
+const RESOURCES_COMPUTE_SPIRV: &[u32] = &[
+    0x07230203, 0x00010000, 0, 17, 0,
+    0x00020011, 1,
+    0x0003000e, 0, 1,
+    0x0005000f, 5, 1, 0x6e69616d, 0,
+    0x00060010, 1, 17, 1, 1, 1,
+    0x00030047, 7, 2,
+    0x00030047, 11, 3,
+    0x00020013, 2,
+    0x00030021, 3, 2,
+    0x00030016, 6, 32,
+    0x0003001e, 7, 6,
+    0x00040020, 8, 2, 7,
+    0x0004003b, 8, 9, 2,
+    0x0003001e, 11, 6,
+    0x00040020, 12, 2, 11,
+    0x0004003b, 12, 13, 2,
+    0x00090019, 14, 6, 1, 0, 0, 0, 1, 0,
+    0x00040020, 15, 0, 14,
+    0x0004003b, 15, 16, 0,
+    0x00050036, 2, 1, 0, 3,
+    0x000200f8, 4,
+    0x000100fd,
+    0x00010038,
+];
+
+#[test]
+fn test_stats() {
+    let spirv: Vec<u8> = RESOURCES_COMPUTE_SPIRV
+        .iter()
+        .flat_map(|w| w.to_le_bytes().to_vec())
+        .collect();
+    let mut sources = compiled_sources();
+    sources[1].shaders[0].data = spirv.clone();
+    let builder = package_build("materials", &sources, &CompileOptions::default()).unwrap();
+    let stats = package_stats(builder.finished_data()).unwrap();
+    assert_eq!(stats.len(), 5);
+    // not spirv
+    assert_eq!(stats[0].instructions, None);
+    let cs = &stats[4];
+    assert_eq!(cs.entry_point, "CSMain");
+    assert_eq!(cs.size, spirv.len() as u32);
+    assert_eq!(cs.instructions, Some(1));
+    assert_eq!(cs.uniform_buffers, 1);
+    assert_eq!(cs.storage_buffers, 1);
+    assert_eq!(cs.resources, 1);
+    let table = stats_table(&stats);
+    assert_eq!(table.lines().count(), 6);
+    assert!(table.lines().nth(5).unwrap().starts_with("blur.hlsl"));
+
+    let budget = Budget::from_json(
+        r#"{
+            "default": { "size": 100, "resources": 0 },
+            "shaders": { "blur.hlsl:CSMain": { "size": 1000 }, "PSMain": { "size": 1 } }
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        budget.check(&stats),
+        vec![
+            "lighting.hlsl:PSMain permutation 0x0: 2 bytes, budget 1",
+            "lighting.hlsl:PSMain permutation 0x1: 2 bytes, budget 1",
+            "blur.hlsl:CSMain permutation 0x0: 1 resources, budget 0",
+        ]
+    );
+    assert!(Budget::from_json(r#"{ "default": { "registers": 1 } }"#).is_err());
+}
+
+// vertex shader with float3 POSITION (UserSemantic), float2 TEXCOORD0 (debug name only), uint4
+// without a name and SV_VertexID
+#[rustfmt::skip]
+const VERTEX_INPUTS_SPIRV: &[u32] = &[
+    0x07230203, 0x00010000, 0, 18, 0,
+    0x00020011, 1,
+    0x0003000e, 0, 1,
+    0x0009000f, 0, 1, 0x6e69616d, 0, 10, 11, 12, 13,
+    0x00070005, 11, 0x762e6e69, 0x542e7261, 0x4f435845, 0x3044524f, 0,
+    0x00040047, 10, 30, 1,
+    0x00040047, 11, 30, 0,
+    0x00040047, 12, 11, 42,
+    0x00040047, 13, 30, 2,
+    0x00061600, 10, 5635, 0x49534f50, 0x4e4f4954, 0,
+    0x00020013, 2,
+    0x00030021, 3, 2,
+    0x00030016, 4, 32,
+    0x00040017, 5, 4, 3,
+    0x00040017, 6, 4, 2,
+    0x00040015, 7, 32, 0,
+    0x00040020, 8, 1, 5,
+    0x00040020, 9, 1, 6,
+    0x00040020, 14, 1, 7,
+    0x00040017, 15, 7, 4,
+    0x00040020, 16, 1, 15,
+    0x0004003b, 8, 10, 1,
+    0x0004003b, 9, 11, 1,
+    0x0004003b, 14, 12, 1,
+    0x0004003b, 16, 13, 1,
+    0x00050036, 2, 1, 0, 3,
+    0x000200f8, 17,
+    0x000100fd,
+    0x00010038,
+];
+
+#[test]
+fn test_vertex_inputs() {
+    let bytes = |words: &[u32]| -> Vec<u8> {
+        words
+            .iter()
+            .flat_map(|w| w.to_le_bytes().to_vec())
+            .collect()
+    };
+    let attribute = |semantic: &str, location, format| VertexAttribute {
+        semantic: semantic.into(),
+        location,
+        format,
+    };
+    let attributes = vec![
+        attribute("TEXCOORD0", 0, VertexFormat::Float2),
+        attribute("POSITION", 1, VertexFormat::Float3),
+        attribute("", 2, VertexFormat::Uint4),
+    ];
+    assert_eq!(
+        vertex_attributes(&bytes(VERTEX_INPUTS_SPIRV)).unwrap(),
+        attributes
+    );
+    assert!(vertex_attributes(&bytes(RESOURCES_COMPUTE_SPIRV)).is_err());
+    assert!(vertex_attributes(&[1, 2, 3, 4]).is_err());
+
+    let mut sources = compiled_sources();
+    sources[0].shaders[0].vertex_inputs = attributes;
+    let builder = package_build("materials", &sources, &CompileOptions::default()).unwrap();
+    assert_eq!(package_sources(builder.finished_data()).unwrap(), sources);
+    let manifest = Manifest::from_package(builder.finished_data());
+    assert_eq!(
+        manifest.shaders[0].vertex_inputs,
+        vec!["0:TEXCOORD0:Float2", "1:POSITION:Float3", "2::Uint4"]
+    );
+    assert!(manifest.shaders[1].vertex_inputs.is_empty());
+}
+
+#[test]
+fn test_variants() {
+    let ship: PackageVariant = "ship:strip,compress=zstd".parse().unwrap();
+    assert!(ship.strip && !ship.embed_source);
+    assert_eq!(ship.compression, Compression::Zstd);
+    let debug: PackageVariant = "debug:embed-source".parse().unwrap();
+    assert!(!debug.strip && debug.embed_source);
+    assert_eq!(
+        "profile".parse::<PackageVariant>().unwrap().compression,
+        Compression::None
+    );
+    assert!("ship:strip,small".parse::<PackageVariant>().is_err());
+    assert!("ship:compress=brotli".parse::<PackageVariant>().is_err());
+    assert!("ship:strip,embed-source".parse::<PackageVariant>().is_err());
+    assert!("../ship:strip".parse::<PackageVariant>().is_err());
+
+    let variants = vec![debug.clone(), ship.clone()];
+    let options = variants_options(&CompileOptions::default(), &variants);
+    assert!(!options.strip && options.embed_source);
+    assert!(variants_options(&options, std::slice::from_ref(&ship)).strip);
+
+    // names and semantics are debug info and reflection, the layout stays
+    let spirv: Vec<u8> = VERTEX_INPUTS_SPIRV
+        .iter()
+        .flat_map(|w| w.to_le_bytes().to_vec())
+        .collect();
+    let stripped = spirv_strip(&spirv).unwrap();
+    assert!(stripped.len() < spirv.len());
+    let attributes = vertex_attributes(&stripped).unwrap();
+    assert_eq!(attributes.len(), 3);
+    assert!(attributes.iter().all(|a| a.semantic.is_empty()));
+    assert_eq!(attributes[1].format, VertexFormat::Float3);
+    assert_eq!(spirv_strip(&stripped).unwrap(), stripped);
+    assert!(spirv_strip(&[1, 2, 3, 4]).is_err());
+
+    let mut sources = compiled_sources();
+    sources.truncate(1);
+    sources[0].text = Some("float4 PSMain() : SV_TARGET { return 1; }\n".into());
+    for s in &mut sources[0].shaders {
+        s.data = spirv.clone();
+        s.arguments = vec!["-spirv".into(), "-O3".into(), "-fspv-reflect".into()];
+    }
+    let (derived, derived_options) = variant_apply(&sources, &debug, &options).unwrap();
+    assert_eq!(derived, sources);
+    assert!(derived_options.embed_source);
+    let (derived, derived_options) = variant_apply(&sources, &ship, &options).unwrap();
+    assert_eq!(derived[0].text, None);
+    assert_eq!(derived[0].shaders[0].data, stripped);
+    assert_eq!(derived[0].shaders[0].arguments, vec!["-spirv", "-O3"]);
+    assert!(derived_options.strip && !derived_options.embed_source);
+    let builder = package_build("lighting", &derived, &derived_options).unwrap();
+    let pkg = get_root_as_shader_package(builder.finished_data());
+    assert_eq!(
+        pkg.shaders().unwrap().get(0).compression(),
+        Compression::Zstd
+    );
+}
+
+#[test]
+fn test_dxbc() {
+    assert_eq!(dxbc_profile(ShaderType::Pixel), Some("ps_5_0"));
+    assert_eq!(dxbc_profile(ShaderType::Compute), Some("cs_5_0"));
+    assert_eq!(dxbc_profile(ShaderType::Mesh), None);
+    assert_eq!(dxbc_profile(ShaderType::Miss), None);
+
+    let missing = fxc_compile(
+        Path::new("missing/fxc.exe"),
+        "lighting.hlsl",
+        "float4 PSMain() : SV_TARGET { return 1; }",
+        "PSMain",
+        "ps_5_0",
+        &[],
+        &CompileOptions::default(),
+    );
+    assert!(missing
+        .unwrap_err()
+        .starts_with("can't run missing/fxc.exe"));
+
+    let diagnostics =
+        diagnostics_parse("lighting.hlsl(12,5-17): error X3004: undeclared identifier 'color'\n");
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].line, 12);
+    assert_eq!(diagnostics[0].column, 5);
+    assert_eq!(diagnostics[0].message, "undeclared identifier 'color'");
+
+    let mut sources = compiled_sources();
+    sources[0].shaders[2].dxbc = vec![0x44, 0x58, 0x42, 0x43];
+    let builder = package_build("materials", &sources, &CompileOptions::default()).unwrap();
+    assert_eq!(package_sources(builder.finished_data()).unwrap(), sources);
+    let manifest = Manifest::from_package(builder.finished_data());
+    assert_eq!(manifest.shaders[2].dxbc_size, 4);
+    assert_eq!(manifest.shaders[3].dxbc_size, 0);
+}
+
+#[test]
+fn test_shader_hash() {
+    let hash = shader_hash(b"abc");
+    assert_eq!(hash.len(), 32);
+    assert_eq!(hash[..4], [0xba, 0x78, 0x16, 0xbf]);
+    assert_eq!(hash, shader_hash(b"abc"));
+    assert_ne!(hash, shader_hash(b"abd"));
+}
+
+#[test]
+fn test_cache() {
+    let dir = test_dir("cache");
+    let cache = ShaderCache::open(&dir.join("cache")).unwrap();
+    let key = cache.key(&["test.hlsl", "float4 PSMain();", "PSMain", "ps_6_0"]);
+    assert_eq!(key.len(), 64);
+    assert_ne!(
+        key,
+        cache.key(&["test.hlsl", "float4 PSMain();", "PSMain", "ps_6_5"])
+    );
+    // inputs are separated, so moving text between them changes the key
+    assert_ne!(cache.key(&["ab", "c"]), cache.key(&["a", "bc"]));
+
+    assert_eq!(cache.get(&key), None);
+    cache.put(&key, &[1, 2, 3]).unwrap();
+    assert_eq!(cache.get(&key), Some(vec![1, 2, 3]));
+    assert_eq!(hex_string(&[0x0f, 0xa0]), "0fa0");
+}
+
+// sources with made up bytecode, so packaging can be tested without dxc
+fn compiled_sources() -> Vec<CompiledSource> {
+    let shader = |entry_point: &str, shader_type, permutation, data: &[u8]| CompiledShader {
+        entry_point: entry_point.into(),
+        shader_type,
+        profile: "".into(),
+        permutation,
+        exports: Vec::new(),
+        arguments: vec!["-spirv".into(), "-O3".into()],
+        cross_code: Vec::new(),
+        data: data.to_vec(),
+        dxbc: Vec::new(),
+        vertex_inputs: Vec::new(),
+    };
+    vec![
+        CompiledSource {
+            name: "lighting.hlsl".into(),
+            shaders: vec![
+                shader("VSMain", ShaderType::Vertex, 0, &[1, 2, 3]),
+                shader("VSMain", ShaderType::Vertex, 1, &[1, 2, 3]),
+                shader("PSMain", ShaderType::Pixel, 0, &[4, 5]),
+                shader("PSMain", ShaderType::Pixel, 1, &[6, 7]),
+            ],
+            permutation_defines: vec!["USE_SHADOWS".into()],
+            ..Default::default()
+        },
+        CompiledSource {
+            name: "blur.hlsl".into(),
+            shaders: vec![shader("CSMain", ShaderType::Compute, 0, &[8, 9, 10])],
+            ..Default::default()
+        },
+    ]
+}
+
+#[test]
+fn test_package_build() {
+    let sources = compiled_sources();
+    let builder = package_build("materials", &sources, &CompileOptions::default()).unwrap();
+    let pkg = get_root_as_shader_package(builder.finished_data());
+    assert_eq!(pkg.name(), Some("materials"));
+    let shaders = pkg.shaders().unwrap();
+    assert_eq!(shaders.len(), 5);
+
+    let vs0 = shaders.get(0);
+    let vs1 = shaders.get(1);
+    assert_eq!(vs1.permutation(), 1);
+    assert_eq!(vs0.source(), Some("lighting.hlsl"));
+    assert_eq!(vs0.permutation_defines().unwrap().get(0), "USE_SHADOWS");
+    // identical bytecode is stored once
+    assert_eq!(vs0.data(), Some(&[1u8, 2, 3][..]));
+    assert_eq!(vs0.data().unwrap().as_ptr(), vs1.data().unwrap().as_ptr());
+    assert_ne!(
+        shaders.get(2).data().unwrap().as_ptr(),
+        shaders.get(3).data().unwrap().as_ptr()
+    );
+
+    let cs = shaders.get(4);
+    assert_eq!(cs.source(), Some("blur.hlsl"));
+    assert_eq!(cs.shader_type(), ShaderType::Compute);
+    assert_eq!(cs.permutation_defines().unwrap().len(), 0);
+    assert_eq!(cs.hash(), Some(&shader_hash(&[8, 9, 10])[..]));
+}
+
+#[test]
+fn test_mesh_shading() {
+    let sm = |s: &str| s.parse::<ShaderModel>().unwrap();
+    assert_eq!(get_shader_target(ShaderType::Mesh, sm("6_0")), "ms_6_5");
+    assert_eq!(
+        get_shader_target(ShaderType::Amplification, sm("6_0")),
+        "as_6_5"
+    );
+    assert_eq!(get_shader_target(ShaderType::Mesh, sm("6_6")), "ms_6_6");
+}
+
+#[test]
+fn test_ray_tracing() {
+    let sm = |s: &str| s.parse::<ShaderModel>().unwrap();
+    assert_eq!(get_shader_target(ShaderType::Pixel, sm("6_0")), "ps_6_0");
+    assert_eq!(get_shader_target(ShaderType::Miss, sm("6_0")), "lib_6_3");
+    assert_eq!(
+        get_shader_target(ShaderType::RayGeneration, sm("6_5")),
+        "lib_6_5"
+    );
+    assert!(shader_type_is_library(ShaderType::Callable));
+    assert!(!shader_type_is_library(ShaderType::Mesh));
+    assert!(!shader_type_is_library(ShaderType::Compute));
+
+    let exports: Vec<String> = vec!["RayGen".into(), "Miss".into()];
+    let shader = |entry_point: &str, shader_type| CompiledShader {
+        entry_point: entry_point.into(),
+        shader_type,
+        profile: "lib_6_3".into(),
+        permutation: 0,
+        exports: exports.clone(),
+        arguments: Vec::new(),
+        cross_code: Vec::new(),
+        data: vec![1, 2, 3, 4],
+        dxbc: Vec::new(),
+        vertex_inputs: Vec::new(),
+    };
+    let sources = vec![CompiledSource {
+        name: "raytrace.hlsl".into(),
+        shaders: vec![
+            shader("RayGen", ShaderType::RayGeneration),
+            shader("Miss", ShaderType::Miss),
+        ],
+        ..Default::default()
+    }];
+    let builder = package_build("raytrace", &sources, &CompileOptions::default()).unwrap();
+    let pkg = get_root_as_shader_package(builder.finished_data());
+    let shaders = pkg.shaders().unwrap();
+    assert_eq!(shaders.len(), 2);
+    let (rg, miss) = (shaders.get(0), shaders.get(1));
+    assert_eq!(miss.shader_type(), ShaderType::Miss);
+    assert_eq!(miss.entry_point(), Some("Miss"));
+    // the library blob is stored once
+    assert_eq!(rg.data().unwrap().as_ptr(), miss.data().unwrap().as_ptr());
+    let rg_exports: Vec<&str> = rg.exports().unwrap().iter().collect();
+    assert_eq!(rg_exports, vec!["RayGen", "Miss"]);
+}
+
+#[test]
+fn test_signature() {
+    let key = b"release key".to_vec();
+    let options = CompileOptions {
+        signing_key: Some(key.clone()),
+        ..Default::default()
+    };
+    let builder = package_build("materials", &compiled_sources(), &options).unwrap();
+    let signed = package_finish(&builder, &options).unwrap();
+    assert_eq!(signed.len(), builder.finished_data().len());
+    assert!(package_verify(&signed, &key).is_ok());
+    assert!(package_load_verified(&signed, &key).is_ok());
+    assert!(package_verify(&signed, b"other key").is_err());
+    // the signature covers every byte, including the shader data
+    let pkg = get_root_as_shader_package(&signed);
+    let data = pkg.shaders().unwrap().get(0).data().unwrap();
+    let offset = data.as_ptr() as usize - signed.as_ptr() as usize;
+    let mut tampered = signed.clone();
+    tampered[offset] ^= 1;
+    assert!(package_verify(&tampered, &key).is_err());
+
+    let unsigned =
+        package_build("materials", &compiled_sources(), &CompileOptions::default()).unwrap();
+    assert!(package_verify(unsigned.finished_data(), &key).is_err());
+    let mut unsigned = unsigned.finished_data().to_vec();
+    assert!(package_sign(&mut unsigned, &key).is_err());
+    // malformed packages fail instead of reading out of bounds
+    assert!(package_verify(&signed[..signed.len() / 2], &key).is_err());
+    assert!(package_verify(&[0xff; 16], &key).is_err());
+}
+
+// a package as written before format versioning
+fn package_v1(format_version: u32) -> Vec<u8> {
+    let mut builder = flatbuffers::FlatBufferBuilder::new();
+    let entry_point = builder.create_string("PSMain");
+    let data = builder.create_vector(&[1u8, 2, 3]);
+    let header = ShaderHeader::create(
+        &mut builder,
+        &ShaderHeaderArgs {
+            entry_point: Some(entry_point),
+            shader_type: ShaderType::Pixel,
+            data: Some(data),
+            ..Default::default()
+        },
+    );
+    let shaders = builder.create_vector(&[header]);
+    let name = builder.create_string("lighting");
+    let package = ShaderPackage::create(
+        &mut builder,
+        &ShaderPackageArgs {
+            name: Some(name),
+            shaders: Some(shaders),
+            format_version,
+            ..Default::default()
+        },
+    );
+    builder.finish(package, None);
+    builder.finished_data().to_vec()
+}
+
+#[test]
+fn test_package_load() {
+    let builder =
+        package_build("materials", &compiled_sources(), &CompileOptions::default()).unwrap();
+    let pkg = get_root_as_shader_package(builder.finished_data());
+    assert_eq!(pkg.format_version(), PACKAGE_FORMAT_VERSION);
+    let loaded = package_load(builder.finished_data()).unwrap();
+    assert!(matches!(loaded, std::borrow::Cow::Borrowed(_)));
+    assert_eq!(loaded.as_ref(), builder.finished_data());
+
+    let v1 = package_v1(1);
+    let upgraded = package_load(&v1).unwrap();
+    let pkg = get_root_as_shader_package(&upgraded);
+    assert_eq!(pkg.format_version(), PACKAGE_FORMAT_VERSION);
+    assert_eq!(pkg.name(), Some("lighting"));
+    let ps = pkg.shaders().unwrap().get(0);
+    assert_eq!(ps.entry_point(), Some("PSMain"));
+    assert_eq!(ps.shader_type(), ShaderType::Pixel);
+    assert_eq!(ps.profile(), Some("ps_6_0"));
+    assert_eq!(ps.uncompressed_size(), 3);
+    assert_eq!(ps.hash(), Some(&shader_hash(&[1, 2, 3])[..]));
+    assert_eq!(shader_data(&ps).unwrap().as_ref(), &[1, 2, 3]);
+    // upgraded packages load as current ones
+    assert_eq!(package_load(&upgraded).unwrap().as_ref(), &upgraded[..]);
+
+    assert!(package_load(&package_v1(PACKAGE_FORMAT_VERSION + 1)).is_err());
+    assert!(package_load(&package_v1(0)).is_err());
+    assert!(package_load(&v1[..v1.len() / 2]).is_err());
+    assert!(package_load(&[0xff; 16]).is_err());
+    assert!(package_load(&[]).is_err());
+}
+
+#[test]
+fn test_runtime() {
+    let dir = test_dir("runtime");
+    let key = b"release key";
+    let options = CompileOptions {
+        signing_key: Some(key.to_vec()),
+        ..Default::default()
+    };
+    let builder = package_build("materials", &compiled_sources(), &options).unwrap();
+    let mut data = builder.finished_data().to_vec();
+    package_sign(&mut data, key).unwrap();
+    let path = dir.join("materials.shader_pkg");
+    std::fs::write(&path, &data).unwrap();
+
+    let file = PackageFile::open_verified(&path, key).unwrap();
+    assert_eq!(file.data(), &data[..]);
+    let view = file.view();
+    assert_eq!(view.name(), "materials");
+    assert_eq!(view.shaders().count(), 5);
+    let ps = view.shader("PSMain", 1).unwrap();
+    assert_eq!(ps.data(), Some(&[6u8, 7][..]));
+    let cs = view.source_shader("blur.hlsl", "CSMain", 0).unwrap();
+    assert_eq!(cs.shader_type(), ShaderType::Compute);
+    assert!(view.shader("PSMain", 2).is_none());
+    assert!(view.source_shader("blur.hlsl", "PSMain", 0).is_none());
+    assert!(PackageFile::open_verified(&path, b"other key").is_err());
+    assert!(PackageFile::open(&dir.join("missing.shader_pkg")).is_err());
+
+    // older formats are upgraded into memory
+    let v1_path = dir.join("v1.shader_pkg");
+    std::fs::write(&v1_path, package_v1(1)).unwrap();
+    let v1 = PackageFile::open(&v1_path).unwrap();
+    assert_eq!(v1.view().package().format_version(), PACKAGE_FORMAT_VERSION);
+    assert_eq!(
+        v1.view().shader("PSMain", 0).unwrap().data(),
+        Some(&[1u8, 2, 3][..])
+    );
+    assert!(PackageView::new(&package_v1(1)).is_err());
+
+    assert!(PackageView::new(&data).is_ok());
+    // truncations are rejected instead of read out of bounds, the last bytes are alignment padding
+    for len in 0..data.len() - 3 {
+        assert!(PackageView::new(&data[..len]).is_err());
+    }
+    let mut corrupted = data.clone();
+    let root = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    corrupted[..4].copy_from_slice(&(root + 0x1000).to_le_bytes());
+    assert!(package_validate(&corrupted).is_err());
+    std::fs::write(&path, &corrupted).unwrap();
+    assert!(PackageFile::open(&path).is_err());
+
+    // out of range enums would be undefined behavior in the accessors
+    let mut builder = flatbuffers::FlatBufferBuilder::new();
+    let header = ShaderHeader::create(
+        &mut builder,
+        &ShaderHeaderArgs {
+            shader_type: ShaderType::Amplification,
+            ..Default::default()
+        },
+    );
+    let shaders = builder.create_vector(&[header]);
+    let package = ShaderPackage::create(
+        &mut builder,
+        &ShaderPackageArgs {
+            shaders: Some(shaders),
+            ..Default::default()
+        },
+    );
+    builder.finish(package, None);
+    let mut data = builder.finished_data().to_vec();
+    assert!(package_validate(&data).is_ok());
+    let amplification = ShaderType::Amplification as u8;
+    let shader_type = data.iter().position(|b| *b == amplification).unwrap();
+    assert_eq!(data.iter().filter(|b| **b == amplification).count(), 1);
+    data[shader_type] = amplification + 1;
+    assert!(package_validate(&data).is_err());
+}
+
+#[test]
+fn test_server() {
+    let request = |entry_point: &str, shader_type: &str| CompileRequest {
+        name: "shaders/lighting.hlsl".into(),
+        source: "//@shader(PSMain, Pixel)\nfloat4 PSMain() : SV_TARGET { return 1; }\n".into(),
+        entry_point: entry_point.into(),
+        shader_type: shader_type.into(),
+        ..Default::default()
+    };
+    let options = CompileOptions::default();
+    let response = request_handle(&request("PSMain", "Pixl"), &options);
+    assert_eq!(response.error, Some("unknown shader type Pixl".into()));
+    assert!(response.shaders.is_empty());
+    // entry points are checked before dxc runs
+    let response = request_handle(&request("PSMian", "Pixel"), &options);
+    assert_eq!(response.errors.len(), 1);
+    assert!(response.errors[0].message.contains("PSMain"));
+    let response = request_handle(
+        &CompileRequest {
+            profile: Some("vs_6_0".into()),
+            ..request("PSMain", "Pixel")
+        },
+        &options,
+    );
+    assert!(response.errors[0].message.contains("vs_6_0"));
+    let response = request_handle(
+        &CompileRequest {
+            defines: vec!["1NVALID".into()],
+            ..request("PSMain", "Pixel")
+        },
+        &options,
+    );
+    assert_eq!(response.error, Some("invalid define 1NVALID".into()));
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let _ = serve(listener, &CompileOptions::default());
+    });
+    let stream = std::net::TcpStream::connect(addr).unwrap();
+    let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut exchange = |request: &str| {
+        use std::io::{BufRead, Write};
+        writeln!(writer, "{}", request).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line
+    };
+    assert!(exchange("{\"source\": 1}").contains("\"error\":\"invalid request"));
+    let response = exchange(r#"{"name": "a.hlsl", "entry_point": "Main", "shader_type": "Pixl"}"#);
+    assert!(response.contains("\"error\":\"unknown shader type Pixl\""));
+}
+
+#[test]
+fn test_package_sources() {
+    let mut sources = compiled_sources();
+    sources[0].text = Some("float4 PSMain() : SV_TARGET { return 1; }\n".into());
+    let options = CompileOptions {
+        compression: Compression::Zstd,
+        ..Default::default()
+    };
+    let builder = package_build("materials", &sources, &options).unwrap();
+    assert_eq!(package_sources(builder.finished_data()).unwrap(), sources);
+    // headers of a source share its embedded text
+    let pkg = get_root_as_shader_package(builder.finished_data());
+    let shaders = pkg.shaders().unwrap();
+    assert_eq!(shaders.get(0).source_text(), sources[0].text.as_deref());
+    assert_eq!(
+        shaders.get(3).source_text().map(|t| t.as_ptr()),
+        shaders.get(0).source_text().map(|t| t.as_ptr())
+    );
+    assert_eq!(shaders.get(4).source_text(), None);
+
+    // merging packages rebuilds them from their sources
+    let a = package_build("a", &sources[..1], &options).unwrap();
+    let b = package_build("b", &sources[1..], &CompileOptions::default()).unwrap();
+    let mut merged = package_sources(a.finished_data()).unwrap();
+    merged.extend(package_sources(b.finished_data()).unwrap());
+    let merged = package_build("materials", &merged, &CompileOptions::default()).unwrap();
+    assert_eq!(package_sources(merged.finished_data()).unwrap(), sources);
+}
+
+#[test]
+fn test_batch() {
+    let dir = test_dir("batch");
+    std::fs::create_dir_all(dir.join("shaders/post")).unwrap();
+    for f in &[
+        "shaders/a.hlsl",
+        "shaders/post/b.hlsl",
+        "shaders/post/c.hlsl",
+        "shaders/readme.txt",
+    ] {
+        std::fs::write(dir.join(f), "").unwrap();
+    }
+    let input = |p: &str| dir.join(p).to_string_lossy().to_string();
+
+    let files = inputs_expand(&[input("shaders")]).unwrap();
+    assert_eq!(files.len(), 3);
+    assert!(files[0].ends_with("a.hlsl"));
+
+    let files =
+        inputs_expand(&[input("shaders/post/*.hlsl"), input("shaders/post/b.hlsl")]).unwrap();
+    assert_eq!(files.len(), 2);
+    assert!(inputs_expand(&[input("shaders/*.glsl")]).is_err());
+    assert!(inputs_expand(&[input("missing.hlsl")]).is_err());
+
+    let squares = parallel_map((0..100u32).collect(), 4, |i| i * i);
+    assert_eq!(squares, (0..100u32).map(|i| i * i).collect::<Vec<_>>());
+
+    let sources = compiled_sources();
+    let written = packages_save(&dir.join("out"), &sources, &CompileOptions::default()).unwrap();
+    assert_eq!(written.len(), 2);
+    assert!(written[0].ends_with("lighting.shader_pkg"));
+    assert!(written[1].is_file());
+    let duplicate = vec![sources[1].clone(), sources[1].clone()];
+    assert!(packages_save(&dir.join("out"), &duplicate, &CompileOptions::default()).is_err());
+}
+
+#[test]
+fn test_depfile() {
+    let mut lighting = compiled_sources().remove(0);
+    lighting.includes = vec!["common/math.hlsli".into(), "common/my lights.hlsli".into()];
+    let mut blur = compiled_sources().remove(1);
+    blur.includes = vec!["common/math.hlsli".into()];
+    let lighting_path = Path::new("shaders/lighting.hlsl");
+    let blur_path = Path::new("shaders/blur.hlsl");
+
+    let rule = DepRule::new(
+        Path::new("out/materials.shader_pkg"),
+        &[(lighting_path, &lighting), (blur_path, &blur)],
+    );
+    assert_eq!(rule.dependencies.len(), 4);
+    assert_eq!(
+        depfile_format(&[rule]),
+        "out/materials.shader_pkg: \\\n  shaders/lighting.hlsl \\\n  common/math.hlsli \\\n  common/my\\ lights.hlsli \\\n  shaders/blur.hlsl\n"
+    );
+}
+
+#[test]
+fn test_manifest() {
+    let mut sources = compiled_sources();
+    sources[1].shaders[0].profile = "cs_6_0".into();
+    let options = CompileOptions {
+        compression: Compression::Lz4,
+        ..Default::default()
+    };
+    let builder = package_build("materials", &sources, &options).unwrap();
+    let manifest = Manifest::from_package(builder.finished_data());
+    assert_eq!(manifest.name, "materials");
+    assert_eq!(manifest.shaders.len(), 5);
+    let cs = &manifest.shaders[4];
+    assert_eq!(cs.source, "blur.hlsl");
+    assert_eq!(cs.entry_point, "CSMain");
+    assert_eq!(cs.shader_type, "Compute");
+    assert_eq!(cs.profile, "cs_6_0");
+    assert_eq!(cs.compression, "Lz4");
+    assert_eq!(cs.size, 3);
+    assert_eq!(cs.hash, hex_string(&shader_hash(&[8, 9, 10])));
+    assert_eq!(manifest.shaders[1].permutation_defines, vec!["USE_SHADOWS"]);
+    assert_eq!(manifest.shaders[1].arguments, vec!["-spirv", "-O3"]);
+
+    let json = manifest.to_json().unwrap();
+    assert!(json.contains("\"entry_point\": \"CSMain\""));
+
+    let dir = test_dir("manifest");
+    let package = dir.join("materials.shader_pkg");
+    std::fs::write(&package, builder.finished_data()).unwrap();
+    let path = manifest_write(&package, &[]).unwrap();
+    assert!(path.ends_with("materials.shader_pkg.json"));
+    assert_eq!(std::fs::read_to_string(path).unwrap(), json);
+
+    let warnings = diagnostics_parse(
+        "blur.hlsl:3:9: warning: implicit truncation of vector type [-Wconversion]\n",
+    );
+    manifest_write(&package, &warnings).unwrap();
+    let json = std::fs::read_to_string(manifest_path(&package)).unwrap();
+    assert!(json.contains("\"severity\": \"warning\""));
+    assert!(json.contains("implicit truncation of vector type"));
+    let report = warnings_report(&warnings).unwrap();
+    assert!(report.starts_with('['));
+    assert!(report.contains("\"file\": \"blur.hlsl\""));
+    assert!(report.contains("\"line\": 3"));
+}
 
 #[test]
 fn test_serialization() {
@@ -187,19 +1461,28 @@ float4 PSMain(PSInput input) : SV_TARGET
 	return diffuseColor * saturate(totalLight);
 }"#;
 
-    if let Ok(builder) = shader_compile(shader_text.as_bytes(), "test") {
-        let pkg = get_root_as_shader_package_flat(builder.finished_data());
+    if let Ok(builder) = shader_compile(shader_text.as_bytes(), "test", &CompileOptions::default())
+    {
+        let pkg = get_root_as_shader_package(builder.finished_data());
         let shaders = pkg.shaders().unwrap();
         assert_eq!(2, shaders.len());
         for (i, s) in shaders.iter().enumerate() {
             if i == 0 {
                 assert_eq!(s.entry_point(), Some("VSMain"));
-                assert_eq!(s.shader_type(), ShaderTypeFlat::Vertex);
+                assert_eq!(s.shader_type(), ShaderType::Vertex);
             } else if i == 1 {
                 assert_eq!(s.entry_point(), Some("PSMain"));
-                assert_eq!(s.shader_type(), ShaderTypeFlat::Pixel);
+                assert_eq!(s.shader_type(), ShaderType::Pixel);
             }
         }
+
+        // entry points compiled in parallel are packaged in the same order
+        let options = CompileOptions {
+            jobs: 4,
+            ..Default::default()
+        };
+        let parallel = shader_compile(shader_text.as_bytes(), "test", &options).unwrap();
+        assert_eq!(parallel.finished_data(), builder.finished_data());
     } else {
         assert!(false, "error compiling shader");
     }